@@ -1,19 +1,42 @@
 mod piano_roll;
 
 use pm64::bgm::*;
-use pm64::sbn::Sbn;
+use pm64::error::{ErrorKind, Report};
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+/// An error thrown to JS: an `Error` with the fields of a `Report` (`kind`, `message`, `offset`, `location`).
+pub struct Error(Report);
+
+impl<E> From<E> for Error
+where
+    Report: From<E>,
+{
+    fn from(error: E) -> Self {
+        Self(Report::from(error))
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(Error(report): Error) -> Self {
+        let error = js_sys::Error::new(&report.message);
+        js_sys::Object::assign(&error, &to_js(&report).unchecked_into());
+        error.into()
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
 
 fn to_js<T: Serialize + for<'a> Deserialize<'a>>(t: &T) -> JsValue {
     #[allow(deprecated)]
     JsValue::from_serde(t).unwrap()
 }
 
-fn from_js<T: Serialize + for<'a> Deserialize<'a>>(value: &JsValue) -> T {
+fn from_js<T: Serialize + for<'a> Deserialize<'a>>(value: &JsValue) -> Result<T> {
     #[allow(deprecated)]
-    JsValue::into_serde(value).unwrap()
+    JsValue::into_serde(value).map_err(|e| Error(Report::new(ErrorKind::InvalidInput, e)))
 }
 
 #[wasm_bindgen]
@@ -28,93 +51,274 @@ pub fn new_bgm() -> JsValue {
     to_js(&bgm)
 }
 
-#[wasm_bindgen]
-pub fn bgm_decode(data: &[u8]) -> JsValue {
+fn decode(data: &[u8]) -> Result<Bgm> {
     let mut f = Cursor::new(data);
 
     if pm64::bgm::midi::is_midi(&mut f).unwrap_or(false) {
         match pm64::bgm::midi::to_bgm(data) {
-            Ok(bgm) => to_js(&bgm),
-            Err(e) => to_js(&e.to_string()),
-        }
-    } else if data[0] == b'B' && data[1] == b'G' && data[2] == b'M' && data[3] == b' ' {
-        match Bgm::decode(&mut f) {
-            Ok(bgm) => to_js(&bgm),
-            Err(e) => {
-                log::error!("Error decoding BGM: {:?}", e);
-                to_js(&e.to_string())
+            Ok((bgm, warnings)) => {
+                for warning in &warnings {
+                    log::warn!("{}", warning);
+                }
+                Ok(bgm)
             }
+            Err(e) => Err(Error(Report::new(ErrorKind::Midi, e))),
         }
+    } else if data.starts_with(b"BGM ") {
+        Bgm::decode(&mut f).map_err(|e| {
+            log::error!("Error decoding BGM: {:?}", e);
+            e.into()
+        })
     } else {
         let input_string = String::from_utf8_lossy(data);
+        Ok(Bgm::from_ron_string(&input_string)?)
+    }
+}
 
-        match Bgm::from_ron_string(&input_string) {
-            Ok(bgm) => to_js(&bgm),
-            Err(e) => to_js(&e.to_string()),
-        }
+#[wasm_bindgen]
+pub fn bgm_decode(data: &[u8]) -> Result<JsValue> {
+    decode(data).map(|bgm| to_js(&bgm))
+}
+
+#[derive(Serialize, Deserialize)]
+struct MidiImport {
+    bgm: Bgm,
+    warnings: Vec<midi::Warning>,
+}
+
+/// Like `bgm_decode` for MIDI files, but also returns the problems found during import.
+#[wasm_bindgen]
+pub fn midi_decode(data: &[u8]) -> Result<JsValue> {
+    match midi::to_bgm(data) {
+        Ok((bgm, warnings)) => Ok(to_js(&MidiImport { bgm, warnings })),
+        Err(e) => Err(Error(Report::new(ErrorKind::Midi, e))),
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct LenientDecode {
+    bgm: Bgm,
+    errors: Vec<Report>,
+}
+
+/// Like `bgm_decode` for .bin files, but decodes as much of a broken file as it can, and also returns the errors it
+/// carried on past.
 #[wasm_bindgen]
-pub fn bgm_encode(bgm: &JsValue, ffwd_variation: usize, ffwd_time: usize) -> JsValue {
-    let mut bgm: Bgm = from_js(bgm);
+pub fn bgm_decode_lenient(data: &[u8]) -> Result<JsValue> {
+    let (bgm, errors) = Bgm::decode_lenient(&mut Cursor::new(data))?;
+    let errors = errors.into_iter().map(Report::from).collect();
+    Ok(to_js(&LenientDecode { bgm, errors }))
+}
 
+fn encode(bgm: &Bgm, ffwd_variation: usize, ffwd_time: usize) -> Result<JsValue> {
+    let mut f = Cursor::new(Vec::new());
     if ffwd_time > 0 {
+        let mut bgm = bgm.clone();
         bgm.fast_forward(ffwd_variation, ffwd_time);
         for (_, track_list) in bgm.track_lists.iter_mut() {
             for track in track_list.tracks.iter_mut() {
                 track.commands.shrink();
             }
         }
+        bgm.encode(&mut f)?;
+    } else {
+        bgm.encode(&mut f)?;
     }
-
-    let mut f = Cursor::new(Vec::new());
-    match bgm.encode(&mut f) {
-        Ok(_) => {
-            let data: Vec<u8> = f.into_inner();
-            let arr = js_sys::Uint8Array::new_with_length(data.len() as u32);
-            for (i, v) in data.into_iter().enumerate() {
-                arr.set_index(i as u32, v);
-            }
-            arr.into()
-        }
-        Err(e) => e.to_string().into(),
+    let data: Vec<u8> = f.into_inner();
+    let arr = js_sys::Uint8Array::new_with_length(data.len() as u32);
+    for (i, v) in data.into_iter().enumerate() {
+        arr.set_index(i as u32, v);
     }
+    Ok(arr.into())
 }
 
 #[wasm_bindgen]
-pub fn ron_encode(bgm: &JsValue) -> JsValue {
-    let bgm: Bgm = from_js(bgm);
+pub fn bgm_encode(bgm: &JsValue, ffwd_variation: usize, ffwd_time: usize) -> Result<JsValue> {
+    encode(&from_js(bgm)?, ffwd_variation, ffwd_time)
+}
 
-    match bgm.to_ron_string() {
-        Ok(ron) => ron.into(),
-        Err(e) => e.to_string().into(),
-    }
+#[wasm_bindgen]
+pub fn ron_encode(bgm: &JsValue) -> Result<String> {
+    let bgm: Bgm = from_js(bgm)?;
+    Ok(bgm.to_ron_string()?)
 }
 
 #[wasm_bindgen]
-pub fn sbn_decode(rom: &[u8]) -> JsValue {
-    const SBN_START: usize = 0xF00000;
-    const SBN_END: usize = SBN_START + 0xA42C40;
+pub fn sbn_decode(rom: &[u8]) -> Result<JsValue> {
+    Ok(to_js(&pm64::rom::read_sbn(rom)?))
+}
 
-    let mut f = Cursor::new(&rom[SBN_START..SBN_END]);
-    match Sbn::decode(&mut f) {
-        Ok(sbn) => to_js(&sbn),
-        Err(e) => to_js(&e.to_string()),
-    }
+#[derive(Serialize, Deserialize)]
+struct TrackSettings {
+    name: String,
+    is_disabled: bool,
+    polyphony: Polyphony,
+    is_drum_track: bool,
 }
 
+/// A song kept in wasm memory, so that editing it doesn't copy the whole song between JS and Rust.
+///
+/// Methods that change the song return an array of `Change`s for the parts of it that changed.
 #[wasm_bindgen]
-pub fn bgm_add_voice(bgm: &JsValue) -> JsValue {
-    let mut bgm: Bgm = from_js(bgm);
-    log::info!("bgm_add_voice {:?}", bgm);
-    bgm.instruments.push(Instrument::default());
-    to_js(&bgm)
+#[derive(Default)]
+pub struct BgmDocument {
+    bgm: Bgm,
+    history: edit::History,
 }
 
 #[wasm_bindgen]
-pub fn bgm_split_variation_at(bgm: &JsValue, variation: usize, time: usize) -> JsValue {
-    let mut bgm: Bgm = from_js(bgm);
-    bgm.split_variation_at(variation, time);
-    to_js(&bgm)
+impl BgmDocument {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            bgm: Bgm::new(),
+            history: edit::History::new(),
+        }
+    }
+
+    /// Opens a .bin, MIDI or RON file, like `bgm_decode`.
+    pub fn decode(data: &[u8]) -> Result<BgmDocument> {
+        let bgm = decode(data)?;
+        Ok(Self {
+            bgm,
+            history: edit::History::new(),
+        })
+    }
+
+    /// A copy of the whole song.
+    pub fn bgm(&self) -> JsValue {
+        to_js(&self.bgm)
+    }
+
+    /// A copy of one track, or `undefined` if it doesn't exist.
+    pub fn track(&self, track_list: u64, track: usize) -> JsValue {
+        match self
+            .bgm
+            .track_lists
+            .get(&track_list)
+            .and_then(|list| list.tracks.get(track))
+        {
+            Some(track) => to_js(track),
+            None => JsValue::UNDEFINED,
+        }
+    }
+
+    /// Applies an array of `Edit`s as one undoable step.
+    pub fn apply(&mut self, edits: &JsValue) -> Result<JsValue> {
+        self.edit(from_js(edits)?)
+    }
+
+    fn edit(&mut self, edits: Vec<edit::Edit>) -> Result<JsValue> {
+        let done = self.history.apply(&mut self.bgm, edits)?;
+        Ok(to_js(&edit::changes(&self.bgm, done)))
+    }
+
+    /// Inserts a command at a time in a track, as one undoable step.
+    pub fn insert_command(&mut self, track_list: u64, track: usize, time: usize, command: &JsValue) -> Result<JsValue> {
+        let command: Command = from_js(command)?;
+        let history = &mut self.history;
+        let changes = self.bgm.with_ids(|bgm| {
+            let edits = edit::Edit::insert_command(bgm, track_list, track, time, command)?;
+            history.apply(bgm, edits).map(|done| edit::changes(bgm, done))
+        })??;
+        Ok(to_js(&changes))
+    }
+
+    /// Where the event with the given id is, as an `EventLocation`, or `undefined` if there is no such event.
+    pub fn find_event(&self, id: u32) -> JsValue {
+        match self.bgm.find_event(id) {
+            Some(location) => to_js(&location),
+            None => JsValue::UNDEFINED,
+        }
+    }
+
+    /// Replaces the command of the event with the given id, wherever it is, as one undoable step.
+    pub fn set_command(&mut self, id: u32, command: &JsValue) -> Result<JsValue> {
+        let EventLocation { track_list, track, .. } = self.locate(id)?;
+        let edits = vec![edit::Edit::SetCommand {
+            track_list,
+            track,
+            id,
+            command: from_js(command)?,
+        }];
+        self.edit(edits)
+    }
+
+    /// Deletes the event with the given id, wherever it is, as one undoable step.
+    pub fn delete_event(&mut self, id: u32) -> Result<JsValue> {
+        let EventLocation { track_list, track, .. } = self.locate(id)?;
+        let edits = vec![edit::Edit::DeleteEvent { track_list, track, id }];
+        self.edit(edits)
+    }
+
+    /// Replaces the name, `is_disabled`, `polyphony` and `is_drum_track` of a track, as one undoable step.
+    pub fn set_track_settings(&mut self, track_list: u64, track: usize, settings: &JsValue) -> Result<JsValue> {
+        let TrackSettings {
+            name,
+            is_disabled,
+            polyphony,
+            is_drum_track,
+        } = from_js(settings)?;
+        let edits = vec![edit::Edit::SetTrackSettings {
+            track_list,
+            track,
+            name,
+            is_disabled,
+            polyphony,
+            is_drum_track,
+        }];
+        self.edit(edits)
+    }
+
+    fn locate(&self, id: u32) -> Result<EventLocation> {
+        self.bgm
+            .find_event(id)
+            .ok_or_else(|| edit::Error::NoEvent { id }.into())
+    }
+
+    pub fn add_voice(&mut self) -> Result<JsValue> {
+        let edits = vec![edit::Edit::InsertInstrument {
+            index: self.bgm.instruments.len(),
+            instrument: Instrument::default(),
+        }];
+        self.edit(edits)
+    }
+
+    /// Splits the variation's segment that plays at `time` in two, as one undoable step.
+    pub fn split_variation_at(&mut self, variation: usize, time: usize) -> Result<JsValue> {
+        let history = &mut self.history;
+        let changes = self.bgm.with_ids(|bgm| {
+            let edits = edit::Edit::split_variation_at(bgm, variation, time);
+            history.apply(bgm, edits).map(|done| edit::changes(bgm, done))
+        })??;
+        Ok(to_js(&changes))
+    }
+
+    pub fn undo(&mut self) -> Result<JsValue> {
+        let done = self.history.undo(&mut self.bgm)?;
+        Ok(to_js(&edit::changes(&self.bgm, done.unwrap_or_default())))
+    }
+
+    pub fn redo(&mut self) -> Result<JsValue> {
+        let done = self.history.redo(&mut self.bgm)?;
+        Ok(to_js(&edit::changes(&self.bgm, done.unwrap_or_default())))
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.history.can_undo()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.history.can_redo()
+    }
+
+    /// Like `bgm_encode`.
+    pub fn encode(&self, ffwd_variation: usize, ffwd_time: usize) -> Result<JsValue> {
+        encode(&self.bgm, ffwd_variation, ffwd_time)
+    }
+
+    /// Like `ron_encode`.
+    pub fn to_ron(&self) -> Result<String> {
+        Ok(self.bgm.to_ron_string()?)
+    }
 }
@@ -2,6 +2,7 @@ use pm64::bgm::{Command, Track};
 use std::f64;
 use wasm_bindgen::prelude::*;
 
+#[allow(unused)]
 const MIDI_PITCH_0: u8 = 107;
 const LOWEST_PITCH: u8 = 0; //MIDI_PITCH_0 + 13; // C1
 const HIGHEST_PITCH: u8 = 255; //MIDI_PITCH_0 + 120;
@@ -33,8 +34,9 @@ impl PianoRoll {
         }
     }
 
-    pub fn set_track(&mut self, track: &JsValue) {
-        self.track = crate::from_js(track);
+    pub fn set_track(&mut self, track: &JsValue) -> crate::Result<()> {
+        self.track = crate::from_js(track)?;
+        Ok(())
     }
 
     pub fn set_viewport(&mut self, width_css_px: f64, height_css_px: f64, dpr: f64) {
@@ -1,6 +1,14 @@
 // Proxy for mamar-wasm-bridge with hot reloading
 
 import type * as WasmBridgeTypes from "mamar-wasm-bridge"
+import type { Report } from "pm64-typegen"
+
+/** What bridge functions throw: an `Error` with the fields of a `Report`. */
+export type BridgeError = Error & Report
+
+export function isBridgeError(error: unknown): error is BridgeError {
+    return error instanceof Error && "kind" in error
+}
 
 let current: typeof WasmBridgeTypes | null = null
 
@@ -8,7 +16,8 @@ const bridge = new Proxy({}, {
     get(_target, prop) {
         if (!current) throw new Error("WASM bridge not loaded yet")
         const value = (current as any)[prop]
-        return typeof value === "function" ? value.bind(current) : value
+        // Classes (which have `free`) aren't bound, as a bound class loses its static methods
+        return typeof value === "function" && !value.prototype?.free ? value.bind(current) : value
     },
 }) as typeof WasmBridgeTypes
 
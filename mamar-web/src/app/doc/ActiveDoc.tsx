@@ -8,11 +8,12 @@ import SegmentMap from "./SegmentMap"
 import SubsegDetails from "./SubsegDetails"
 import TimeProvider from "./TimeProvider"
 
-import { useDoc } from "../store"
+import { useBgm, useDoc } from "../store"
 import WelcomeScreen from "../WelcomeScreen"
 
 export default function ActiveDoc() {
-    const [doc, dispatch] = useDoc()
+    const [doc] = useDoc()
+    const [, dispatch] = useBgm()
 
     const title = doc ? (doc.isSaved ? doc.name : `${doc.name} (unsaved)`) : "Mamar"
     useEffect(() => {
@@ -44,24 +45,18 @@ export default function ActiveDoc() {
 
         if (result.destination.droppableId === "trash") {
             dispatch({
-                type: "bgm",
-                action: {
-                    type: "delete_track_command",
-                    trackList: trackListId,
-                    track: trackIndex,
-                    index: result.source.index,
-                },
+                type: "delete_track_command",
+                trackList: trackListId,
+                track: trackIndex,
+                index: result.source.index,
             })
         } else {
             dispatch({
-                type: "bgm",
-                action: {
-                    type: "move_track_command",
-                    trackList: trackListId,
-                    track: trackIndex,
-                    oldIndex: result.source.index,
-                    newIndex: result.destination.index,
-                },
+                type: "move_track_command",
+                trackList: trackListId,
+                track: trackIndex,
+                oldIndex: result.source.index,
+                newIndex: result.destination.index,
             })
         }
     }
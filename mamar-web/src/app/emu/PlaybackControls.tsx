@@ -1,4 +1,5 @@
 import { ActionButton, ToggleButton, View } from "@adobe/react-spectrum"
+import type { BgmDocument } from "mamar-wasm-bridge"
 import { EmulatorControls } from "mupen64plus-web"
 import * as patches from "patches"
 import { Bgm } from "pm64-typegen"
@@ -7,7 +8,6 @@ import { Play, SkipBack } from "react-feather"
 
 import styles from "./PlaybackControls.module.scss"
 
-import Bridge from "../bridge"
 import { CONTEXT as PLAYHEAD_CONTEXT } from "../doc/Playhead"
 import { useDoc } from "../store"
 import DramView from "../util/DramView"
@@ -34,31 +34,26 @@ function writePatches(emu: EmulatorControls) {
 
 let tickTock = false
 
-function writeBgm(emu: EmulatorControls, bgm: Bgm, variation: number, startTime: number) {
+function writeBgm(emu: EmulatorControls, song: BgmDocument, bgm: Bgm, variation: number, startTime: number) {
     if (variation < 0 || variation >= bgm.variations.length) {
         return
     }
 
-    const bgmBin: Uint8Array | string = Bridge.bgm_encode(bgm, variation, startTime)
-
-    if (bgmBin instanceof Uint8Array) {
-        const dram = new DramView(emu)
+    const bgmBin: Uint8Array = song.encode(variation, startTime)
+    const dram = new DramView(emu)
 
-        if (bgmBin.length > 0x20000) {
-            throw new Error(`Encoded BGM too large, ${bgmBin.length} > 0x20000 bytes`)
-        }
+    if (bgmBin.length > 0x20000) {
+        throw new Error(`Encoded BGM too large, ${bgmBin.length} > 0x20000 bytes`)
+    }
 
-        console.log(`Writing BGM to ${patches.RAM_MAMAR_bgm.toString(16)}`)
-        dram.writeU8(patches.RAM_MAMAR_bgm, bgmBin)
-        dram.writeU32(patches.RAM_MAMAR_bgm_size, bgmBin.length)
-        dram.writeU32(patches.RAM_MAMAR_bk_files, new Uint32Array([0, 0, 0]))
-        dram.writeU32(patches.RAM_MAMAR_song_id, tickTock ? 0 : 1)
-        dram.writeU32(patches.RAM_MAMAR_song_variation, variation)
+    console.log(`Writing BGM to ${patches.RAM_MAMAR_bgm.toString(16)}`)
+    dram.writeU8(patches.RAM_MAMAR_bgm, bgmBin)
+    dram.writeU32(patches.RAM_MAMAR_bgm_size, bgmBin.length)
+    dram.writeU32(patches.RAM_MAMAR_bk_files, new Uint32Array([0, 0, 0]))
+    dram.writeU32(patches.RAM_MAMAR_song_id, tickTock ? 0 : 1)
+    dram.writeU32(patches.RAM_MAMAR_song_variation, variation)
 
-        tickTock = !tickTock
-    } else {
-        throw new Error(bgmBin)
-    }
+    tickTock = !tickTock
 }
 
 function writeAmbientSound(emu: EmulatorControls, ambientSound: number) {
@@ -68,6 +63,7 @@ function writeAmbientSound(emu: EmulatorControls, ambientSound: number) {
 
 export default function PlaybackControls() {
     const [doc, dispatch] = useDoc()
+    const song = doc?.document ?? null
     const bgm = doc?.bgm ?? null
     const activeVariation = doc?.activeVariation ?? -1
     const [isPlaying, setIsPlaying] = useState(false)
@@ -129,11 +125,11 @@ export default function PlaybackControls() {
 
     useEffect(() => {
         console.log("bgm change")
-        if (!bgm || activeVariation < 0)
+        if (!song || !bgm || activeVariation < 0)
             return
 
-        writeBgm(emu, bgm, activeVariation, playhead.position)
-    }, [emu, bgm, activeVariation, playhead])
+        writeBgm(emu, song, bgm, activeVariation, playhead.position)
+    }, [emu, song, bgm, activeVariation, playhead])
 
     useEffect(() => {
         writeAmbientSound(emu, ambientSound)
@@ -4,8 +4,8 @@ import { CSSProperties, useCallback, useEffect } from "react"
 
 import OpenButton from "./OpenButton"
 
-import Bridge from "../bridge"
 import { useDoc, useRoot } from "../store"
+import { newDoc } from "../store/root"
 
 function createBgmFileName(fileName: string) {
     // Remove supported extension
@@ -32,12 +32,8 @@ export default function BgmActionGroup() {
             return
         }
 
-        const bgmBin: Uint8Array<ArrayBuffer> | string = Bridge.bgm_encode(doc.bgm, 0, 0)
-
-        if (typeof bgmBin === "string") {
-            // TODO: surface error in a dialog
-            throw new Error(bgmBin)
-        }
+        // TODO: surface errors in a dialog
+        const bgmBin: Uint8Array<ArrayBuffer> = doc.document.encode(0, 0)
 
         const fileHandle = await fileSave(new Blob([bgmBin]), {
             fileName: createBgmFileName(doc.name),
@@ -48,7 +44,7 @@ export default function BgmActionGroup() {
         // If it was saved as .ron, overwrite the file contents (currently BGM) with the RON
         if (fileHandle?.name.endsWith(".ron")) {
             const writable = await fileHandle.createWritable({ keepExistingData: false })
-            await writable.write(Bridge.ron_encode(doc.bgm))
+            await writable.write(doc.document.to_ron())
             await writable.close()
         }
 
@@ -76,7 +72,7 @@ export default function BgmActionGroup() {
         "WebkitAppRegion": "no-drag",
     } as CSSProperties}>
         <ActionButton
-            onPress={() => dispatch({ type: "open_doc" })}
+            onPress={() => dispatch(newDoc())}
             {...props}
         >New</ActionButton>
         <OpenButton />
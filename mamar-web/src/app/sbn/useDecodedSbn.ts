@@ -1,4 +1,4 @@
-import { Sbn } from "pm64-typegen"
+import { Report, Sbn } from "pm64-typegen"
 import { useEffect, useState } from "react"
 
 export default function useDecodedSbn(romData: ArrayBuffer): Sbn | null {
@@ -11,14 +11,14 @@ export default function useDecodedSbn(romData: ArrayBuffer): Sbn | null {
 
         new Promise<Sbn | Error>(resolve => {
             worker.addEventListener("message", evt => {
-                const data = evt.data as Sbn | string
+                const data = evt.data as "READY" | { sbn: Sbn } | { error: Report }
 
                 if (data === "READY") {
                     worker.postMessage(romData)
-                } else if (typeof data === "string") {
-                    resolve(new Error(data))
+                } else if ("error" in data) {
+                    resolve(Object.assign(new Error(data.error.message), data.error))
                 } else {
-                    resolve(data)
+                    resolve(data.sbn)
                 }
             })
         }).then(sbn => {
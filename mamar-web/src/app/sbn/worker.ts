@@ -1,4 +1,4 @@
-import Bridge, { ensureBridge } from "../bridge"
+import Bridge, { BridgeError, ensureBridge } from "../bridge"
 
 await ensureBridge()
 Bridge.init_logging?.()
@@ -6,6 +6,11 @@ postMessage("READY")
 
 onmessage = evt => {
     const romData = evt.data as ArrayBuffer
-    const sbn = Bridge.sbn_decode(new Uint8Array(romData))
-    postMessage(sbn)
+    try {
+        postMessage({ sbn: Bridge.sbn_decode(new Uint8Array(romData)) })
+    } catch (error) {
+        // Posting an Error drops its extra fields, so post them on their own
+        const { kind, message, offset, location } = error as BridgeError
+        postMessage({ error: { kind, message, offset, location } })
+    }
 }
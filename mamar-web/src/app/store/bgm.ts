@@ -1,12 +1,11 @@
 import produce, { setAutoFreeze } from "immer"
-import { Bgm, Event, Instrument, Polyphony } from "pm64-typegen"
+import type { BgmDocument } from "mamar-wasm-bridge"
+import { Bgm, Change, Edit, Event, Instrument, Polyphony, Segment } from "pm64-typegen"
 import { arrayMove } from "react-movable"
 
 import { useDoc } from "./doc"
 import { VariationAction, variationReducer } from "./variation"
 
-import Bridge from "../bridge"
-
 // Freezing react-tracked proxies can cause proxy invariant errors
 setAutoFreeze(false)
 
@@ -50,74 +49,113 @@ export type BgmAction = {
     time: number
 }
 
-export function bgmReducer(bgm: Bgm, action: BgmAction): Bgm {
+function replaceSegments(variation: number, from: Segment[], to: Segment[]): Edit[] {
+    if (from.length === to.length) {
+        return to.flatMap((segment, index) => segment === from[index] ? [] : [{ SetSegment: { variation, index, segment } }])
+    }
+    return [
+        ...from.map(() => ({ DeleteSegment: { variation, index: 0 } })),
+        ...to.map((segment, index) => ({ InsertSegment: { variation, index, segment } })),
+    ]
+}
+
+/** The edits that make an action happen to the document, or `null` if the document has a method for it. */
+function actionEdits(bgm: Bgm, action: BgmAction): Edit[] | null {
     switch (action.type) {
     case "variation": {
-        const applyVariation = (index: number) => {
-            const variation = bgm.variations[index]
-            if (index === action.index && variation) {
-                return variationReducer(variation, action.action)
-            } else {
-                return variation
-            }
-        }
-        return {
-            ...bgm,
-            variations: [
-                applyVariation(0),
-                applyVariation(1),
-                applyVariation(2),
-                applyVariation(3),
-            ],
-        }
-    } case "add_voice":
-        return Bridge.bgm_add_voice(bgm)
-    case "move_track_command":
-        return produce(bgm, draft => {
-            const track = draft.track_lists[action.trackList].tracks[action.track]
-            track.commands = arrayMove(track.commands, action.oldIndex, action.newIndex)
+        const variation = bgm.variations[action.index]
+        if (!variation) return []
+        const edited = variationReducer(variation, action.action)
+        return replaceSegments(action.index, variation.segments, edited.segments)
+    } case "move_track_command": {
+        if (action.oldIndex === action.newIndex) return []
+        const commands = bgm.track_lists[action.trackList].tracks[action.track].commands
+        const event = commands[action.oldIndex]
+        const moved = arrayMove(commands, action.oldIndex, action.newIndex)
+        const after = action.newIndex > 0 ? moved[action.newIndex - 1].id : null
+        return [
+            { DeleteEvent: { track_list: action.trackList, track: action.track, id: event.id } },
+            { InsertEvent: { track_list: action.trackList, track: action.track, after, event } },
+        ]
+    } case "update_instrument":
+        return [{
+            SetInstrument: {
+                index: action.index,
+                instrument: { ...bgm.instruments[action.index], ...action.partial },
+            },
+        }]
+    default:
+        return null
+    }
+}
+
+/**
+ * Makes an action happen to the document, returning the `Change`s to apply to `bgm`, its copy of the song. Each
+ * action is one step in the document's undo history.
+ */
+export function editBgm(document: BgmDocument, bgm: Bgm, action: BgmAction): Change[] {
+    const edits = actionEdits(bgm, action)
+    if (edits) {
+        return edits.length > 0 ? document.apply(edits) : []
+    }
+
+    switch (action.type) {
+    case "add_voice":
+        return document.add_voice()
+    case "update_track_command": {
+        const { id, ...command } = action.command
+        return document.set_command(id, command)
+    } case "delete_track_command": {
+        const event = bgm.track_lists[action.trackList].tracks[action.track].commands[action.index]
+        return document.delete_event(event.id)
+    } case "modify_track_settings": {
+        const track = bgm.track_lists[action.trackList].tracks[action.track]
+        return document.set_track_settings(action.trackList, action.track, {
+            name: action.name ?? track.name ?? "",
+            is_disabled: action.isDisabled ?? track.is_disabled,
+            polyphony: action.polyphony ?? track.polyphony,
+            is_drum_track: action.isDrumTrack ?? track.is_drum_track,
         })
-    case "update_track_command":
-        return produce(bgm, draft => {
-            const track = draft.track_lists[action.trackList].tracks[action.track]
-            for (let i = 0; i < track.commands.length; i++) {
-                if (track.commands[i].id === action.command.id) {
-                    track.commands[i] = action.command
+    } case "split_variation":
+        return document.split_variation_at(action.variation, action.time)
+    default:
+        return []
+    }
+}
+
+/** Updates a copy of a song with the `Change`s returned by a `BgmDocument`. */
+export function applyChanges(bgm: Bgm, changes: Change[]): Bgm {
+    for (const change of changes) {
+        if ("Bgm" in change) {
+            bgm = change.Bgm
+            continue
+        }
+        bgm = produce(bgm, draft => {
+            if ("Track" in change) {
+                const { track_list, track, value } = change.Track
+                draft.track_lists[track_list].tracks[track] = value
+            } else if ("TrackList" in change) {
+                const { id, value } = change.TrackList
+                if (value) {
+                    draft.track_lists[id] = value
+                } else {
+                    delete draft.track_lists[id]
                 }
+            } else if ("Variation" in change) {
+                draft.variations[change.Variation.index] = change.Variation.value
+            } else if ("Instruments" in change) {
+                draft.instruments = change.Instruments
             }
         })
-    case "delete_track_command":
-        return produce(bgm, draft => {
-            const track = draft.track_lists[action.trackList].tracks[action.track]
-            track.commands.splice(action.index, 1)
-        })
-    case "modify_track_settings":
-        return produce(bgm, draft => {
-            const track = draft.track_lists[action.trackList].tracks[action.track]
-            if (action.name !== undefined) {
-                track.name = action.name
-            }
-            if (action.isDisabled !== undefined) {
-                track.is_disabled = action.isDisabled
-            }
-            if (action.polyphony !== undefined) {
-                track.polyphony = action.polyphony
-            }
-            if (action.isDrumTrack !== undefined) {
-                track.is_drum_track = action.isDrumTrack
-            }
-        })
-    case "update_instrument":
-        return produce(bgm, draft => {
-            const instrument = draft.instruments[action.index]
-            Object.assign(instrument, action.partial)
-        })
-    case "split_variation":
-        return Bridge.bgm_split_variation_at(bgm, action.variation, action.time)
     }
+    return bgm
 }
 
 export const useBgm = (docId?: string): [Bgm | undefined, (action: BgmAction) => void] => {
     const [doc, dispatch] = useDoc(docId)
-    return [doc?.bgm, action => dispatch({ type: "bgm", action })]
+    return [doc?.bgm, action => {
+        if (doc) {
+            dispatch({ type: "apply_changes", changes: editBgm(doc.document, doc.bgm, action) })
+        }
+    }]
 }
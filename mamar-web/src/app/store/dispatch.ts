@@ -1,6 +1,8 @@
+import { Change } from "pm64-typegen"
+import { useState } from "react"
 import { createContainer } from "react-tracked"
-import useUndoable from "use-undoable"
 
+import type { Doc } from "./doc"
 import { Root, RootAction, rootReducer } from "./root"
 
 interface Dispatch {
@@ -11,17 +13,6 @@ interface Dispatch {
     canRedo: boolean
 }
 
-function shouldActionCommitToHistory(action: RootAction): boolean {
-    switch (action.type) {
-    case "doc":
-        switch (action.action.type) {
-        case "bgm":
-            return true
-        }
-    }
-    return false
-}
-
 interface Action {
     type: string
     action?: Action
@@ -39,32 +30,38 @@ const {
     Provider,
     useTracked,
 } = createContainer(() => {
-    const [state, setState, { undo, redo, canUndo, canRedo }] = useUndoable<Root>({
+    const [state, setState] = useState<Root>({
         docs: {},
-    }, {
-        behavior: "destroyFuture", // "mergePastReversed",
-        historyLimit: 100,
     })
 
     const dispatch: Dispatch = (...actions) => {
         console.info("dispatch", actions.map(action => joinActionTypes(action)), actions)
-        setState(
-            prevState => {
-                let newState = prevState
-                for (const action of actions) {
-                    newState = rootReducer(newState, action)
-                }
-                console.log("new state", newState)
-                return newState
-            },
-            undefined,
-            actions.map(action => !shouldActionCommitToHistory(action)).reduce((a, b) => a && b, false),
-        )
+        for (const action of actions) {
+            if (action.type === "close_doc") {
+                state.docs[action.id]?.document.free()
+            }
+        }
+        setState(prevState => {
+            let newState = prevState
+            for (const action of actions) {
+                newState = rootReducer(newState, action)
+            }
+            console.log("new state", newState)
+            return newState
+        })
+    }
+
+    // Undo history is kept by each doc's BgmDocument
+    const activeDoc = state.activeDocId ? state.docs[state.activeDocId] : undefined
+    const applyToActiveDoc = (edit: (doc: Doc) => Change[]) => {
+        if (activeDoc) {
+            dispatch({ type: "doc", id: activeDoc.id, action: { type: "apply_changes", changes: edit(activeDoc) } })
+        }
     }
-    dispatch.undo = undo
-    dispatch.redo = redo
-    dispatch.canUndo = canUndo
-    dispatch.canRedo = canRedo
+    dispatch.undo = () => applyToActiveDoc(doc => doc.document.undo())
+    dispatch.redo = () => applyToActiveDoc(doc => doc.document.redo())
+    dispatch.canUndo = activeDoc?.document.can_undo() ?? false
+    dispatch.canRedo = activeDoc?.document.can_redo() ?? false
 
     return [state, dispatch]
 })
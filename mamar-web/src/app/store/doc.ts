@@ -1,6 +1,7 @@
-import { Bgm } from "pm64-typegen"
+import type { BgmDocument } from "mamar-wasm-bridge"
+import { Bgm, Change } from "pm64-typegen"
 
-import { BgmAction, bgmReducer } from "./bgm"
+import { applyChanges } from "./bgm"
 import { useRoot } from "./dispatch"
 
 export type PanelContent = {
@@ -14,6 +15,9 @@ export type PanelContent = {
 
 export interface Doc {
     id: string
+    /** The song, which every edit goes through so that it can be undone. */
+    document: BgmDocument
+    /** A copy of the song in `document`, kept up to date by the changes its methods return. */
     bgm: Bgm
     fileHandle?: FileSystemFileHandle
     name: string
@@ -23,8 +27,8 @@ export interface Doc {
 }
 
 export type DocAction = {
-    type: "bgm"
-    action: BgmAction
+    type: "apply_changes"
+    changes: Change[]
 } | {
     type: "mark_saved"
     fileHandle?: FileSystemFileHandle | null
@@ -38,10 +42,13 @@ export type DocAction = {
 
 export function docReducer(state: Doc, action: DocAction): Doc {
     switch (action.type) {
-    case "bgm":
+    case "apply_changes":
+        if (action.changes.length === 0) {
+            return state
+        }
         return {
             ...state,
-            bgm: bgmReducer(state.bgm, action.action),
+            bgm: applyChanges(state.bgm, action.changes),
             isSaved: false,
         }
     case "mark_saved":
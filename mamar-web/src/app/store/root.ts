@@ -1,5 +1,5 @@
 import { FileWithHandle } from "browser-fs-access"
-import { Bgm } from "pm64-typegen"
+import type { BgmDocument } from "mamar-wasm-bridge"
 
 import { Doc, DocAction, docReducer } from "./doc"
 
@@ -25,7 +25,7 @@ export type RootAction = {
     type: "open_doc"
     file?: FileWithHandle
     name?: string
-    bgm?: Bgm
+    document: BgmDocument
 } | {
     type: "close_doc"
     id: string
@@ -51,7 +51,8 @@ export function rootReducer(root: Root, action: RootAction): Root {
         const saveSupported = fileExtension === "bgm" || fileExtension === "ron"
         const newDoc: Doc = {
             id: generateId(),
-            bgm: action.bgm ?? Bridge.new_bgm(),
+            document: action.document,
+            bgm: action.document.bgm(),
             fileHandle: saveSupported ? action.file?.handle : undefined,
             name: action.name || action.file?.name || "New song",
             isSaved: saveSupported,
@@ -84,31 +85,30 @@ export function rootReducer(root: Root, action: RootAction): Root {
     }
 }
 
+export function newDoc(): RootAction {
+    return {
+        type: "open_doc",
+        document: new Bridge.BgmDocument(),
+    }
+}
+
 export async function openFile(file: FileWithHandle): Promise<RootAction> {
     const data = new Uint8Array(await file.arrayBuffer())
-    const bgm: Bgm | string = Bridge.bgm_decode(data)
-
-    if (typeof bgm === "string") {
-        throw new Error(bgm)
-    }
+    const document = Bridge.BgmDocument.decode(data)
 
     return {
         type: "open_doc",
         file,
-        bgm,
+        document,
     }
 }
 
 export function openData(data: Uint8Array, name?: string): RootAction {
-    const bgm: Bgm | string = Bridge.bgm_decode(data)
-
-    if (typeof bgm === "string") {
-        throw new Error(bgm)
-    }
+    const document = Bridge.BgmDocument.decode(data)
 
     return {
         type: "open_doc",
         name,
-        bgm,
+        document,
     }
 }
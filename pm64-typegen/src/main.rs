@@ -1,8 +1,11 @@
-use pm64::bgm::Bgm;
+use pm64::bgm::edit::{Change, Edit};
+use pm64::bgm::midi::Warning;
+use pm64::bgm::{Bgm, EventLocation};
+use pm64::error::Report;
 use pm64::sbn::Sbn;
 use typescript_type_def::*;
 
-type Api = (Bgm, Sbn);
+type Api = (Bgm, Sbn, Warning, Edit, Change, Report, EventLocation);
 
 fn main() {
     let path = concat!(env!("CARGO_MANIFEST_DIR"), "/pm64.d.ts");
@@ -19,11 +22,5 @@ fn main() {
         .unwrap();
     }
 
-    // typescript_type_def doesn't handle flattened enums very well, so patch to fix CommandSeq
-    // replace instances of `"commands": CommandSeq` in file with `"commands": Event[]`
-    let content = std::fs::read_to_string(path).unwrap();
-    let content = content.replace(r#""commands": CommandSeq"#, r#""commands": Event[]"#);
-    std::fs::write(path, content).unwrap();
-
     println!("Wrote type definitions to {}", path);
 }
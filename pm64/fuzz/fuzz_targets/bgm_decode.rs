@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use pm64::bgm::Bgm;
+
+fuzz_target!(|data: &[u8]| {
+    // Songs that decode can be re-encoded, which mustn't panic either
+    if let Ok(bgm) = Bgm::from_bytes(data) {
+        let _ = bgm.as_bytes();
+    }
+    if let Ok((bgm, _)) = Bgm::decode_lenient(&mut Cursor::new(data)) {
+        let _ = bgm.as_bytes();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pm64::bgm::midi;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((bgm, _)) = midi::to_bgm(data) {
+        let _ = bgm.as_bytes();
+    }
+});
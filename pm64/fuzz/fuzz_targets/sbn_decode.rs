@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pm64::sbn::Sbn;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Sbn::from_bytes(data);
+});
@@ -0,0 +1,1004 @@
+use std::fmt::{self, Write as _};
+
+use super::*;
+use crate::id::{IdAllocator, OutOfIds, gen_id};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Line the error was found on, starting from 1.
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnterminatedString,
+    UnknownDirective(String),
+    UnknownMnemonic(String),
+    UnknownAttribute(String),
+    WrongArgCount {
+        expected: usize,
+        found: usize,
+    },
+    InvalidNumber(String),
+    InvalidArgument(String),
+    /// A command or label was found outside of a section that can hold it.
+    OutsideSection,
+    InvalidVariation(usize),
+    InvalidTrack(usize),
+    DuplicateTrackList(TrackListId),
+    OutOfIds,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnterminatedString => write!(f, "Missing closing quote"),
+            ErrorKind::UnknownDirective(name) => write!(f, "Unknown directive: .{}", name),
+            ErrorKind::UnknownMnemonic(name) => write!(f, "Unknown command: {}", name),
+            ErrorKind::UnknownAttribute(name) => write!(f, "Unknown attribute: {}", name),
+            ErrorKind::WrongArgCount { expected, found } => {
+                write!(f, "Expected {} arguments, but found {}", expected, found)
+            }
+            ErrorKind::InvalidNumber(s) => write!(f, "Invalid number: {}", s),
+            ErrorKind::InvalidArgument(s) => write!(f, "Invalid argument: {}", s),
+            ErrorKind::OutsideSection => write!(f, "Commands must come after a .variation, .track or .unknown"),
+            ErrorKind::InvalidVariation(index) => write!(f, "Variation {} does not exist (there are 4)", index),
+            ErrorKind::InvalidTrack(index) => write!(f, "Track {} does not exist (there are 16)", index),
+            ErrorKind::DuplicateTrackList(id) => write!(f, "Track list {} is defined more than once", id),
+            ErrorKind::OutOfIds => write!(f, "{}", OutOfIds),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Bgm {
+    /// Writes this song in the text assembly format. See [Bgm::from_asm_string].
+    pub fn to_asm_string(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(out, ".name {}", quote(&self.name)).unwrap();
+
+        for (index, variation) in self.variations.iter().enumerate() {
+            let Some(variation) = variation else {
+                continue;
+            };
+
+            writeln!(out, "\n.variation {}", index).unwrap();
+            for segment in &variation.segments {
+                writeln!(out, "    {}", segment_text(segment)).unwrap();
+            }
+        }
+
+        if !self.drums.is_empty() || !self.instruments.is_empty() {
+            writeln!(out).unwrap();
+        }
+        for drum in &self.drums {
+            writeln!(out, ".drum {}", drum_attributes(drum)).unwrap();
+        }
+        for instrument in &self.instruments {
+            writeln!(out, ".instrument {}", instrument_attributes(instrument)).unwrap();
+        }
+
+        for (id, track_list) in &self.track_lists {
+            match track_list.pos {
+                Some(pos) => writeln!(out, "\n.track_list {} at={:#X}", id, pos).unwrap(),
+                None => writeln!(out, "\n.track_list {}", id).unwrap(),
+            }
+
+            for (index, track) in track_list.tracks.iter().enumerate() {
+                if is_default_track(track) {
+                    continue;
+                }
+
+                write!(out, ".track {}", index).unwrap();
+                if !track.name.is_empty() {
+                    write!(out, " name={}", quote(&track.name)).unwrap();
+                }
+                if track.is_disabled {
+                    write!(out, " disabled").unwrap();
+                }
+                if track.is_drum_track {
+                    write!(out, " drums").unwrap();
+                }
+                match track.polyphony {
+                    Polyphony::Automatic => write!(out, " polyphony=auto").unwrap(),
+                    Polyphony::Manual { voices } => write!(out, " polyphony=voices:{}", voices).unwrap(),
+                    Polyphony::Link { parent } => write!(out, " polyphony=link:{}", parent).unwrap(),
+                    Polyphony::Other { priority } => write!(out, " polyphony=priority:{}", priority).unwrap(),
+                }
+                if let Some(pos) = track.pos {
+                    write!(out, " at={:#X}", pos).unwrap();
+                }
+                writeln!(out).unwrap();
+
+                for event in track.commands.iter() {
+                    write_command(&mut out, &event.command);
+                }
+            }
+        }
+
+        for unknown in &self.unknowns {
+            writeln!(out, "\n.unknown {:#X}", unknown.start).unwrap();
+            for chunk in unknown.data.chunks(16) {
+                let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                writeln!(out, "    bytes {}", bytes.join(" ")).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// Parses the text assembly format written by [Bgm::to_asm_string]. For example:
+    ///
+    /// ```text
+    /// .name "117 "
+    ///
+    /// .variation 0
+    ///     subseg 1
+    ///
+    /// .instrument bank_set=Music bank=1 instrument=3 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+    ///
+    /// .track_list 1 at=0x54
+    /// .track 0 polyphony=voices:0
+    ///     master_tempo 120
+    ///     delay 48
+    ///     end
+    /// .track 1 name="Melody" polyphony=auto at=0xA4
+    ///     set_track_voice 0
+    /// intro:
+    ///     note 140 100 24
+    ///     delay 24
+    /// "intro end":
+    ///     detour intro "intro end"
+    ///     end
+    /// ```
+    ///
+    /// Each command is written on its own line as the snake_case name of its [Command] variant followed by its fields in
+    /// order. [Markers](Command::Marker) are written as labels, quoted if they are not a plain identifier. Numbers may be
+    /// decimal or `0x`-prefixed hex, and `;` starts a comment.
+    pub fn from_asm_string(source: &str) -> Result<Self, Error> {
+        IdAllocator::new()
+            .scope(|| Self::parse_asm(source))
+            .unwrap_or_else(|OutOfIds| {
+                Err(Error {
+                    line: source.lines().count(),
+                    kind: ErrorKind::OutOfIds,
+                })
+            })
+    }
+
+    fn parse_asm(source: &str) -> Result<Self, Error> {
+        let mut parser = Parser {
+            bgm: Bgm {
+                name: String::new(),
+                ..Default::default()
+            },
+            section: Section::None,
+            track_list: None,
+        };
+
+        for (index, line) in source.lines().enumerate() {
+            parser
+                .parse_line(line)
+                .map_err(|kind| Error { line: index + 1, kind })?;
+        }
+
+        Ok(parser.bgm)
+    }
+}
+
+fn is_default_track(track: &Track) -> bool {
+    let default = Track::default();
+    track.commands.is_empty()
+        && track.name == default.name
+        && track.is_disabled == default.is_disabled
+        && track.polyphony == default.polyphony
+        && track.is_drum_track == default.is_drum_track
+}
+
+fn patch_attributes(patch: &PatchAddress) -> String {
+    format!(
+        "bank_set={:?} bank={} instrument={} envelope={}",
+        patch.bank_set, patch.bank, patch.instrument, patch.envelope
+    )
+}
+
+pub(super) fn segment_text(segment: &Segment) -> String {
+    match segment {
+        Segment::Subseg { track_list, .. } => format!("subseg {}", track_list),
+        Segment::StartLoop { label_index, .. } => format!("start_loop {}", label_index),
+        Segment::Wait { .. } => "wait".to_owned(),
+        Segment::EndLoop {
+            label_index,
+            iter_count,
+            ..
+        } => format!("end_loop {} {}", label_index, iter_count),
+        Segment::Unknown6 {
+            label_index,
+            iter_count,
+            ..
+        } => format!("unknown6 {} {}", label_index, iter_count),
+        Segment::Unknown7 {
+            label_index,
+            iter_count,
+            ..
+        } => format!("unknown7 {} {}", label_index, iter_count),
+    }
+}
+
+pub(super) fn drum_attributes(drum: &Drum) -> String {
+    let mut attributes = format!(
+        "{} coarse_tune={} fine_tune={} volume={} pan={} reverb={} rand_tune={} rand_volume={} rand_pan={} \
+         rand_reverb={}",
+        patch_attributes(&drum.patch),
+        drum.coarse_tune,
+        drum.fine_tune,
+        drum.volume,
+        drum.pan,
+        drum.reverb,
+        drum.rand_tune,
+        drum.rand_volume,
+        drum.rand_pan,
+        drum.rand_reverb,
+    );
+    if drum.pad_0b != 0 {
+        write!(attributes, " pad_0b={}", drum.pad_0b).unwrap();
+    }
+    attributes
+}
+
+pub(super) fn instrument_attributes(instrument: &Instrument) -> String {
+    let mut attributes = format!(
+        "{} volume={} pan={} reverb={} coarse_tune={} fine_tune={}",
+        patch_attributes(&instrument.patch),
+        instrument.volume,
+        instrument.pan,
+        instrument.reverb,
+        instrument.coarse_tune,
+        instrument.fine_tune,
+    );
+    if instrument.pad_07 != 0 {
+        write!(attributes, " pad_07={}", instrument.pad_07).unwrap();
+    }
+    attributes
+}
+
+fn write_command(out: &mut String, command: &Command) {
+    match command {
+        Command::Marker { .. } => writeln!(out, "{}", command_text(command)).unwrap(),
+        _ => writeln!(out, "    {}", command_text(command)).unwrap(),
+    }
+}
+
+/// A command as it is written in a listing. [Markers](Command::Marker) are written as labels.
+pub(super) fn command_text(command: &Command) -> String {
+    match command {
+        Command::Marker { label } => format!("{}:", quote_label(label)),
+        Command::End => "end".to_owned(),
+        Command::Delay(ticks) => format!("delay {}", ticks),
+        Command::Note {
+            pitch,
+            velocity,
+            length,
+        } => format!("note {} {} {}", pitch, velocity, length),
+        Command::MasterTempo(bpm) => format!("master_tempo {}", bpm),
+        Command::MasterVolume(volume) => format!("master_volume {}", volume),
+        Command::MasterPitchShift { cent } => format!("master_pitch_shift {}", cent),
+        Command::UnkCmdE3 { effect_type } => format!("unk_cmd_e3 {}", effect_type),
+        Command::MasterTempoFade { time, value } => format!("master_tempo_fade {} {}", time, value),
+        Command::MasterVolumeFade { time, volume } => format!("master_volume_fade {} {}", time, volume),
+        Command::MasterEffect { index, value } => format!("master_effect {} {}", index, value),
+        Command::TrackOverridePatch(patch) => format!(
+            "track_override_patch {:?} {} {} {}",
+            patch.bank_set, patch.bank, patch.instrument, patch.envelope
+        ),
+        Command::SubTrackVolume(volume) => format!("sub_track_volume {}", volume),
+        Command::SubTrackPan(pan) => format!("sub_track_pan {}", pan),
+        Command::SubTrackReverb(reverb) => format!("sub_track_reverb {}", reverb),
+        Command::SegTrackVolume(volume) => format!("seg_track_volume {}", volume),
+        Command::SubTrackCoarseTune(tune) => format!("sub_track_coarse_tune {}", tune),
+        Command::SubTrackFineTune(tune) => format!("sub_track_fine_tune {}", tune),
+        Command::SegTrackTune { bend } => format!("seg_track_tune {}", bend),
+        Command::TrackTremolo { amount, speed, time } => format!("track_tremolo {} {} {}", amount, speed, time),
+        Command::TrackTremoloSpeed(speed) => format!("track_tremolo_speed {}", speed),
+        Command::TrackTremoloTime { time } => format!("track_tremolo_time {}", time),
+        Command::TrackTremoloStop => "track_tremolo_stop".to_owned(),
+        Command::UnkCmdF4 { pan0, pan1 } => format!("unk_cmd_f4 {} {}", pan0, pan1),
+        Command::SetTrackVoice { index } => format!("set_track_voice {}", index),
+        Command::TrackVolumeFade { time, value } => format!("track_volume_fade {} {}", time, value),
+        Command::SubTrackReverbType { index } => format!("sub_track_reverb_type {}", index),
+        Command::Jump { unk_00, unk_02 } => format!("jump {:#X} {}", unk_00, unk_02),
+        Command::EventTrigger { event_info } => format!("event_trigger {:#X}", event_info),
+        Command::Detour { start_label, end_label } => {
+            format!("detour {} {}", quote_label(start_label), quote_label(end_label))
+        }
+        Command::UnkCmdFF { unk_00, unk_01, unk_02 } => format!("unk_cmd_ff {} {} {}", unk_00, unk_01, unk_02),
+    }
+}
+
+pub(super) fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn quote_label(label: &str) -> String {
+    let is_identifier = label
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_identifier { label.to_owned() } else { quote(label) }
+}
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+
+    /// Whether the token ended with an unquoted `:`, which is not included in `text`.
+    is_label: bool,
+}
+
+/// Splits a line into whitespace-separated tokens, removing quotes and comments.
+fn tokenize(line: &str) -> Result<Vec<Token>, ErrorKind> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek() {
+            None | Some(';') => return Ok(tokens),
+            Some(_) => {}
+        }
+
+        let mut token = Token {
+            text: String::new(),
+            is_label: false,
+        };
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ';') {
+            if c == '"' {
+                loop {
+                    match chars.next() {
+                        None => return Err(ErrorKind::UnterminatedString),
+                        Some('"') => break,
+                        Some('\\') => token.text.push(chars.next().ok_or(ErrorKind::UnterminatedString)?),
+                        Some(c) => token.text.push(c),
+                    }
+                }
+            } else if c == ':' && chars.peek().is_none_or(|c| c.is_whitespace() || *c == ';') {
+                token.is_label = true;
+            } else {
+                token.text.push(c);
+            }
+        }
+        tokens.push(token);
+    }
+}
+
+fn parse_int<T: TryFrom<i64>>(s: &str) -> Result<T, ErrorKind> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse(),
+    }
+    .map_err(|_| ErrorKind::InvalidNumber(s.to_owned()))?;
+
+    T::try_from(if negative { -value } else { value }).map_err(|_| ErrorKind::InvalidNumber(s.to_owned()))
+}
+
+fn parse_bank_set(s: &str) -> Result<BankSetIndex, ErrorKind> {
+    (0..8)
+        .filter_map(|i| BankSetIndex::try_from(i).ok())
+        .find(|bank_set| format!("{:?}", bank_set) == s)
+        .ok_or_else(|| ErrorKind::InvalidArgument(s.to_owned()))
+}
+
+fn parse_polyphony(s: &str) -> Result<Polyphony, ErrorKind> {
+    if s == "auto" {
+        return Ok(Polyphony::Automatic);
+    }
+    match s.split_once(':') {
+        Some(("voices", voices)) => Ok(Polyphony::Manual {
+            voices: parse_int(voices)?,
+        }),
+        Some(("link", parent)) => Ok(Polyphony::Link {
+            parent: parse_int(parent)?,
+        }),
+        Some(("priority", priority)) => Ok(Polyphony::Other {
+            priority: parse_int(priority)?,
+        }),
+        _ => Err(ErrorKind::InvalidArgument(s.to_owned())),
+    }
+}
+
+/// Arguments following a mnemonic or directive.
+struct Args<'a>(&'a [Token]);
+
+impl Args<'_> {
+    fn expect(&self, count: usize) -> Result<(), ErrorKind> {
+        if self.0.len() == count {
+            Ok(())
+        } else {
+            Err(ErrorKind::WrongArgCount {
+                expected: count,
+                found: self.0.len(),
+            })
+        }
+    }
+
+    fn int<T: TryFrom<i64>>(&self, index: usize) -> Result<T, ErrorKind> {
+        parse_int(&self.0[index].text)
+    }
+
+    fn text(&self, index: usize) -> String {
+        self.0[index].text.clone()
+    }
+
+    /// Iterates over `key=value` (or just `key`) attributes.
+    fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|token| token.text.split_once('=').unwrap_or((token.text.as_str(), "")))
+    }
+}
+
+enum Section {
+    None,
+    Variation(usize),
+    Track(TrackListId, usize),
+    Unknown(usize),
+}
+
+struct Parser {
+    bgm: Bgm,
+    section: Section,
+
+    /// The track list that `.track` directives refer to.
+    track_list: Option<TrackListId>,
+}
+
+impl Parser {
+    fn parse_line(&mut self, line: &str) -> Result<(), ErrorKind> {
+        let tokens = tokenize(line)?;
+        let Some((first, rest)) = tokens.split_first() else {
+            return Ok(());
+        };
+        let args = Args(rest);
+
+        if first.is_label {
+            args.expect(0)?;
+            return self.push_command(Command::Marker {
+                label: first.text.clone(),
+            });
+        }
+
+        if let Some(directive) = first.text.strip_prefix('.') {
+            return self.parse_directive(directive, args);
+        }
+
+        match self.section {
+            Section::None => Err(ErrorKind::OutsideSection),
+            Section::Variation(index) => {
+                let segment = parse_segment(&first.text, args)?;
+                self.bgm.variations[index]
+                    .get_or_insert_with(|| Variation { segments: Vec::new() })
+                    .segments
+                    .push(segment);
+                Ok(())
+            }
+            Section::Track(..) => self.push_command(parse_command(&first.text, args)?),
+            Section::Unknown(index) => {
+                if first.text != "bytes" {
+                    return Err(ErrorKind::UnknownMnemonic(first.text.clone()));
+                }
+                let unknown = &mut self.bgm.unknowns[index];
+                for token in args.0 {
+                    let byte = u8::from_str_radix(&token.text, 16)
+                        .map_err(|_| ErrorKind::InvalidNumber(token.text.clone()))?;
+                    unknown.data.push(byte);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn parse_directive(&mut self, directive: &str, args: Args) -> Result<(), ErrorKind> {
+        match directive {
+            "name" => {
+                args.expect(1)?;
+                self.bgm.name = args.text(0);
+                self.section = Section::None;
+            }
+            "variation" => {
+                args.expect(1)?;
+                let index: usize = args.int(0)?;
+                if index >= self.bgm.variations.len() {
+                    return Err(ErrorKind::InvalidVariation(index));
+                }
+                self.bgm.variations[index].get_or_insert_with(|| Variation { segments: Vec::new() });
+                self.section = Section::Variation(index);
+            }
+            "drum" => {
+                let mut drum = Drum::default();
+                for (key, value) in args.attributes() {
+                    if !parse_patch_attribute(&mut drum.patch, key, value)? {
+                        match key {
+                            "coarse_tune" => drum.coarse_tune = parse_int(value)?,
+                            "fine_tune" => drum.fine_tune = parse_int(value)?,
+                            "volume" => drum.volume = parse_int(value)?,
+                            "pan" => drum.pan = parse_int(value)?,
+                            "reverb" => drum.reverb = parse_int(value)?,
+                            "rand_tune" => drum.rand_tune = parse_int(value)?,
+                            "rand_volume" => drum.rand_volume = parse_int(value)?,
+                            "rand_pan" => drum.rand_pan = parse_int(value)?,
+                            "rand_reverb" => drum.rand_reverb = parse_int(value)?,
+                            "pad_0b" => drum.pad_0b = parse_int(value)?,
+                            _ => return Err(ErrorKind::UnknownAttribute(key.to_owned())),
+                        }
+                    }
+                }
+                self.bgm.drums.push(drum);
+                self.section = Section::None;
+            }
+            "instrument" => {
+                let mut instrument = Instrument::default();
+                for (key, value) in args.attributes() {
+                    if !parse_patch_attribute(&mut instrument.patch, key, value)? {
+                        match key {
+                            "volume" => instrument.volume = parse_int(value)?,
+                            "pan" => instrument.pan = parse_int(value)?,
+                            "reverb" => instrument.reverb = parse_int(value)?,
+                            "coarse_tune" => instrument.coarse_tune = parse_int(value)?,
+                            "fine_tune" => instrument.fine_tune = parse_int(value)?,
+                            "pad_07" => instrument.pad_07 = parse_int(value)?,
+                            _ => return Err(ErrorKind::UnknownAttribute(key.to_owned())),
+                        }
+                    }
+                }
+                self.bgm.instruments.push(instrument);
+                self.section = Section::None;
+            }
+            "track_list" => {
+                let Some((id, attributes)) = args.0.split_first() else {
+                    return Err(ErrorKind::WrongArgCount { expected: 1, found: 0 });
+                };
+                let id: TrackListId = parse_int(&id.text)?;
+
+                let mut track_list = TrackList::default();
+                for (key, value) in Args(attributes).attributes() {
+                    match key {
+                        "at" => track_list.pos = Some(parse_int(value)?),
+                        _ => return Err(ErrorKind::UnknownAttribute(key.to_owned())),
+                    }
+                }
+
+                if self.bgm.track_lists.insert(id, track_list).is_some() {
+                    return Err(ErrorKind::DuplicateTrackList(id));
+                }
+                self.track_list = Some(id);
+                self.section = Section::None;
+            }
+            "track" => {
+                let Some(track_list) = self.track_list else {
+                    return Err(ErrorKind::OutsideSection);
+                };
+                let Some((index, attributes)) = args.0.split_first() else {
+                    return Err(ErrorKind::WrongArgCount { expected: 1, found: 0 });
+                };
+                let index: usize = parse_int(&index.text)?;
+
+                let mut track = Track {
+                    is_disabled: false,
+                    ..Default::default()
+                };
+                for (key, value) in Args(attributes).attributes() {
+                    match key {
+                        "name" => track.name = value.to_owned(),
+                        "disabled" => track.is_disabled = true,
+                        "drums" => track.is_drum_track = true,
+                        "polyphony" => track.polyphony = parse_polyphony(value)?,
+                        "at" => track.pos = Some(parse_int(value)?),
+                        _ => return Err(ErrorKind::UnknownAttribute(key.to_owned())),
+                    }
+                }
+
+                let tracks = &mut self.bgm.track_lists.get_mut(&track_list).unwrap().tracks;
+                *tracks.get_mut(index).ok_or(ErrorKind::InvalidTrack(index))? = track;
+                self.section = Section::Track(track_list, index);
+            }
+            "unknown" => {
+                args.expect(1)?;
+                let start = args.int(0)?;
+                self.bgm.unknowns.push(Unknown {
+                    start,
+                    data: Vec::new(),
+                });
+                self.section = Section::Unknown(self.bgm.unknowns.len() - 1);
+            }
+            _ => return Err(ErrorKind::UnknownDirective(directive.to_owned())),
+        }
+        Ok(())
+    }
+
+    fn push_command(&mut self, command: Command) -> Result<(), ErrorKind> {
+        let Section::Track(track_list, index) = self.section else {
+            return Err(ErrorKind::OutsideSection);
+        };
+        let track = &mut self.bgm.track_lists.get_mut(&track_list).unwrap().tracks[index];
+        track.commands.push(command);
+        Ok(())
+    }
+}
+
+/// Returns whether `key` was a patch attribute.
+fn parse_patch_attribute(patch: &mut PatchAddress, key: &str, value: &str) -> Result<bool, ErrorKind> {
+    match key {
+        "bank_set" => patch.bank_set = parse_bank_set(value)?,
+        "bank" => patch.bank = parse_int(value)?,
+        "instrument" => patch.instrument = parse_int(value)?,
+        "envelope" => patch.envelope = parse_int(value)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_segment(mnemonic: &str, args: Args) -> Result<Segment, ErrorKind> {
+    let id = Some(gen_id());
+    Ok(match mnemonic {
+        "subseg" => {
+            args.expect(1)?;
+            Segment::Subseg {
+                id,
+                track_list: args.int(0)?,
+            }
+        }
+        "start_loop" => {
+            args.expect(1)?;
+            Segment::StartLoop {
+                id,
+                label_index: args.int(0)?,
+            }
+        }
+        "wait" => {
+            args.expect(0)?;
+            Segment::Wait { id }
+        }
+        "end_loop" => {
+            args.expect(2)?;
+            Segment::EndLoop {
+                id,
+                label_index: args.int(0)?,
+                iter_count: args.int(1)?,
+            }
+        }
+        "unknown6" => {
+            args.expect(2)?;
+            Segment::Unknown6 {
+                id,
+                label_index: args.int(0)?,
+                iter_count: args.int(1)?,
+            }
+        }
+        "unknown7" => {
+            args.expect(2)?;
+            Segment::Unknown7 {
+                id,
+                label_index: args.int(0)?,
+                iter_count: args.int(1)?,
+            }
+        }
+        _ => return Err(ErrorKind::UnknownMnemonic(mnemonic.to_owned())),
+    })
+}
+
+fn parse_command(mnemonic: &str, args: Args) -> Result<Command, ErrorKind> {
+    let arity = match mnemonic {
+        "end" | "track_tremolo_stop" => 0,
+        "delay"
+        | "master_tempo"
+        | "master_volume"
+        | "master_pitch_shift"
+        | "unk_cmd_e3"
+        | "sub_track_volume"
+        | "sub_track_pan"
+        | "sub_track_reverb"
+        | "seg_track_volume"
+        | "sub_track_coarse_tune"
+        | "sub_track_fine_tune"
+        | "seg_track_tune"
+        | "track_tremolo_speed"
+        | "track_tremolo_time"
+        | "set_track_voice"
+        | "sub_track_reverb_type"
+        | "event_trigger" => 1,
+        "master_tempo_fade" | "master_volume_fade" | "master_effect" | "unk_cmd_f4" | "track_volume_fade" | "jump"
+        | "detour" => 2,
+        "note" | "track_tremolo" | "unk_cmd_ff" => 3,
+        "track_override_patch" => 4,
+        _ => return Err(ErrorKind::UnknownMnemonic(mnemonic.to_owned())),
+    };
+    args.expect(arity)?;
+
+    Ok(match mnemonic {
+        "end" => Command::End,
+        "delay" => Command::Delay(args.int(0)?),
+        "note" => Command::Note {
+            pitch: args.int(0)?,
+            velocity: args.int(1)?,
+            length: args.int(2)?,
+        },
+        "master_tempo" => Command::MasterTempo(args.int(0)?),
+        "master_volume" => Command::MasterVolume(args.int(0)?),
+        "master_pitch_shift" => Command::MasterPitchShift { cent: args.int(0)? },
+        "unk_cmd_e3" => Command::UnkCmdE3 {
+            effect_type: args.int(0)?,
+        },
+        "master_tempo_fade" => Command::MasterTempoFade {
+            time: args.int(0)?,
+            value: args.int(1)?,
+        },
+        "master_volume_fade" => Command::MasterVolumeFade {
+            time: args.int(0)?,
+            volume: args.int(1)?,
+        },
+        "master_effect" => Command::MasterEffect {
+            index: args.int(0)?,
+            value: args.int(1)?,
+        },
+        "track_override_patch" => Command::TrackOverridePatch(PatchAddress {
+            bank_set: parse_bank_set(&args.0[0].text)?,
+            bank: args.int(1)?,
+            instrument: args.int(2)?,
+            envelope: args.int(3)?,
+        }),
+        "sub_track_volume" => Command::SubTrackVolume(args.int(0)?),
+        "sub_track_pan" => Command::SubTrackPan(args.int(0)?),
+        "sub_track_reverb" => Command::SubTrackReverb(args.int(0)?),
+        "seg_track_volume" => Command::SegTrackVolume(args.int(0)?),
+        "sub_track_coarse_tune" => Command::SubTrackCoarseTune(args.int(0)?),
+        "sub_track_fine_tune" => Command::SubTrackFineTune(args.int(0)?),
+        "seg_track_tune" => Command::SegTrackTune { bend: args.int(0)? },
+        "track_tremolo" => Command::TrackTremolo {
+            amount: args.int(0)?,
+            speed: args.int(1)?,
+            time: args.int(2)?,
+        },
+        "track_tremolo_speed" => Command::TrackTremoloSpeed(args.int(0)?),
+        "track_tremolo_time" => Command::TrackTremoloTime { time: args.int(0)? },
+        "track_tremolo_stop" => Command::TrackTremoloStop,
+        "unk_cmd_f4" => Command::UnkCmdF4 {
+            pan0: args.int(0)?,
+            pan1: args.int(1)?,
+        },
+        "set_track_voice" => Command::SetTrackVoice { index: args.int(0)? },
+        "track_volume_fade" => Command::TrackVolumeFade {
+            time: args.int(0)?,
+            value: args.int(1)?,
+        },
+        "sub_track_reverb_type" => Command::SubTrackReverbType { index: args.int(0)? },
+        "jump" => Command::Jump {
+            unk_00: args.int(0)?,
+            unk_02: args.int(1)?,
+        },
+        "event_trigger" => Command::EventTrigger {
+            event_info: args.int(0)?,
+        },
+        "detour" => Command::Detour {
+            start_label: args.text(0),
+            end_label: args.text(1),
+        },
+        "unk_cmd_ff" => Command::UnkCmdFF {
+            unk_00: args.int(0)?,
+            unk_01: args.int(1)?,
+            unk_02: args.int(2)?,
+        },
+        _ => unreachable!(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every command, so that the writer and parser are checked against each other.
+    fn every_command() -> Vec<Command> {
+        vec![
+            Command::Marker { label: "start".into() },
+            Command::MasterTempo(120),
+            Command::MasterVolume(100),
+            Command::MasterPitchShift { cent: 3 },
+            Command::UnkCmdE3 { effect_type: 1 },
+            Command::MasterTempoFade { time: 48, value: 140 },
+            Command::MasterVolumeFade { time: 96, volume: 50 },
+            Command::MasterEffect { index: 0, value: 1 },
+            Command::TrackOverridePatch(PatchAddress {
+                bank_set: BankSetIndex::Aux,
+                bank: 2,
+                instrument: 5,
+                envelope: 1,
+            }),
+            Command::SubTrackVolume(90),
+            Command::SubTrackPan(-64),
+            Command::SubTrackReverb(10),
+            Command::SegTrackVolume(80),
+            Command::SubTrackCoarseTune(12),
+            Command::SubTrackFineTune(7),
+            Command::SegTrackTune { bend: -200 },
+            Command::TrackTremolo {
+                amount: 1,
+                speed: 2,
+                time: 3,
+            },
+            Command::TrackTremoloSpeed(4),
+            Command::TrackTremoloTime { time: 5 },
+            Command::TrackTremoloStop,
+            Command::UnkCmdF4 { pan0: 6, pan1: 7 },
+            Command::SetTrackVoice { index: 0 },
+            Command::TrackVolumeFade { time: 8, value: 9 },
+            Command::SubTrackReverbType { index: 1 },
+            Command::Jump {
+                unk_00: 0x1234,
+                unk_02: 2,
+            },
+            Command::EventTrigger { event_info: 0xDEADBEEF },
+            Command::UnkCmdFF {
+                unk_00: 6,
+                unk_01: 0,
+                unk_02: 0,
+            },
+            Command::Note {
+                pitch: 140,
+                velocity: 100,
+                length: 0x1000,
+            },
+            Command::Delay(0x200),
+            Command::Marker {
+                label: "Offset 0x40".into(),
+            },
+            Command::Detour {
+                start_label: "start".into(),
+                end_label: "Offset 0x40".into(),
+            },
+            Command::End,
+        ]
+    }
+
+    fn bgm() -> Bgm {
+        let mut track_list = TrackList {
+            pos: Some(0x80),
+            tracks: core::array::from_fn(|_| Track::default()),
+        };
+        track_list.tracks[0].commands = vec![Command::MasterTempo(100), Command::Delay(48), Command::End].into();
+        track_list.tracks[1] = Track {
+            name: "Lead \"1\"".into(),
+            is_disabled: false,
+            polyphony: Polyphony::Manual { voices: 2 },
+            is_drum_track: true,
+            commands: every_command().into(),
+            pos: Some(0x1234),
+        };
+        track_list.tracks[2].polyphony = Polyphony::Link { parent: 1 };
+
+        let mut bgm = Bgm::new();
+        bgm.name = "117 ".into();
+        let track_list = bgm.add_track_list(track_list);
+        bgm.variations[1] = Some(Variation {
+            segments: vec![
+                Segment::StartLoop {
+                    id: None,
+                    label_index: 0,
+                },
+                Segment::Subseg { id: None, track_list },
+                Segment::Wait { id: None },
+                Segment::EndLoop {
+                    id: None,
+                    label_index: 0,
+                    iter_count: 3,
+                },
+            ],
+        });
+        bgm.drums.push(Drum {
+            pan: -1,
+            pad_0b: 2,
+            ..Default::default()
+        });
+        bgm.instruments.push(Instrument {
+            patch: PatchAddress {
+                bank_set: BankSetIndex::Music,
+                bank: 1,
+                instrument: 3,
+                envelope: 0,
+            },
+            volume: 100,
+            pan: 64,
+            ..Default::default()
+        });
+        bgm.unknowns.push(Unknown {
+            start: 0x1000,
+            data: (0..0x14).collect(),
+        });
+        bgm
+    }
+
+    #[test]
+    fn round_trip() {
+        let bgm = bgm();
+        let source = bgm.to_asm_string();
+        let assembled = Bgm::from_asm_string(&source).unwrap();
+
+        assert_eq!(assembled.to_asm_string(), source);
+        assert_eq!(assembled.as_bytes().unwrap(), bgm.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn hand_written() {
+        let bgm = Bgm::from_asm_string(
+            r#"
+            .name "Test" ; comment
+            .variation 0
+                subseg 1
+            .track_list 1
+            .track 1 polyphony=auto
+                set_track_voice 0
+            loop: ; a label
+                note 0x8C 100 48 ; a note
+                delay 48
+            "loop end":
+                detour loop "loop end"
+                end
+            "#,
+        )
+        .unwrap();
+
+        let commands: Vec<Command> = bgm.track_lists[&1].tracks[1]
+            .commands
+            .iter()
+            .map(|event| event.command.clone())
+            .collect();
+        assert_eq!(commands.len(), 7);
+        assert_eq!(commands[1], Command::Marker { label: "loop".into() });
+        assert_eq!(
+            commands[2],
+            Command::Note {
+                pitch: 140,
+                velocity: 100,
+                length: 48
+            }
+        );
+        assert_eq!(
+            commands[5],
+            Command::Detour {
+                start_label: "loop".into(),
+                end_label: "loop end".into()
+            }
+        );
+        assert!(bgm.track_lists[&1].tracks[2].is_disabled);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let error = Bgm::from_asm_string(".name \"x\"\n\n.track_list 1\n.track 0\n    not_a_command 1").unwrap_err();
+        assert_eq!(
+            error,
+            Error {
+                line: 5,
+                kind: ErrorKind::UnknownMnemonic("not_a_command".into()),
+            }
+        );
+
+        let error = Bgm::from_asm_string("delay 1").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::OutsideSection);
+
+        let error = Bgm::from_asm_string(".track_list 1\n.track 0\n    note 1 2").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::WrongArgCount { expected: 3, found: 2 });
+    }
+}
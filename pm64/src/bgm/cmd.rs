@@ -1,9 +1,12 @@
+use std::collections::{BTreeMap, HashSet, btree_map};
 use std::hash::Hash;
-use std::iter;
-use std::ops::Range;
+use std::ops::{Range, RangeInclusive};
+use std::{fmt, iter, mem};
 
+use serde::{Deserializer, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use typescript_type_def::TypeDef;
+use typescript_type_def::type_expr::TypeInfo;
 
 use crate::{
     bgm::PatchAddress,
@@ -23,51 +26,50 @@ use crate::{
 ///
 /// ## Time efficiency
 ///
-/// | Method         | Worst-case | Naïve [`Vec<Command>`] | Explanation                                               |
-/// | -------------- | ---------- | ---------------------- | --------------------------------------------------------- |
-/// | get/lookup     | O(n)       | N/A                    | Must iterate to find insertion point                      |
-/// | insert         | O(2n)      | N/A                    | Must iterate to find insertion point and commands to the
-/// right must be shifted | | push           | O(1)       | O(1)                   | Same implementation as [Vec]
-/// | | remove         | O(1)       | O(n)                   | [Vec] shifts elements, whereas [CommandSeq] replaces with
-/// [Command::default] |
+/// Commands are indexed by the relative-time they happen at, so finding a time is a search of the index rather than
+/// a walk over every [Delay] before it. Here n is the number of commands in the sequence, and k the number of commands
+/// at the time in question (usually a handful).
 ///
-/// [CommandSeq] is backed by a [`Vec<Command>`] with some domain-specific optimisations and methods. Note, however,
-/// that this collection is not equivalent to [Vec] - in many ways it acts more like a
-/// [HashMap](std::collections::HashMap) (i.e. a dictionary) with relative-time keys and [Command] values (for
-/// example, you cannot lookup by vector index, because ordering is undefined between [Delay] partitions).
-#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize, TypeDef)]
-#[serde(transparent)]
+/// | Method                          | Worst-case   | Explanation                                                  |
+/// | ------------------------------- | ------------ | ------------------------------------------------------------ |
+/// | [at_time](Self::at_time)        | O(log n + k) | Lookup in the time index                                     |
+/// | [insert_start](Self::insert_start) | O(log n + k) | Lookup, then an insert among the commands at that time    |
+/// | [insert_end](Self::insert_end)  | O(log n + k) | As above                                                     |
+/// | [push](Self::push)              | O(log n)     | Appends to the last time                                     |
+/// | [len_time](Self::len_time)      | O(log n)     | The last time is the last key of the index                   |
+/// | [split_at](Self::split_at)      | O(m log m)   | The m commands after the split move to the new sequence      |
+/// | Add, remove or change a [Delay] | O(n)         | Every command after the delay moves in time                  |
+/// | [get_by_id](Self::get_by_id), [time_of](Self::time_of), [update_by_id](Self::update_by_id) | O(n) | Ids are not indexed, so finding one is a scan |
+/// | [move_notes](Self::move_notes) and other note edits | O(n + m log n) | Finding the m notes by id is a scan |
+///
+/// The times are worked out from the [Delay]s when commands are added, and [Delay]s are still kept as commands rather
+/// than derived from the times when encoding: songs split long delays in ways that must be kept for them to match,
+/// and edits refer to delays by id.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone)]
 pub struct CommandSeq {
-    /// List of [Command]s in time order. Sets of [Command]s at the same time value have undefined ordering, so this
-    /// is not a public field. Similarly, [CommandSeq] does not [Deref](std::ops::Deref) to the [Vec] it wraps
-    /// (which is as close as Rust gets to OOP-style inheritance).
-    /// However, a number of [Vec] operations _are_ safe to provide; these are wrapped in the `impl CommandSeq`, such
-    /// as [CommandSeq::len].
-    vec: Vec<Event>,
-    /* TODO: consider implementing this optimisation because get/insert are hot */
-    /*
-    /// Lookup table (time -> vec index of first command with that time) for avoiding linear searches.
-    /// A binary tree is used so it is trivial to find the last time value (i.e. the time of the last Command).
-    ///
-    /// Interior mutability (RefCell) is needed in order for lookup methods to not require &mut self (which, logically,
-    /// doesn't make sense - why would a CommandSeq user need a mutable reference just to perform a lookup?). This will
-    /// panic if time_cache is borrowed past the lifetime of a method here, hence, this field is private and must not
-    /// have a reference to it leaked.
-    time_index_map: RefCell<BTreeMap<usize, usize>>,
-    */
+    /// The [Event]s at each relative-time, in order. A [Delay] of more than zero ticks is only ever the last event at
+    /// its time, and every time but the last ends with one, which leads to the next time. Times with no events are
+    /// not kept, so there is only one way to group any sequence of events.
+    groups: BTreeMap<usize, Vec<Event>>,
+}
+
+/// The number of ticks `event` moves time on by.
+fn delay_length(event: &Event) -> usize {
+    match event.command {
+        Delay(delay) => delay,
+        _ => 0,
+    }
 }
 
 impl CommandSeq {
     pub fn new() -> Self {
-        Self::with_capacity(0)
+        Self::default()
     }
 
     /// Inserts the given command at the start of the specified time, keeping the temporal position of all other
-    /// commands in the sequence consistent. Has the side-effect of combining an adjacient Delay sequence, similar to
-    /// that of [`shrink`](CommandSeq::shrink).
+    /// commands in the sequence consistent.
     ///
-    /// A delay will also be inserted after the subsequence where required,
-    /// with delays after the insertion point combined into one.
+    /// If the time is within a delay, the delay is split in two around the command.
     pub fn insert_start(&mut self, time: usize, command: Command) {
         self.insert_many_start(time, iter::once(command))
     }
@@ -77,164 +79,220 @@ impl CommandSeq {
     /// the sequence.
     ///
     /// The order of the inserted subsequence is maintained.
-    ///
-    /// Has the side-effect of combining delays to the immediate right of the inserted subsequence.
     pub fn insert_many_start<C: Into<Event>, I: IntoIterator<Item = C>>(&mut self, time: usize, subsequence: I) {
-        // Turn subsequence members into Commands if they are not already (C: Into<Command>)
-        let subsequence = subsequence.into_iter().map(|cmd| cmd.into());
-
-        match self.lookup_delay(time) {
-            DelayLookup::Found(delay_index) => {
-                // Insert the command immediately after the Delay which introduces `time`.
-                let index = delay_index + 1;
-                self.vec.splice(
-                    index..index, // Remove no elements
-                    subsequence,
-                );
+        // Turn subsequence members into Events if they are not already (C: Into<Event>)
+        let mut subsequence: Vec<Event> = subsequence.into_iter().map(|cmd| cmd.into()).collect();
+        // Delays in the subsequence move what comes after them, so it can't be left in one group
+        let has_delay = subsequence.iter().any(|event| delay_length(event) > 0);
+
+        if let Some(group) = self.groups.get_mut(&time) {
+            group.splice(0..0, subsequence);
+            if has_delay {
+                self.regroup_from(time);
             }
+            return;
+        }
 
-            DelayLookup::Missing { index, time_at_index } => {
-                debug_assert!(time >= time_at_index);
-
-                let mut old_delay_range = index..index;
-                let mut delta_time: usize = 0;
-                while let Some(Event { command: Delay(t), .. }) = self.vec.get(old_delay_range.end) {
-                    old_delay_range.end += 1;
-                    delta_time += *t;
-                }
-
-                let insert_time = {
-                    let before_time = time - time_at_index;
-                    let after_time = delta_time.saturating_sub(before_time); // For delta_time = 0
-                    (before_time, after_time)
-                };
-
-                fn delay(time: usize) -> Box<dyn Iterator<Item = Event>> {
-                    if time > 0 {
-                        Box::new(iter::once(Command::Delay(time).into()))
-                    } else {
-                        Box::new(iter::empty())
-                    }
-                }
-
-                // Vec::splice and using an iterator is more efficient than a naive while loop that inserts delays.
-                // See https://stackoverflow.com/questions/28678615.
-                self.vec.splice(
-                    old_delay_range, // Replace old delays
-                    delay(insert_time.0).chain(subsequence).chain(delay(insert_time.1)),
-                );
+        // Within a delay, which is split in two around the subsequence
+        if let Some((&start, group)) = self.groups.range_mut(..time).next_back()
+            && let Some(Event {
+                command: Delay(delay), ..
+            }) = group.last_mut()
+            && start + *delay > time
+        {
+            subsequence.push(Command::Delay(start + *delay - time).into());
+            *delay = time - start;
+            self.groups.insert(time, subsequence);
+            if has_delay {
+                self.regroup_from(time);
             }
+            return;
+        }
+
+        // At or after the end of the sequence
+        let end = self.len_time();
+        if time > end {
+            self.push(Command::Delay(time - end));
+        }
+        for event in subsequence {
+            self.push(event);
         }
     }
 
     /// Inserts the given command at the end of specified time, keeping the temporal position of all other commands in
-    /// the sequence consistent. Has the side-effect of combining an adjacient Delay sequence, similar to that of
-    /// [`shrink`](CommandSeq::shrink).
+    /// the sequence consistent.
     ///
-    /// A delay will also be inserted after the subsequence where required,
-    /// with delays after the insertion point combined into one.
+    /// If the time is within a delay, the delay is split in two around the command.
     pub fn insert_end(&mut self, time: usize, command: Command) {
         self.insert_many_end(time, iter::once(command))
     }
 
-    /// Consumes the given iterator of commands and inserts them at the end of the subsequence at the specified time.
+    /// Consumes the given iterator of commands and inserts them at the end of the subsequence at the specified time,
+    /// before the [Delay] that ends it and before any [End](Command::End) (so that they are still played).
     /// [Delays](Delay) are adjusted and inserted in order to maintain the time values of commands before and after in
     /// the sequence.
     ///
     /// The order of the inserted subsequence is maintained.
-    ///
-    /// Has the side-effect of combining delays to the immediate right of the inserted subsequence.
     pub fn insert_many_end<C: Into<Event>, I: IntoIterator<Item = C>>(&mut self, time: usize, subsequence: I) {
-        // Turn subsequence members into Commands if they are not already (C: Into<Command>)
-        let subsequence = subsequence.into_iter().map(|cmd| cmd.into());
-
-        match self.lookup_delay(time) {
-            DelayLookup::Found(start) => {
-                // Find the next delay, which ends `time`, and insert before it.
-                let mut index = self.vec.len();
-                for (end, Event { command, .. }) in self.vec.iter().enumerate().skip(start + 1) {
-                    if let Command::Delay { .. } = command {
-                        index = end - 1;
-                        break;
-                    } else if let Command::End = command {
-                        index = end - 1;
-                        break;
-                    }
+        match self.groups.get_mut(&time) {
+            Some(group) => {
+                let subsequence: Vec<Event> = subsequence.into_iter().map(|cmd| cmd.into()).collect();
+                let has_delay = subsequence.iter().any(|event| delay_length(event) > 0);
+                let index = group
+                    .iter()
+                    .position(|event| event.command == Command::End || delay_length(event) > 0)
+                    .unwrap_or(group.len());
+                group.splice(index..index, subsequence);
+                if has_delay {
+                    self.regroup_from(time);
                 }
-                self.vec.splice(
-                    index..index, // Remove no elements
-                    subsequence,
-                );
             }
+            // Nothing happens at `time` yet, so the start and end of it are the same place
+            None => self.insert_many_start(time, subsequence),
+        }
+    }
 
-            DelayLookup::Missing { index, time_at_index } => {
-                debug_assert!(time >= time_at_index);
+    /// Returns the commands occurring at the given time, including the terminating Delay command if there is one.
+    pub fn at_time(&self, wanted_time: usize) -> Vec<&Event> {
+        self.groups
+            .get(&wanted_time)
+            .map_or(Vec::new(), |group| group.iter().collect())
+    }
 
-                let mut old_delay_range = index..index;
+    // TODO: remove
 
-                for (index, Event { command, .. }) in self.vec.iter().enumerate().skip(index + 1) {
-                    if let Command::Delay { .. } = command {
-                        old_delay_range = index..index;
-                        break;
-                    }
-                }
+    /// Iterates over the commands in this sequence in time-order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Event> + Clone {
+        self.groups.values().flatten()
+    }
 
-                let mut delta_time: usize = 0;
-                while let Some(Event { command: Delay(t), .. }) = self.vec.get(old_delay_range.end) {
-                    old_delay_range.end += 1;
-                    delta_time += *t;
-                }
+    /// The ids of the events in this sequence. Unlike the events themselves, these can be changed freely.
+    pub fn ids_mut(&mut self) -> impl Iterator<Item = &mut Id> {
+        self.groups.values_mut().flatten().map(|event| &mut event.id)
+    }
 
-                let insert_time = {
-                    let before_time = time - time_at_index;
-                    let after_time = delta_time.saturating_sub(before_time); // For delta_time = 0
-                    (before_time, after_time)
-                };
+    fn into_events(self) -> impl Iterator<Item = Event> {
+        self.groups.into_values().flatten()
+    }
 
-                fn delay(time: usize) -> Box<dyn Iterator<Item = Event>> {
-                    if time > 0 {
-                        Box::new(iter::once(Command::Delay(time).into()))
-                    } else {
-                        Box::new(iter::empty())
-                    }
-                }
+    /// The time of the event with the given id, and its index among the events at that time. A scan, so O(n).
+    fn position_of(&self, id: Id) -> Option<(usize, usize)> {
+        self.groups
+            .iter()
+            .find_map(|(&time, group)| Some((time, group.iter().position(|event| event.id == id)?)))
+    }
 
-                // Vec::splice and using an iterator is more efficient than a naive while loop that inserts delays.
-                // See https://stackoverflow.com/questions/28678615.
-                self.vec.splice(
-                    old_delay_range, // Replace old delays
-                    delay(insert_time.0).chain(subsequence).chain(delay(insert_time.1)),
-                );
-            }
+    /// Moves the events at `time` and after it to the times they should be at, after a change to a [Delay] at `time`.
+    fn regroup_from(&mut self, time: usize) {
+        let after = self.groups.split_off(&time);
+        for event in after.into_values().flatten() {
+            self.push(event);
         }
     }
 
-    /// Returns the commands occurring at the given time, including the terminating Delay command if there is one.
-    pub fn at_time(&self, wanted_time: usize) -> Vec<&Event> {
-        self.iter_time_groups()
-            .find(|&(time, _)| time == wanted_time)
-            .map_or(Vec::new(), |(_, subseq)| subseq)
+    /// Runs `update` on the command at the given position, then moves any events that it changed the time of.
+    fn update_at<T>(&mut self, time: usize, index: usize, update: impl FnOnce(&mut Command) -> T) -> T {
+        let Some(event) = self.groups.get_mut(&time).and_then(|group| group.get_mut(index)) else {
+            panic!("no event {} at time {}", index, time);
+        };
+        let was_delay = matches!(event.command, Delay(_));
+        let ret = update(&mut event.command);
+        if was_delay || matches!(event.command, Delay(_)) {
+            self.regroup_from(time);
+        }
+        ret
     }
 
-    // TODO: remove
+    /// Returns the event with the given id. Ids are not indexed, so this is O(n).
+    pub fn get_by_id(&self, id: Id) -> Option<&Event> {
+        let (time, index) = self.position_of(id)?;
+        Some(&self.groups[&time][index])
+    }
 
-    /// Iterates over the commands in this sequence in time-order.
-    pub fn iter(&self) -> std::slice::Iter<'_, Event> {
-        self.vec.iter()
+    /// Returns the relative-time of the event with the given id.
+    pub fn time_of(&self, id: Id) -> Option<usize> {
+        self.position_of(id).map(|(time, _)| time)
+    }
+
+    /// Runs `update` on the command of the event with the given id, returning what it returns, or `None` if there is
+    /// no such event. If the command is or becomes a [Delay], the events after it are moved in time to match.
+    ///
+    /// There is no `get_by_id_mut`, because changing a [Delay] through a plain reference would leave the events after
+    /// it at the wrong times.
+    pub fn update_by_id<T>(&mut self, id: Id, update: impl FnOnce(&mut Command) -> T) -> Option<T> {
+        let (time, index) = self.position_of(id)?;
+        Some(self.update_at(time, index, update))
+    }
+
+    /// Inserts `event` directly after the event with id `after`, or at the very start if `after` is `None`. Returns
+    /// `false`, without inserting, if there is no such event.
+    pub(super) fn insert_after(&mut self, after: Option<Id>, event: Event) -> bool {
+        let (time, index) = match after {
+            Some(after) => match self.position_of(after) {
+                Some((time, index)) => (time, index + 1),
+                None => return false,
+            },
+            None => (0, 0),
+        };
+
+        // Anything after a delay is at the time the delay leads to
+        let previous_delay = match index.checked_sub(1) {
+            Some(previous) => delay_length(&self.groups[&time][previous]),
+            None => 0,
+        };
+        let (time, index) = if previous_delay > 0 {
+            (time + previous_delay, 0)
+        } else {
+            (time, index)
+        };
+
+        let moves_time = delay_length(&event) > 0;
+        self.groups.entry(time).or_default().insert(index, event);
+        if moves_time {
+            self.regroup_from(time);
+        }
+        true
+    }
+
+    /// Removes the event with the given id, returning it and the id of the event that was before it (to put it back
+    /// with [Edit::InsertEvent](super::edit::Edit::InsertEvent)). If it is a [Delay], the events after it are moved
+    /// earlier in time to match.
+    pub fn remove_by_id(&mut self, id: Id) -> Option<(Option<Id>, Event)> {
+        let (time, index) = self.position_of(id)?;
+        let before = match index.checked_sub(1) {
+            Some(before) => Some(self.groups[&time][before].id),
+            None => self
+                .groups
+                .range(..time)
+                .next_back()
+                .and_then(|(_, group)| group.last())
+                .map(|event| event.id),
+        };
+
+        let group = self.groups.get_mut(&time)?;
+        let event = group.remove(index);
+        if group.is_empty() {
+            self.groups.remove(&time);
+        }
+        if delay_length(&event) > 0 {
+            self.regroup_from(time);
+        }
+        Some((before, event))
     }
 
     /// Iterates over each command in this sequence annotated with its time relative to the start of the sequence.
     pub fn iter_time(&self) -> TimeIter<'_> {
         TimeIter {
-            seq: self.vec.iter(),
-            current_time: 0,
+            groups: self.groups.iter(),
+            time: 0,
+            events: [].iter(),
         }
     }
 
     /// Iterates over subsequences of commands that execute at the same time.
     pub fn iter_time_groups(&self) -> TimeGroupIter<'_> {
         TimeGroupIter {
-            seq: self.iter_time().peekable(),
+            groups: self.groups.iter(),
         }
     }
 
@@ -242,19 +300,9 @@ impl CommandSeq {
     /// *playback* time (not the relative-time), that is, [Command::Note] (use [CommandSeq::playback_time] to find
     /// this value).
     pub fn len_time(&self) -> usize {
-        let mut time = 0;
-
-        for command in self.vec.iter() {
-            if let Event {
-                command: Delay(delta_time),
-                ..
-            } = command
-            {
-                time += *delta_time;
-            }
-        }
-
-        time
+        self.groups
+            .last_key_value()
+            .map_or(0, |(&time, group)| time + group.last().map_or(0, delay_length))
     }
 
     /// Calculates the time it takes for this [CommandSeq] to finish in terms of audio playback (i.e. when all
@@ -262,61 +310,62 @@ impl CommandSeq {
     ///
     /// Equivalent to [CommandSeq::len_time] for a sequence with no [Command::Note]s.
     pub fn playback_time(&self) -> usize {
-        self.iter_time().last().map_or(0, |(time, event)| match event.command {
-            Command::Delay(delta) => time + delta,
-            Command::Note { length, .. } => time + length as usize,
+        let Some((&time, group)) = self.groups.last_key_value() else {
+            return 0;
+        };
+        match group.last().map(|event| &event.command) {
+            Some(Command::Delay(delta)) => time + delta,
+            Some(Command::Note { length, .. }) => time + *length as usize,
             _ => time,
-        })
+        }
     }
 
-    /// See [Vec::with_capacity].
-    pub fn with_capacity(capacity: usize) -> Self {
-        Self {
-            vec: Vec::with_capacity(capacity),
-        }
+    /// Kept for compatibility with [Vec]-like use; the time index has no use for a capacity, so this is the same as
+    /// [CommandSeq::new].
+    pub fn with_capacity(_capacity: usize) -> Self {
+        Self::new()
     }
 
     /// Optimises this sequence to take up as little memory as possible whilst still being playback-equivalent (i.e.
     /// sounds the same).
     pub fn shrink(&mut self) {
-        // Remove useless commands
-        self.vec
-            .retain(|event| !matches!(event.command, Command::Delay(0) | Command::Note { length: 0, .. }));
+        let mut events: Vec<Event> = mem::take(self).into_events().collect();
 
-        if self.vec.is_empty() {
-            return;
-        }
+        // Remove useless commands
+        events.retain(|event| !matches!(event.command, Command::Delay(0) | Command::Note { length: 0, .. }));
 
         // Combine contiguous Delay commands
-        let mut i = 0;
-        while i < self.vec.len() - 1 {
-            if let Command::Delay(delay) = self.vec[i].command {
-                if let Command::Delay(next_delay) = self.vec[i + 1].command {
-                    self.vec[i].command = Command::Delay(delay + next_delay);
-                    self.vec.remove(i + 1);
-                } else {
-                    i += 1;
-                }
-            } else {
-                i += 1;
+        events.dedup_by(|next, previous| match (&next.command, &mut previous.command) {
+            (Command::Delay(next_delay), Command::Delay(delay)) => {
+                *delay += next_delay;
+                true
             }
-        }
+            _ => false,
+        });
 
         // TODO: combine redundant stateful subsequences e.g. MasterTempo .. MasterTempo with no delay inbetween
+
+        *self = events.into_iter().collect();
     }
 
     /// Appends the given [Command] to the end of the sequence.
     pub fn push<C: Into<Event>>(&mut self, command: C) {
-        self.vec.push(command.into())
+        let time = self.len_time();
+        self.groups.entry(time).or_default().push(command.into())
     }
 
     /// Returns the number of commands ("length") in the sequence.
     pub fn len(&self) -> usize {
-        self.vec.len()
+        self.groups.values().map(Vec::len).sum()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.vec.len() == 0 || (self.vec.len() == 1 && self.vec[0].command == Command::End)
+        let mut events = self.iter();
+        match (events.next(), events.next()) {
+            (None, _) => true,
+            (Some(event), None) => event.command == Command::End,
+            _ => false,
+        }
     }
 
     pub fn pitch_range(&self) -> Range<u8> {
@@ -343,20 +392,34 @@ impl CommandSeq {
         range
     }
 
+    /// Replaces the command at index `idx` with a no-op. Indices shift whenever commands are added or removed, so
+    /// prefer [CommandSeq::update_by_id] or [CommandSeq::remove_by_id] for commands that are known by id.
     pub fn clear_command(&mut self, idx: usize) {
-        self.vec[idx] = Command::Delay(0).into();
+        let mut remaining = idx;
+        let position = self.groups.iter().find_map(|(&time, group)| {
+            if remaining < group.len() {
+                Some((time, remaining))
+            } else {
+                remaining -= group.len();
+                None
+            }
+        });
+        match position {
+            Some((time, index)) => {
+                self.update_at(time, index, |command| *command = Command::Delay(0));
+            }
+            None => panic!("index {} out of range for sequence of length {}", idx, self.len()),
+        }
     }
 
     pub fn zero_all_delays(&mut self) {
-        for cmd in &mut self.vec {
-            if let Event {
-                command: Command::Delay(_),
-                ..
-            } = cmd
-            {
-                *cmd = Command::Delay(0).into();
-            }
-        }
+        *self = mem::take(self)
+            .into_events()
+            .map(|event| match event.command {
+                Command::Delay(_) => Command::Delay(0).into(),
+                _ => event,
+            })
+            .collect();
     }
 
     // TODO
@@ -367,6 +430,10 @@ impl CommandSeq {
     }
     */
 
+    pub fn to_command_vec(self) -> Vec<Command> {
+        self.into_events().map(|e| e.command).collect()
+    }
+
     /*
     /// Searches this sequence for the given command **by reference**.
     /// ```
@@ -427,100 +494,100 @@ impl CommandSeq {
     }
     */
 
-    /// Performs a search for the [Delay] introducing the given time. `Delay(0)`s are ignored.
-    fn lookup_delay(&self, time: usize) -> DelayLookup {
-        if time == 0 {
-            return DelayLookup::Missing {
-                index: 0,
-                time_at_index: 0,
-            };
-        }
-
-        let mut current_time = 0;
-
-        for (index, command) in self.vec.iter().enumerate() {
-            if let Event {
-                command: Delay(delta_time),
-                ..
-            } = command
-            {
-                let time_at_index = current_time;
-
-                // Advance past this Delay.
-                current_time += *delta_time;
-
-                if current_time == time {
-                    // This Delay introduced the time we want! :)
-                    return DelayLookup::Found(index);
-                }
-
-                if current_time > time {
-                    // This Delay introduced a time *after* the one we're looking up.
-                    return DelayLookup::Missing {
-                        index, // Inserting at this index would move this Delay right.
-                        time_at_index,
-                    };
-                }
-            }
-        }
-
-        // We never reached the target time, so inserting a Delay at the end of the vec would introduce it.
-        DelayLookup::Missing {
-            index: self.vec.len(),
-            time_at_index: current_time,
-        }
-    }
-
-    // TODO
-    /*
-    fn lookup_delay_cached(&self, time: usize) -> DelayLookup {
-        // Attempt cache lookup
-        if let Ok(cache) = self.time_cache.try_borrow() {
-            if let Some(index) = cache.get(time) {
-                // Cache hit!
-                let ret = DelayLookup::Found(index);
-                debug_assert_eq!(time, ret);
-                return ret;
-            }
-        }
-
-        // TODO: Perform a trivial bounds check to see if time > biggest key in cache
-
-        let index = self.lookup_delay(time);
-        // ...
-
-        // TODO: Attempt cache update
-    }
-    */
-
-    pub fn to_command_vec(self) -> Vec<Command> {
-        self.vec.into_iter().map(|e| e.command).collect()
-    }
-
     /// Calculate the maximum number of notes that play at once.
     pub fn max_polyphony(&self) -> u8 {
         let mut polyphony = 0;
         let mut notes = [0; u8::MAX as usize]; // Maps pitch->end_time of played notes
         for (time, event) in self.iter_time() {
-            let time = time as u16;
-
             if let Event {
                 command: Command::Note { pitch, length, .. },
                 ..
             } = event
             {
-                notes[*pitch as usize] = *length + time;
+                notes[*pitch as usize] = *length as usize + time;
 
                 let current_polyphony = notes.iter().filter(|end_time| **end_time > time).count() as u8;
                 if current_polyphony > polyphony {
                     polyphony = current_polyphony;
-                    dbg!(current_polyphony, time);
                 }
             }
         }
         polyphony
     }
 
+    /// Moves notes out of this sequence until no more than `max_voices` notes play at once. Each note stays in the
+    /// first layer (this sequence being the first, followed by at most `max_layers` new sequences) that has a voice
+    /// free when the note starts; notes that fit in no layer are dropped.
+    ///
+    /// New layers are copies of this sequence with only their own notes kept, so they have the same setup commands
+    /// (voice, volume, etc.) as this one.
+    pub fn split_polyphony(&mut self, max_voices: u8, max_layers: usize) -> PolyphonySplit {
+        // Maps pitch->end_time of played notes, for each layer
+        let mut layer_notes: Vec<[usize; 256]> = Vec::new();
+        let mut note_layers: Vec<(usize, Option<usize>)> = Vec::new(); // (vec index, layer)
+        let mut split = PolyphonySplit::default();
+
+        for (index, (time, event)) in self.iter_time().enumerate() {
+            let Command::Note { pitch, length, .. } = event.command else {
+                continue;
+            };
+
+            let layer = (0..=max_layers).find(|&layer| match layer_notes.get(layer) {
+                None => true,
+                Some(notes) => {
+                    // Replaying a pitch that is already playing does not take up another voice
+                    let playing = notes
+                        .iter()
+                        .enumerate()
+                        .filter(|&(other_pitch, end_time)| *end_time > time && other_pitch != pitch as usize)
+                        .count();
+                    playing < max_voices as usize
+                }
+            });
+
+            if let Some(layer) = layer {
+                if layer == layer_notes.len() {
+                    layer_notes.push([0; 256]);
+                }
+                layer_notes[layer][pitch as usize] = time + length as usize;
+            } else {
+                split.dropped += 1;
+            }
+
+            if layer != Some(0) && split.first_overflow.is_none() {
+                split.first_overflow = Some(time);
+            }
+
+            note_layers.push((index, layer));
+        }
+
+        // Notes are cleared by index all at once, rather than with `clear_command`, which has to find each index
+        for layer in 1..layer_notes.len() {
+            let mut events: Vec<Event> = self.iter().map(|event| event.command.clone().into()).collect();
+            for &(index, note_layer) in &note_layers {
+                if note_layer != Some(layer) {
+                    events[index] = Command::Delay(0).into();
+                }
+            }
+            let mut seq: CommandSeq = events.into_iter().collect();
+            seq.shrink();
+            split.layers.push(seq);
+        }
+
+        if split.first_overflow.is_some() {
+            let mut events: Vec<Event> = mem::take(self).into_events().collect();
+            for &(index, note_layer) in &note_layers {
+                if note_layer != Some(0) {
+                    events[index] = Command::Delay(0).into();
+                }
+            }
+            *self = events.into_iter().collect();
+            self.shrink();
+        }
+
+        split
+    }
+
     /// Splits this sequence at the given time such that self is the 'before `time`' sequence and the returned
     /// sequence is the 'after `time`' sequence. Adjusts Wait commands on the boundaries to keep the sum len_time
     /// the same as before this was called.
@@ -528,27 +595,20 @@ impl CommandSeq {
         // insert_start has all the logic for finding and adjusting Wait commands
         self.insert_start(time, Command::End);
 
-        // Find the End we just inserted
-        let Some((idx, _)) = self.vec.iter().enumerate().find(|(_, event)| {
-            matches!(
-                event,
-                Event {
-                    command: Command::End,
-                    ..
-                }
-            )
-        }) else {
+        // The End we just inserted is the first event at `time`
+        let mut after = self.groups.split_off(&time);
+        let Some(mut end) = after.remove(&time) else {
             return Default::default();
         };
+        let rest = end.split_off(1); // So that End is left on self
+        self.groups.insert(time, end);
 
-        let mut after_time = CommandSeq {
-            vec: self.vec.split_off(idx + 1), // +1 so that End is left on self
-        };
+        let mut after_time: CommandSeq = rest.into_iter().chain(after.into_values().flatten()).collect();
 
         // Persist stateful events
         let setup = {
             let mut stateful_events = Vec::new();
-            for event in self.vec.iter() {
+            for event in self.iter() {
                 if let Event {
                     command:
                         Command::SetTrackVoice { .. }
@@ -563,12 +623,13 @@ impl CommandSeq {
                     ..
                 } = event
                 {
-                    stateful_events.push(event.clone())
+                    // The copies need ids of their own
+                    stateful_events.push(Event::from(event.command.clone()))
                 }
             }
-            let mut seq = CommandSeq { vec: stateful_events };
+            let mut seq: CommandSeq = stateful_events.into_iter().collect();
             seq.shrink();
-            seq.vec
+            seq.into_events()
         };
         after_time.insert_many_start(0, setup);
         after_time
@@ -576,8 +637,9 @@ impl CommandSeq {
 
     /// Zeroes delays and notes before `time` as if the sequence started `time` ticks earlier.
     pub fn fast_forward(&mut self, time: usize) {
+        let mut events: Vec<Event> = mem::take(self).into_events().collect();
         let mut remaining = time;
-        for event in self.vec.iter_mut() {
+        for event in events.iter_mut() {
             match &mut event.command {
                 Command::Delay(delay) => {
                     if remaining >= *delay {
@@ -599,9 +661,161 @@ impl CommandSeq {
                 break;
             }
         }
+        *self = events.into_iter().collect();
+    }
+
+    /// Moves the notes with the given ids `delta` ticks later (or earlier, if negative), keeping every other command
+    /// at the time it was at. Moved notes go after the commands already at their new time, so that any setup commands
+    /// there still apply to them. A [Delay](Command::Delay) passing over a new time is split in two, with the new half
+    /// given an id from [gen_id](crate::id::gen_id), so call this in the song's [scope](super::Bgm::with_ids).
+    pub fn move_notes(&mut self, ids: &[Id], delta: isize) -> Result<(), NoteEditError> {
+        let notes = self.find_notes(ids)?;
+        let end = self.len_time();
+        let moved = notes
+            .iter()
+            .map(|(time, note)| Ok((shift_note(*time, delta, end, note.id)?, note.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (time, note) in &notes {
+            self.remove_note(*time, note.id);
+        }
+        for (time, note) in moved {
+            self.insert_many_end(time, iter::once(note));
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` ticks to the length of each of the notes with the given ids.
+    pub fn resize_notes(&mut self, ids: &[Id], delta: isize) -> Result<(), NoteEditError> {
+        self.update_notes(ids, |id, pitch, length| {
+            let length = (length as isize)
+                .checked_add(delta)
+                .filter(|length| (1..=NOTE_LENGTH_MAX as isize).contains(length))
+                .ok_or(NoteEditError::LengthOutOfRange { id })?;
+            Ok((pitch, length as u16))
+        })
+    }
+
+    /// Raises the pitch of each of the notes with the given ids by `semitones` (or lowers it, if negative).
+    pub fn transpose_notes(&mut self, ids: &[Id], semitones: i8) -> Result<(), NoteEditError> {
+        self.update_notes(ids, |id, pitch, length| {
+            let pitch = pitch
+                .checked_add_signed(semitones)
+                .filter(|pitch| NOTE_PITCHES.contains(pitch))
+                .ok_or(NoteEditError::PitchOutOfRange { id })?;
+            Ok((pitch, length))
+        })
+    }
+
+    /// Removes the notes with the given ids, returning them in sequence order. Notes don't move time on, so every
+    /// other command stays where it was.
+    pub fn delete_notes(&mut self, ids: &[Id]) -> Result<Vec<Event>, NoteEditError> {
+        let notes = self.find_notes(ids)?;
+        for (time, note) in &notes {
+            self.remove_note(*time, note.id);
+        }
+        Ok(notes.into_iter().map(|(_, note)| note).collect())
+    }
+
+    /// Copies the notes with the given ids to `delta` ticks after them (or before them, if negative), as
+    /// [move_notes](CommandSeq::move_notes) would move them. Returns the ids of the copies, in the same order as the
+    /// notes they are copies of are in the sequence. Like [move_notes](CommandSeq::move_notes), new ids come from
+    /// [gen_id](crate::id::gen_id), so call this in the song's [scope](super::Bgm::with_ids).
+    pub fn duplicate_notes(&mut self, ids: &[Id], delta: isize) -> Result<Vec<Id>, NoteEditError> {
+        let notes = self.find_notes(ids)?;
+        let end = self.len_time();
+        let copies = notes
+            .iter()
+            .map(|(time, note)| {
+                Ok((
+                    shift_note(*time, delta, end, note.id)?,
+                    Event::from(note.command.clone()),
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(copies
+            .into_iter()
+            .map(|(time, copy)| {
+                let id = copy.id;
+                self.insert_many_end(time, iter::once(copy));
+                id
+            })
+            .collect())
+    }
+
+    /// The notes with the given ids and their times, in sequence order. Fails if any id is not of a note in this
+    /// sequence.
+    fn find_notes(&self, ids: &[Id]) -> Result<Vec<(usize, Event)>, NoteEditError> {
+        let wanted: HashSet<Id> = ids.iter().copied().collect();
+        let mut notes = Vec::with_capacity(wanted.len());
+        for (time, event) in self.iter_time().filter(|(_, event)| wanted.contains(&event.id)) {
+            if !matches!(event.command, Command::Note { .. }) {
+                return Err(NoteEditError::NotANote { id: event.id });
+            }
+            notes.push((time, event.clone()));
+        }
+
+        if notes.len() < wanted.len() {
+            let found: HashSet<Id> = notes.iter().map(|(_, note)| note.id).collect();
+            if let Some(&id) = ids.iter().find(|id| !found.contains(id)) {
+                return Err(NoteEditError::NoEvent { id });
+            }
+        }
+        Ok(notes)
+    }
+
+    /// Removes the note with the given id from the events at `time`. Nothing else moves, because notes don't move
+    /// time on.
+    fn remove_note(&mut self, time: usize, id: Id) {
+        if let Some(group) = self.groups.get_mut(&time) {
+            group.retain(|event| event.id != id);
+            if group.is_empty() {
+                self.groups.remove(&time);
+            }
+        }
+    }
+
+    /// Changes the pitch and length of each of the notes with the given ids. `update` is given the id, pitch and
+    /// length of each note, and returns its new pitch and length. No note is changed unless `update` succeeds for
+    /// every one of them.
+    fn update_notes(
+        &mut self,
+        ids: &[Id],
+        mut update: impl FnMut(Id, u8, u16) -> Result<(u8, u16), NoteEditError>,
+    ) -> Result<(), NoteEditError> {
+        let mut updates = Vec::new();
+        for (time, note) in self.find_notes(ids)? {
+            if let Command::Note { pitch, length, .. } = note.command {
+                updates.push((time, note.id, update(note.id, pitch, length)?));
+            }
+        }
+
+        for (time, id, (new_pitch, new_length)) in updates {
+            let note = self
+                .groups
+                .get_mut(&time)
+                .and_then(|group| group.iter_mut().find(|event| event.id == id));
+            if let Some(Event {
+                command: Command::Note { pitch, length, .. },
+                ..
+            }) = note
+            {
+                *pitch = new_pitch;
+                *length = new_length;
+            }
+        }
+        Ok(())
     }
 }
 
+/// `time` moved by `delta`, if that is still within a sequence ending at `end`.
+fn shift_note(time: usize, delta: isize, end: usize, id: Id) -> Result<usize, NoteEditError> {
+    time.checked_add_signed(delta)
+        .filter(|&time| time <= end)
+        .ok_or(NoteEditError::TimeOutOfRange { id })
+}
+
 impl<C: Into<Event>> From<Vec<C>> for CommandSeq {
     fn from(vec: Vec<C>) -> Self {
         let mut new = Self::new();
@@ -612,20 +826,80 @@ impl<C: Into<Event>> From<Vec<C>> for CommandSeq {
 
 impl iter::FromIterator<Event> for CommandSeq {
     fn from_iter<T: IntoIterator<Item = Event>>(iter: T) -> Self {
-        Self {
-            vec: iter.into_iter().collect(),
+        let mut seq = Self::new();
+        for event in iter {
+            seq.push(event);
         }
+        seq
+    }
+}
+
+impl serde::Serialize for CommandSeq {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        super::ron_format::events::serialize(self.iter(), serializer)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum DelayLookup {
-    /// The index of the [Delay] that introduces the time being looked up. That is, the command (if any) immediately
-    /// following this index is at the time being looked up.
-    Found(usize),
+impl<'de> serde::Deserialize<'de> for CommandSeq {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        super::ron_format::events::deserialize(deserializer).map(|events| events.into_iter().collect())
+    }
+}
 
-    /// The index where a Delay should be inserted to introduce the time being looked up.
-    Missing { index: usize, time_at_index: usize },
+/// A sequence is (de)serialized as just its events, in order.
+impl TypeDef for CommandSeq {
+    const INFO: TypeInfo = <Vec<Event> as TypeDef>::INFO;
+}
+
+/// Error from the note editing methods of [CommandSeq], such as [CommandSeq::move_notes]. When one is returned, the
+/// sequence is left as it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteEditError {
+    NoEvent {
+        id: Id,
+    },
+    NotANote {
+        id: Id,
+    },
+    /// The note would start before the start of the sequence, or after its end.
+    TimeOutOfRange {
+        id: Id,
+    },
+    /// The note's pitch would not be one of [NOTE_PITCHES].
+    PitchOutOfRange {
+        id: Id,
+    },
+    /// The note's length would be zero, or more than [NOTE_LENGTH_MAX].
+    LengthOutOfRange {
+        id: Id,
+    },
+}
+
+impl fmt::Display for NoteEditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoteEditError::NoEvent { id } => write!(f, "no event has id {}", id),
+            NoteEditError::NotANote { id } => write!(f, "event {} is not a note", id),
+            NoteEditError::TimeOutOfRange { id } => write!(f, "note {} would be outside of the track", id),
+            NoteEditError::PitchOutOfRange { id } => write!(f, "note {} would be too high or too low", id),
+            NoteEditError::LengthOutOfRange { id } => write!(f, "note {} would be too short or too long", id),
+        }
+    }
+}
+
+impl std::error::Error for NoteEditError {}
+
+/// Result of [CommandSeq::split_polyphony].
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct PolyphonySplit {
+    /// Sequences holding the notes that were moved out.
+    pub layers: Vec<CommandSeq>,
+
+    /// Number of notes that did not fit in any layer.
+    pub dropped: usize,
+
+    /// Time of the first note that did not fit in the original sequence, if any.
+    pub first_overflow: Option<usize>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize, TypeDef)]
@@ -788,6 +1062,12 @@ use Command::Delay;
 
 pub const DELAY_MAX: u8 = 0x78;
 
+/// The pitches a [Command::Note] can have.
+pub const NOTE_PITCHES: RangeInclusive<u8> = 0x80..=0xD3;
+
+/// The longest [Command::Note] that can be encoded.
+pub const NOTE_LENGTH_MAX: u16 = 0xD3FF;
+
 impl Default for Command {
     /// Returns a no-op command. Cannot be encoded.
     fn default() -> Self {
@@ -847,68 +1127,36 @@ impl From<Command> for Event {
 
 #[derive(Clone)]
 pub struct TimeIter<'a> {
-    seq: std::slice::Iter<'a, Event>,
-    current_time: usize,
+    groups: btree_map::Iter<'a, usize, Vec<Event>>,
+    time: usize,
+    events: std::slice::Iter<'a, Event>,
 }
 
 impl<'a> Iterator for TimeIter<'a> {
     type Item = (usize, &'a Event);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.seq.next() {
-            Some(command) => {
-                let ret = (self.current_time, command);
-
-                if let Event {
-                    command: Delay(delta_time),
-                    ..
-                } = command
-                {
-                    self.current_time += *delta_time;
-                }
-
-                Some(ret)
+        loop {
+            if let Some(event) = self.events.next() {
+                return Some((self.time, event));
             }
-            None => None,
+
+            let (&time, events) = self.groups.next()?;
+            self.time = time;
+            self.events = events.iter();
         }
     }
 }
 
 pub struct TimeGroupIter<'a> {
-    seq: iter::Peekable<TimeIter<'a>>,
+    groups: btree_map::Iter<'a, usize, Vec<Event>>,
 }
 
 impl<'a> Iterator for TimeGroupIter<'a> {
     type Item = (usize, Vec<&'a Event>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.seq.next() {
-            Some((time, command)) => {
-                let ret = Some((
-                    time,
-                    iter::once(command)
-                        .chain(
-                            self.seq
-                                .clone()
-                                .take_while(move |(t, _)| *t == time)
-                                .map(|(_, command)| command),
-                        )
-                        .collect(),
-                ));
-
-                // Because we returned a cloned iterator above (in order to use take_while), we need to advance
-                // self.seq past all of the elements returned.
-                //
-                // See https://stackoverflow.com/questions/31374051
-                while self.seq.peek().map_or(false, |(t, _)| *t == time) {
-                    // Consume the peeked element
-                    self.seq.next();
-                }
-
-                ret
-            }
-            None => None,
-        }
+        self.groups.next().map(|(&time, group)| (time, group.iter().collect()))
     }
 }
 
@@ -934,9 +1182,18 @@ mod test {
         seq.insert_end(10, Command::Marker { label: "test2".into() });
         dbg!(&seq);
 
-        assert!(matches!(seq.vec[2].command, Command::Marker { .. }));
+        assert!(matches!(seq.iter().nth(2).unwrap().command, Command::Marker { .. }));
+
+        assert!(matches!(seq.iter().last().unwrap().command, Command::Marker { .. }));
 
-        assert!(matches!(seq.vec.last().unwrap().command, Command::Marker { .. }));
+        // Within a delay
+        seq.insert_many_start(20, vec![Command::End]);
+        seq.insert_end(15, Command::Marker { label: "test3".into() });
+        assert_eq!(
+            seq.at_time(15).first().map(|event| &event.command),
+            Some(&Command::Marker { label: "test3".into() })
+        );
+        assert_eq!(seq.len_time(), 20);
     }
 
     #[test]
@@ -1000,6 +1257,56 @@ mod test {
         assert_eq!(seq.max_polyphony(), 1);
     }
 
+    #[test]
+    fn split_polyphony() {
+        let note = |pitch| Command::Note {
+            pitch,
+            velocity: 100,
+            length: 10,
+        };
+        let mut seq: CommandSeq = vec![
+            Command::SetTrackVoice { index: 0 },
+            note(100),
+            note(101),
+            note(102),
+            Command::Delay(10),
+            note(103), // previous notes have finished
+        ]
+        .into();
+
+        let split = seq.split_polyphony(2, 1);
+        assert_eq!(split.dropped, 0);
+        assert_eq!(split.first_overflow, Some(0));
+        assert_eq!(split.layers.len(), 1);
+        assert_eq!(seq.max_polyphony(), 2);
+        assert_eq!(seq.len_time(), 10);
+
+        let layer = &split.layers[0];
+        assert_eq!(layer.max_polyphony(), 1);
+        assert_eq!(layer.len_time(), 10);
+        assert!(matches!(
+            layer.iter().next().unwrap().command,
+            Command::SetTrackVoice { .. }
+        ));
+        assert!(matches!(
+            layer.iter().nth(1).unwrap().command,
+            Command::Note { pitch: 102, .. }
+        ));
+
+        // Without any extra layers, overflowing notes are dropped
+        let mut seq: CommandSeq = vec![note(100), note(101), note(102)].into();
+        let split = seq.split_polyphony(2, 0);
+        assert_eq!(split.dropped, 1);
+        assert!(split.layers.is_empty());
+        assert_eq!(seq.max_polyphony(), 2);
+
+        // Nothing to do
+        let mut seq: CommandSeq = vec![note(100), note(101)].into();
+        let original = seq.clone();
+        assert_eq!(seq.split_polyphony(2, 1), PolyphonySplit::default());
+        assert_eq!(seq, original);
+    }
+
     #[test]
     fn split_at() {
         let mut seq = CommandSeq::from(vec![
@@ -1012,7 +1319,7 @@ mod test {
         let split = seq.split_at(4);
 
         assert!(matches!(
-            seq.vec[0],
+            seq.iter().next().unwrap(),
             Event {
                 command: Command::Marker { .. },
                 ..
@@ -1021,4 +1328,204 @@ mod test {
         assert_eq!(seq.len_time(), 4);
         assert_eq!(split.len_time(), 6);
     }
+
+    #[test]
+    fn edits_by_id_keep_times() {
+        // Times worked out by walking the delays, as the sequence did before it had an index
+        fn walked_times(seq: &CommandSeq) -> Vec<(usize, Command)> {
+            let mut time = 0;
+            let mut times = Vec::new();
+            for event in seq.iter() {
+                times.push((time, event.command.clone()));
+                time += delay_length(event);
+            }
+            times
+        }
+        fn check(seq: &CommandSeq) {
+            let indexed: Vec<(usize, Command)> = seq
+                .iter_time()
+                .map(|(time, event)| (time, event.command.clone()))
+                .collect();
+            assert_eq!(indexed, walked_times(seq));
+            assert_eq!(*seq, seq.iter().cloned().collect::<CommandSeq>());
+        }
+
+        let note = |pitch| Command::Note {
+            pitch,
+            velocity: 100,
+            length: 10,
+        };
+        let mut seq: CommandSeq = vec![
+            note(100),
+            Command::Delay(10),
+            note(101),
+            Command::Delay(10),
+            Command::End,
+        ]
+        .into();
+        let ids: Vec<Id> = seq.iter().map(|event| event.id).collect();
+
+        // After a delay is at the time it leads to
+        assert!(seq.insert_after(Some(ids[1]), note(102).into()));
+        assert_eq!(seq.at_time(10).len(), 3);
+        assert_eq!(seq.time_of(ids[2]), Some(10));
+        assert_eq!(seq.get_by_id(ids[2]).map(|event| &event.command), Some(&note(101)));
+        check(&seq);
+
+        // A new delay moves everything after it
+        assert!(seq.insert_after(Some(ids[0]), Command::Delay(5).into()));
+        assert_eq!(seq.len_time(), 25);
+        check(&seq);
+
+        assert_eq!(
+            seq.update_by_id(ids[1], |command| mem::replace(command, Command::Delay(1))),
+            Some(Command::Delay(10))
+        );
+        assert_eq!(seq.len_time(), 16);
+        check(&seq);
+
+        let (before, removed) = seq.remove_by_id(ids[3]).unwrap();
+        assert_eq!(before, Some(ids[2]));
+        assert_eq!(removed.command, Command::Delay(10));
+        assert_eq!(seq.len_time(), 6);
+        check(&seq);
+
+        // Zero-length delays don't start a new time
+        assert!(seq.insert_after(Some(ids[0]), Command::Delay(0).into()));
+        check(&seq);
+
+        seq.insert_start(3, note(103));
+        seq.insert_end(3, note(104));
+        assert_eq!(seq.at_time(3).len(), 3);
+        check(&seq);
+
+        // Inserted delays move what comes after them, whether the time has a group yet or not
+        for insert in [
+            CommandSeq::insert_many_start::<Command, Vec<Command>>,
+            CommandSeq::insert_many_end,
+        ] {
+            for time in [0, 5] {
+                let mut seq: CommandSeq = vec![note(100), Command::Delay(10), note(101)].into();
+                insert(&mut seq, time, vec![note(102), Command::Delay(5), note(103)]);
+                assert_eq!(seq.len_time(), 15);
+                check(&seq);
+            }
+        }
+        let mut seq: CommandSeq = vec![note(100), Command::Delay(10), note(101)].into();
+        seq.insert_many_start(0, [note(102), Command::Delay(5), note(103)]);
+        let times: Vec<usize> = seq.iter_time().map(|(time, _)| time).collect();
+        assert_eq!(times, [0, 0, 5, 5, 5, 15]);
+    }
+
+    #[test]
+    fn note_edits() {
+        let note = |pitch| Command::Note {
+            pitch,
+            velocity: 100,
+            length: 10,
+        };
+        let mut seq: CommandSeq = vec![
+            Command::SetTrackVoice { index: 0 },
+            note(0x90),
+            Command::Delay(10),
+            note(0x91),
+            Command::Delay(10),
+            Command::End,
+        ]
+        .into();
+        let ids: Vec<Id> = seq.iter().map(|event| event.id).collect();
+        let (voice, a, b, end) = (ids[0], ids[1], ids[3], ids[5]);
+        let unchanged = |seq: &CommandSeq| {
+            assert_eq!(seq.time_of(voice), Some(0));
+            assert_eq!(seq.time_of(end), Some(20));
+            assert_eq!(seq.len_time(), 20);
+        };
+
+        // Into the middle of a delay and back
+        seq.move_notes(&[a], 15).unwrap();
+        assert_eq!(seq.time_of(a), Some(15));
+        assert_eq!(seq.time_of(b), Some(10));
+        unchanged(&seq);
+        seq.move_notes(&[a], -15).unwrap();
+        assert_eq!(seq.time_of(a), Some(0));
+        assert_eq!(seq.iter().position(|event| event.id == a), Some(1)); // After the voice
+        unchanged(&seq);
+
+        // Nothing changes when any note can't be edited
+        let original = seq.clone();
+        assert_eq!(
+            seq.move_notes(&[b, a], -5),
+            Err(NoteEditError::TimeOutOfRange { id: a })
+        );
+        assert_eq!(seq.move_notes(&[a], 100), Err(NoteEditError::TimeOutOfRange { id: a }));
+        assert_eq!(
+            seq.resize_notes(&[a, b], -10),
+            Err(NoteEditError::LengthOutOfRange { id: a })
+        );
+        assert_eq!(
+            seq.transpose_notes(&[a, b], 0x43),
+            Err(NoteEditError::PitchOutOfRange { id: b })
+        );
+        assert_eq!(
+            seq.delete_notes(&[a, voice]),
+            Err(NoteEditError::NotANote { id: voice })
+        );
+        assert_eq!(seq.delete_notes(&[a, 12345]), Err(NoteEditError::NoEvent { id: 12345 }));
+        assert_eq!(seq, original);
+
+        seq.move_notes(&[a, b], 5).unwrap();
+        assert_eq!(seq.time_of(a), Some(5));
+        assert_eq!(seq.time_of(b), Some(15));
+        unchanged(&seq);
+
+        seq.resize_notes(&[a, b], 5).unwrap();
+        seq.transpose_notes(&[a, b], -0x10).unwrap();
+        assert_eq!(
+            seq.get_by_id(b).map(|event| &event.command),
+            Some(&Command::Note {
+                pitch: 0x81,
+                velocity: 100,
+                length: 15
+            })
+        );
+
+        let copies = seq.duplicate_notes(&[b, a], 1).unwrap();
+        assert_eq!(copies.len(), 2);
+        assert_eq!(seq.time_of(copies[0]), Some(6));
+        assert_eq!(seq.time_of(copies[1]), Some(16));
+        assert_eq!(
+            seq.get_by_id(copies[1]).unwrap().command,
+            seq.get_by_id(b).unwrap().command
+        );
+        unchanged(&seq);
+
+        let deleted = seq.delete_notes(&[a, b]).unwrap();
+        assert_eq!(deleted.iter().map(|note| note.id).collect::<Vec<_>>(), vec![a, b]);
+        assert_eq!(seq.time_of(copies[0]), Some(6));
+        unchanged(&seq);
+    }
+
+    #[test]
+    fn note_edits_in_a_song() {
+        let mut bgm = crate::bgm::Bgm::from_bytes(include_bytes!("../../tests/fixtures/commands.bin")).unwrap();
+        assert_eq!(bgm.ids().max(), Some(31));
+
+        let copies = bgm
+            .with_ids(|bgm| {
+                let commands = &mut bgm.track_lists.get_mut(&1).unwrap().tracks[1].commands;
+                let copies = commands.duplicate_notes(&[28], 0).unwrap();
+                // Splits the delay after the notes
+                commands.move_notes(&copies, 48).unwrap();
+                copies
+            })
+            .unwrap();
+        assert_eq!(copies, [32]);
+
+        let mut ids: Vec<Id> = bgm.ids().collect();
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+        assert_eq!(ids.last(), Some(&33));
+    }
 }
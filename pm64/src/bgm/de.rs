@@ -1,37 +1,338 @@
+use std::collections::HashMap;
 use std::collections::btree_map::{BTreeMap, Entry};
 use std::fmt;
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
-use std::mem::MaybeUninit;
 
 use log::{debug, warn};
 
 use super::*;
-use crate::id::gen_id;
+use crate::id::{IdAllocator, OutOfIds, gen_id};
 use crate::rw::*;
 
 #[derive(Debug)]
-pub enum Error {
+pub struct Error {
+    /// Position in the file the error was found at. Only `None` if the error came from a reader that can't tell its
+    /// position.
+    pub offset: Option<u64>,
+    pub context: Box<Context>,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
     InvalidMagic,
-    SizeMismatch { true_size: u32, internal_size: u32 },
+    SizeMismatch {
+        true_size: u32,
+        internal_size: u32,
+    },
     InvalidNumVariations(u8),
     UnknownSegmentCommand(u32),
     UnknownSeqCommand(u8),
     UnknownBankSet(u8),
+    /// A [Detour](Command::Detour) to before the start of its sequence.
+    InvalidDetour(u16),
+    /// A [Detour](Command::Detour) into the middle of a command, or past the end of its sequence.
+    InvalidMarker(usize),
+    EmptySequence,
+    /// The song has more events than [MAX_EVENTS_PER_BYTE] allows for its size.
+    TooManyEvents,
+    OutOfIds,
     Io(io::Error),
 }
 
+/// What was being decoded when an [Error] happened, from the outside in. Parts that don't apply are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub variation: Option<usize>,
+    pub segment: Option<usize>,
+    /// Position of the track list in the file. Track lists are only given ids once they are decoded.
+    pub track_list: Option<u64>,
+    pub track: Option<usize>,
+    /// Index of the command in its track, counting from 0.
+    pub command: Option<usize>,
+    pub drum: Option<usize>,
+    pub instrument: Option<usize>,
+}
+
+impl Error {
+    pub(super) fn at(offset: u64, kind: ErrorKind) -> Self {
+        Self {
+            offset: Some(offset),
+            context: Default::default(),
+            kind,
+        }
+    }
+
+    /// Errors from reads are made without knowing where the read was, so take the position from the reader, which is
+    /// left where the failed read started.
+    fn or_at<R: Seek>(mut self, f: &mut R) -> Self {
+        if self.offset.is_none() {
+            self.offset = f.stream_position().ok();
+        }
+        self
+    }
+
+    fn in_variation(mut self, variation: usize) -> Self {
+        self.context.variation = Some(variation);
+        self
+    }
+
+    fn in_segment(mut self, segment: usize) -> Self {
+        self.context.segment = Some(segment);
+        self
+    }
+
+    fn in_track(mut self, track_list: u64, track: usize) -> Self {
+        self.context.track_list = Some(track_list);
+        self.context.track = Some(track);
+        self
+    }
+
+    fn in_command(mut self, command: usize) -> Self {
+        self.context.command = Some(command);
+        self
+    }
+
+    fn in_drum(mut self, drum: usize) -> Self {
+        self.context.drum = Some(drum);
+        self
+    }
+
+    fn in_instrument(mut self, instrument: usize) -> Self {
+        self.context.instrument = Some(instrument);
+        self
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self {
+            offset: None,
+            context: Default::default(),
+            kind,
+        }
+    }
+}
+
+impl From<OutOfIds> for Error {
+    fn from(_: OutOfIds) -> Self {
+        ErrorKind::OutOfIds.into()
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(io: io::Error) -> Self {
-        Self::Io(io)
+        ErrorKind::Io(io).into()
     }
 }
 
 impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at {:#X}", offset)?;
+        }
+        if *self.context != Context::default() {
+            write!(f, " (in {})", self.context)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(variation) = self.variation {
+            parts.push(format!("variation {}", variation));
+        }
+        if let Some(segment) = self.segment {
+            parts.push(format!("segment {}", segment));
+        }
+        if let Some(track_list) = self.track_list {
+            parts.push(format!("track list at {:#X}", track_list));
+        }
+        if let Some(track) = self.track {
+            parts.push(format!("track {}", track));
+        }
+        if let Some(command) = self.command {
+            parts.push(format!("command {}", command));
+        }
+        if let Some(drum) = self.drum {
+            parts.push(format!("drum {}", drum));
+        }
+        if let Some(instrument) = self.instrument {
+            parts.push(format!("instrument {}", instrument));
+        }
+        write!(f, "{}", parts.join(" > "))
+    }
+}
+
+/// How many events a song may decode to per byte of input. Tracks can share a sequence, so a song can have more
+/// events than bytes, but without a limit a small file can point thousands of tracks at one long sequence.
+const MAX_EVENTS_PER_BYTE: usize = 32;
+
+/// What has been decoded so far, and what [Bgm::decode_lenient] recovered from. Decoding is strict when `errors` is
+/// `None`.
+#[derive(Default)]
+struct State {
+    errors: Option<Vec<Error>>,
+    unknowns: Vec<Unknown>,
+    /// Sequences already decoded, by position, so that tracks sharing one only read it once.
+    sequences: HashMap<u64, CommandSeq>,
+    /// Events decoded so far, counting each track that shares a sequence.
+    events: usize,
+    max_events: usize,
+}
+
+impl State {
+    fn lenient() -> Self {
+        Self {
+            errors: Some(Vec::new()),
+            ..Default::default()
+        }
+    }
+
+    /// Decodes the sequence at `pos`, or copies it with new ids if another track has decoded it already.
+    fn sequence<R: Read + Seek>(&mut self, f: &mut R, pos: u64) -> Result<CommandSeq, Error> {
+        let seq = match self.sequences.get(&pos) {
+            Some(seq) => {
+                let mut seq = seq.clone();
+                for id in seq.ids_mut() {
+                    *id = gen_id();
+                }
+                seq
+            }
+            None => {
+                f.seek(SeekFrom::Start(pos))?;
+                let seq = CommandSeq::decode(f, self)?;
+                if seq.iter().next().is_none() {
+                    return Err(Error::at(pos, ErrorKind::EmptySequence));
+                }
+                self.sequences.insert(pos, seq.clone());
+                seq
+            }
+        };
+
+        self.events += seq.len();
+        if self.events > self.max_events {
+            return Err(Error::at(pos, ErrorKind::TooManyEvents));
+        }
+        Ok(seq)
+    }
+
+    /// Records `error` and returns `Ok` if decoding is lenient, or returns it if not.
+    fn recover(&mut self, error: Error) -> Result<(), Error> {
+        match &mut self.errors {
+            Some(errors) => {
+                warn!("recovered from error: {}", error);
+                errors.push(error);
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.errors.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Adds context to the errors recovered since there were `count` of them, like propagating them would have.
+    fn annotate(&mut self, count: usize, annotate: impl Fn(Error) -> Error) {
+        if let Some(errors) = &mut self.errors {
+            let annotated: Vec<Error> = errors.drain(count..).map(annotate).collect();
+            errors.extend(annotated);
+        }
+    }
+
+    /// Keeps the bytes from `start` to the end of the sequence there (the next zero byte) as an [Unknown], so that
+    /// encoding writes them back as they were.
+    fn keep_bytes<R: Read + Seek>(&mut self, f: &mut R, start: u64) -> io::Result<()> {
+        f.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        let mut byte = [0];
+        while f.read(&mut byte)? == 1 {
+            data.push(byte[0]);
+            if byte[0] == 0 {
+                break;
+            }
+        }
+        if !data.is_empty() {
+            self.unknowns.push(Unknown { start, data });
+        }
+        Ok(())
+    }
+}
+
+/// A reader that remembers which bytes were read from it, so that the ones that weren't can be kept as [Unknown]s.
+struct Tracked<'a, R> {
+    inner: &'a mut R,
+    /// Ranges that were read, in the order they were read. Reads that carry on from the last one extend it.
+    read: Vec<Range<u64>>,
+}
+
+impl<'a, R: Read + Seek> Tracked<'a, R> {
+    fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            read: Vec::new(),
+        }
+    }
+
+    /// The reader underneath, for peeking at bytes without counting them as read.
+    fn untracked(&mut self) -> &mut R {
+        self.inner
+    }
+
+    /// Every run of bytes between the ones that were read, unless it is all zero (i.e. padding).
+    fn unread(&mut self) -> io::Result<Vec<Unknown>> {
+        let eof = self.inner.seek(SeekFrom::End(0))?;
+        let mut read = std::mem::take(&mut self.read);
+        read.sort_by_key(|range| range.start);
+
+        let mut unknowns = Vec::new();
+        let mut pos = 0;
+        for range in read.into_iter().chain(std::iter::once(eof..eof)) {
+            if range.start > pos {
+                let mut data = vec![0; (range.start - pos) as usize];
+                self.inner.seek(SeekFrom::Start(pos))?;
+                self.inner.read_exact(&mut data)?;
+                if data.iter().any(|&byte| byte != 0) {
+                    warn!("unused data at {:#X}..{:#X}", pos, range.start);
+                    unknowns.push(Unknown { start: pos, data });
+                }
+            }
+            pos = pos.max(range.end);
+        }
+        Ok(unknowns)
+    }
+}
+
+impl<R: Read + Seek> Read for Tracked<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.inner.stream_position()?;
+        let len = self.inner.read(buf)?;
+        let end = start + len as u64;
+        match self.read.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ if len > 0 => self.read.push(start..end),
+            _ => {}
+        }
+        Ok(len)
+    }
+}
+
+impl<R: Seek> Seek for Tracked<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::InvalidMagic => write!(f, "Missing 'BGM' signature at start"),
-            Error::SizeMismatch {
+            ErrorKind::InvalidMagic => write!(f, "Missing 'BGM' signature at start"),
+            ErrorKind::SizeMismatch {
                 true_size,
                 internal_size,
             } => write!(
@@ -39,15 +340,24 @@ impl fmt::Display for Error {
                 "The file says it is {}B, but it is actually {}B",
                 internal_size, true_size
             ),
-            Error::InvalidNumVariations(num_segments) => write!(
+            ErrorKind::InvalidNumVariations(num_segments) => write!(
                 f,
                 "Exactly 4 variations are supported, but this file has {}",
                 num_segments
             ),
-            Error::UnknownSegmentCommand(cmd) => write!(f, "Unknown segment command: {:#X}", cmd),
-            Error::UnknownSeqCommand(cmd) => write!(f, "Unknown sequence command: {:#X}", cmd),
-            Error::UnknownBankSet(bank_set) => write!(f, "Unknown bank set: {:#X}", bank_set),
-            Error::Io(source) => {
+            ErrorKind::UnknownSegmentCommand(cmd) => write!(f, "Unknown segment command: {:#X}", cmd),
+            ErrorKind::UnknownSeqCommand(cmd) => write!(f, "Unknown sequence command: {:#X}", cmd),
+            ErrorKind::UnknownBankSet(bank_set) => write!(f, "Unknown bank set: {:#X}", bank_set),
+            ErrorKind::InvalidDetour(start) => write!(f, "Detour to {:#X}, before the start of its sequence", start),
+            ErrorKind::InvalidMarker(offset) => write!(
+                f,
+                "Detour to offset {:#X} of its sequence, which is not the start of a command",
+                offset
+            ),
+            ErrorKind::EmptySequence => write!(f, "Sequence has no commands"),
+            ErrorKind::TooManyEvents => write!(f, "Too many events for the size of the file"),
+            ErrorKind::OutOfIds => write!(f, "{}", OutOfIds),
+            ErrorKind::Io(source) => {
                 if let io::ErrorKind::UnexpectedEof = source.kind() {
                     write!(f, "Unexpected end-of-file")
                 } else {
@@ -60,60 +370,50 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::Io(source) => Some(source),
+        match &self.kind {
+            ErrorKind::Io(source) => Some(source),
             _ => None,
         }
     }
 }
 
-trait CollectArray<T, E, U: Default + AsMut<[T]>>: Sized + Iterator<Item = Result<T, E>> {
-    /// Doesn't panic if the iterator is too large or too small for the output array. If the iterator is too short,
-    /// the remaining elements have their default value.
-    #[allow(unused)]
-    fn collect_array(mut self) -> Result<U, E> {
-        let mut container = U::default();
-
-        for a in container.as_mut().iter_mut() {
-            match self.next() {
-                None => break,
-                Some(v) => *a = v?,
-            }
-        }
-
-        Ok(container)
-    }
-
-    /// Same as `collect_array`, but panics if the iterator is not exactly the same size as the array.
-    /// Based on https://stackoverflow.com/a/60572615
-    fn collect_array_pedantic(mut self) -> Result<U, E> {
-        let mut container = U::default(); // Could use std::mem::zerored and drop Default requirement here
-
-        for a in container.as_mut().iter_mut() {
-            match self.next() {
-                None => panic!("iterator has too few members"),
-                Some(v) => *a = v?,
-            }
-        }
-
-        assert!(self.next().is_none(), "iterator has too many members");
-        Ok(container)
-    }
-}
-
-impl<T, E, U: Iterator<Item = Result<T, E>>, V: Default + AsMut<[T]>> CollectArray<T, E, V> for U {}
-
 impl Bgm {
     pub fn from_bytes(f: &[u8]) -> Result<Self, Error> {
         Self::decode(&mut std::io::Cursor::new(f))
     }
 
+    /// Ids are allocated from zero, so decoding the same data always gives the same ids.
     pub fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+        IdAllocator::new()
+            .scope(|| Self::decode_song(&mut Tracked::new(f), &mut State::default()))
+            .map_err(Error::from)
+            .and_then(|bgm| bgm)
+            .map_err(|e| e.or_at(f))
+    }
+
+    /// Like [Bgm::decode], but carries on past errors in variations, tracks, drums and instruments, returning them
+    /// with what could be decoded. Errors in the header still stop decoding.
+    ///
+    /// A command sequence with an error stops where the error is, without an [End](Command::End), and the rest of its
+    /// bytes are kept in [Bgm::unknowns], so that encoding the song writes them back as they were. Remove them and end
+    /// the sequence to get a song that decodes without errors.
+    pub fn decode_lenient<R: Read + Seek>(f: &mut R) -> Result<(Self, Vec<Error>), Error> {
+        let mut state = State::lenient();
+        let bgm = IdAllocator::new()
+            .scope(|| Self::decode_song(&mut Tracked::new(f), &mut state))
+            .map_err(Error::from)
+            .and_then(|bgm| bgm)
+            .map_err(|e| e.or_at(f))?;
+        Ok((bgm, state.errors.unwrap_or_default()))
+    }
+
+    /// Bytes that are never read are kept in [Bgm::unknowns].
+    fn decode_song<R: Read + Seek>(f: &mut Tracked<R>, state: &mut State) -> Result<Self, Error> {
         f.seek(SeekFrom::Start(0))?;
         let mut magic = [0; 4];
         f.read_exact(&mut magic)?;
         if magic != MAGIC.as_bytes() {
-            return Err(Error::InvalidMagic);
+            return Err(Error::at(0, ErrorKind::InvalidMagic));
         }
 
         debug_assert!(f.pos()? == 0x04);
@@ -124,7 +424,8 @@ impl Bgm {
         if internal_size == true_size {
             // Ok
         } else if {
-            // Check for Mamar metadata magic string
+            // Check for Mamar metadata magic string. It's read again below if it's there.
+            let f = f.untracked();
             f.seek(SeekFrom::Start(mamar_magic_pos))?;
             matches!(f.read_cstring(mamar::MAGIC_MAX_LEN as u64).as_deref(), Ok(s) if s == mamar::MAGIC)
         } {
@@ -140,11 +441,14 @@ impl Bgm {
             );
         }
         let has_mamar_metadata = has_mamar_metadata;
+        state.max_events = (true_size as usize).saturating_mul(MAX_EVENTS_PER_BYTE);
 
         let mut bgm = Bgm::new();
 
         f.seek(SeekFrom::Start(0x08))?;
-        bgm.name = f.read_cstring(4)?;
+        let mut name = [0; 4];
+        f.read_exact(&mut name)?;
+        bgm.name = name.as_slice().read_cstring(4)?;
 
         debug_assert!(f.pos()? == 0x0C);
         f.read_padding(4)?;
@@ -152,7 +456,7 @@ impl Bgm {
         debug_assert!(f.pos()? == 0x10);
         let num_variations = f.read_u8()?;
         if num_variations != 4 {
-            return Err(Error::InvalidNumVariations(num_variations));
+            return Err(Error::at(0x10, ErrorKind::InvalidNumVariations(num_variations)));
         }
 
         debug_assert!(f.pos()? == 0x11);
@@ -171,107 +475,106 @@ impl Bgm {
 
         debug_assert!(f.pos()? == 0x24); // End of struct
 
-        let mut furthest_read_pos = 0; // TODO: have `f` track this (i.e. a wrapper over f)
-
-        // Special cases to get problematic BGMs to match
-        let unknown = match bgm.name.as_str() {
-            // Bowser's Castle Caves (entire segment? TODO look into this)
-            "169 " => Unknown::decode(f, 0x0064..0x1294).ok(),
+        let mut variations: [Option<Variation>; 4] = Default::default();
+        for (variation, (&pos, slot)) in variation_offsets.iter().zip(&mut variations).enumerate() {
+            if pos == 0 {
+                // Null (no segments)
+                continue;
+            }
 
-            // These appear to be extract.py artifacts as they are not present in the SBN files
-            "117 " => Unknown::decode(f, 0x1934..0x19A0).ok(), // Battle Fanfare (very short segment at eof?)
-            "322 " => Unknown::decode(f, 0x0D15..0x0D70).ok(), /* Bowser's Castle Explodes (very short segment at */
-            // eof?)
-            _ => None,
-        };
-        bgm.unknowns = if let Some(unknown) = unknown {
-            vec![unknown]
-        } else {
-            vec![]
-        };
+            // Seek to the offset and decode the segment(s) there
+            let pos = pos as u64;
+            f.seek(SeekFrom::Start(pos))?;
 
-        bgm.variations = variation_offsets
-            .iter()
-            .map(|&pos| -> Result<Option<Variation>, Error> {
-                if pos == 0 {
-                    // Null (no segments)
-                    Ok(None)
-                } else {
-                    // Seek to the offset and decode the segment(s) there
-                    let pos = pos as u64;
-                    f.seek(SeekFrom::Start(pos))?;
+            debug!("segment {:#X}", pos);
 
-                    debug!("segment {:#X}", pos);
+            let mut subsegments = vec![];
+            let mut i = 0;
+            loop {
+                f.seek(SeekFrom::Start(pos + i * 4))?;
 
-                    let mut subsegments = vec![];
-                    let mut i = 0;
-                    while {
-                        f.seek(SeekFrom::Start(pos + i * 4))?;
+                // Peek for null terminator
+                let word = match f.read_u32_be() {
+                    Ok(word) => word,
+                    Err(e) => {
+                        // Keep the segments before the end of the file
+                        state.recover(Error::from(e).in_variation(variation).or_at(f))?;
+                        break;
+                    }
+                };
+                if word == 0 {
+                    break;
+                }
+                f.seek(SeekFrom::Current(-4))?;
 
-                        // Peek for null terminator
-                        let word = f.read_u32_be()?;
-                        f.seek(SeekFrom::Current(-4))?;
-                        word != 0
-                    } {
-                        subsegments.push(Segment::decode(f, &mut bgm, pos, &mut furthest_read_pos)?);
+                let count = state.count();
+                match Segment::decode(f, &mut bgm, pos, state) {
+                    Ok(segment) => subsegments.push(segment),
+                    Err(e) => state.recover(e.in_segment(i as usize).in_variation(variation).or_at(f))?,
+                }
+                state.annotate(count, |e| e.in_segment(i as usize).in_variation(variation));
 
-                        i += 1;
-                    }
+                i += 1;
+            }
 
-                    debug!("segment end {:#X}", f.pos()?);
+            debug!("segment end {:#X}", f.pos()?);
 
-                    Ok(Some(Variation { segments: subsegments }))
-                }
-            })
-            .collect_array_pedantic()?;
+            *slot = Some(Variation { segments: subsegments });
+        }
+        bgm.variations = variations;
 
+        // If one fails, keep the ones before it
         if drums_offset != 0 {
             f.seek(SeekFrom::Start(drums_offset))?;
-            bgm.drums = (0..drums_count).map(|_| Drum::decode(f)).collect::<Result<_, _>>()?;
+            for i in 0..drums_count as usize {
+                match Drum::decode(f) {
+                    Ok(drum) => bgm.drums.push(drum),
+                    Err(e) => {
+                        state.recover(e.in_drum(i).or_at(f))?;
+                        break;
+                    }
+                }
+            }
         }
 
         if voices_offset != 0 {
             f.seek(SeekFrom::Start(voices_offset))?;
-            bgm.instruments = (0..voices_count)
-                .map(|_| Instrument::decode(f))
-                .collect::<Result<_, _>>()?;
+            for i in 0..voices_count as usize {
+                match Instrument::decode(f) {
+                    Ok(instrument) => bgm.instruments.push(instrument),
+                    Err(e) => {
+                        state.recover(e.in_instrument(i).or_at(f))?;
+                        break;
+                    }
+                }
+            }
         };
 
         if has_mamar_metadata {
-            f.seek(SeekFrom::Start(mamar_magic_pos + mamar::MAGIC_MAX_LEN as u64))?;
+            f.seek(SeekFrom::Start(mamar_magic_pos))?;
+            f.read_cstring(mamar::MAGIC_MAX_LEN as u64)?;
             if let Ok(metadata) = rmp_serde::from_read::<_, mamar::Metadata>(&mut *f) {
                 metadata.apply_to_bgm(&mut bgm);
             } else {
                 warn!("unable to decode Mamar metadata, ignoring it");
             }
-            furthest_read_pos = f.pos()?;
         }
 
-        // TODO: check that all the data between furthest_read_pos and align(furthest_read_pos) is padding (zero)
-
-        let eof_pos = f.seek(SeekFrom::End(0))?;
-        if align(furthest_read_pos as u32, 16) < eof_pos as u32 {
-            // TODO: make this an error
-            warn!("unused data at {:#X}", furthest_read_pos);
-        }
+        bgm.unknowns.append(&mut state.unknowns);
+        bgm.unknowns.append(&mut f.unread()?);
+        bgm.unknowns.sort_by_key(|unknown| unknown.start);
 
         Ok(bgm)
     }
 }
 
 impl Segment {
-    fn decode<R: Read + Seek>(
-        f: &mut R,
-        bgm: &mut Bgm,
-        start: u64,
-        furthest_read_pos: &mut u64,
-    ) -> Result<Self, Error> {
+    fn decode<R: Read + Seek>(f: &mut R, bgm: &mut Bgm, start: u64, state: &mut State) -> Result<Self, Error> {
         // Equivalent engine func: au_bgm_player_read_segment
 
         debug!("subsegment {:#X}", f.pos()?);
         let data = f.read_u32_be()?;
         match data >> 12 {
-            segment_commands::END => unreachable!("END should be handled by the caller"),
             segment_commands::SUBSEG => {
                 f.seek(SeekFrom::Current(-2))?;
                 let offset = (f.read_u16_be()? as u64) << 2;
@@ -283,33 +586,25 @@ impl Segment {
                 let track_list = match bgm.find_track_list_with_pos(track_list_pos) {
                     Some(id) => id,
                     None => {
-                        let mut tracks: [MaybeUninit<Track>; 16] = unsafe {
-                            // SAFETY: this is an array of uninitialised elements, so the array overall does not require
-                            // initialisation.
-                            MaybeUninit::uninit().assume_init()
-                        };
-
+                        let mut tracks: [Track; 16] = Default::default();
                         for (track_no, track) in tracks.iter_mut().enumerate() {
                             let track_no = track_no as u64;
 
                             f.seek(SeekFrom::Start(track_list_pos + track_no * 4))?;
-                            *track = MaybeUninit::new(Track::decode(f, track_list_pos)?);
-
-                            let pos = f.pos()?;
-                            if pos > *furthest_read_pos {
-                                *furthest_read_pos = pos;
-                            }
+                            let count = state.count();
+                            *track = match Track::decode(f, track_list_pos, state) {
+                                Ok(track) => track,
+                                Err(e) => {
+                                    state.recover(e.in_track(track_list_pos, track_no as usize).or_at(f))?;
+                                    Track::default()
+                                }
+                            };
+                            state.annotate(count, |e| e.in_track(track_list_pos, track_no as usize));
                         }
 
                         bgm.add_track_list(TrackList {
                             pos: Some(track_list_pos),
-                            tracks: unsafe {
-                                // TODO: tbh just make this a vec, modding is a thing
-                                // SAFETY: the for loop above has initialised the array. This is also how the std
-                                // documentation suggests initialising an array element-by-element:
-                                // https://doc.rust-lang.org/std/mem/union.MaybeUninit.html#initializing-an-array-element-by-element
-                                std::mem::transmute::<[MaybeUninit<Track>; 16], [Track; 16]>(tracks)
-                            },
+                            tracks,
                         })
                     }
                 };
@@ -348,13 +643,15 @@ impl Segment {
                     iter_count: ((data >> 5) & 0x7F) as u8, // bits 5-11
                 })
             }
-            _ => Err(Error::UnknownSegmentCommand(data)),
+            // A zero word ends the variation and is handled by the caller, so this is an END with arguments
+            segment_commands::END => Err(Error::at(f.pos()? - 4, ErrorKind::UnknownSegmentCommand(data))),
+            _ => Err(Error::at(f.pos()? - 4, ErrorKind::UnknownSegmentCommand(data))),
         }
     }
 }
 
 impl Track {
-    fn decode<R: Read + Seek>(f: &mut R, segment_start: u64) -> Result<Self, Error> {
+    fn decode<R: Read + Seek>(f: &mut R, segment_start: u64, state: &mut State) -> Result<Self, Error> {
         let commands_offset = f.read_u16_be()?;
         let flags = f.read_u16_be()?;
 
@@ -363,13 +660,11 @@ impl Track {
         let is_drum_track = (flags & 0x0080) != 0;
         let parent_track_idx = ((flags & (0xF << 9)) >> 9) as u8;
 
-        let commands = if commands_offset == 0 {
-            CommandSeq::with_capacity(0)
+        let (commands, pos) = if commands_offset == 0 {
+            (CommandSeq::with_capacity(0), None)
         } else {
-            f.seek(SeekFrom::Start(segment_start + commands_offset as u64))?;
-            let seq = CommandSeq::decode(f)?;
-            assert_ne!(seq.len(), 0);
-            seq
+            let pos = segment_start + commands_offset as u64;
+            (state.sequence(f, pos)?, Some(pos))
         };
 
         Ok(Self {
@@ -389,12 +684,26 @@ impl Track {
             },
             is_drum_track,
             commands,
+            pos,
         })
     }
 }
 
 impl CommandSeq {
-    fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+    fn decode<R: Read + Seek>(f: &mut R, state: &mut State) -> Result<Self, Error> {
+        let (events, _) = OffsetEventMap::decode_with(f, state)?;
+        Ok(events.into())
+    }
+}
+
+impl OffsetEventMap {
+    /// Decodes the command sequence at the current position, returning its commands keyed by offset and its size in
+    /// bytes.
+    pub(super) fn decode<R: Read + Seek>(f: &mut R) -> Result<(Self, usize), Error> {
+        Self::decode_with(f, &mut State::default())
+    }
+
+    fn decode_with<R: Read + Seek>(f: &mut R, state: &mut State) -> Result<(Self, usize), Error> {
         let start = f.pos()? as usize;
 
         // A binary tree mapping input offset -> Command. This is then trivially converted to a
@@ -402,18 +711,12 @@ impl CommandSeq {
         let mut events = OffsetEventMap::new();
 
         let mut seen_terminator = false;
+        let mut index = 0;
 
         loop {
             let cmd_offset = (f.pos()? as usize) - start;
 
             if seen_terminator {
-                // TEMP: for matching. This should really look at the BGM index and not run for all files...
-                // There's probably some command that I don't yet know about that points to this data.
-                if f.pos()? == 0x38EB {
-                    // 0x64 Bowser's Castle
-                    events.upsert_marker(0x39EB - start);
-                }
-
                 // Sometimes there is a terminator followed by some marked commands (i.e. a subroutine section), so
                 // keep reading until every marker has been passed.
                 if cmd_offset >= events.last_offset() {
@@ -421,148 +724,175 @@ impl CommandSeq {
                 }
             }
 
-            let cmd_byte = f.read_u8()?;
+            let command = match events.decode_command(f, start, &mut seen_terminator) {
+                Ok(command) => command,
+                Err(error) => {
+                    state.recover(error.in_command(index).or_at(f))?;
 
-            let command = match cmd_byte {
-                // Sentinel (zero-terminator)
-                0x00 => {
-                    seen_terminator = true;
-                    Command::End
+                    // End the sequence here, and drop any markers after it. The rest is kept as bytes, which encode
+                    // straight after the sequence, so there is no End: the game reads on into them as it did before.
+                    state.keep_bytes(f, (start + cmd_offset) as u64)?;
+                    events.0.split_off(&OffsetEventMap::atob(cmd_offset));
+                    return Ok((events, cmd_offset));
                 }
+            };
+            index += 1;
 
-                // Delay
-                0x01..=0x77 => Command::Delay(cmd_byte as usize),
+            events.insert(cmd_offset, command.into());
+        }
 
-                // Long delay
-                0x78..=0x7F => {
-                    // It's possible that this logic is entirely wrong, I just derived it from the inverse
-                    // of the midi2bgm routine encoding delays.
+        let size = f.pos()? as usize - start;
+        //debug!("end commandseq {:#X}", f.pos()?);
 
-                    let num_256s = (cmd_byte - 0x78) as usize;
-                    let extend = f.read_u8()? as usize;
+        // There can't be anything (i.e. markers) past the end of the sequence
+        if let Some((&key, _)) = events.0.range(OffsetEventMap::atob(size)..).next() {
+            let offset = OffsetEventMap::btoa(key);
+            return Err(Error::at((start + offset) as u64, ErrorKind::InvalidMarker(offset)));
+        }
 
-                    Command::Delay(0x78 + num_256s * 256 + extend)
+        Ok((events, size))
+    }
 
-                    // This logic taken from N64MidiTool
-                    //Command::Delay(0x78 + (cmd_byte as usize) + ((f.read_u8()? & 7) as usize) << 8)
-                }
+    /// Decodes the command at the current position of a sequence starting at `start`.
+    fn decode_command<R: Read + Seek>(
+        &mut self,
+        f: &mut R,
+        start: usize,
+        seen_terminator: &mut bool,
+    ) -> Result<Command, Error> {
+        let events = self;
+        let cmd_byte = f.read_u8()?;
+
+        Ok(match cmd_byte {
+            // Sentinel (zero-terminator)
+            0x00 => {
+                *seen_terminator = true;
+                Command::End
+            }
 
-                // Note
-                0x80..=0xD3 => {
-                    let pitch = cmd_byte;
-                    let velocity = f.read_u8()?;
-                    let length = {
-                        let first_byte = f.read_u8()? as u16;
+            // Delay
+            0x01..=0x77 => Command::Delay(cmd_byte as usize),
 
-                        // This logic taken from N64MidiTool
-                        if first_byte < 0xC0 {
-                            first_byte
-                        } else {
-                            let second_byte = f.read_u8()? as u16;
+            // Long delay
+            0x78..=0x7F => {
+                // It's possible that this logic is entirely wrong, I just derived it from the inverse
+                // of the midi2bgm routine encoding delays.
 
-                            debug_assert_eq!(first_byte & 0xC0, 0xC0);
+                let num_256s = (cmd_byte - 0x78) as usize;
+                let extend = f.read_u8()? as usize;
 
-                            0xC0 + (((first_byte & !0xC0) << 8) | second_byte)
-                        }
-                    };
-                    //assert!(length < 0x4000, "{:#X}", length);
+                Command::Delay(0x78 + num_256s * 256 + extend)
 
-                    Command::Note {
-                        pitch,
-                        velocity,
-                        length,
-                    }
-                }
+                // This logic taken from N64MidiTool
+                //Command::Delay(0x78 + (cmd_byte as usize) + ((f.read_u8()? & 7) as usize) << 8)
+            }
 
-                0xE0 => Command::MasterTempo(f.read_u16_be()?),
-                0xE1 => Command::MasterVolume(f.read_u8()?),
-                0xE2 => Command::MasterPitchShift { cent: f.read_u8()? },
-                0xE3 => Command::UnkCmdE3 {
-                    effect_type: f.read_u8()?,
-                },
-                0xE4 => Command::MasterTempoFade {
-                    time: f.read_u16_be()?,
-                    value: f.read_u16_be()?,
-                },
-                0xE5 => Command::MasterVolumeFade {
-                    time: f.read_u16_be()?,
-                    volume: f.read_u8()?,
-                },
-                0xE6 => Command::MasterEffect {
-                    index: f.read_u8()?,
-                    value: f.read_u8()?,
-                },
-                // command 0xE7 unused
-                0xE8 => Command::TrackOverridePatch(PatchAddress::decode(f)?),
-                0xE9 => Command::SubTrackVolume(f.read_u8()?),
-                0xEA => Command::SubTrackPan(f.read_i8()?),
-                0xEB => Command::SubTrackReverb(f.read_u8()?),
-                0xEC => Command::SegTrackVolume(f.read_u8()?),
-                0xED => Command::SubTrackCoarseTune(f.read_u8()?),
-                0xEE => Command::SubTrackFineTune(f.read_u8()?),
-                0xEF => Command::SegTrackTune { bend: f.read_i16_be()? },
-                0xF0 => Command::TrackTremolo {
-                    amount: f.read_u8()?,
-                    speed: f.read_u8()?,
-                    time: f.read_u8()?,
-                },
-                0xF1 => Command::TrackTremoloSpeed(f.read_u8()?),
-                0xF2 => Command::TrackTremoloTime { time: f.read_u8()? },
-                0xF3 => Command::TrackTremoloStop,
-                0xF4 => Command::UnkCmdF4 {
-                    pan0: f.read_u8()?,
-                    pan1: f.read_u8()?,
-                },
-                0xF5 => Command::SetTrackVoice { index: f.read_u8()? },
-                0xF6 => Command::TrackVolumeFade {
-                    time: f.read_u16_be()?,
-                    value: f.read_u8()?,
-                },
-                0xF7 => Command::SubTrackReverbType { index: f.read_u8()? },
-                // commands 0xF8-FB unused
-                0xFC => Command::Jump {
-                    unk_00: f.read_u16_be()?, // TODO: this is an offset, go there!!
-                    unk_02: f.read_u8()?,
-                },
-                0xFD => Command::EventTrigger {
-                    event_info: f.read_u32_be()?,
-                },
-                0xFE => {
-                    let start_offset = f.read_u16_be()? as usize - start;
-                    let end_offset = start_offset + (f.read_u8()? as usize);
+            // Note
+            0x80..=0xD3 => {
+                let pitch = cmd_byte;
+                let velocity = f.read_u8()?;
+                let length = {
+                    let first_byte = f.read_u8()? as u16;
 
-                    Command::Detour {
-                        start_label: events.upsert_marker(start_offset),
-                        end_label: events.upsert_marker(end_offset),
-                    }
-                }
-                0xFF => Command::UnkCmdFF {
-                    unk_00: f.read_u8()?,
-                    unk_01: f.read_u8()?,
-                    unk_02: f.read_u8()?,
-                },
+                    // This logic taken from N64MidiTool
+                    if first_byte < 0xC0 {
+                        first_byte
+                    } else {
+                        let second_byte = f.read_u8()? as u16;
 
-                _ => return Err(Error::UnknownSeqCommand(cmd_byte)),
-            };
+                        debug_assert_eq!(first_byte & 0xC0, 0xC0);
 
-            events.insert(cmd_offset, command.into());
-        }
+                        0xC0 + (((first_byte & !0xC0) << 8) | second_byte)
+                    }
+                };
+                //assert!(length < 0x4000, "{:#X}", length);
 
-        let size = f.pos()? as usize - start;
-        //debug!("end commandseq {:#X}", f.pos()?);
+                Command::Note {
+                    pitch,
+                    velocity,
+                    length,
+                }
+            }
 
-        // Explode if there are no commands (must be markers) past the end of the file
-        if let Some((offset, event)) = events.0.split_off(&OffsetEventMap::atob(size)).into_iter().next() {
-            panic!("command after end of parsed sequence {:?} @ {:#X}", event, offset);
-        }
+            0xE0 => Command::MasterTempo(f.read_u16_be()?),
+            0xE1 => Command::MasterVolume(f.read_u8()?),
+            0xE2 => Command::MasterPitchShift { cent: f.read_u8()? },
+            0xE3 => Command::UnkCmdE3 {
+                effect_type: f.read_u8()?,
+            },
+            0xE4 => Command::MasterTempoFade {
+                time: f.read_u16_be()?,
+                value: f.read_u16_be()?,
+            },
+            0xE5 => Command::MasterVolumeFade {
+                time: f.read_u16_be()?,
+                volume: f.read_u8()?,
+            },
+            0xE6 => Command::MasterEffect {
+                index: f.read_u8()?,
+                value: f.read_u8()?,
+            },
+            // command 0xE7 unused
+            0xE8 => Command::TrackOverridePatch(PatchAddress::decode(f)?),
+            0xE9 => Command::SubTrackVolume(f.read_u8()?),
+            0xEA => Command::SubTrackPan(f.read_i8()?),
+            0xEB => Command::SubTrackReverb(f.read_u8()?),
+            0xEC => Command::SegTrackVolume(f.read_u8()?),
+            0xED => Command::SubTrackCoarseTune(f.read_u8()?),
+            0xEE => Command::SubTrackFineTune(f.read_u8()?),
+            0xEF => Command::SegTrackTune { bend: f.read_i16_be()? },
+            0xF0 => Command::TrackTremolo {
+                amount: f.read_u8()?,
+                speed: f.read_u8()?,
+                time: f.read_u8()?,
+            },
+            0xF1 => Command::TrackTremoloSpeed(f.read_u8()?),
+            0xF2 => Command::TrackTremoloTime { time: f.read_u8()? },
+            0xF3 => Command::TrackTremoloStop,
+            0xF4 => Command::UnkCmdF4 {
+                pan0: f.read_u8()?,
+                pan1: f.read_u8()?,
+            },
+            0xF5 => Command::SetTrackVoice { index: f.read_u8()? },
+            0xF6 => Command::TrackVolumeFade {
+                time: f.read_u16_be()?,
+                value: f.read_u8()?,
+            },
+            0xF7 => Command::SubTrackReverbType { index: f.read_u8()? },
+            // commands 0xF8-FB unused
+            0xFC => Command::Jump {
+                unk_00: f.read_u16_be()?, // TODO: this is an offset, go there!!
+                unk_02: f.read_u8()?,
+            },
+            0xFD => Command::EventTrigger {
+                event_info: f.read_u32_be()?,
+            },
+            0xFE => {
+                let raw_start = f.read_u16_be()?;
+                let start_offset = (raw_start as usize)
+                    .checked_sub(start)
+                    .ok_or(ErrorKind::InvalidDetour(raw_start))?;
+                let end_offset = start_offset + (f.read_u8()? as usize);
+
+                Command::Detour {
+                    start_label: events.upsert_marker(start_offset)?,
+                    end_label: events.upsert_marker(end_offset)?,
+                }
+            }
+            0xFF => Command::UnkCmdFF {
+                unk_00: f.read_u8()?,
+                unk_01: f.read_u8()?,
+                unk_02: f.read_u8()?,
+            },
 
-        Ok(events.into())
+            _ => return Err(Error::at(f.pos()? - 1, ErrorKind::UnknownSeqCommand(cmd_byte))),
+        })
     }
 }
 
 /// Temporary struct for [CommandSeq::decode].
 #[derive(Debug)]
-struct OffsetEventMap(pub(self) BTreeMap<usize, Event>);
+pub(super) struct OffsetEventMap(pub(super) BTreeMap<usize, Event>);
 
 impl OffsetEventMap {
     pub fn new() -> Self {
@@ -571,12 +901,12 @@ impl OffsetEventMap {
 
     /// Tree offset keys are shifted from the input to make space for abstract commands such as Command::Marker to
     /// be inserted between. This is fine, because, in the end, only the order of the keys matters (not their values).
-    pub(self) fn atob(offset: usize) -> usize {
+    pub(super) fn atob(offset: usize) -> usize {
         (offset + 1) * 2
     }
 
     /// Performs the inverse of [`atob`](OffsetCommandMap::atob). Lossy.
-    pub(self) fn btoa(key: usize) -> usize {
+    pub(super) fn btoa(key: usize) -> usize {
         key / 2
     }
 
@@ -584,8 +914,8 @@ impl OffsetEventMap {
         self.0.insert(Self::atob(offset), command);
     }
 
-    /// Finds a marker at `offset`, or inserts it if it cannot be found.
-    pub fn upsert_marker(&mut self, offset: usize) -> MarkerId {
+    /// Finds a marker at `offset`, or inserts it if it cannot be found. Fails if there is a command there instead.
+    pub fn upsert_marker(&mut self, offset: usize) -> Result<MarkerId, Error> {
         let shifted_offset = Self::atob(offset) - 1;
 
         match self.0.entry(shifted_offset) {
@@ -593,17 +923,14 @@ impl OffsetEventMap {
                 // Insert the new marker here.
                 let id: MarkerId = format!("Offset {:#X}", offset);
                 entry.insert(Command::Marker { label: id.clone() }.into());
-                id
+                Ok(id)
             }
             Entry::Occupied(entry) => match entry.get() {
                 Event {
                     command: Command::Marker { label },
                     ..
-                } => label.clone(),
-                other_command => panic!(
-                    "non-marker command {:?} found in label range (shifted_offset = {:#X})",
-                    other_command, shifted_offset,
-                ),
+                } => Ok(label.clone()),
+                _ => Err(ErrorKind::InvalidMarker(offset).into()),
             },
         }
     }
@@ -632,7 +959,7 @@ impl Deref for OffsetCommandMap {
 */
 
 impl Drum {
-    fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+    pub(super) fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
         debug!("drum = {:#X}", f.pos()?);
         Ok(Self {
             patch: PatchAddress::decode(f)?,
@@ -651,7 +978,7 @@ impl Drum {
 }
 
 impl Instrument {
-    fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+    pub(super) fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
         debug!("drum = {:#X}", f.pos()?);
         Ok(Self {
             patch: PatchAddress::decode(f)?,
@@ -665,26 +992,9 @@ impl Instrument {
     }
 }
 
-impl Unknown {
-    fn decode<R: Read + Seek>(f: &mut R, range: Range<u64>) -> Result<Self, Error> {
-        let range_len = range.end - range.start;
-
-        log::warn!("forcibly reading {:X} bytes from {:X}..", range_len, range.start);
-
-        Ok(Self {
-            data: {
-                let mut buf = vec![0; range_len as usize];
-                f.seek(SeekFrom::Start(range.start))?;
-                f.read_exact(&mut buf)?;
-                buf
-            },
-            range,
-        })
-    }
-}
-
 impl PatchAddress {
-    fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+    pub(super) fn decode<R: Read + Seek>(f: &mut R) -> Result<Self, Error> {
+        let pos = f.pos()?;
         let raw_bank = f.read_u8()?;
         let bank_set = (raw_bank & 0x70) >> 4;
         let envelope = raw_bank & 3;
@@ -694,7 +1004,9 @@ impl PatchAddress {
         let instrument = raw_patch % 16;
 
         Ok(PatchAddress {
-            bank_set: bank_set.try_into().map_err(|_| Error::UnknownBankSet(bank_set))?,
+            bank_set: bank_set
+                .try_into()
+                .map_err(|_| Error::at(pos, ErrorKind::UnknownBankSet(bank_set)))?,
             bank,
             instrument,
             envelope,
@@ -715,6 +1027,272 @@ mod test {
         assert!(Bgm::from_bytes(data).is_err());
     }
 
+    #[test]
+    fn malformed_header_and_segments_are_errors() {
+        // Too short for its name
+        assert!(Bgm::from_bytes(b"BGM \x1fMGB").is_err());
+
+        // A variation ended by an END command with arguments, rather than a zero word
+        let mut data = crate::bgm::mml::to_bgm("1: l8 cdef").unwrap().as_bytes().unwrap();
+        let variation_pos = (u16::from_be_bytes([data[0x14], data[0x15]]) as usize) << 2;
+        let terminator_pos = variation_pos + 4;
+        assert_eq!(data[terminator_pos..terminator_pos + 4], [0, 0, 0, 0]);
+        data[terminator_pos + 3] = 1;
+        let error = Bgm::from_bytes(&data).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::UnknownSegmentCommand(1)));
+        assert_eq!(error.offset, Some(terminator_pos as u64));
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let mut bgm = crate::bgm::mml::to_bgm("1: l8 cdef\n2: l8 gab").unwrap();
+        let commands = &mut bgm.track_lists.get_mut(&1).unwrap().tracks[2].commands;
+        commands.insert_after(None, Command::MasterVolume(0x7B).into());
+        let mut data = bgm.as_bytes().unwrap();
+
+        // Replace the command with an unused one
+        let pos = data.windows(2).position(|w| w == [0xE1, 0x7B]).unwrap();
+        data[pos] = 0xE7;
+
+        let error = Bgm::from_bytes(&data).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::UnknownSeqCommand(0xE7)));
+        assert_eq!(error.offset, Some(pos as u64));
+        assert_eq!(error.context.variation, Some(0));
+        assert_eq!(error.context.track, Some(2));
+        assert_eq!(error.context.command, Some(0));
+        assert!(error.to_string().contains("> track 2 > command 0"), "{}", error);
+
+        // Cut off at the start of the track
+        let error = Bgm::from_bytes(&data[..pos]).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::Io(_)));
+        assert_eq!(error.offset, Some(pos as u64));
+        assert_eq!(error.context.track, Some(2));
+    }
+
+    #[test]
+    fn jump_and_event_trigger_round_trip() {
+        let mut bgm = crate::bgm::mml::to_bgm("1: l8 cdef").unwrap();
+        let commands = &mut bgm.track_lists.get_mut(&1).unwrap().tracks[1].commands;
+        commands.insert_after(
+            None,
+            Command::Jump {
+                unk_00: 0x1234,
+                unk_02: 5,
+            }
+            .into(),
+        );
+        commands.insert_after(None, Command::EventTrigger { event_info: 0xDEADBEEF }.into());
+        let data = bgm.as_bytes().unwrap();
+
+        let decoded = Bgm::from_bytes(&data).unwrap();
+        let commands: Vec<&Command> = decoded.track_lists[&1].tracks[1]
+            .commands
+            .iter()
+            .map(|e| &e.command)
+            .collect();
+        assert_eq!(commands[0], &Command::EventTrigger { event_info: 0xDEADBEEF });
+        assert_eq!(
+            commands[1],
+            &Command::Jump {
+                unk_00: 0x1234,
+                unk_02: 5
+            }
+        );
+    }
+
+    #[test]
+    fn shared_commands_stay_shared() {
+        let bgm = crate::bgm::mml::to_bgm("1: l8 cdef\n2: l8 gab\n3: l4 c").unwrap();
+        let mut decoded = Bgm::from_bytes(&bgm.as_bytes().unwrap()).unwrap();
+
+        // Point track 3 at track 1's commands, and put track 2's after them
+        let tracks = &mut decoded.track_lists.get_mut(&1).unwrap().tracks;
+        tracks[3].commands = tracks[1].commands.clone();
+        tracks[3].pos = tracks[1].pos;
+        tracks[2].pos = tracks[2].pos.map(|pos| pos + 0x40);
+        let data = decoded.as_bytes().unwrap();
+
+        let reloaded = Bgm::from_bytes(&data).unwrap();
+        let tracks = &reloaded.track_lists[&1].tracks;
+        assert_eq!(tracks[3].pos, tracks[1].pos);
+        assert!(tracks[2].pos > tracks[1].pos);
+        assert_eq!(reloaded.as_bytes().unwrap(), data);
+    }
+
+    /// A song with `track_lists` track lists in one variation, whose tracks all point at one sequence of `delays`
+    /// one-tick delays.
+    fn song_sharing_one_sequence(track_lists: usize, delays: usize) -> Vec<u8> {
+        let mut data = vec![0; 0x30];
+        data[0..4].copy_from_slice(MAGIC.as_bytes());
+        data[0x10] = 4;
+        data[0x14..0x16].copy_from_slice(&(0x30u16 >> 2).to_be_bytes());
+
+        let track_lists_pos = 0x30 + 4 * (track_lists + 1);
+        let seq_pos = track_lists_pos + 0x40 * track_lists;
+        for i in 0..track_lists {
+            let offset = (0x40 * i + track_lists_pos - 0x30) as u32 >> 2;
+            data.extend((segment_commands::SUBSEG << 12 | offset).to_be_bytes());
+        }
+        data.extend([0; 4]);
+        for i in 0..track_lists {
+            for _ in 0..16 {
+                data.extend((seq_pos as u16 - (track_lists_pos + 0x40 * i) as u16).to_be_bytes());
+                data.extend([0; 2]);
+            }
+        }
+        data.extend(std::iter::repeat_n(0x01, delays));
+        data.push(0x00);
+
+        let size = data.len() as u32;
+        data[4..8].copy_from_slice(&size.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn shared_sequences_are_decoded_once() {
+        let bgm = Bgm::from_bytes(&song_sharing_one_sequence(2, 100)).unwrap();
+        let tracks: Vec<&Track> = bgm.track_lists.values().flat_map(|list| &list.tracks).collect();
+        assert_eq!(tracks.len(), 32);
+        for track in &tracks {
+            assert_eq!(track.pos, tracks[0].pos);
+            assert_eq!(track.commands.len_time(), 100);
+        }
+
+        // Each copy has its own ids
+        let mut ids: Vec<_> = bgm.ids().collect();
+        let count = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), count);
+    }
+
+    #[test]
+    fn too_many_events_is_an_error() {
+        let error = Bgm::from_bytes(&song_sharing_one_sequence(64, 4000)).unwrap_err();
+        assert!(matches!(error.kind, ErrorKind::TooManyEvents), "{}", error);
+    }
+
+    #[test]
+    fn unread_bytes_are_kept() {
+        let mut bgm = crate::bgm::mml::to_bgm("1: l8 cdef").unwrap();
+        bgm.name = "Test".to_owned();
+        let mut data = bgm.as_bytes().unwrap();
+
+        // Junk that nothing points to, counted in the file size
+        let junk_pos = data.len();
+        data.extend([0xDE, 0xAD, 0xBE, 0xEF]);
+        let size = data.len() as u32;
+        data[4..8].copy_from_slice(&size.to_be_bytes());
+
+        let decoded = Bgm::from_bytes(&data).unwrap();
+        assert_eq!(
+            decoded.unknowns,
+            vec![Unknown {
+                start: junk_pos as u64,
+                data: vec![0xDE, 0xAD, 0xBE, 0xEF],
+            }]
+        );
+        assert_eq!(decoded.as_bytes().unwrap(), data);
+
+        let reloaded = Bgm::from_ron_string(&decoded.to_ron_string().unwrap()).unwrap();
+        assert_eq!(reloaded.as_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn bytes_after_a_sequence_are_kept() {
+        let bgm = crate::bgm::mml::to_bgm("1: l8 cdef\n2: l8 gab").unwrap();
+        let mut decoded = Bgm::from_bytes(&bgm.as_bytes().unwrap()).unwrap();
+
+        // Leave a gap after track 1's commands, and put something in it that nothing points to
+        let tracks = &mut decoded.track_lists.get_mut(&1).unwrap().tracks;
+        let gap = tracks[2].pos.unwrap();
+        tracks[2].pos = Some(gap + 0x10);
+        let mut data = decoded.as_bytes().unwrap();
+        data[gap as usize..gap as usize + 4].copy_from_slice(&[0xFE, 0xDC, 0xBA, 0x00]);
+
+        let reloaded = Bgm::from_bytes(&data).unwrap();
+        assert_eq!(reloaded.unknowns.len(), 1);
+        assert_eq!(reloaded.unknowns[0].start, gap);
+        assert_eq!(reloaded.as_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn size_leaves_out_padding() {
+        let mut data = crate::bgm::mml::to_bgm("1: l8 cdef").unwrap().as_bytes().unwrap();
+
+        // Junk counted in the file size, then zero padding that isn't
+        data.extend([0xDE, 0xAD, 0xBE, 0xEF]);
+        if data.len().is_multiple_of(16) {
+            data.extend([0xDE, 0xAD, 0xBE, 0xEF]);
+        }
+        let size = data.len() as u32;
+        data[4..8].copy_from_slice(&size.to_be_bytes());
+        data.resize(align(size, 16) as usize, 0);
+
+        let decoded = Bgm::from_bytes(&data).unwrap();
+        let mut encoded = decoded.as_bytes().unwrap();
+        assert_eq!(encoded[4..8], size.to_be_bytes());
+        encoded.resize(data.len(), 0);
+        assert_eq!(encoded, data);
+    }
+
+    #[test]
+    fn lenient() {
+        let mut bgm = crate::bgm::mml::to_bgm("1: l8 cdef\n2: l8 gab").unwrap();
+        let commands = &mut bgm.track_lists.get_mut(&1).unwrap().tracks[2].commands;
+        commands.insert_end(24, Command::MasterVolume(0x7B));
+        let mut data = bgm.as_bytes().unwrap();
+        let pos = data.windows(2).position(|w| w == [0xE1, 0x7B]).unwrap();
+        data[pos] = 0xE7;
+
+        assert!(Bgm::from_bytes(&data).is_err());
+        let (salvaged, errors) = Bgm::decode_lenient(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].context.track, Some(2));
+
+        // The track keeps the commands before the error, and the rest of it is kept as bytes
+        let tracks = &salvaged.track_lists[&1].tracks;
+        let commands = |track: &Track| {
+            track
+                .commands
+                .iter()
+                .map(|event| event.command.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(commands(&tracks[1]), commands(&bgm.track_lists[&1].tracks[1]));
+        assert_eq!(tracks[2].commands.len_time(), 24);
+        assert_eq!(salvaged.unknowns.len(), 1);
+        assert_eq!(salvaged.unknowns[0].start, pos as u64);
+        assert_eq!(salvaged.unknowns[0].data[0], 0xE7);
+        assert_eq!(salvaged.unknowns[0].data.last(), Some(&0));
+
+        // Encoding it gives back the broken song
+        assert_eq!(salvaged.as_bytes().unwrap(), data);
+
+        // Without the kept bytes, and with the track ended where the error was, the song decodes cleanly
+        let mut fixed = salvaged;
+        fixed.unknowns.clear();
+        fixed.track_lists.get_mut(&1).unwrap().tracks[2]
+            .commands
+            .push(Command::End);
+        assert!(Bgm::from_bytes(&fixed.as_bytes().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn ids_are_deterministic() {
+        let data = crate::bgm::mml::to_bgm("1: l8 cdef [gab]3\n2: @1 o3 c1")
+            .unwrap()
+            .as_bytes()
+            .unwrap();
+
+        let bgm = Bgm::from_bytes(&data).unwrap();
+        assert_eq!(bgm, Bgm::from_bytes(&data).unwrap());
+
+        let mut ids: Vec<_> = bgm.ids().collect();
+        ids.sort();
+        assert_eq!(ids, (0..ids.len() as u32).collect::<Vec<_>>());
+    }
+
     #[test]
     fn decode_subroutine() {
         let bytecode: Vec<u8> = vec![
@@ -727,7 +1305,7 @@ mod test {
             0x00, // End - at offset 15
         ];
 
-        let seq = CommandSeq::decode(&mut Cursor::new(bytecode)).unwrap();
+        let seq = CommandSeq::decode(&mut Cursor::new(bytecode), &mut State::default()).unwrap();
         dbg!(&seq);
 
         let start_labels: Vec<&MarkerId> = seq
@@ -807,4 +1385,30 @@ mod test {
         assert_eq!(subroutine_labels[1].1, end_labels[0]);
         assert_eq!(subroutine_labels[2].1, end_labels[0]);
     }
+
+    #[test]
+    fn jump_and_event_trigger_opcodes() {
+        // Opcodes as the game's command table has them
+        let bytecode: Vec<u8> = vec![
+            0xFC, 0x12, 0x34, 0x56, // Jump
+            0xFD, 0xDE, 0xAD, 0xBE, 0xEF, // EventTrigger
+            0x00, // End
+        ];
+
+        let seq = CommandSeq::decode(&mut Cursor::new(&bytecode), &mut State::default()).unwrap();
+        let mut encoded = Cursor::new(Vec::new());
+        seq.encode(&mut encoded).unwrap();
+        assert_eq!(
+            seq.to_command_vec(),
+            vec![
+                Command::Jump {
+                    unk_00: 0x1234,
+                    unk_02: 0x56,
+                },
+                Command::EventTrigger { event_info: 0xDEADBEEF },
+                Command::End,
+            ]
+        );
+        assert_eq!(encoded.into_inner(), bytecode);
+    }
 }
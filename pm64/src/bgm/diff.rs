@@ -0,0 +1,518 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::asm::{command_text, drum_attributes, instrument_attributes, quote, segment_text};
+use super::*;
+
+/// Differences between two songs, as found by [diff].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// The old and new name, if it changed.
+    pub name: Option<(String, String)>,
+    pub variations: Vec<VariationDiff>,
+    pub drums: Vec<ItemDiff<Drum>>,
+    pub instruments: Vec<ItemDiff<Instrument>>,
+    pub track_lists: Vec<TrackListDiff>,
+}
+
+/// A change to an entry of [Bgm::drums] or [Bgm::instruments]. These are referred to by index, so entries are
+/// compared with the entry at the same index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemDiff<T> {
+    Added { index: usize, new: T },
+    Removed { index: usize, old: T },
+    Changed { index: usize, fields: Vec<FieldChange> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VariationDiff {
+    Added { index: usize, segments: Vec<Segment> },
+    Removed { index: usize },
+    Changed { index: usize, segments: Vec<SegmentChange> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentChange {
+    /// `segment` was inserted, and is at `index` in the new variation.
+    Inserted { index: usize, segment: Segment },
+    /// `segment` was deleted from `index` in the old variation.
+    Deleted { index: usize, segment: Segment },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackListDiff {
+    Added {
+        id: TrackListId,
+        track_list: Box<TrackList>,
+    },
+    Removed {
+        id: TrackListId,
+    },
+    Changed {
+        id: TrackListId,
+        tracks: Vec<TrackDiff>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackDiff {
+    /// Index of the track in [TrackList::tracks].
+    pub index: usize,
+    pub fields: Vec<FieldChange>,
+    /// Changed commands in time order.
+    pub commands: Vec<CommandChange>,
+}
+
+/// A command that is only in one of the two tracks, at the given time. [Delays](Command::Delay) are not compared;
+/// they are what give commands their times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandChange {
+    Inserted { time: usize, command: Command },
+    Deleted { time: usize, command: Command },
+}
+
+impl CommandChange {
+    pub fn time(&self) -> usize {
+        match self {
+            CommandChange::Inserted { time, .. } | CommandChange::Deleted { time, .. } => *time,
+        }
+    }
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.variations.is_empty()
+            && self.drums.is_empty()
+            && self.instruments.is_empty()
+            && self.track_lists.is_empty()
+    }
+}
+
+/// Finds what changed between `old` and `new`. Ids, file positions and [unknown](Bgm::unknowns) data are not compared.
+pub fn diff(old: &Bgm, new: &Bgm) -> Diff {
+    let mut diff = Diff::default();
+
+    if old.name != new.name {
+        diff.name = Some((old.name.clone(), new.name.clone()));
+    }
+
+    for (index, (old_variation, new_variation)) in old.variations.iter().zip(&new.variations).enumerate() {
+        match (old_variation, new_variation) {
+            (None, None) => {}
+            (None, Some(new_variation)) => diff.variations.push(VariationDiff::Added {
+                index,
+                segments: new_variation.segments.clone(),
+            }),
+            (Some(_), None) => diff.variations.push(VariationDiff::Removed { index }),
+            (Some(old_variation), Some(new_variation)) => {
+                let segments = diff_segments(&old_variation.segments, &new_variation.segments);
+                if !segments.is_empty() {
+                    diff.variations.push(VariationDiff::Changed { index, segments });
+                }
+            }
+        }
+    }
+
+    diff.drums = diff_items(&old.drums, &new.drums, drum_fields);
+    diff.instruments = diff_items(&old.instruments, &new.instruments, instrument_fields);
+
+    for (&id, old_track_list) in &old.track_lists {
+        match new.track_lists.get(&id) {
+            None => diff.track_lists.push(TrackListDiff::Removed { id }),
+            Some(new_track_list) => {
+                let tracks: Vec<TrackDiff> = old_track_list
+                    .tracks
+                    .iter()
+                    .zip(&new_track_list.tracks)
+                    .enumerate()
+                    .map(|(index, (old_track, new_track))| diff_track(index, old_track, new_track))
+                    .filter(|track| !track.fields.is_empty() || !track.commands.is_empty())
+                    .collect();
+                if !tracks.is_empty() {
+                    diff.track_lists.push(TrackListDiff::Changed { id, tracks });
+                }
+            }
+        }
+    }
+    for (&id, new_track_list) in &new.track_lists {
+        if !old.track_lists.contains_key(&id) {
+            diff.track_lists.push(TrackListDiff::Added {
+                id,
+                track_list: Box::new(new_track_list.clone()),
+            });
+        }
+    }
+    diff.track_lists.sort_by_key(|track_list| match track_list {
+        TrackListDiff::Added { id, .. } | TrackListDiff::Removed { id } | TrackListDiff::Changed { id, .. } => *id,
+    });
+
+    diff
+}
+
+fn field<T: fmt::Debug + PartialEq>(fields: &mut Vec<FieldChange>, field: &'static str, old: &T, new: &T) {
+    if old != new {
+        fields.push(FieldChange {
+            field,
+            old: format!("{:?}", old),
+            new: format!("{:?}", new),
+        });
+    }
+}
+
+fn patch_fields(fields: &mut Vec<FieldChange>, old: &PatchAddress, new: &PatchAddress) {
+    field(fields, "bank_set", &old.bank_set, &new.bank_set);
+    field(fields, "bank", &old.bank, &new.bank);
+    field(fields, "instrument", &old.instrument, &new.instrument);
+    field(fields, "envelope", &old.envelope, &new.envelope);
+}
+
+fn drum_fields(old: &Drum, new: &Drum) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    patch_fields(&mut fields, &old.patch, &new.patch);
+    field(&mut fields, "coarse_tune", &old.coarse_tune, &new.coarse_tune);
+    field(&mut fields, "fine_tune", &old.fine_tune, &new.fine_tune);
+    field(&mut fields, "volume", &old.volume, &new.volume);
+    field(&mut fields, "pan", &old.pan, &new.pan);
+    field(&mut fields, "reverb", &old.reverb, &new.reverb);
+    field(&mut fields, "rand_tune", &old.rand_tune, &new.rand_tune);
+    field(&mut fields, "rand_volume", &old.rand_volume, &new.rand_volume);
+    field(&mut fields, "rand_pan", &old.rand_pan, &new.rand_pan);
+    field(&mut fields, "rand_reverb", &old.rand_reverb, &new.rand_reverb);
+    field(&mut fields, "pad_0b", &old.pad_0b, &new.pad_0b);
+    fields
+}
+
+fn instrument_fields(old: &Instrument, new: &Instrument) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    patch_fields(&mut fields, &old.patch, &new.patch);
+    field(&mut fields, "volume", &old.volume, &new.volume);
+    field(&mut fields, "pan", &old.pan, &new.pan);
+    field(&mut fields, "reverb", &old.reverb, &new.reverb);
+    field(&mut fields, "coarse_tune", &old.coarse_tune, &new.coarse_tune);
+    field(&mut fields, "fine_tune", &old.fine_tune, &new.fine_tune);
+    field(&mut fields, "pad_07", &old.pad_07, &new.pad_07);
+    fields
+}
+
+fn diff_items<T: Clone>(old: &[T], new: &[T], fields: fn(&T, &T) -> Vec<FieldChange>) -> Vec<ItemDiff<T>> {
+    let mut diffs = Vec::new();
+    for (index, (old_item, new_item)) in old.iter().zip(new).enumerate() {
+        let fields = fields(old_item, new_item);
+        if !fields.is_empty() {
+            diffs.push(ItemDiff::Changed { index, fields });
+        }
+    }
+    for (index, old_item) in old.iter().enumerate().skip(new.len()) {
+        diffs.push(ItemDiff::Removed {
+            index,
+            old: old_item.clone(),
+        });
+    }
+    for (index, new_item) in new.iter().enumerate().skip(old.len()) {
+        diffs.push(ItemDiff::Added {
+            index,
+            new: new_item.clone(),
+        });
+    }
+    diffs
+}
+
+/// Aligns the segments with a longest common subsequence, ignoring ids. Variations only have a handful of segments,
+/// so the quadratic table is fine.
+pub(super) fn diff_segments(old: &[Segment], new: &[Segment]) -> Vec<SegmentChange> {
+    let same = |a: &Segment, b: &Segment| {
+        let (mut a, mut b) = (a.clone(), b.clone());
+        *a.id_mut() = None;
+        *b.id_mut() = None;
+        a == b
+    };
+
+    // lengths[i][j] = length of the LCS of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if same(&old[i], &new[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && same(&old[i], &new[j]) {
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lengths[i][j + 1] >= lengths[i + 1][j]) {
+            changes.push(SegmentChange::Inserted {
+                index: j,
+                segment: new[j].clone(),
+            });
+            j += 1;
+        } else {
+            changes.push(SegmentChange::Deleted {
+                index: i,
+                segment: old[i].clone(),
+            });
+            i += 1;
+        }
+    }
+    changes
+}
+
+fn diff_track(index: usize, old: &Track, new: &Track) -> TrackDiff {
+    let mut fields = Vec::new();
+    field(&mut fields, "name", &old.name, &new.name);
+    field(&mut fields, "is_disabled", &old.is_disabled, &new.is_disabled);
+    field(&mut fields, "polyphony", &old.polyphony, &new.polyphony);
+    field(&mut fields, "is_drum_track", &old.is_drum_track, &new.is_drum_track);
+
+    TrackDiff {
+        index,
+        fields,
+        commands: diff_commands(&old.commands, &new.commands),
+    }
+}
+
+/// Commands at the same time have no defined order, so the commands at each time are compared as multisets.
+pub(super) fn diff_commands(old: &CommandSeq, new: &CommandSeq) -> Vec<CommandChange> {
+    fn by_time(seq: &CommandSeq) -> BTreeMap<usize, Vec<&Command>> {
+        let mut map: BTreeMap<usize, Vec<&Command>> = BTreeMap::new();
+        for (time, event) in seq.iter_time() {
+            if !matches!(event.command, Command::Delay(_)) {
+                map.entry(time).or_default().push(&event.command);
+            }
+        }
+        map
+    }
+
+    let old = by_time(old);
+    let mut new = by_time(new);
+    let mut changes = Vec::new();
+
+    for (time, old_commands) in old {
+        let mut new_commands = new.remove(&time).unwrap_or_default();
+        for command in old_commands {
+            match new_commands.iter().position(|new_command| *new_command == command) {
+                Some(index) => {
+                    new_commands.remove(index);
+                }
+                None => changes.push(CommandChange::Deleted {
+                    time,
+                    command: command.clone(),
+                }),
+            }
+        }
+        changes.extend(new_commands.into_iter().map(|command| CommandChange::Inserted {
+            time,
+            command: command.clone(),
+        }));
+    }
+    for (time, new_commands) in new {
+        changes.extend(new_commands.into_iter().map(|command| CommandChange::Inserted {
+            time,
+            command: command.clone(),
+        }));
+    }
+
+    // Stable, so deletions stay before insertions at the same time
+    changes.sort_by_key(CommandChange::time);
+    changes
+}
+
+fn write_fields(f: &mut fmt::Formatter<'_>, fields: &[FieldChange]) -> fmt::Result {
+    for field in fields {
+        writeln!(f, "    {}: {} -> {}", field.field, field.old, field.new)?;
+    }
+    Ok(())
+}
+
+/// Renders the diff for reading, with `-` for removed and `+` for added lines. Segments, drums, instruments and
+/// commands are written like in the [text assembly format](Bgm::to_asm_string). Commands are prefixed with their time.
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((old, new)) = &self.name {
+            writeln!(f, "name: {} -> {}", quote(old), quote(new))?;
+        }
+
+        for variation in &self.variations {
+            match variation {
+                VariationDiff::Added { index, segments } => {
+                    writeln!(f, "+ variation {}", index)?;
+                    for segment in segments {
+                        writeln!(f, "+   {}", segment_text(segment))?;
+                    }
+                }
+                VariationDiff::Removed { index } => writeln!(f, "- variation {}", index)?,
+                VariationDiff::Changed { index, segments } => {
+                    writeln!(f, "variation {}", index)?;
+                    for change in segments {
+                        match change {
+                            SegmentChange::Inserted { index, segment } => {
+                                writeln!(f, "+   {}: {}", index, segment_text(segment))?
+                            }
+                            SegmentChange::Deleted { index, segment } => {
+                                writeln!(f, "-   {}: {}", index, segment_text(segment))?
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for drum in &self.drums {
+            match drum {
+                ItemDiff::Added { index, new } => writeln!(f, "+ drum {} {}", index, drum_attributes(new))?,
+                ItemDiff::Removed { index, old } => writeln!(f, "- drum {} {}", index, drum_attributes(old))?,
+                ItemDiff::Changed { index, fields } => {
+                    writeln!(f, "drum {}", index)?;
+                    write_fields(f, fields)?;
+                }
+            }
+        }
+        for instrument in &self.instruments {
+            match instrument {
+                ItemDiff::Added { index, new } => writeln!(f, "+ instrument {} {}", index, instrument_attributes(new))?,
+                ItemDiff::Removed { index, old } => {
+                    writeln!(f, "- instrument {} {}", index, instrument_attributes(old))?
+                }
+                ItemDiff::Changed { index, fields } => {
+                    writeln!(f, "instrument {}", index)?;
+                    write_fields(f, fields)?;
+                }
+            }
+        }
+
+        for track_list in &self.track_lists {
+            match track_list {
+                TrackListDiff::Added { id, track_list } => {
+                    let tracks = track_list
+                        .tracks
+                        .iter()
+                        .filter(|track| !track.commands.is_empty())
+                        .count();
+                    writeln!(f, "+ track_list {} ({} tracks with commands)", id, tracks)?;
+                }
+                TrackListDiff::Removed { id } => writeln!(f, "- track_list {}", id)?,
+                TrackListDiff::Changed { id, tracks } => {
+                    for track in tracks {
+                        writeln!(f, "track_list {} track {}", id, track.index)?;
+                        write_fields(f, &track.fields)?;
+                        for change in &track.commands {
+                            match change {
+                                CommandChange::Inserted { time, command } => {
+                                    writeln!(f, "+   {:>6}  {}", time, command_text(command))?
+                                }
+                                CommandChange::Deleted { time, command } => {
+                                    writeln!(f, "-   {:>6}  {}", time, command_text(command))?
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn song() -> Bgm {
+        let mut bgm = mml::to_bgm("1: @0 l8 cdef gab\n2: @1 o3 c2 g2").unwrap();
+        bgm.name = "Test".to_owned();
+        bgm
+    }
+
+    #[test]
+    fn identical() {
+        let bgm = song();
+        let reloaded = Bgm::from_bytes(&bgm.as_bytes().unwrap()).unwrap();
+        assert!(reloaded.track_lists.values().all(|track_list| track_list.pos.is_some()));
+
+        assert!(diff(&bgm, &song()).is_empty());
+        assert!(diff(&bgm, &reloaded).is_empty(), "{}", diff(&bgm, &reloaded));
+    }
+
+    #[test]
+    fn changes() {
+        let old = song();
+        let mut new = song();
+        new.name = "Changed".to_owned();
+        new.instruments[1].volume = 80;
+        new.drums.push(Drum::default());
+        new.variations[0].as_mut().unwrap().segments.insert(
+            0,
+            Segment::Wait {
+                id: Some(crate::id::gen_id()),
+            },
+        );
+
+        let track = &mut new.track_lists.get_mut(&1).unwrap().tracks[1];
+        track.name = "Melody".to_owned();
+        track.commands.insert_end(
+            48,
+            Command::Note {
+                pitch: 150,
+                velocity: 100,
+                length: 24,
+            },
+        );
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.name, Some(("Test".to_owned(), "Changed".to_owned())));
+        assert_eq!(
+            diff.instruments,
+            vec![ItemDiff::Changed {
+                index: 1,
+                fields: vec![FieldChange {
+                    field: "volume",
+                    old: "100".to_owned(),
+                    new: "80".to_owned(),
+                }],
+            }]
+        );
+        assert!(matches!(diff.drums[..], [ItemDiff::Added { index: 0, .. }]));
+        assert!(matches!(
+            &diff.variations[..],
+            [VariationDiff::Changed { index: 0, segments }]
+                if matches!(segments[..], [SegmentChange::Inserted { index: 0, segment: Segment::Wait { .. } }])
+        ));
+
+        let [TrackListDiff::Changed { id: 1, tracks }] = &diff.track_lists[..] else {
+            panic!("{:?}", diff.track_lists);
+        };
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].fields[0].field, "name");
+        assert_eq!(
+            tracks[0].commands,
+            vec![CommandChange::Inserted {
+                time: 48,
+                command: Command::Note {
+                    pitch: 150,
+                    velocity: 100,
+                    length: 24,
+                },
+            }]
+        );
+
+        let text = diff.to_string();
+        assert!(text.contains("name: \"Test\" -> \"Changed\"\n"), "{}", text);
+        assert!(text.contains("instrument 1\n    volume: 100 -> 80\n"), "{}", text);
+        assert!(text.contains("+   0: wait\n"), "{}", text);
+        assert!(text.contains("track_list 1 track 1\n    name: \"\" -> \"Melody\"\n+       48  note 150 100 24\n"));
+    }
+}
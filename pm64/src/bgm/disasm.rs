@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::prelude::*;
+use std::io::{self, Cursor, SeekFrom};
+
+use super::de::{Error, ErrorKind, OffsetEventMap};
+use super::segment_commands::SUBSEG;
+use super::*;
+use crate::rw::*;
+
+/// Number of raw bytes shown per line before wrapping.
+const BYTES_PER_LINE: usize = 8;
+
+/// Something in the file that the listing gives its own labelled section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Block {
+    Header,
+    Variation(usize),
+    TrackList,
+    Seq,
+    Drums(u16),
+    Instruments(u16),
+}
+
+/// Disassembles BGM data into a listing of every segment, track list and command it contains, alongside their file
+/// offset and raw bytes. Sections are listed in file order, and bytes that nothing refers to are shown too.
+pub fn disassemble(data: &[u8]) -> Result<String, Error> {
+    let mut f = Cursor::new(data);
+    let mut out = String::new();
+
+    f.seek(SeekFrom::Start(0))?;
+    if f.read_cstring(4)? != MAGIC {
+        return Err(Error::at(0, ErrorKind::InvalidMagic));
+    }
+    let internal_size = f.read_u32_be()?;
+    let name = f.read_cstring(4)?;
+    writeln!(out, "; BGM {:?}, {:#X} bytes", name, internal_size).unwrap();
+
+    f.seek(SeekFrom::Start(0x14))?;
+    let variation_offsets: Vec<u64> = (0..4)
+        .map(|_| Ok((f.read_u16_be()? as u64) << 2))
+        .collect::<Result<_, io::Error>>()?;
+    let drums_offset = (f.read_u16_be()? as u64) << 2;
+    let drums_count = f.read_u16_be()?;
+    let voices_offset = (f.read_u16_be()? as u64) << 2;
+    let voices_count = f.read_u16_be()?;
+
+    // Find everything reachable from the header, so it can be listed in file order
+    let mut blocks = BTreeMap::from([(0, Block::Header)]);
+    for (i, &pos) in variation_offsets.iter().enumerate() {
+        if pos != 0 {
+            blocks.insert(pos, Block::Variation(i));
+        }
+    }
+    if drums_offset != 0 {
+        blocks.insert(drums_offset, Block::Drums(drums_count));
+    }
+    if voices_offset != 0 {
+        blocks.insert(voices_offset, Block::Instruments(voices_count));
+    }
+    for &pos in &variation_offsets {
+        if pos == 0 {
+            continue;
+        }
+        for (_, segment, track_list_pos) in read_variation(&mut f, pos)? {
+            if let (Some(track_list_pos), SUBSEG) = (track_list_pos, segment >> 12) {
+                blocks.insert(track_list_pos, Block::TrackList);
+                for (_, commands_offset, _) in read_track_list(&mut f, track_list_pos)? {
+                    if commands_offset != 0 {
+                        blocks.insert(track_list_pos + commands_offset as u64, Block::Seq);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut pos = 0;
+    for (&start, &block) in &blocks {
+        if start > pos {
+            write_unreferenced(&mut out, data, pos, start);
+        } else if start < pos {
+            writeln!(out, "; {:#X} overlaps the previous section", start).unwrap();
+        }
+
+        writeln!(out).unwrap();
+        f.seek(SeekFrom::Start(start))?;
+        pos = match block {
+            Block::Header => {
+                writeln!(out, "header:").unwrap();
+                write_line(&mut out, data, 0x00, 0x04, format!("magic {:?}", MAGIC));
+                write_line(&mut out, data, 0x04, 0x08, format!("size {:#X}", internal_size));
+                write_line(&mut out, data, 0x08, 0x0C, format!("name {:?}", name));
+                write_line(&mut out, data, 0x0C, 0x10, String::new());
+                write_line(&mut out, data, 0x10, 0x14, format!("{} variations", data[0x10]));
+                for (i, &pos) in variation_offsets.iter().enumerate() {
+                    let at = 0x14 + i as u64 * 2;
+                    write_line(&mut out, data, at, at + 2, format!("variation {} at {}", i, label(pos)));
+                }
+                write_line(
+                    &mut out,
+                    data,
+                    0x1C,
+                    0x20,
+                    format!("{} drums at {}", drums_count, label(drums_offset)),
+                );
+                write_line(
+                    &mut out,
+                    data,
+                    0x20,
+                    0x24,
+                    format!("{} instruments at {}", voices_count, label(voices_offset)),
+                );
+                0x24
+            }
+            Block::Variation(index) => {
+                writeln!(out, "{}: ; variation {}", label(start), index).unwrap();
+                let segments = read_variation(&mut f, start)?;
+                for &(at, segment, track_list_pos) in &segments {
+                    write_line(&mut out, data, at, at + 4, describe_segment(segment, track_list_pos)?);
+                }
+                let end = segments.last().map_or(start, |&(at, _, _)| at + 4);
+                write_line(&mut out, data, end, end + 4, "End".to_owned());
+                end + 4
+            }
+            Block::TrackList => {
+                writeln!(out, "{}: ; track list", label(start)).unwrap();
+                for (i, (at, commands_offset, flags)) in read_track_list(&mut f, start)?.into_iter().enumerate() {
+                    let text = if commands_offset == 0 {
+                        format!("track {}: none", i)
+                    } else {
+                        format!(
+                            "track {}: {}, {}",
+                            i,
+                            label(start + commands_offset as u64),
+                            describe_track_flags(flags)
+                        )
+                    };
+                    write_line(&mut out, data, at, at + 4, text);
+                }
+                start + 16 * 4
+            }
+            Block::Seq => {
+                writeln!(out, "{}: ; commands", label(start)).unwrap();
+                let (events, size) = OffsetEventMap::decode(&mut f)?;
+                let seq_start = start as usize;
+                let end = seq_start + size;
+
+                // Keys are shifted offsets; see OffsetEventMap::atob
+                let commands: Vec<(usize, &Command)> = events
+                    .0
+                    .iter()
+                    .map(|(&key, event)| (key.div_ceil(2) - 1, &event.command))
+                    .collect();
+                for (i, &(offset, command)) in commands.iter().enumerate() {
+                    match command {
+                        Command::Marker { label } => {
+                            writeln!(out, "  {}: ; {:#06X}", label, seq_start + offset).unwrap();
+                        }
+                        Command::Detour { start_label, end_label } => {
+                            let target = |name: &str| {
+                                commands.iter().find_map(|&(offset, command)| match command {
+                                    Command::Marker { label } if label == name => Some(seq_start + offset),
+                                    _ => None,
+                                })
+                            };
+                            let next = next_command_offset(&commands, i).map_or(end, |next| seq_start + next);
+                            let text = match (target(start_label), target(end_label)) {
+                                (Some(a), Some(b)) => format!("{:?} ; {:#06X}..{:#06X}", command, a, b),
+                                _ => format!("{:?}", command),
+                            };
+                            write_line(&mut out, data, (seq_start + offset) as u64, next as u64, text);
+                        }
+                        _ => {
+                            let next = next_command_offset(&commands, i).map_or(end, |next| seq_start + next);
+                            let text = format!("{:?}", command);
+                            write_line(&mut out, data, (seq_start + offset) as u64, next as u64, text);
+                        }
+                    }
+                }
+                end as u64
+            }
+            Block::Drums(count) => {
+                writeln!(out, "{}: ; drums", label(start)).unwrap();
+                for i in 0..count as u64 {
+                    let at = start + i * 12;
+                    f.seek(SeekFrom::Start(at))?;
+                    let drum = Drum::decode(&mut f)?;
+                    write_line(&mut out, data, at, at + 12, format!("{:?}", drum));
+                }
+                start + count as u64 * 12
+            }
+            Block::Instruments(count) => {
+                writeln!(out, "{}: ; instruments", label(start)).unwrap();
+                for i in 0..count as u64 {
+                    let at = start + i * 8;
+                    f.seek(SeekFrom::Start(at))?;
+                    let instrument = Instrument::decode(&mut f)?;
+                    write_line(&mut out, data, at, at + 8, format!("{:?}", instrument));
+                }
+                start + count as u64 * 8
+            }
+        }
+        .max(pos);
+    }
+
+    if (pos as usize) < data.len() {
+        write_unreferenced(&mut out, data, pos, data.len() as u64);
+    }
+
+    Ok(out)
+}
+
+/// Returns (offset, raw word, track list position if it is a subsegment) for each segment command of a variation, not
+/// including the terminator.
+fn read_variation(f: &mut Cursor<&[u8]>, start: u64) -> Result<Vec<(u64, u32, Option<u64>)>, Error> {
+    let mut segments = Vec::new();
+    f.seek(SeekFrom::Start(start))?;
+    loop {
+        let at = f.pos()?;
+        let word = f.read_u32_be()?;
+        if word == 0 {
+            return Ok(segments);
+        }
+
+        let track_list_pos = if word >> 12 == SUBSEG {
+            Some(start + ((word as u64 & 0xFFFF) << 2))
+        } else {
+            None
+        };
+        segments.push((at, word, track_list_pos));
+    }
+}
+
+/// Returns (offset, commands offset, flags) for each track in a track list.
+fn read_track_list(f: &mut Cursor<&[u8]>, start: u64) -> Result<Vec<(u64, u16, u16)>, Error> {
+    f.seek(SeekFrom::Start(start))?;
+    (0..16)
+        .map(|i| Ok((start + i * 4, f.read_u16_be()?, f.read_u16_be()?)))
+        .collect()
+}
+
+fn describe_segment(word: u32, track_list_pos: Option<u64>) -> Result<String, Error> {
+    let label_index = word & 0x1F;
+    let iter_count = (word >> 5) & 0x7F;
+
+    Ok(match word >> 12 {
+        segment_commands::SUBSEG => format!("Subseg {}", label(track_list_pos.unwrap_or_default())),
+        segment_commands::START_LOOP => format!("StartLoop {{ label_index: {} }}", word & 0xFFFF),
+        segment_commands::WAIT => "Wait".to_owned(),
+        segment_commands::END_LOOP => format!("EndLoop {{ label_index: {}, iter_count: {} }}", label_index, iter_count),
+        segment_commands::UNKNOWN_6 => format!(
+            "Unknown6 {{ label_index: {}, iter_count: {} }}",
+            label_index, iter_count
+        ),
+        segment_commands::UNKNOWN_7 => format!(
+            "Unknown7 {{ label_index: {}, iter_count: {} }}",
+            label_index, iter_count
+        ),
+        _ => return Err(ErrorKind::UnknownSegmentCommand(word).into()),
+    })
+}
+
+fn describe_track_flags(flags: u16) -> String {
+    let polyphonic_idx = ((flags & (0x7 << 0xD)) >> 0xD) as u8;
+    let parent_track_idx = ((flags & (0xF << 9)) >> 9) as u8;
+
+    let mut text = format!("{:?}", Polyphony::from_raw(polyphonic_idx, parent_track_idx));
+    if flags & 0x0100 != 0 {
+        text.push_str(", disabled");
+    }
+    if flags & 0x0080 != 0 {
+        text.push_str(", drums");
+    }
+    text
+}
+
+/// Finds the offset of the first non-marker command after `commands[i]`.
+fn next_command_offset(commands: &[(usize, &Command)], i: usize) -> Option<usize> {
+    commands[i + 1..]
+        .iter()
+        .find(|(_, command)| !matches!(command, Command::Marker { .. }))
+        .map(|&(offset, _)| offset)
+}
+
+fn label(pos: u64) -> String {
+    if pos == 0 {
+        "null".to_owned()
+    } else {
+        format!("L{:04X}", pos)
+    }
+}
+
+/// Writes `data[start..end]` alongside `text`, wrapping long runs of bytes onto multiple lines.
+fn write_line(out: &mut String, data: &[u8], start: u64, end: u64, text: String) {
+    let end = (end as usize).min(data.len());
+    let start = (start as usize).min(end);
+
+    let mut lines: Vec<(usize, &[u8])> = data[start..end]
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, chunk)| (start + i * BYTES_PER_LINE, chunk))
+        .collect();
+    if lines.is_empty() {
+        lines.push((start, &[]));
+    }
+
+    for (i, (offset, chunk)) in lines.into_iter().enumerate() {
+        let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text = if i == 0 { text.as_str() } else { "" };
+        let line = format!(
+            "  {:04X}  {:width$}  {}",
+            offset,
+            bytes.join(" "),
+            text,
+            width = BYTES_PER_LINE * 3 - 1
+        );
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+}
+
+fn write_unreferenced(out: &mut String, data: &[u8], start: u64, end: u64) {
+    let range = start as usize..(end as usize).min(data.len());
+
+    writeln!(out).unwrap();
+    if data[range.clone()].iter().all(|&byte| byte == 0) {
+        writeln!(out, "; {:#X} bytes of padding at {:#06X}", range.len(), range.start).unwrap();
+    } else {
+        writeln!(out, "; unreferenced:").unwrap();
+        write_line(out, data, start, end, String::new());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listing() {
+        let mut track_list = TrackList {
+            pos: None,
+            tracks: core::array::from_fn(|_| Track::default()),
+        };
+        track_list.tracks[0].commands = vec![Command::MasterTempo(120), Command::Delay(48), Command::End].into();
+        track_list.tracks[1].is_disabled = false;
+        track_list.tracks[1].commands = vec![
+            Command::Marker { label: "a".into() },
+            Command::Note {
+                pitch: 140,
+                velocity: 100,
+                length: 24,
+            },
+            Command::Delay(24),
+            Command::Marker { label: "b".into() },
+            Command::Detour {
+                start_label: "a".into(),
+                end_label: "b".into(),
+            },
+            Command::End,
+        ]
+        .into();
+
+        let mut bgm = Bgm::new();
+        let track_list = bgm.add_track_list(track_list);
+        bgm.variations[0] = Some(Variation {
+            segments: vec![Segment::Subseg { id: None, track_list }],
+        });
+
+        let data = bgm.as_bytes().unwrap();
+        let listing = disassemble(&data).unwrap();
+
+        assert!(listing.contains("magic \"BGM \""));
+        assert!(listing.contains("MasterTempo(120)"));
+        assert!(listing.contains("Note { pitch: 140, velocity: 100, length: 24 }"));
+
+        // The detour should be resolved to where its markers are
+        let detour = listing.lines().find(|line| line.contains("Detour")).unwrap();
+        let markers: Vec<&str> = listing
+            .lines()
+            .filter(|line| line.trim_start().starts_with("Offset"))
+            .map(|line| line.rsplit("; ").next().unwrap())
+            .collect();
+        assert_eq!(markers.len(), 2);
+        assert!(
+            detour.ends_with(&format!("; {}..{}", markers[0], markers[1])),
+            "{}",
+            detour
+        );
+    }
+}
@@ -0,0 +1,754 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+
+use super::*;
+
+/// A change to a [Bgm] that can be undone exactly: [Edit::apply] returns the edit that undoes it.
+///
+/// Events are referred to by id, so that edits stay meaningful while other edits move commands around. Segments,
+/// instruments and tracks are referred to by index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub enum Edit {
+    /// Inserts `event` directly after the event with id `after`, or at the start of the track if `after` is `None`.
+    /// See [Edit::insert_command] to insert at a time instead.
+    InsertEvent {
+        track_list: TrackListId,
+        track: usize,
+        after: Option<Id>,
+        event: Event,
+    },
+    DeleteEvent {
+        track_list: TrackListId,
+        track: usize,
+        id: Id,
+    },
+    /// Replaces the command of an event, keeping its id.
+    SetCommand {
+        track_list: TrackListId,
+        track: usize,
+        id: Id,
+        command: Command,
+    },
+    /// Replaces all of the commands of a track.
+    SetCommands {
+        track_list: TrackListId,
+        track: usize,
+        commands: CommandSeq,
+    },
+    /// Replaces everything about a track but its commands.
+    SetTrackSettings {
+        track_list: TrackListId,
+        track: usize,
+        name: String,
+        is_disabled: bool,
+        polyphony: Polyphony,
+        is_drum_track: bool,
+    },
+    InsertSegment {
+        variation: usize,
+        index: usize,
+        segment: Segment,
+    },
+    DeleteSegment {
+        variation: usize,
+        index: usize,
+    },
+    SetSegment {
+        variation: usize,
+        index: usize,
+        segment: Segment,
+    },
+    InsertTrackList {
+        id: TrackListId,
+        track_list: Box<TrackList>,
+    },
+    DeleteTrackList {
+        id: TrackListId,
+    },
+    InsertInstrument {
+        index: usize,
+        instrument: Instrument,
+    },
+    DeleteInstrument {
+        index: usize,
+    },
+    SetInstrument {
+        index: usize,
+        instrument: Instrument,
+    },
+}
+
+/// Why an [Edit] could not be applied. The song is left unchanged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    NoTrack { track_list: TrackListId, track: usize },
+    NoTrackList { id: TrackListId },
+    TrackListExists { id: TrackListId },
+    NoEvent { id: Id },
+    NoVariation { variation: usize },
+    NoSegment { variation: usize, index: usize },
+    NoInstrument { index: usize },
+    OutOfIds,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoTrack { track_list, track } => {
+                write!(f, "track {} of track list {} does not exist", track, track_list)
+            }
+            Error::NoTrackList { id } => write!(f, "track list {} does not exist", id),
+            Error::TrackListExists { id } => write!(f, "track list {} already exists", id),
+            Error::NoEvent { id } => write!(f, "no event has id {}", id),
+            Error::NoVariation { variation } => write!(f, "variation {} does not exist", variation),
+            Error::NoSegment { variation, index } => {
+                write!(f, "variation {} has no segment {}", variation, index)
+            }
+            Error::NoInstrument { index } => write!(f, "instrument {} does not exist", index),
+            Error::OutOfIds => write!(f, "{}", OutOfIds),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<OutOfIds> for Error {
+    fn from(_: OutOfIds) -> Self {
+        Self::OutOfIds
+    }
+}
+
+fn track_mut(bgm: &mut Bgm, track_list: TrackListId, track: usize) -> Result<&mut Track, Error> {
+    bgm.track_lists
+        .get_mut(&track_list)
+        .and_then(|list| list.tracks.get_mut(track))
+        .ok_or(Error::NoTrack { track_list, track })
+}
+
+fn segments_mut(bgm: &mut Bgm, variation: usize) -> Result<&mut Vec<Segment>, Error> {
+    match bgm.variations.get_mut(variation) {
+        Some(Some(Variation { segments })) => Ok(segments),
+        _ => Err(Error::NoVariation { variation }),
+    }
+}
+
+impl Edit {
+    /// Applies this edit to `bgm`, returning the edit that undoes it.
+    pub fn apply(self, bgm: &mut Bgm) -> Result<Edit, Error> {
+        match self {
+            Edit::InsertEvent {
+                track_list,
+                track,
+                after,
+                event,
+            } => {
+                let id = event.id;
+                let commands = &mut track_mut(bgm, track_list, track)?.commands;
+                if !commands.insert_after(after, event) {
+                    return Err(Error::NoEvent { id: after.unwrap() });
+                }
+                Ok(Edit::DeleteEvent { track_list, track, id })
+            }
+            Edit::DeleteEvent { track_list, track, id } => {
+                let commands = &mut track_mut(bgm, track_list, track)?.commands;
+                let (after, event) = commands.remove_by_id(id).ok_or(Error::NoEvent { id })?;
+                Ok(Edit::InsertEvent {
+                    track_list,
+                    track,
+                    after,
+                    event,
+                })
+            }
+            Edit::SetCommand {
+                track_list,
+                track,
+                id,
+                command,
+            } => {
+                let commands = &mut track_mut(bgm, track_list, track)?.commands;
+                let command = commands
+                    .update_by_id(id, |old| std::mem::replace(old, command))
+                    .ok_or(Error::NoEvent { id })?;
+                Ok(Edit::SetCommand {
+                    track_list,
+                    track,
+                    id,
+                    command,
+                })
+            }
+            Edit::SetCommands {
+                track_list,
+                track,
+                commands,
+            } => {
+                let old = &mut track_mut(bgm, track_list, track)?.commands;
+                Ok(Edit::SetCommands {
+                    track_list,
+                    track,
+                    commands: std::mem::replace(old, commands),
+                })
+            }
+            Edit::SetTrackSettings {
+                track_list,
+                track,
+                name,
+                is_disabled,
+                polyphony,
+                is_drum_track,
+            } => {
+                let t = track_mut(bgm, track_list, track)?;
+                Ok(Edit::SetTrackSettings {
+                    track_list,
+                    track,
+                    name: std::mem::replace(&mut t.name, name),
+                    is_disabled: std::mem::replace(&mut t.is_disabled, is_disabled),
+                    polyphony: std::mem::replace(&mut t.polyphony, polyphony),
+                    is_drum_track: std::mem::replace(&mut t.is_drum_track, is_drum_track),
+                })
+            }
+            Edit::InsertSegment {
+                variation,
+                index,
+                segment,
+            } => {
+                let segments = segments_mut(bgm, variation)?;
+                if index > segments.len() {
+                    return Err(Error::NoSegment { variation, index });
+                }
+                segments.insert(index, segment);
+                Ok(Edit::DeleteSegment { variation, index })
+            }
+            Edit::DeleteSegment { variation, index } => {
+                let segments = segments_mut(bgm, variation)?;
+                if index >= segments.len() {
+                    return Err(Error::NoSegment { variation, index });
+                }
+                Ok(Edit::InsertSegment {
+                    variation,
+                    index,
+                    segment: segments.remove(index),
+                })
+            }
+            Edit::SetSegment {
+                variation,
+                index,
+                segment,
+            } => {
+                let old = segments_mut(bgm, variation)?
+                    .get_mut(index)
+                    .ok_or(Error::NoSegment { variation, index })?;
+                Ok(Edit::SetSegment {
+                    variation,
+                    index,
+                    segment: std::mem::replace(old, segment),
+                })
+            }
+            Edit::InsertTrackList { id, track_list } => {
+                if bgm.track_lists.contains_key(&id) {
+                    return Err(Error::TrackListExists { id });
+                }
+                bgm.track_lists.insert(id, *track_list);
+                Ok(Edit::DeleteTrackList { id })
+            }
+            Edit::DeleteTrackList { id } => {
+                let track_list = bgm.track_lists.remove(&id).ok_or(Error::NoTrackList { id })?;
+                Ok(Edit::InsertTrackList {
+                    id,
+                    track_list: Box::new(track_list),
+                })
+            }
+            Edit::InsertInstrument { index, instrument } => {
+                if index > bgm.instruments.len() {
+                    return Err(Error::NoInstrument { index });
+                }
+                bgm.instruments.insert(index, instrument);
+                Ok(Edit::DeleteInstrument { index })
+            }
+            Edit::DeleteInstrument { index } => {
+                if index >= bgm.instruments.len() {
+                    return Err(Error::NoInstrument { index });
+                }
+                Ok(Edit::InsertInstrument {
+                    index,
+                    instrument: bgm.instruments.remove(index),
+                })
+            }
+            Edit::SetInstrument { index, instrument } => {
+                let old = bgm.instruments.get_mut(index).ok_or(Error::NoInstrument { index })?;
+                Ok(Edit::SetInstrument {
+                    index,
+                    instrument: std::mem::replace(old, instrument),
+                })
+            }
+        }
+    }
+
+    /// The edits that insert `command` at the end of `time` in a track, keeping every other command at the same time.
+    /// A [Delay](Command::Delay) passing over `time` is split in two, and if `time` is after the [End](Command::End)
+    /// of the track, the track is lengthened. New ids come from [gen_id](crate::id::gen_id), so call this in the song's
+    /// [scope](Bgm::with_ids).
+    pub fn insert_command(
+        bgm: &Bgm,
+        track_list: TrackListId,
+        track: usize,
+        time: usize,
+        command: Command,
+    ) -> Result<Vec<Edit>, Error> {
+        let commands = &bgm
+            .track_lists
+            .get(&track_list)
+            .and_then(|list| list.tracks.get(track))
+            .ok_or(Error::NoTrack { track_list, track })?
+            .commands;
+        let insert = |after: Option<Id>, command: Command| {
+            let event = Event::from(command);
+            let id = event.id;
+            let edit = Edit::InsertEvent {
+                track_list,
+                track,
+                after,
+                event,
+            };
+            (id, edit)
+        };
+
+        // Find the event to insert after, and the time just after it
+        let mut after = None;
+        let mut after_time = 0;
+        for (start, event) in commands.iter_time() {
+            match event.command {
+                Command::Delay(length) if start + length <= time => {
+                    after = Some(event.id);
+                    after_time = start + length;
+                }
+                Command::Delay(length) if start < time => {
+                    let (id, insert_command) = insert(Some(event.id), command);
+                    let (_, insert_delay) = insert(Some(id), Command::Delay(start + length - time));
+                    let shorten = Edit::SetCommand {
+                        track_list,
+                        track,
+                        id: event.id,
+                        command: Command::Delay(time - start),
+                    };
+                    return Ok(vec![shorten, insert_command, insert_delay]);
+                }
+                Command::Delay(_) | Command::End => break,
+                _ => {
+                    after = Some(event.id);
+                    after_time = start;
+                }
+            }
+        }
+
+        let mut edits = Vec::new();
+        if after_time < time {
+            let (id, insert_delay) = insert(after, Command::Delay(time - after_time));
+            edits.push(insert_delay);
+            after = Some(id);
+        }
+        edits.push(insert(after, command).1);
+        Ok(edits)
+    }
+
+    /// The edits that split the segment of a variation playing at `time` in two, moving what plays from `time` on
+    /// into a new track list. There are none if a segment already starts or ends at `time`. New ids come from
+    /// [gen_id](crate::id::gen_id), so call this in the song's [scope](Bgm::with_ids).
+    pub fn split_variation_at(bgm: &Bgm, variation: usize, time: usize) -> Vec<Edit> {
+        let Some(Some(Variation { segments })) = bgm.variations.get(variation) else {
+            return Vec::new();
+        };
+
+        let mut seg_start = 0;
+        for (index, segment) in segments.iter().enumerate() {
+            let Segment::Subseg { track_list: id, .. } = *segment else {
+                continue;
+            };
+            let Some(track_list) = bgm.track_lists.get(&id) else {
+                continue;
+            };
+            let seg_end = seg_start + track_list.len_time();
+
+            if seg_start < time && time < seg_end {
+                let mut first = track_list.clone();
+                let second = first.split_at(time - seg_start);
+                let new_id = bgm.next_track_list_id();
+
+                let mut edits: Vec<Edit> = first
+                    .tracks
+                    .into_iter()
+                    .enumerate()
+                    .map(|(track, Track { commands, .. })| Edit::SetCommands {
+                        track_list: id,
+                        track,
+                        commands,
+                    })
+                    .collect();
+                edits.push(Edit::InsertTrackList {
+                    id: new_id,
+                    track_list: Box::new(second),
+                });
+                edits.push(Edit::InsertSegment {
+                    variation,
+                    index: index + 1,
+                    segment: Segment::Subseg {
+                        id: Some(gen_id()),
+                        track_list: new_id,
+                    },
+                });
+                return edits;
+            } else if seg_start == time || seg_end == time {
+                break;
+            }
+
+            seg_start = seg_end;
+        }
+        Vec::new()
+    }
+}
+
+/// Applies `edits` in order, returning the edits that undo all of them. If one fails, those before it are undone.
+pub(super) fn apply_all(bgm: &mut Bgm, edits: Vec<Edit>) -> Result<Vec<Edit>, Error> {
+    let mut inverses = Vec::with_capacity(edits.len());
+    for edit in edits {
+        match edit.apply(bgm) {
+            Ok(inverse) => inverses.push(inverse),
+            Err(error) => {
+                for inverse in inverses.into_iter().rev() {
+                    inverse.apply(bgm).expect("undoing an edit failed");
+                }
+                return Err(error);
+            }
+        }
+    }
+    inverses.reverse();
+    Ok(inverses)
+}
+
+/// Undo and redo stacks of groups of [Edit]s made to one song.
+///
+/// Each method returns the edits that would reverse what it just did, which touch the same [Part]s of the song.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    undo: Vec<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies `edits` as a single step that can be undone. Either all of the edits apply, or none do. No step is
+    /// added if there are no edits.
+    pub fn apply(&mut self, bgm: &mut Bgm, edits: Vec<Edit>) -> Result<&[Edit], Error> {
+        if edits.is_empty() {
+            return Ok(&[]);
+        }
+        let inverses = apply_all(bgm, edits)?;
+        self.undo.push(inverses);
+        self.redo.clear();
+        Ok(self.undo.last().unwrap())
+    }
+
+    /// Undoes the last step, returning `None` if there is nothing to undo.
+    pub fn undo(&mut self, bgm: &mut Bgm) -> Result<Option<&[Edit]>, Error> {
+        let Some(edits) = self.undo.pop() else {
+            return Ok(None);
+        };
+        match apply_all(bgm, edits.clone()) {
+            Ok(inverses) => {
+                self.redo.push(inverses);
+                Ok(self.redo.last().map(Vec::as_slice))
+            }
+            Err(error) => {
+                self.undo.push(edits);
+                Err(error)
+            }
+        }
+    }
+
+    /// Redoes the last undone step, returning `None` if there is nothing to redo.
+    pub fn redo(&mut self, bgm: &mut Bgm) -> Result<Option<&[Edit]>, Error> {
+        let Some(edits) = self.redo.pop() else {
+            return Ok(None);
+        };
+        match apply_all(bgm, edits.clone()) {
+            Ok(inverses) => {
+                self.undo.push(inverses);
+                Ok(self.undo.last().map(Vec::as_slice))
+            }
+            Err(error) => {
+                self.redo.push(edits);
+                Err(error)
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Forgets every step, for when the song is changed by something other than an [Edit].
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+/// A part of a song that an [Edit] changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Part {
+    Track { track_list: TrackListId, track: usize },
+    TrackList(TrackListId),
+    Variation(usize),
+    Instruments,
+}
+
+/// The new value of a [Part] of a song, so that a copy of the song kept elsewhere can be updated without copying all
+/// of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub enum Change {
+    Track {
+        track_list: TrackListId,
+        track: usize,
+        value: Track,
+    },
+    /// `value` is `None` if the track list was deleted.
+    TrackList {
+        id: TrackListId,
+        value: Option<Box<TrackList>>,
+    },
+    Variation {
+        index: usize,
+        value: Option<Variation>,
+    },
+    Instruments(Vec<Instrument>),
+    /// The whole song changed.
+    Bgm(Box<Bgm>),
+}
+
+impl Edit {
+    pub fn part(&self) -> Part {
+        match *self {
+            Edit::InsertEvent { track_list, track, .. }
+            | Edit::DeleteEvent { track_list, track, .. }
+            | Edit::SetCommand { track_list, track, .. }
+            | Edit::SetCommands { track_list, track, .. }
+            | Edit::SetTrackSettings { track_list, track, .. } => Part::Track { track_list, track },
+            Edit::InsertTrackList { id, .. } | Edit::DeleteTrackList { id } => Part::TrackList(id),
+            Edit::InsertSegment { variation, .. }
+            | Edit::DeleteSegment { variation, .. }
+            | Edit::SetSegment { variation, .. } => Part::Variation(variation),
+            Edit::InsertInstrument { .. } | Edit::DeleteInstrument { .. } | Edit::SetInstrument { .. } => {
+                Part::Instruments
+            }
+        }
+    }
+}
+
+impl Part {
+    /// The current value of this part of `bgm`, or `None` if it doesn't exist.
+    pub fn change(self, bgm: &Bgm) -> Option<Change> {
+        match self {
+            Part::Track { track_list, track } => {
+                let value = bgm.track_lists.get(&track_list)?.tracks.get(track)?.clone();
+                Some(Change::Track {
+                    track_list,
+                    track,
+                    value,
+                })
+            }
+            Part::TrackList(id) => Some(Change::TrackList {
+                id,
+                value: bgm.track_lists.get(&id).cloned().map(Box::new),
+            }),
+            Part::Variation(index) => Some(Change::Variation {
+                index,
+                value: bgm.variations.get(index)?.clone(),
+            }),
+            Part::Instruments => Some(Change::Instruments(bgm.instruments.clone())),
+        }
+    }
+}
+
+/// The [Change]s to `bgm` made by `edits`, one per part changed.
+pub fn changes(bgm: &Bgm, edits: &[Edit]) -> Vec<Change> {
+    let parts: BTreeSet<Part> = edits.iter().map(Edit::part).collect();
+    parts.into_iter().filter_map(|part| part.change(bgm)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn song() -> Bgm {
+        mml::to_bgm("1: @0 l4 c r e g").unwrap()
+    }
+
+    fn note(pitch: u8) -> Command {
+        Command::Note {
+            pitch,
+            velocity: 100,
+            length: 24,
+        }
+    }
+
+    #[test]
+    fn insert_command() {
+        let mut bgm = song();
+        let original = bgm.clone();
+
+        // Into the middle of the rest, at the end of the song, and after it
+        for time in [72, 192, 240] {
+            let edits = bgm
+                .with_ids(|bgm| Edit::insert_command(bgm, 1, 1, time, note(150)))
+                .unwrap()
+                .unwrap();
+            History::new().apply(&mut bgm, edits).unwrap();
+
+            let commands = &bgm.track_lists[&1].tracks[1].commands;
+            assert!(
+                commands.at_time(time).iter().any(|event| event.command == note(150)),
+                "{:?}",
+                commands
+            );
+        }
+        let commands = &bgm.track_lists[&1].tracks[1].commands;
+        assert_eq!(commands.len_time(), 240);
+        // The three notes, and the end moving
+        assert_eq!(
+            diff::diff_commands(&original.track_lists[&1].tracks[1].commands, commands).len(),
+            5
+        );
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut bgm = song();
+        let original = bgm.clone();
+        let mut history = History::new();
+
+        let mut edits = bgm
+            .with_ids(|bgm| Edit::insert_command(bgm, 1, 1, 72, note(150)))
+            .unwrap()
+            .unwrap();
+        edits.push(Edit::SetInstrument {
+            index: 0,
+            instrument: Instrument {
+                volume: 10,
+                ..Default::default()
+            },
+        });
+        edits.push(Edit::DeleteSegment { variation: 0, index: 0 });
+        edits.push(Edit::SetTrackSettings {
+            track_list: 1,
+            track: 1,
+            name: "Lead".to_owned(),
+            is_disabled: true,
+            polyphony: Polyphony::Manual { voices: 2 },
+            is_drum_track: true,
+        });
+        history.apply(&mut bgm, edits).unwrap();
+        let edited = bgm.clone();
+        assert_ne!(edited, original);
+
+        let undone = history.undo(&mut bgm).unwrap().unwrap().to_vec();
+        assert_eq!(bgm, original);
+        assert!(history.undo(&mut bgm).unwrap().is_none());
+        let parts: Vec<Part> = changes(&bgm, &undone)
+            .into_iter()
+            .map(|change| match change {
+                Change::Track { track_list, track, .. } => Part::Track { track_list, track },
+                Change::TrackList { id, .. } => Part::TrackList(id),
+                Change::Variation { index, .. } => Part::Variation(index),
+                Change::Instruments(_) => Part::Instruments,
+                Change::Bgm(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            parts,
+            [
+                Part::Track {
+                    track_list: 1,
+                    track: 1
+                },
+                Part::Variation(0),
+                Part::Instruments
+            ]
+        );
+
+        assert!(history.redo(&mut bgm).unwrap().is_some());
+        assert_eq!(bgm, edited);
+        assert!(!history.can_redo());
+        assert!(history.can_undo());
+    }
+
+    #[test]
+    fn split_variation_at() {
+        let mut bgm = song();
+        let original = bgm.clone();
+        let mut history = History::new();
+
+        let edits = bgm.with_ids(|bgm| Edit::split_variation_at(bgm, 0, 48)).unwrap();
+        let done = history.apply(&mut bgm, edits).unwrap().to_vec();
+        let mut split = original.clone();
+        split.split_variation_at(0, 48).unwrap();
+        assert_eq!(bgm.track_lists.len(), 2);
+        assert_eq!(bgm.variations[0].as_ref().unwrap().segments.len(), 2);
+        assert_eq!(bgm.track_lists[&1], split.track_lists[&1]);
+
+        let changes = changes(&bgm, &done);
+        assert!(
+            changes
+                .iter()
+                .any(|change| matches!(change, Change::Variation { index: 0, .. }))
+        );
+        assert!(
+            changes
+                .iter()
+                .any(|change| matches!(change, Change::TrackList { id: 2, value: Some(_) }))
+        );
+        assert!(!changes.iter().any(|change| matches!(change, Change::Bgm(_))));
+
+        history.undo(&mut bgm).unwrap();
+        assert_eq!(bgm, original);
+
+        // Nothing to split at the start of a segment
+        assert!(
+            bgm.with_ids(|bgm| Edit::split_variation_at(bgm, 0, 0))
+                .unwrap()
+                .is_empty()
+        );
+        assert!(history.apply(&mut bgm, Vec::new()).unwrap().is_empty());
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn failed_edits_change_nothing() {
+        let mut bgm = song();
+        let original = bgm.clone();
+        let mut history = History::new();
+
+        let edits = vec![
+            Edit::InsertInstrument {
+                index: 0,
+                instrument: Instrument::default(),
+            },
+            Edit::DeleteEvent {
+                track_list: 1,
+                track: 1,
+                id: 9999,
+            },
+        ];
+        assert_eq!(history.apply(&mut bgm, edits), Err(Error::NoEvent { id: 9999 }));
+        assert_eq!(bgm, original);
+        assert!(!history.can_undo());
+    }
+}
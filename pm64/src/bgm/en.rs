@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::fmt;
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
+use std::iter::Peekable;
 
 use log::{debug, info, warn};
 
@@ -48,6 +49,9 @@ impl std::error::Error for Error {
     }
 }
 
+/// The largest BGM the game can load.
+const MAX_SIZE: u64 = 0x8A8F;
+
 impl Bgm {
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut encoded = io::Cursor::new(Vec::new());
@@ -134,9 +138,8 @@ impl Bgm {
                 tracks_pos: u64,
                 segment_start: u64,
             },
-            Unknown(Unknown),
         }
-        let mut to_write: Vec<ToWrite> = self.unknowns.iter().map(|unk| ToWrite::Unknown(unk.clone())).collect();
+        let mut to_write: Vec<ToWrite> = Vec::new();
 
         // Write segments
         for (offset, segment) in segment_offsets.into_iter().zip(self.variations.iter()) {
@@ -171,9 +174,13 @@ impl Bgm {
 
         to_write.sort_by_key(|w| match w {
             ToWrite::TrackList { track_list_id, .. } => self.track_lists[track_list_id].pos.unwrap_or_default(),
-            ToWrite::Unknown(unk) => unk.range.start,
         });
 
+        // Unknowns go between the track lists and sequences around them
+        let mut unknowns: Vec<&Unknown> = self.unknowns.iter().collect();
+        unknowns.sort_by_key(|unknown| unknown.start);
+        let mut unknowns = unknowns.into_iter().peekable();
+
         let mut encoded_tracks: HashMap<TrackListId, u64> = HashMap::new();
 
         for w in to_write.into_iter() {
@@ -196,16 +203,20 @@ impl Bgm {
                         continue;
                     }
 
+                    if let Some(pos) = track_list.pos {
+                        write_unknowns_before(f, &mut unknowns, pos)?;
+                    }
+
                     f.align(4)?; // This position needs to be right-shifted by 2 without loss
 
-                    // For matching
-                    let track_data_start = if let Some(pos) = track_list.pos {
+                    // For matching. If something before it got bigger, it goes where it can.
+                    if let Some(pos) = track_list.pos
+                        && pos > f.pos()?
+                    {
                         // TODO: turn track_list.pos into a range and make sure this will fit there
                         f.seek(SeekFrom::Start(pos))?;
-                        pos
-                    } else {
-                        f.pos()?
-                    };
+                    }
+                    let track_data_start = f.pos()?;
 
                     debug!(
                         "tracks start = {:#X} (offset = {:#X})",
@@ -230,7 +241,7 @@ impl Bgm {
                             polyphony,
                             is_drum_track,
                             commands,
-                            ..
+                            pos,
                         },
                     ) in track_list.tracks.iter().enumerate()
                     {
@@ -240,7 +251,7 @@ impl Bgm {
 
                         if !commands.is_empty() {
                             // Need to write command data after the track
-                            todo_commands.push((f.pos()?, commands));
+                            todo_commands.push((f.pos()?, *pos, commands));
                         }
                         f.write_u16_be(0)?; // Replaced later if !null
 
@@ -261,43 +272,46 @@ impl Bgm {
                         f.write_u16_be(flags)?;
                     }
 
-                    // Write command sequences
-                    for (offset, seq) in todo_commands.into_iter() {
-                        //debug!("commandseq = {:#X} (offset = {:#X})", f.pos()?, f.pos()? - track_data_start);
+                    // Write command sequences, in the order they were decoded in. New ones go last.
+                    todo_commands.sort_by_key(|(_, pos, _)| (pos.is_none(), *pos));
+                    let mut encoded_commands: Vec<(FilePos, &CommandSeq, u64)> = Vec::new();
+                    for (offset, pos, seq) in todo_commands.into_iter() {
+                        // Tracks that shared commands when decoded still do, unless they have been edited apart
+                        let shared = encoded_commands.iter().find(|(shared_pos, shared_seq, _)| {
+                            Some(*shared_pos) == pos && same_commands(shared_seq, seq)
+                        });
 
-                        // Write pointer to here
-                        let pos = f.pos()? - track_data_start; // Notice no shift
-                        f.write_u16_be_at(pos as u16, SeekFrom::Start(offset))?;
+                        let seq_start = match shared {
+                            Some((_, _, seq_start)) => *seq_start,
+                            None => {
+                                // For matching
+                                if let Some(pos) = pos {
+                                    write_unknowns_before(f, &mut unknowns, pos)?;
+                                    if pos > f.pos()? {
+                                        f.seek(SeekFrom::Start(pos))?;
+                                    }
+                                }
+
+                                let seq_start = f.pos()?;
+                                seq.encode(f)?;
+                                if let Some(pos) = pos {
+                                    encoded_commands.push((pos, seq, seq_start));
+                                }
+                                seq_start
+                            }
+                        };
 
-                        seq.encode(f)?;
+                        // Write pointer to the sequence
+                        let pos = seq_start - track_data_start; // Notice no shift
+                        f.write_u16_be_at(pos as u16, SeekFrom::Start(offset))?;
                     }
                 }
-                ToWrite::Unknown(unk) => {
-                    f.seek(SeekFrom::Start(unk.range.start))?;
-                    debug!(
-                        "write unknown {:X}..{:X} @ {:X}",
-                        unk.range.start,
-                        unk.range.end,
-                        f.pos()?
-                    );
-                    f.write_all(&unk.data)?;
-                    f.seek(SeekFrom::Start(unk.range.end))?;
-                }
             }
         }
+        write_unknowns_before(f, &mut unknowns, FilePos::MAX)?;
 
         // Write file size
-        let mut file_size = f.pos()? as u32;
-
-        // Matching: file size overrides (!)
-        if self.name.as_str() == "117 " && file_size == 0x19A0 {
-            // Battle Fanfare's file size does not include the junk unknown at the end of it.
-            file_size = 0x1998;
-        } else if self.name.as_str() == "322 " && file_size == 0x0D70 {
-            // Bowser's Castle Explodes
-            file_size = 0x0D64;
-        }
-
+        let file_size = f.pos()? as u32;
         f.write_u32_be_at(file_size, file_size_offset)?;
 
         debug!("end = {:#X}", f.pos()?);
@@ -313,7 +327,7 @@ impl Bgm {
             }
         }
 
-        if f.pos()? <= 0x8A8F {
+        if f.pos()? <= MAX_SIZE {
             Ok(())
         } else {
             Err(Error::TooBig) // TODO: make into warning and surface to caller somehow
@@ -321,6 +335,37 @@ impl Bgm {
     }
 }
 
+/// Writes the unknowns that start before `pos`, where they were unless something before them got bigger.
+fn write_unknowns_before<'a, W: Write + Seek>(
+    f: &mut W,
+    unknowns: &mut Peekable<impl Iterator<Item = &'a Unknown>>,
+    pos: FilePos,
+) -> Result<(), Error> {
+    while let Some(unknown) = unknowns.next_if(|unknown| unknown.start < pos) {
+        let range = unknown.range();
+        let end = f.pos()?;
+        if range.start < end {
+            // Keep the bytes, even though they can't go where they were
+            warn!(
+                "unknown {:X}..{:X} overlaps data ending at {:X}",
+                range.start, range.end, end
+            );
+        } else {
+            f.seek(SeekFrom::Start(range.start))?;
+        }
+        debug!("write unknown {:X}..{:X} @ {:X}", range.start, range.end, f.pos()?);
+        f.write_all(&unknown.data)?;
+    }
+    Ok(())
+}
+
+/// Whether two sequences have the same commands, ignoring their ids.
+fn same_commands(a: &CommandSeq, b: &CommandSeq) -> bool {
+    a.iter()
+        .map(|event| &event.command)
+        .eq(b.iter().map(|event| &event.command))
+}
+
 impl Drum {
     pub fn encode<W: Write + Seek>(&self, f: &mut W) -> Result<(), Error> {
         self.patch.encode(f)?;
@@ -395,7 +440,6 @@ impl Segment {
                 ..
             } => {
                 f.write_u16_be((segment_commands::UNKNOWN_6 >> 4) as u16)?;
-                f.seek(SeekFrom::Current(-2))?;
                 f.write_u16_be((*label_index as u16 & 0x1F) | ((*iter_count as u16 & 0x7F) << 5))?;
                 Ok(None)
             }
@@ -405,7 +449,6 @@ impl Segment {
                 ..
             } => {
                 f.write_u16_be((segment_commands::UNKNOWN_7 >> 4) as u16)?;
-                f.seek(SeekFrom::Current(-2))?;
                 f.write_u16_be((*label_index as u16 & 0x1F) | ((*iter_count as u16 & 0x7F) << 5))?;
                 Ok(None)
             }
@@ -424,6 +467,11 @@ impl CommandSeq {
                     let mut delay = *delay;
                     // https://github.com/KernelEquinox/midi2bgm/blob/master/midi2bgm.cpp#L202
                     while delay > 0 {
+                        // A long enough delay would take forever to write, so give up once it can't fit
+                        if f.pos()? > MAX_SIZE {
+                            return Err(Error::TooBig);
+                        }
+
                         if delay < 0x78 {
                             f.write_u8(delay as u8)?;
                             delay = 0;
@@ -449,7 +497,7 @@ impl CommandSeq {
                     velocity,
                     length,
                 } => {
-                    let length = if *length > 0xD3FF { 0xD3FF } else { *length };
+                    let length = (*length).min(NOTE_LENGTH_MAX);
 
                     f.write_u8(*pitch)?;
                     f.write_u8(*velocity)?;
@@ -580,12 +628,12 @@ impl CommandSeq {
                     f.write_u8(*pan1)?;
                 }
                 Command::Jump { unk_00, unk_02 } => {
-                    f.write_u8(0xF8)?;
+                    f.write_u8(0xFC)?;
                     f.write_u16_be(*unk_00)?;
                     f.write_u8(*unk_02)?;
                 }
                 Command::EventTrigger { event_info } => {
-                    f.write_u8(0xF9)?;
+                    f.write_u8(0xFD)?;
                     f.write_u32_be(*event_info)?;
                 }
                 Command::UnkCmdFF { unk_00, unk_01, unk_02 } => {
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use log::debug;
 use serde_derive::{Deserialize, Serialize};
@@ -11,7 +11,7 @@ pub const MAGIC_MAX_LEN: usize = 8;
 #[serde(default)]
 pub struct Metadata {
     /// Maps track list pos to vec of track names, excluding the master track.
-    track_names: HashMap<u16, Vec<String>>,
+    track_names: BTreeMap<u16, Vec<String>>,
 }
 
 impl Metadata {
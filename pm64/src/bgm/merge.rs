@@ -0,0 +1,535 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use super::diff::{CommandChange, diff_commands, diff_segments};
+use super::*;
+use crate::id::{IdAllocator, OutOfIds};
+
+/// Result of [merge].
+#[derive(Debug, Clone)]
+pub struct Merge {
+    pub bgm: Bgm,
+    /// Places where both sides made different changes. In each of these, [Merge::bgm] has our side's version.
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    Name,
+    Variation {
+        index: usize,
+    },
+    Drums,
+    Drum {
+        index: usize,
+    },
+    Instruments,
+    Instrument {
+        index: usize,
+    },
+    /// One side deleted the track list and the other changed it.
+    TrackList {
+        id: TrackListId,
+    },
+    TrackField {
+        track_list: TrackListId,
+        track: usize,
+        field: &'static str,
+    },
+    /// Both sides changed commands of the track within overlapping time ranges.
+    Commands {
+        track_list: TrackListId,
+        track: usize,
+        ours: RangeInclusive<usize>,
+        theirs: RangeInclusive<usize>,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::Name => write!(f, "both sides renamed the song"),
+            Conflict::Variation { index } => write!(f, "both sides changed variation {}", index),
+            Conflict::Drums => write!(f, "both sides changed the number of drums"),
+            Conflict::Drum { index } => write!(f, "both sides changed drum {}", index),
+            Conflict::Instruments => write!(f, "both sides changed the number of instruments"),
+            Conflict::Instrument { index } => write!(f, "both sides changed instrument {}", index),
+            Conflict::TrackList { id } => {
+                write!(f, "track_list {} was deleted on one side and changed on the other", id)
+            }
+            Conflict::TrackField {
+                track_list,
+                track,
+                field,
+            } => write!(
+                f,
+                "both sides changed {} of track_list {} track {}",
+                field, track_list, track
+            ),
+            Conflict::Commands {
+                track_list,
+                track,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "both sides changed commands of track_list {} track {} (ours at {}..={}, theirs at {}..={})",
+                track_list,
+                track,
+                ours.start(),
+                ours.end(),
+                theirs.start(),
+                theirs.end()
+            ),
+        }
+    }
+}
+
+/// Three-way merge of two songs, `ours` and `theirs`, that were both changed from `base`.
+///
+/// Anything changed on only one side is taken from that side. Changes to different tracks always merge, as do changes
+/// to the same track that are at different times: the time range between the first and last changed command on each
+/// side must not overlap. Ids are compared like [diff] does, and any that both sides allocated are renumbered. If both
+/// sides added different track lists with the same id, theirs is given a new id.
+pub fn merge(base: &Bgm, ours: &Bgm, theirs: &Bgm) -> Result<Merge, OutOfIds> {
+    let theirs = &*separate_added_track_lists(base, ours, theirs);
+    let mut conflicts = Vec::new();
+    let mut bgm = ours.clone();
+
+    bgm.name = pick(&base.name, &ours.name, &theirs.name, PartialEq::eq)
+        .unwrap_or_else(|| {
+            conflicts.push(Conflict::Name);
+            &ours.name
+        })
+        .clone();
+
+    for index in 0..bgm.variations.len() {
+        let same = |a: &Option<Variation>, b: &Option<Variation>| match (a, b) {
+            (Some(a), Some(b)) => diff_segments(&a.segments, &b.segments).is_empty(),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        match pick(
+            &base.variations[index],
+            &ours.variations[index],
+            &theirs.variations[index],
+            same,
+        ) {
+            Some(variation) => bgm.variations[index] = variation.clone(),
+            None => conflicts.push(Conflict::Variation { index }),
+        }
+    }
+
+    let (drums, item_conflicts) = merge_items(&base.drums, &ours.drums, &theirs.drums);
+    bgm.drums = drums;
+    conflicts.extend(item_conflicts.into_iter().map(|index| match index {
+        Some(index) => Conflict::Drum { index },
+        None => Conflict::Drums,
+    }));
+
+    let (instruments, item_conflicts) = merge_items(&base.instruments, &ours.instruments, &theirs.instruments);
+    bgm.instruments = instruments;
+    conflicts.extend(item_conflicts.into_iter().map(|index| match index {
+        Some(index) => Conflict::Instrument { index },
+        None => Conflict::Instruments,
+    }));
+
+    let ids: BTreeSet<TrackListId> = base
+        .track_lists
+        .keys()
+        .chain(ours.track_lists.keys())
+        .chain(theirs.track_lists.keys())
+        .copied()
+        .collect();
+    let mut ids_allocator = IdAllocator::after(ours.ids().chain(theirs.ids()));
+    ids_allocator.scope(|| {
+        for id in ids {
+            let base_track_list = base.track_lists.get(&id);
+            let ours_track_list = ours.track_lists.get(&id);
+            let theirs_track_list = theirs.track_lists.get(&id);
+
+            match (base_track_list, ours_track_list, theirs_track_list) {
+                (_, None, None) => {}
+                (None, Some(_), None) => {}
+                (None, None, Some(track_list)) => {
+                    bgm.track_lists.insert(id, track_list.clone());
+                }
+                (Some(base), Some(kept), None) | (Some(base), None, Some(kept)) => {
+                    if is_unchanged(base, kept) {
+                        bgm.track_lists.remove(&id);
+                    } else {
+                        conflicts.push(Conflict::TrackList { id });
+                    }
+                }
+                (base, Some(ours), Some(theirs)) => {
+                    let empty = TrackList::default();
+                    let base = base.unwrap_or(&empty);
+                    let merged = bgm.track_lists.get_mut(&id).unwrap();
+                    for track in 0..merged.tracks.len() {
+                        merge_track(
+                            &base.tracks[track],
+                            &ours.tracks[track],
+                            &theirs.tracks[track],
+                            &mut merged.tracks[track],
+                            |field| Conflict::TrackField {
+                                track_list: id,
+                                track,
+                                field,
+                            },
+                            |ours, theirs| Conflict::Commands {
+                                track_list: id,
+                                track,
+                                ours,
+                                theirs,
+                            },
+                            &mut conflicts,
+                        );
+                    }
+                }
+            }
+        }
+    })?;
+
+    renumber_duplicate_ids(&mut bgm)?;
+
+    Ok(Merge { bgm, conflicts })
+}
+
+/// Both sides give a track list they add the next free id, so track lists added on both sides can share an id
+/// without being the same. Such track lists of `theirs` are moved to new ids, along with the segments that play them.
+fn separate_added_track_lists<'a>(base: &Bgm, ours: &Bgm, theirs: &'a Bgm) -> Cow<'a, Bgm> {
+    let clashes: Vec<TrackListId> = theirs
+        .track_lists
+        .iter()
+        .filter(|(id, track_list)| {
+            !base.track_lists.contains_key(id)
+                && ours
+                    .track_lists
+                    .get(id)
+                    .is_some_and(|ours| !is_unchanged(ours, track_list))
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    if clashes.is_empty() {
+        return Cow::Borrowed(theirs);
+    }
+
+    let mut theirs = theirs.clone();
+    let first_new_id = base
+        .track_lists
+        .keys()
+        .chain(ours.track_lists.keys())
+        .chain(theirs.track_lists.keys())
+        .max()
+        .map_or(0, |max| max + 1);
+    for (old_id, new_id) in clashes.into_iter().zip(first_new_id..) {
+        let track_list = theirs.track_lists.remove(&old_id).unwrap();
+        theirs.track_lists.insert(new_id, track_list);
+        for variation in theirs.variations.iter_mut().flatten() {
+            for segment in &mut variation.segments {
+                if let Segment::Subseg { track_list, .. } = segment
+                    && *track_list == old_id
+                {
+                    *track_list = new_id;
+                }
+            }
+        }
+    }
+    Cow::Owned(theirs)
+}
+
+/// Picks the side that changed, or `None` if both changed differently.
+fn pick<'a, T>(base: &'a T, ours: &'a T, theirs: &'a T, same: impl Fn(&T, &T) -> bool) -> Option<&'a T> {
+    if same(ours, theirs) || same(base, theirs) {
+        Some(ours)
+    } else if same(base, ours) {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+/// Merges [Bgm::drums] or [Bgm::instruments] entry by entry, returning the indices of conflicting entries. If the
+/// entries cannot line up, because one side removed entries that the other changed or added to, the conflict is
+/// `None` and the whole list is ours.
+fn merge_items<T: Clone + PartialEq>(base: &[T], ours: &[T], theirs: &[T]) -> (Vec<T>, Vec<Option<usize>>) {
+    let len = base.len().max(ours.len()).max(theirs.len());
+    let mut merged = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for index in 0..len {
+        let (base_item, ours_item, theirs_item) = (base.get(index), ours.get(index), theirs.get(index));
+        let item = match pick(&base_item, &ours_item, &theirs_item, PartialEq::eq) {
+            Some(item) => *item,
+            None => {
+                conflicts.push(Some(index));
+                ours_item
+            }
+        };
+        match item {
+            Some(item) if merged.len() == index => merged.push(item.clone()),
+            Some(_) => return (ours.to_vec(), vec![None]),
+            None => {}
+        }
+    }
+
+    (merged, conflicts)
+}
+
+fn is_unchanged(base: &TrackList, other: &TrackList) -> bool {
+    base.tracks.iter().zip(&other.tracks).all(|(base, other)| {
+        base.name == other.name
+            && base.is_disabled == other.is_disabled
+            && base.polyphony == other.polyphony
+            && base.is_drum_track == other.is_drum_track
+            && diff_commands(&base.commands, &other.commands).is_empty()
+    })
+}
+
+fn merge_track(
+    base: &Track,
+    ours: &Track,
+    theirs: &Track,
+    merged: &mut Track,
+    field_conflict: impl Fn(&'static str) -> Conflict,
+    commands_conflict: impl Fn(RangeInclusive<usize>, RangeInclusive<usize>) -> Conflict,
+    conflicts: &mut Vec<Conflict>,
+) {
+    macro_rules! merge_field {
+        ($field:ident) => {
+            match pick(&base.$field, &ours.$field, &theirs.$field, PartialEq::eq) {
+                Some(value) => merged.$field = value.clone(),
+                None => conflicts.push(field_conflict(stringify!($field))),
+            }
+        };
+    }
+    merge_field!(name);
+    merge_field!(is_disabled);
+    merge_field!(polyphony);
+    merge_field!(is_drum_track);
+
+    let ours_changes = diff_commands(&base.commands, &ours.commands);
+    let theirs_changes = diff_commands(&base.commands, &theirs.commands);
+    let changed_range = |changes: &[CommandChange]| changes.first().unwrap().time()..=changes.last().unwrap().time();
+
+    if theirs_changes.is_empty() || diff_commands(&ours.commands, &theirs.commands).is_empty() {
+        // Keep ours
+    } else if ours_changes.is_empty() {
+        merged.commands = theirs.commands.clone();
+    } else {
+        let ours_range = changed_range(&ours_changes);
+        let theirs_range = changed_range(&theirs_changes);
+
+        if ours_range.start() <= theirs_range.end() && theirs_range.start() <= ours_range.end() {
+            conflicts.push(commands_conflict(ours_range, theirs_range));
+        } else {
+            merged.commands = splice(&ours.commands, &theirs.commands, &theirs_range);
+        }
+    }
+}
+
+/// Takes the commands of `theirs` within `range` and those of `ours` elsewhere. The commands are re-timed with new
+/// [Delays](Command::Delay).
+fn splice(ours: &CommandSeq, theirs: &CommandSeq, range: &RangeInclusive<usize>) -> CommandSeq {
+    let mut events: Vec<(usize, &Event)> = ours
+        .iter_time()
+        .filter(|(time, _)| !range.contains(time))
+        .chain(theirs.iter_time().filter(|(time, _)| range.contains(time)))
+        .filter(|(_, event)| !matches!(event.command, Command::Delay(_)))
+        .collect();
+    events.sort_by_key(|(time, _)| *time);
+
+    let mut seq = CommandSeq::new();
+    let mut current_time = 0;
+    for (time, event) in events {
+        if time > current_time {
+            seq.push(Command::Delay(time - current_time));
+            current_time = time;
+        }
+        seq.push(event.clone());
+    }
+    seq
+}
+
+/// Both sides allocate ids after those in the base, so anything added on both sides can share ids.
+fn renumber_duplicate_ids(bgm: &mut Bgm) -> Result<(), OutOfIds> {
+    let mut ids = IdAllocator::after(bgm.ids());
+    let mut seen = HashSet::new();
+
+    for variation in bgm.variations.iter_mut().flatten() {
+        for segment in &mut variation.segments {
+            if let Some(id) = segment.id_mut()
+                && !seen.insert(*id)
+            {
+                *id = ids.next_id()?;
+            }
+        }
+    }
+    for track_list in bgm.track_lists.values_mut() {
+        for track in &mut track_list.tracks {
+            for id in track.commands.ids_mut() {
+                if !seen.insert(*id) {
+                    *id = ids.next_id()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn base() -> Bgm {
+        mml::to_bgm("1: @0 l8 cdef gab>c\n2: @0 o3 c2 g2 c2 g2").unwrap()
+    }
+
+    fn note(pitch: u8) -> Command {
+        Command::Note {
+            pitch,
+            velocity: 100,
+            length: 24,
+        }
+    }
+
+    fn track(bgm: &mut Bgm, index: usize) -> &mut Track {
+        &mut bgm.track_lists.get_mut(&1).unwrap().tracks[index]
+    }
+
+    #[test]
+    fn different_tracks() {
+        let base = base();
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+
+        ours.with_ids(|bgm| track(bgm, 1).commands.insert_end(48, note(150)))
+            .unwrap();
+        theirs
+            .with_ids(|bgm| track(bgm, 2).commands.insert_end(48, note(130)))
+            .unwrap();
+        theirs.instruments[0].volume = 50;
+        track(&mut theirs, 2).name = "Bass".to_owned();
+
+        let Merge { mut bgm, conflicts } = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(conflicts, vec![]);
+        assert_eq!(bgm.instruments[0].volume, 50);
+        assert_eq!(track(&mut bgm, 2).name, "Bass");
+        assert!(
+            track(&mut bgm, 1)
+                .commands
+                .at_time(48)
+                .iter()
+                .any(|event| event.command == note(150))
+        );
+        assert!(
+            track(&mut bgm, 2)
+                .commands
+                .at_time(48)
+                .iter()
+                .any(|event| event.command == note(130))
+        );
+
+        // Both sides allocated the same ids for their new commands
+        let ids: Vec<_> = bgm.ids().collect();
+        assert_eq!(ids.len(), ids.iter().collect::<HashSet<_>>().len());
+    }
+
+    #[test]
+    fn same_track() {
+        let base = base();
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+        ours.with_ids(|bgm| track(bgm, 1).commands.insert_end(24, note(150)))
+            .unwrap();
+        theirs
+            .with_ids(|bgm| track(bgm, 1).commands.insert_end(144, note(130)))
+            .unwrap();
+
+        let Merge { mut bgm, conflicts } = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(conflicts, vec![]);
+        let commands = &track(&mut bgm, 1).commands;
+        assert!(commands.at_time(24).iter().any(|event| event.command == note(150)));
+        assert!(commands.at_time(144).iter().any(|event| event.command == note(130)));
+        assert_eq!(
+            diff_commands(&base.track_lists[&1].tracks[1].commands, commands).len(),
+            2
+        );
+
+        theirs
+            .with_ids(|bgm| track(bgm, 1).commands.insert_end(0, note(131)))
+            .unwrap();
+        let Merge { mut bgm, conflicts } = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![Conflict::Commands {
+                track_list: 1,
+                track: 1,
+                ours: 24..=24,
+                theirs: 0..=144,
+            }]
+        );
+        assert_eq!(track(&mut bgm, 1).commands, ours.track_lists[&1].tracks[1].commands);
+    }
+
+    #[test]
+    fn conflicting_fields() {
+        let base = base();
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+        ours.name = "Ours".to_owned();
+        theirs.name = "Thrs".to_owned();
+        ours.instruments[0].pan = 0;
+        theirs.instruments[0].pan = 127;
+        theirs.instruments.push(Instrument::default());
+        ours.track_lists.remove(&1);
+        track(&mut theirs, 1).is_disabled = true;
+
+        let Merge { bgm, conflicts } = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict::Name,
+                Conflict::Instrument { index: 0 },
+                Conflict::TrackList { id: 1 }
+            ]
+        );
+        assert_eq!(bgm.name, "Ours");
+        assert_eq!(bgm.instruments, [ours.instruments[0].clone(), Instrument::default()]);
+        assert!(bgm.track_lists.is_empty());
+    }
+
+    #[test]
+    fn track_lists_added_on_both_sides() {
+        let base = base();
+        let mut ours = base.clone();
+        let mut theirs = base.clone();
+        let ours_list = mml::compile("1: c").unwrap();
+        let theirs_list = mml::compile("1: g").unwrap();
+        assert_eq!(ours.add_track_list(ours_list.clone()), 2);
+        assert_eq!(theirs.add_track_list(theirs_list.clone()), 2);
+        theirs.variations[1] = Some(Variation {
+            segments: vec![Segment::Subseg {
+                id: Some(100),
+                track_list: 2,
+            }],
+        });
+
+        let Merge { bgm, conflicts } = merge(&base, &ours, &theirs).unwrap();
+        assert_eq!(conflicts, vec![]);
+        assert!(is_unchanged(&bgm.track_lists[&2], &ours_list));
+        assert!(is_unchanged(&bgm.track_lists[&3], &theirs_list));
+        assert_eq!(
+            bgm.variations[1].as_ref().unwrap().segments,
+            [Segment::Subseg {
+                id: Some(100),
+                track_list: 3
+            }]
+        );
+
+        // The same track list added on both sides is kept once
+        let Merge { bgm, .. } = merge(&base, &ours, &ours).unwrap();
+        assert_eq!(bgm.track_lists.len(), 2);
+    }
+}
@@ -1,11 +1,16 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::io::SeekFrom;
 use std::io::prelude::*;
+use std::ops::Range;
 
 use midly::{MetaMessage, Smf};
+use serde_derive::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
 
 use crate::bgm::*;
-use crate::id::gen_id;
+use crate::id::{IdAllocator, gen_id};
 use crate::rw::*;
 
 pub fn is_midi<R: Read + Seek>(file: &mut R) -> Result<bool, std::io::Error> {
@@ -19,17 +24,145 @@ pub fn is_midi<R: Read + Seek>(file: &mut R) -> Result<bool, std::io::Error> {
     Ok(is_midi)
 }
 
-pub fn to_bgm(raw: &[u8]) -> Result<Bgm, Box<dyn Error>> {
+/// A problem found while importing a MIDI file that did not stop the import, but may make the resulting [Bgm] sound
+/// different to the original.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub struct Warning {
+    /// Index of the MIDI track the problem was found in.
+    pub track: usize,
+
+    /// Time of the problem, in MIDI ticks since the start of the track.
+    pub time: usize,
+
+    pub kind: WarningKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub enum WarningKind {
+    /// A NoteOff (or NoteOn with zero velocity) was found for a key that was not playing. It was ignored.
+    UnmatchedNoteOff { key: u8 },
+
+    /// A note was still playing at the end of the track. It was dropped.
+    UnendedNote { key: u8 },
+
+    /// Tempo changes are only read from the first (master) track. This one was ignored.
+    NonMasterTempo { bpm: u16 },
+
+    /// The first (master) track cannot play notes. This one was ignored.
+    MasterTrackNote { key: u8 },
+
+    /// BGMs have at most 16 tracks, so this track and everything on it was ignored.
+    TrackIgnored,
+
+    /// This track plays more notes at once than the game can. Notes that did not fit were moved to the given linked
+    /// tracks.
+    PolyphonySplit { voices: u8, linked_tracks: Vec<usize> },
+
+    /// This track plays more notes at once than the game can, and there was no room to split it. Notes that did not
+    /// fit were dropped.
+    PolyphonyTrimmed { voices: u8, dropped_notes: usize },
+
+    /// The file uses timecode (SMPTE) timing, so it was converted at a single fixed tempo. This tempo change was
+    /// ignored.
+    TimecodeTempoChange { bpm: u16 },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "track {} at tick {}: {}", self.track, self.time, self.kind)
+    }
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WarningKind::UnmatchedNoteOff { key } => write!(f, "found NoteOff {} but saw no NoteOn", key),
+            WarningKind::UnendedNote { key } => write!(f, "note {} is never ended", key),
+            WarningKind::NonMasterTempo { bpm } => write!(f, "ignoring non-master tempo change to {} BPM", bpm),
+            WarningKind::MasterTrackNote { key } => write!(f, "master track cannot have notes, ignoring note {}", key),
+            WarningKind::TrackIgnored => write!(f, "too many tracks, ignoring this one"),
+            WarningKind::PolyphonySplit { voices, linked_tracks } => write!(
+                f,
+                "plays up to {} notes at once, split across linked tracks {:?}",
+                voices, linked_tracks
+            ),
+            WarningKind::PolyphonyTrimmed { voices, dropped_notes } => write!(
+                f,
+                "plays up to {} notes at once, dropped {} notes",
+                voices, dropped_notes
+            ),
+            WarningKind::TimecodeTempoChange { bpm } => {
+                write!(f, "ignoring tempo change to {} BPM in timecode-timed file", bpm)
+            }
+        }
+    }
+}
+
+/// Tempo that MIDI files play at until they say otherwise.
+const DEFAULT_BPM: u16 = 120;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Tempo to convert timecode (SMPTE) timed files at, in beats per minute. If `None`, the first tempo event of the
+    /// master track is used, or 120 BPM if it has none. Ignored for files with metrical timing.
+    pub timecode_bpm: Option<u16>,
+
+    /// What to do with tracks that play more than [MAX_TRACK_VOICES] notes at once.
+    pub polyphony: PolyphonyMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PolyphonyMode {
+    /// Move notes that don't fit onto unused tracks linked to the original. If there aren't enough unused tracks, the
+    /// rest are dropped.
+    #[default]
+    Split,
+
+    /// Drop notes that don't fit.
+    Trim,
+}
+
+/// Converts a Standard MIDI File to a [Bgm]. Anything that could not be converted faithfully is reported in the
+/// returned [Warning] list.
+pub fn to_bgm(raw: &[u8]) -> Result<(Bgm, Vec<Warning>), Box<dyn Error>> {
+    to_bgm_with_options(raw, &Options::default())
+}
+
+pub fn to_bgm_with_options(raw: &[u8], options: &Options) -> Result<(Bgm, Vec<Warning>), Box<dyn Error>> {
+    IdAllocator::new().scope(|| convert(raw, options))?
+}
+
+fn convert(raw: &[u8], options: &Options) -> Result<(Bgm, Vec<Warning>), Box<dyn Error>> {
+    // midly negates the frame rate of timecode timing as an i8, which overflows (and panics in debug builds) for -128.
+    // No frame rate is -128 anyway, so reject any header chunk with it.
+    if smf_chunks(rmid_data(raw).unwrap_or(raw)).any(|(id, data)| id == b"MThd" && data.get(4) == Some(&0x80)) {
+        return Err("invalid SMPTE frame rate".into());
+    }
     let smf = Smf::parse(raw)?;
     let mut bgm = Bgm::new();
 
-    // Timing information (ticks per beat, aka "division"). MIDI files can use what they want, but the game always(?)
-    // uses 48 ticks per beat - so we have to convert the MIDI timescale to the BGM timescale.
-    let ticks_per_beat = match smf.header.timing {
-        midly::Timing::Metrical(tpb) => tpb.as_int() as f32,
-        midly::Timing::Timecode(fps, subframe) => 1.0 / fps.as_f32() / subframe as f32, // Uncommon, untested
+    // Timing information. MIDI files can use what they want, but the game always(?) uses 48 ticks per beat - so we
+    // have to convert the MIDI timescale to the BGM timescale.
+    let (ticks_per_beat, timecode_bpm) = match smf.header.timing {
+        // Ticks per beat, aka "division"
+        midly::Timing::Metrical(tpb) => (tpb.as_int() as f32, None),
+
+        // Ticks per second. Event times are absolute, so tempo events have no effect on them; to get beats we have
+        // to pick a single tempo for the whole song.
+        midly::Timing::Timecode(fps, subframe) => {
+            let bpm = options
+                .timecode_bpm
+                .or_else(|| smf.tracks.first().and_then(|track| first_tempo(track)))
+                .unwrap_or(DEFAULT_BPM);
+            let ticks_per_second = fps.as_f32() * subframe as f32;
+            log::debug!("timecode: {} ticks/second at {} bpm", ticks_per_second, bpm);
+            (ticks_per_second * 60.0 / bpm as f32, Some(bpm))
+        }
     };
     log::debug!("original ticks/beat: {}", ticks_per_beat);
+    if ticks_per_beat <= 0.0 {
+        return Err("MIDI file has no ticks per beat".into());
+    }
     let time_divisor = ticks_per_beat / 48.0; // Divide all MIDI times by this value to convert to BGM timescale!
 
     bgm.name = "New Song".to_string();
@@ -57,123 +190,32 @@ pub fn to_bgm(raw: &[u8]) -> Result<Bgm, Box<dyn Error>> {
 
     log::debug!("song length: {} ticks (48 ticks/beat)", total_song_length);
 
-    let track_list = TrackList {
+    let mut warnings = Vec::new();
+
+    for track_number in 16..smf.tracks.len() {
+        warnings.push(Warning {
+            track: track_number,
+            time: 0,
+            kind: WarningKind::TrackIgnored,
+        });
+    }
+
+    let mut track_list = TrackList {
         pos: None,
-        tracks: [
-            midi_track_to_bgm_track(
-                smf.tracks.first(),
-                total_song_length,
-                0,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(1),
-                total_song_length,
-                1,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(2),
-                total_song_length,
-                2,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(3),
-                total_song_length,
-                3,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(4),
-                total_song_length,
-                4,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(5),
-                total_song_length,
-                5,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(6),
-                total_song_length,
-                6,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(7),
-                total_song_length,
-                7,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(8),
-                total_song_length,
-                8,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(9),
-                total_song_length,
-                9,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(10),
-                total_song_length,
-                10,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(11),
-                total_song_length,
-                11,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(12),
-                total_song_length,
-                12,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(13),
-                total_song_length,
-                13,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
+        tracks: std::array::from_fn(|track_number| {
             midi_track_to_bgm_track(
-                smf.tracks.get(14),
+                smf.tracks.get(track_number),
                 total_song_length,
-                14,
+                track_number,
                 time_divisor,
+                timecode_bpm,
                 &mut bgm.instruments,
-            ),
-            midi_track_to_bgm_track(
-                smf.tracks.get(15),
-                total_song_length,
-                15,
-                time_divisor,
-                &mut bgm.instruments,
-            ),
-        ],
+                &mut warnings,
+            )
+        }),
     };
+    limit_polyphony(&mut track_list.tracks, options.polyphony, time_divisor, &mut warnings);
+
     let track_list_id = bgm.add_track_list(track_list);
 
     let (_, variation) = bgm.add_variation().unwrap();
@@ -182,7 +224,7 @@ pub fn to_bgm(raw: &[u8]) -> Result<Bgm, Box<dyn Error>> {
         track_list: track_list_id,
     }];
 
-    Ok(bgm)
+    Ok((bgm, warnings))
 }
 
 fn midi_track_to_bgm_track(
@@ -190,7 +232,9 @@ fn midi_track_to_bgm_track(
     total_song_length: usize,
     track_number: usize,
     time_divisor: f32,
+    timecode_bpm: Option<u16>,
     instruments: &mut Vec<Instrument>,
+    warnings: &mut Vec<Warning>,
 ) -> Track {
     use midly::{MidiMessage, TrackEventKind};
 
@@ -210,6 +254,7 @@ fn midi_track_to_bgm_track(
                 polyphony: Polyphony::Automatic,
                 is_drum_track: false,
                 commands: CommandSeq::new(),
+                pos: None,
             };
 
             let voice_idx = instruments.len();
@@ -253,11 +298,6 @@ fn midi_track_to_bgm_track(
                                 let key = key.as_int();
 
                                 if let Some(start) = started_notes.remove(&key) {
-                                    if track_number == 0 {
-                                        log::warn!("master track has notes, ignoring");
-                                        break;
-                                    }
-
                                     let length = time - start.time;
 
                                     track.commands.insert_end(
@@ -269,7 +309,11 @@ fn midi_track_to_bgm_track(
                                         },
                                     );
                                 } else {
-                                    log::warn!("found NoteOff {} but saw no NoteOn", key);
+                                    warnings.push(Warning {
+                                        track: track_number,
+                                        time,
+                                        kind: WarningKind::UnmatchedNoteOff { key },
+                                    });
                                 }
                             }
                             MidiMessage::NoteOn { key, vel } => {
@@ -278,11 +322,6 @@ fn midi_track_to_bgm_track(
 
                                 if vel == 0 {
                                     if let Some(start) = started_notes.remove(&key) {
-                                        if track_number == 0 {
-                                            log::warn!("master track has notes, ignoring");
-                                            break;
-                                        }
-
                                         let length = time - start.time;
 
                                         track.commands.insert_end(
@@ -294,7 +333,11 @@ fn midi_track_to_bgm_track(
                                             },
                                         );
                                     } else {
-                                        log::warn!("found NoteOn(vel=0) {} but saw no NoteOn(vel>0)", key);
+                                        warnings.push(Warning {
+                                            track: track_number,
+                                            time,
+                                            kind: WarningKind::UnmatchedNoteOff { key },
+                                        });
                                     }
                                 } else {
                                     started_notes.insert(key, Note { time, vel });
@@ -449,16 +492,38 @@ fn midi_track_to_bgm_track(
                             }
                         }
                     }
+                    TrackEventKind::Midi {
+                        message: MidiMessage::NoteOn { key, vel },
+                        ..
+                    } if vel.as_int() > 0 => {
+                        // Matched arm above for all other tracks
+                        warnings.push(Warning {
+                            track: track_number,
+                            time,
+                            kind: WarningKind::MasterTrackNote { key: key.as_int() },
+                        });
+                    }
                     TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
-                        if track_number == 0 {
-                            let microseconds_per_beat = tempo.as_int() as f32;
-                            let beats_per_minute = (60_000_000.0 / microseconds_per_beat).round() as u16;
+                        let beats_per_minute = tempo_to_bpm(tempo);
+                        if track_number != 0 {
+                            warnings.push(Warning {
+                                track: track_number,
+                                time,
+                                kind: WarningKind::NonMasterTempo { bpm: beats_per_minute },
+                            });
+                        } else if let Some(timecode_bpm) = timecode_bpm {
+                            if beats_per_minute != timecode_bpm {
+                                warnings.push(Warning {
+                                    track: track_number,
+                                    time,
+                                    kind: WarningKind::TimecodeTempoChange { bpm: beats_per_minute },
+                                });
+                            }
+                        } else {
                             track
                                 .commands
                                 .insert_end(time_cvt, Command::MasterTempo(beats_per_minute));
                             log::debug!("bpm: {}", beats_per_minute);
-                        } else {
-                            log::warn!("ignoring non-master tempo change");
                         }
                     }
                     TrackEventKind::Meta(MetaMessage::InstrumentName(s)) => {
@@ -476,15 +541,19 @@ fn midi_track_to_bgm_track(
                 }
             }
 
-            if !started_notes.is_empty() {
-                log::warn!("{} unended notes", started_notes.len());
+            for (&key, start) in &started_notes {
+                warnings.push(Warning {
+                    track: track_number,
+                    time: start.time,
+                    kind: WarningKind::UnendedNote { key },
+                });
             }
 
             if track_number == 0 {
                 track.commands.insert_many_start(
                     0,
                     vec![
-                        Command::MasterTempo(120),
+                        Command::MasterTempo(timecode_bpm.unwrap_or(DEFAULT_BPM)),
                         Command::MasterVolume(100),
                         Command::MasterEffect { index: 0, value: 1 },
                     ],
@@ -528,6 +597,663 @@ fn midi_track_to_bgm_track(
     }
 }
 
+/// How deeply nested [Detours](Command::Detour) are followed when exporting. The game has no limit, but a sequence
+/// can detour into itself forever.
+const MAX_DETOUR_DEPTH: usize = 4;
+
+/// Converts a variation of a [Bgm] to a Standard MIDI File with 48 ticks per beat, one MIDI track per BGM track.
+/// Segments are played once each, in order; loops are not followed.
+pub fn from_bgm(bgm: &Bgm, variation: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    use midly::num::{u4, u7, u15, u24};
+    use midly::{Format, Header, MidiMessage, PitchBend, Timing, TrackEvent, TrackEventKind};
+
+    let Some(Some(variation)) = bgm.variations.get(variation) else {
+        return Err(format!("variation {} does not exist", variation).into());
+    };
+
+    // (time, sort order, event) for each track. Sort order makes NoteOffs come before NoteOns at the same time.
+    let mut tracks: [Vec<(usize, u8, TrackEventKind)>; 16] = Default::default();
+
+    let program = |patch: &PatchAddress| u7::new((patch.bank * 16 + patch.instrument).min(127));
+
+    let mut segment_start = 0;
+    for segment in &variation.segments {
+        let Segment::Subseg { track_list, .. } = segment else {
+            continue;
+        };
+        let Some(track_list) = bgm.track_lists.get(track_list) else {
+            continue;
+        };
+
+        for (track_no, track) in track_list.tracks.iter().enumerate() {
+            let channel = u4::new(if track.is_drum_track { 9 } else { track_no as u8 });
+            let events = &mut tracks[track_no];
+            let midi = |message| TrackEventKind::Midi { channel, message };
+
+            play_seq(&track.commands, |time, command| {
+                let time = segment_start + time;
+                match command {
+                    Command::Note {
+                        pitch,
+                        velocity,
+                        length,
+                    } => {
+                        let key = u7::new(pitch.saturating_sub(104).min(127));
+                        let vel = u7::new((*velocity).clamp(1, 127));
+                        events.push((time, 1, midi(MidiMessage::NoteOn { key, vel })));
+                        events.push((
+                            time + *length as usize,
+                            0,
+                            midi(MidiMessage::NoteOff { key, vel: u7::new(0) }),
+                        ));
+                    }
+                    Command::MasterTempo(bpm) if *bpm > 0 => {
+                        // Only the last tempo change at any given time has an effect
+                        events.retain(|(t, _, kind)| {
+                            *t != time || !matches!(kind, TrackEventKind::Meta(MetaMessage::Tempo(_)))
+                        });
+
+                        let microseconds_per_beat = 60_000_000 / *bpm as u32;
+                        events.push((
+                            time,
+                            1,
+                            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(microseconds_per_beat))),
+                        ));
+                    }
+                    Command::SubTrackVolume(volume) => {
+                        events.push((
+                            time,
+                            1,
+                            midi(MidiMessage::Controller {
+                                controller: u7::new(7),
+                                value: u7::new((*volume).min(127)),
+                            }),
+                        ));
+                    }
+                    Command::SubTrackPan(pan) => {
+                        events.push((
+                            time,
+                            1,
+                            midi(MidiMessage::Controller {
+                                controller: u7::new(10),
+                                value: u7::new((*pan).clamp(0, 127) as u8),
+                            }),
+                        ));
+                    }
+                    Command::SubTrackReverb(reverb) => {
+                        events.push((
+                            time,
+                            1,
+                            midi(MidiMessage::Controller {
+                                controller: u7::new(12),
+                                value: u7::new((*reverb).min(127)),
+                            }),
+                        ));
+                    }
+                    Command::SetTrackVoice { index } => {
+                        if let Some(instrument) = bgm.instruments.get(*index as usize) {
+                            events.push((
+                                time,
+                                1,
+                                midi(MidiMessage::ProgramChange {
+                                    program: program(&instrument.patch),
+                                }),
+                            ));
+                        }
+                    }
+                    Command::TrackOverridePatch(patch) => {
+                        events.push((
+                            time,
+                            1,
+                            midi(MidiMessage::ProgramChange {
+                                program: program(patch),
+                            }),
+                        ));
+                    }
+                    Command::SegTrackTune { bend } => {
+                        // Inverse of the import, assuming the default pitch bend range of 2 semitones
+                        let bend = PitchBend::from_f32((*bend as f32 / 200.0).clamp(-1.0, 1.0));
+                        events.push((time, 1, midi(MidiMessage::PitchBend { bend })));
+                    }
+                    Command::Marker { label } => {
+                        events.push((time, 1, TrackEventKind::Meta(MetaMessage::Marker(label.as_bytes()))));
+                    }
+                    _ => {}
+                }
+            });
+        }
+
+        segment_start += track_list.len_time();
+    }
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(48))));
+    for (track_no, mut events) in tracks.into_iter().enumerate() {
+        events.sort_by_key(|&(time, order, _)| (time, order));
+
+        let mut track = Vec::with_capacity(events.len() + 2);
+        let name = bgm
+            .track_lists
+            .values()
+            .map(|track_list| &track_list.tracks[track_no].name)
+            .find(|name| !name.is_empty());
+        if let Some(name) = name {
+            track.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+            });
+        }
+
+        let mut last_time = 0;
+        for (time, _, kind) in events {
+            track.push(TrackEvent {
+                delta: ((time - last_time) as u32).into(),
+                kind,
+            });
+            last_time = time;
+        }
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+
+        smf.tracks.push(track);
+    }
+
+    let mut raw = Vec::new();
+    smf.write_std(&mut raw)?;
+    Ok(raw)
+}
+
+/// Calls `visit` with the time and command of every command the game would execute when playing `seq`, following
+/// [Detours](Command::Detour) and stopping at the first [Command::End].
+fn play_seq<'a>(seq: &'a CommandSeq, mut visit: impl FnMut(usize, &'a Command)) {
+    let commands: Vec<&Command> = seq.iter().map(|event| &event.command).collect();
+    let markers: HashMap<&str, usize> = commands
+        .iter()
+        .enumerate()
+        .filter_map(|(index, command)| match command {
+            Command::Marker { label } => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect();
+
+    fn play_range<'a>(
+        commands: &[&'a Command],
+        markers: &HashMap<&str, usize>,
+        range: Range<usize>,
+        time: &mut usize,
+        depth: usize,
+        visit: &mut impl FnMut(usize, &'a Command),
+    ) {
+        for command in &commands[range] {
+            match command {
+                Command::Delay(delta) => *time += delta,
+                Command::End => return,
+                Command::Detour { start_label, end_label } if depth < MAX_DETOUR_DEPTH => {
+                    if let (Some(&start), Some(&end)) =
+                        (markers.get(start_label.as_str()), markers.get(end_label.as_str()))
+                        && start <= end
+                    {
+                        play_range(commands, markers, start..end, time, depth + 1, visit);
+                    }
+                }
+                _ => visit(*time, command),
+            }
+        }
+    }
+
+    let mut time = 0;
+    play_range(&commands, &markers, 0..commands.len(), &mut time, 0, &mut visit);
+}
+
+/// Makes sure no track plays more notes at once than the game supports, reporting what was done about it. Tracks the
+/// file set a number of voices for (such as mono mode, CC126) are kept to that many, and are trimmed rather than split.
+fn limit_polyphony(tracks: &mut [Track; 16], mode: PolyphonyMode, time_divisor: f32, warnings: &mut Vec<Warning>) {
+    for track_number in 1..tracks.len() {
+        let (max_voices, can_split) = match tracks[track_number].polyphony {
+            Polyphony::Link { .. } => continue,
+            Polyphony::Manual { voices } if voices > 0 => (voices.min(MAX_TRACK_VOICES), false),
+            _ => (MAX_TRACK_VOICES, true),
+        };
+        let voices = tracks[track_number].commands.max_polyphony();
+        if voices <= max_voices {
+            continue;
+        }
+
+        let free_tracks: Vec<usize> = match mode {
+            PolyphonyMode::Split if can_split => (1..tracks.len())
+                .filter(|&i| tracks[i].commands.is_empty() && tracks[i].is_disabled)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let split = tracks[track_number]
+            .commands
+            .split_polyphony(max_voices, free_tracks.len());
+        let time = (split.first_overflow.unwrap_or_default() as f32 * time_divisor).round() as usize;
+
+        let mut linked_tracks = Vec::new();
+        for (layer, free_track) in split.layers.into_iter().zip(free_tracks) {
+            tracks[free_track] = Track {
+                name: tracks[track_number].name.clone(),
+                is_disabled: false,
+                polyphony: Polyphony::Link {
+                    parent: track_number as u8,
+                },
+                is_drum_track: tracks[track_number].is_drum_track,
+                commands: layer,
+                pos: None,
+            };
+            linked_tracks.push(free_track);
+        }
+
+        if !linked_tracks.is_empty() {
+            warnings.push(Warning {
+                track: track_number,
+                time,
+                kind: WarningKind::PolyphonySplit { voices, linked_tracks },
+            });
+        }
+        if split.dropped > 0 {
+            warnings.push(Warning {
+                track: track_number,
+                time,
+                kind: WarningKind::PolyphonyTrimmed {
+                    voices,
+                    dropped_notes: split.dropped,
+                },
+            });
+        }
+    }
+}
+
+fn tempo_to_bpm(tempo: midly::num::u24) -> u16 {
+    let microseconds_per_beat = tempo.as_int() as f32;
+    (60_000_000.0 / microseconds_per_beat).round() as u16
+}
+
+/// Finds the tempo of the first tempo event in the track, if any.
+fn first_tempo(events: &[midly::TrackEvent]) -> Option<u16> {
+    events.iter().find_map(|event| match event.kind {
+        midly::TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => Some(tempo_to_bpm(tempo)),
+        _ => None,
+    })
+}
+
+/// The Standard MIDI File inside an RMID (RIFF) file, found the way midly finds it.
+fn rmid_data(raw: &[u8]) -> Option<&[u8]> {
+    fn chunks(mut raw: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+        std::iter::from_fn(move || {
+            let (id, rest) = raw.split_at_checked(4)?;
+            let (len, rest) = rest.split_at_checked(4)?;
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let (data, rest) = rest.split_at_checked(len).unwrap_or((rest, &[]));
+            raw = if len % 2 == 1 {
+                rest.get(1..).unwrap_or_default()
+            } else {
+                rest
+            };
+            Some((id, data))
+        })
+    }
+
+    let (id, riff) = chunks(raw).next()?;
+    let (form_type, riff) = riff.split_at_checked(4)?;
+    if id != b"RIFF" || form_type != b"RMID" {
+        return None;
+    }
+    chunks(riff).find(|(id, _)| *id == b"data").map(|(_, data)| data)
+}
+
+/// The id and contents of each chunk of a Standard MIDI File, up to the first that midly can't read either of.
+fn smf_chunks(mut raw: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, rest) = raw.split_at_checked(4)?;
+        let (len, rest) = rest.split_at_checked(4)?;
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        // Like midly, a chunk that runs past the end of the file is the rest of the file
+        let (data, rest) = rest.split_at_checked(len).unwrap_or((rest, &[]));
+        raw = rest;
+        Some((id, data))
+    })
+}
+
 fn convert_time(t: usize, time_divisor: f32) -> usize {
     (t as f32 / time_divisor).round() as usize
 }
+
+#[cfg(test)]
+mod test {
+    use midly::num::{u4, u7, u15, u24, u28};
+    use midly::{Format, Fps, Header, MidiMessage, Timing, TrackEvent, TrackEventKind};
+
+    use super::*;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        }
+    }
+
+    fn note(delta: u32, key: u8, vel: u8) -> TrackEvent<'static> {
+        event(
+            delta,
+            TrackEventKind::Midi {
+                channel: u4::new(0),
+                message: MidiMessage::NoteOn {
+                    key: u7::new(key),
+                    vel: u7::new(vel),
+                },
+            },
+        )
+    }
+
+    fn tempo(delta: u32, bpm: u32) -> TrackEvent<'static> {
+        event(
+            delta,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(60_000_000 / bpm))),
+        )
+    }
+
+    fn encode(tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<u8> {
+        encode_with_timing(Timing::Metrical(u15::new(48)), tracks)
+    }
+
+    fn encode_with_timing(timing: Timing, tracks: Vec<Vec<TrackEvent<'static>>>) -> Vec<u8> {
+        let smf = Smf {
+            header: Header::new(Format::Parallel, timing),
+            tracks,
+        };
+        let mut raw = Vec::new();
+        smf.write_std(&mut raw).unwrap();
+        raw
+    }
+
+    /// Returns the (time, length) of every note in the given track.
+    fn notes(bgm: &Bgm, track: usize) -> Vec<(usize, u16)> {
+        let (_, track_list) = bgm.track_lists.iter().next().unwrap();
+        track_list.tracks[track]
+            .commands
+            .iter_time()
+            .filter_map(|(time, event)| match event.command {
+                Command::Note { length, .. } => Some((time, length)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn master_tempos(bgm: &Bgm) -> Vec<u16> {
+        let (_, track_list) = bgm.track_lists.iter().next().unwrap();
+        track_list.tracks[0]
+            .commands
+            .iter()
+            .filter_map(|event| match event.command {
+                Command::MasterTempo(bpm) => Some(bpm),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_import_has_no_warnings() {
+        let raw = encode(vec![vec![tempo(0, 120)], vec![note(0, 60, 100), note(48, 60, 0)]]);
+
+        let (_, warnings) = to_bgm(&raw).unwrap();
+        assert_eq!(warnings, vec![]);
+    }
+
+    #[test]
+    fn warnings_have_locations() {
+        let raw = encode(vec![
+            vec![note(10, 60, 100)],
+            vec![note(0, 60, 0), note(24, 62, 100), tempo(12, 120)],
+        ]);
+
+        let (_, warnings) = to_bgm(&raw).unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    track: 0,
+                    time: 10,
+                    kind: WarningKind::MasterTrackNote { key: 60 },
+                },
+                Warning {
+                    track: 1,
+                    time: 0,
+                    kind: WarningKind::UnmatchedNoteOff { key: 60 },
+                },
+                Warning {
+                    track: 1,
+                    time: 36,
+                    kind: WarningKind::NonMasterTempo { bpm: 120 },
+                },
+                Warning {
+                    track: 1,
+                    time: 24,
+                    kind: WarningKind::UnendedNote { key: 62 },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_tracks_are_reported() {
+        let raw = encode((0..18).map(|_| vec![]).collect());
+
+        let (_, warnings) = to_bgm(&raw).unwrap();
+        let ignored: Vec<usize> = warnings
+            .iter()
+            .filter(|w| w.kind == WarningKind::TrackIgnored)
+            .map(|w| w.track)
+            .collect();
+        assert_eq!(ignored, vec![16, 17]);
+    }
+
+    #[test]
+    fn timecode_uses_detected_tempo() {
+        // 25 fps * 40 subframes = 1000 ticks per second
+        let raw = encode_with_timing(
+            Timing::Timecode(Fps::Fps25, 40),
+            vec![vec![tempo(0, 60)], vec![note(1000, 60, 100), note(500, 60, 0)]],
+        );
+
+        // At 60 BPM, one second is one beat (48 ticks)
+        let (bgm, warnings) = to_bgm(&raw).unwrap();
+        assert_eq!(warnings, vec![]);
+        assert_eq!(notes(&bgm, 1), vec![(48, 24)]);
+        assert_eq!(master_tempos(&bgm), vec![60]);
+    }
+
+    #[test]
+    fn timecode_uses_chosen_tempo() {
+        let raw = encode_with_timing(
+            Timing::Timecode(Fps::Fps25, 40),
+            vec![vec![tempo(0, 60)], vec![note(1000, 60, 100), note(500, 60, 0)]],
+        );
+
+        let options = Options {
+            timecode_bpm: Some(120),
+            ..Default::default()
+        };
+        let (bgm, warnings) = to_bgm_with_options(&raw, &options).unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                track: 0,
+                time: 0,
+                kind: WarningKind::TimecodeTempoChange { bpm: 60 },
+            }]
+        );
+        assert_eq!(notes(&bgm, 1), vec![(96, 48)]);
+        assert_eq!(master_tempos(&bgm), vec![120]);
+    }
+
+    #[test]
+    fn timecode_defaults_to_120_bpm() {
+        // 30 fps * 80 subframes = 2400 ticks per second
+        let raw = encode_with_timing(
+            Timing::Timecode(Fps::Fps30, 80),
+            vec![vec![], vec![note(2400, 60, 100), note(1200, 60, 0)]],
+        );
+
+        let (bgm, _) = to_bgm(&raw).unwrap();
+        assert_eq!(notes(&bgm, 1), vec![(96, 48)]);
+        assert_eq!(master_tempos(&bgm), vec![120]);
+    }
+
+    /// Six notes at once.
+    fn chord_track() -> Vec<TrackEvent<'static>> {
+        let mut track: Vec<_> = (60..66).map(|key| note(0, key, 100)).collect();
+        track.extend((60..66).map(|key| note(if key == 60 { 48 } else { 0 }, key, 0)));
+        track
+    }
+
+    /// Six notes at once on the first track.
+    fn chord() -> Vec<u8> {
+        encode(vec![vec![], chord_track()])
+    }
+
+    #[test]
+    fn unusable_timing_is_an_error() {
+        let tracks = vec![vec![note(0, 60, 100)]];
+        assert!(to_bgm(&encode_with_timing(Timing::Metrical(u15::new(0)), tracks.clone())).is_err());
+
+        // A frame rate of -128, which midly can't negate
+        let mut raw = encode_with_timing(Timing::Timecode(Fps::Fps24, 4), tracks);
+        raw[12] = 0x80;
+        assert!(to_bgm(&raw).is_err());
+    }
+
+    #[test]
+    fn header_id_in_text_is_not_a_header() {
+        let text = b"MThd\0\0\0\x06\0\x01\0\x01\x80\x04";
+        let raw = encode(vec![
+            vec![event(0, TrackEventKind::Meta(MetaMessage::Text(text)))],
+            vec![note(0, 60, 100), note(48, 60, 0)],
+        ]);
+
+        let (bgm, warnings) = to_bgm(&raw).unwrap();
+        assert_eq!(warnings, vec![]);
+        assert_eq!(notes(&bgm, 1), vec![(0, 48)]);
+    }
+
+    #[test]
+    fn unusable_timing_in_rmid_is_an_error() {
+        let mut smf = encode_with_timing(Timing::Timecode(Fps::Fps24, 4), vec![vec![note(0, 60, 100)]]);
+        smf[12] = 0x80;
+
+        let mut riff = b"RMID".to_vec();
+        riff.extend(b"data");
+        riff.extend((smf.len() as u32).to_le_bytes());
+        riff.extend(&smf);
+        let mut raw = b"RIFF".to_vec();
+        raw.extend((riff.len() as u32).to_le_bytes());
+        raw.extend(riff);
+        assert!(to_bgm(&raw).is_err());
+    }
+
+    #[test]
+    fn polyphony_split() {
+        let (bgm, warnings) = to_bgm(&chord()).unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                track: 1,
+                time: 0,
+                kind: WarningKind::PolyphonySplit {
+                    voices: 6,
+                    linked_tracks: vec![2],
+                },
+            }]
+        );
+
+        let (_, track_list) = bgm.track_lists.iter().next().unwrap();
+        assert_eq!(track_list.tracks[1].commands.max_polyphony(), MAX_TRACK_VOICES);
+        assert_eq!(track_list.tracks[2].commands.max_polyphony(), 2);
+        assert_eq!(track_list.tracks[2].polyphony, Polyphony::Link { parent: 1 });
+        assert!(!track_list.tracks[2].is_disabled);
+        assert_eq!(notes(&bgm, 2), vec![(0, 48), (0, 48)]);
+    }
+
+    #[test]
+    fn polyphony_trim() {
+        let options = Options {
+            polyphony: PolyphonyMode::Trim,
+            ..Default::default()
+        };
+        let (bgm, warnings) = to_bgm_with_options(&chord(), &options).unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                track: 1,
+                time: 0,
+                kind: WarningKind::PolyphonyTrimmed {
+                    voices: 6,
+                    dropped_notes: 2,
+                },
+            }]
+        );
+
+        let (_, track_list) = bgm.track_lists.iter().next().unwrap();
+        assert_eq!(track_list.tracks[1].commands.max_polyphony(), MAX_TRACK_VOICES);
+        assert!(track_list.tracks[2].commands.is_empty());
+    }
+
+    #[test]
+    fn mono_tracks_are_trimmed() {
+        let mono = event(
+            0,
+            TrackEventKind::Midi {
+                channel: u4::new(0),
+                message: MidiMessage::Controller {
+                    controller: u7::new(126),
+                    value: u7::new(1),
+                },
+            },
+        );
+        let mut track = chord_track();
+        track.insert(0, mono);
+
+        let (bgm, warnings) = to_bgm(&encode(vec![vec![], track])).unwrap();
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                track: 1,
+                time: 0,
+                kind: WarningKind::PolyphonyTrimmed {
+                    voices: 6,
+                    dropped_notes: 5,
+                },
+            }]
+        );
+
+        let (_, track_list) = bgm.track_lists.iter().next().unwrap();
+        assert_eq!(track_list.tracks[1].polyphony, Polyphony::Manual { voices: 1 });
+        assert_eq!(track_list.tracks[1].commands.max_polyphony(), 1);
+        assert!(track_list.tracks[2].commands.is_empty());
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let raw = encode(vec![
+            vec![tempo(0, 140)],
+            vec![
+                note(0, 60, 100),
+                note(0, 64, 100),
+                note(48, 60, 0),
+                note(24, 64, 0),
+                note(0, 67, 90),
+                note(96, 67, 0),
+            ],
+        ]);
+        let (bgm, _) = to_bgm(&raw).unwrap();
+
+        let exported = from_bgm(&bgm, 0).unwrap();
+        let (reimported, warnings) = to_bgm(&exported).unwrap();
+        assert_eq!(warnings, vec![]);
+        assert_eq!(notes(&reimported, 1), notes(&bgm, 1));
+        assert_eq!(master_tempos(&reimported), master_tempos(&bgm));
+    }
+}
@@ -0,0 +1,622 @@
+use std::fmt;
+
+use super::*;
+use crate::id::{IdAllocator, OutOfIds, gen_id};
+
+/// Ticks per quarter note.
+const TICKS_PER_BEAT: usize = 48;
+const TICKS_PER_WHOLE_NOTE: usize = TICKS_PER_BEAT * 4;
+
+/// MIDI key of `o0 c`. `o4 c` is middle C.
+const KEY_C0: i32 = 12;
+
+/// Converts MIDI keys to [Command::Note] pitches.
+const KEY_TO_PITCH: i32 = 104;
+
+const MIN_PITCH: i32 = 0x80;
+const MAX_PITCH: i32 = 0xD3;
+
+/// Most times a loop can play its music.
+const MAX_LOOP_COUNT: usize = 255;
+
+/// Most commands a track can compile to once its loops are unrolled, which is already far more than fits in a song.
+const MAX_TRACK_COMMANDS: usize = 0x10000;
+
+const DEFAULT_BPM: u16 = 120;
+const DEFAULT_VELOCITY: u8 = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    /// Line the error was found on, starting from 1.
+    pub line: usize,
+    /// Character within the line, starting from 1.
+    pub column: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnexpectedChar(char),
+    /// The command needs a number after it.
+    MissingNumber(char),
+    /// Tracks are numbered 1 to 15; track 0 is used for tempo changes.
+    InvalidTrack(usize),
+    /// Music was found before the first `<track>:` prefix.
+    MissingTrack,
+    /// Note lengths must divide a whole note into whole ticks.
+    InvalidLength(usize),
+    PitchOutOfRange,
+    ValueOutOfRange {
+        command: char,
+        value: usize,
+    },
+    UnclosedLoop,
+    UnopenedLoop,
+    /// A tie (`^`) must follow a note or rest.
+    UnattachedTie,
+    /// The track would compile to more than [MAX_TRACK_COMMANDS] commands once its loops are unrolled.
+    TooLong,
+    OutOfIds,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} column {}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedChar(c) => write!(f, "Unexpected {:?}", c),
+            ErrorKind::MissingNumber(c) => write!(f, "Expected a number after {:?}", c),
+            ErrorKind::InvalidTrack(track) => write!(f, "Track {} does not exist (use 1 to 15)", track),
+            ErrorKind::MissingTrack => write!(f, "Expected a track number, like '1:'"),
+            ErrorKind::InvalidLength(length) => write!(f, "Invalid note length {}", length),
+            ErrorKind::PitchOutOfRange => write!(f, "Note is too high or low to play"),
+            ErrorKind::ValueOutOfRange { command, value } => write!(f, "{} is out of range for {:?}", value, command),
+            ErrorKind::UnclosedLoop => write!(f, "Loop is missing a closing ']'"),
+            ErrorKind::UnopenedLoop => write!(f, "Found ']' without a matching '['"),
+            ErrorKind::UnattachedTie => write!(f, "'^' must follow a note or rest"),
+            ErrorKind::TooLong => write!(f, "Track is too long once its loops are played out"),
+            ErrorKind::OutOfIds => write!(f, "{}", OutOfIds),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A character of source, with where it came from.
+#[derive(Debug, Clone, Copy)]
+struct Char {
+    c: char,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug)]
+enum Element {
+    Note { pitch: u8, length: usize },
+    Rest { length: usize },
+    Tempo(u16),
+    Volume(u8),
+    Voice(u8),
+    Loop { body: Vec<Element>, count: usize },
+}
+
+impl Element {
+    /// How many commands [expand] turns this into.
+    fn len_commands(&self) -> usize {
+        match self {
+            Element::Note { .. } => 2,
+            Element::Tempo(_) => 0,
+            Element::Rest { .. } | Element::Volume(_) | Element::Voice(_) => 1,
+            Element::Loop { body, count } => body
+                .iter()
+                .map(Element::len_commands)
+                .fold(0, usize::saturating_add)
+                .saturating_mul(*count),
+        }
+    }
+}
+
+/// Compiles Music Macro Language source into a track list. Each line starts with the number of the track (1 to 15)
+/// it plays on, and lines for the same track are joined together:
+///
+/// ```text
+/// ; Comments start with ';'
+/// 1: t120 l8 o4 @0 v100 c4 d e f+ g4. r [c e g]2 > c1
+/// 2: o3 c1^1
+/// ```
+///
+/// | Syntax      | Meaning                                                                                  |
+/// |-------------|------------------------------------------------------------------------------------------|
+/// | `c`..`b`    | Note, optionally followed by `+`/`#` (sharp) or `-` (flat), a length, and dots (`c+8.`) |
+/// | `r`         | Rest, optionally followed by a length and dots                                           |
+/// | `^`         | Tie: lengthens the previous note or rest, e.g. `c4^16`                                   |
+/// | `l`n        | Sets the length of notes and rests that don't give one. Lengths are fractions of a whole note; `4` is a beat |
+/// | `o`n        | Sets the octave. `o4 c` is middle C                                                      |
+/// | `<` / `>`   | Octave down / up                                                                         |
+/// | `t`n        | Sets the tempo in beats per minute (for every track)                                     |
+/// | `v`n        | Sets the track volume, 0 to 127                                                          |
+/// | `@`n        | Sets the track voice, i.e. which of the [Bgm::instruments] to play                       |
+/// | `[`...`]`n  | Plays the enclosed music n times (default 2, at most 255)                                |
+/// | `\|`        | Ignored, for marking bars                                                                |
+///
+/// Tempo changes are placed on track 0, the master track.
+pub fn compile(source: &str) -> Result<TrackList, Error> {
+    // Split the source by track
+    let mut track_sources: [Vec<Char>; 16] = Default::default();
+    for (line_index, line) in source.lines().enumerate() {
+        let mut chars = line
+            .chars()
+            .enumerate()
+            .map(|(column_index, c)| Char {
+                c,
+                line: line_index + 1,
+                column: column_index + 1,
+            })
+            .take_while(|c| c.c != ';')
+            .skip_while(|c| c.c.is_whitespace())
+            .peekable();
+        let Some(&start) = chars.peek() else {
+            continue;
+        };
+
+        let digits: String = std::iter::from_fn(|| chars.next_if(|c| c.c.is_ascii_digit()).map(|c| c.c)).collect();
+        if digits.is_empty() || chars.next_if(|c| c.c == ':').is_none() {
+            return Err(error(start, ErrorKind::MissingTrack));
+        }
+        let track: usize = digits
+            .parse()
+            .map_err(|_| error(start, ErrorKind::InvalidTrack(usize::MAX)))?;
+        if !(1..16).contains(&track) {
+            return Err(error(start, ErrorKind::InvalidTrack(track)));
+        }
+
+        let track_source = &mut track_sources[track];
+        // Keep notes on different lines apart
+        if let Some(&last) = track_source.last() {
+            track_source.push(Char { c: ' ', ..last });
+        }
+        track_source.extend(chars);
+    }
+
+    let mut track_list = TrackList {
+        pos: None,
+        tracks: Default::default(),
+    };
+    let mut tempos = Vec::new();
+    let mut song_length = 0;
+
+    for (track_number, source) in track_sources.iter().enumerate() {
+        if source.is_empty() {
+            continue;
+        }
+
+        let mut parser = Parser {
+            chars: source.iter().copied().peekable(),
+            octave: 4,
+            default_length: TICKS_PER_BEAT,
+        };
+        let elements = parser.parse_sequence(None)?;
+
+        let track = &mut track_list.tracks[track_number];
+        track.is_disabled = false;
+
+        let mut time = 0;
+        expand(&elements, &mut track.commands, &mut tempos, &mut time);
+        song_length = song_length.max(time);
+    }
+
+    // Master track
+    tempos.sort_by_key(|&(time, _)| time);
+    let master = &mut track_list.tracks[0];
+    master.is_disabled = false;
+    master.commands.push(Command::MasterTempo(DEFAULT_BPM));
+    master.commands.push(Command::MasterVolume(100));
+    master.commands.push(Command::MasterEffect { index: 0, value: 1 });
+    let mut time = 0;
+    for (tempo_time, bpm) in tempos {
+        if tempo_time > time {
+            master.commands.push(Command::Delay(tempo_time - time));
+            time = tempo_time;
+        }
+        master.commands.push(Command::MasterTempo(bpm));
+    }
+    if song_length > time {
+        master.commands.push(Command::Delay(song_length - time));
+    }
+
+    for track in track_list.tracks.iter_mut().filter(|track| !track.is_disabled) {
+        track.commands.push(Command::End);
+    }
+
+    Ok(track_list)
+}
+
+/// Compiles Music Macro Language source into a song that plays it. See [compile] for the syntax.
+///
+/// Each voice used (`@`n) is given a default instrument, ready to be changed to something that sounds nice.
+pub fn to_bgm(source: &str) -> Result<Bgm, Error> {
+    IdAllocator::new()
+        .scope(|| compile_song(source))
+        .unwrap_or_else(|OutOfIds| {
+            Err(Error {
+                line: source.lines().count(),
+                column: 1,
+                kind: ErrorKind::OutOfIds,
+            })
+        })
+}
+
+fn compile_song(source: &str) -> Result<Bgm, Error> {
+    let mut track_list = compile(source)?;
+
+    let mut num_instruments = 1;
+    for track in track_list.tracks.iter_mut().skip(1).filter(|track| !track.is_disabled) {
+        let mut has_voice = false;
+        for event in track.commands.iter() {
+            if let Command::SetTrackVoice { index } = event.command {
+                num_instruments = num_instruments.max(index as usize + 1);
+                has_voice = true;
+            }
+        }
+        if !has_voice {
+            track
+                .commands
+                .insert_many_start(0, vec![Command::SetTrackVoice { index: 0 }]);
+        }
+    }
+
+    let mut bgm = Bgm::new();
+    bgm.instruments = (0..num_instruments)
+        .map(|_| Instrument {
+            patch: PatchAddress {
+                bank_set: BankSetIndex::Music,
+                bank: 0,
+                instrument: 0,
+                envelope: 0,
+            },
+            pan: 64,
+            volume: 100,
+            ..Default::default()
+        })
+        .collect();
+
+    let track_list = bgm.add_track_list(track_list);
+    bgm.variations[0] = Some(Variation {
+        segments: vec![Segment::Subseg {
+            id: Some(gen_id()),
+            track_list,
+        }],
+    });
+    Ok(bgm)
+}
+
+fn error(at: Char, kind: ErrorKind) -> Error {
+    Error {
+        line: at.line,
+        column: at.column,
+        kind,
+    }
+}
+
+/// Appends the commands for `elements` to `commands`, unrolling loops. Tempo changes are collected into `tempos`.
+fn expand(elements: &[Element], commands: &mut CommandSeq, tempos: &mut Vec<(usize, u16)>, time: &mut usize) {
+    for element in elements {
+        match element {
+            Element::Note { pitch, length } => {
+                commands.push(Command::Note {
+                    pitch: *pitch,
+                    velocity: DEFAULT_VELOCITY,
+                    length: (*length).min(u16::MAX as usize) as u16,
+                });
+                commands.push(Command::Delay(*length));
+                *time += length;
+            }
+            Element::Rest { length } => {
+                commands.push(Command::Delay(*length));
+                *time += length;
+            }
+            Element::Tempo(bpm) => tempos.push((*time, *bpm)),
+            Element::Volume(volume) => commands.push(Command::SubTrackVolume(*volume)),
+            Element::Voice(index) => commands.push(Command::SetTrackVoice { index: *index }),
+            Element::Loop { body, count } => {
+                for _ in 0..*count {
+                    expand(body, commands, tempos, time);
+                }
+            }
+        }
+    }
+}
+
+struct Parser<I: Iterator<Item = Char>> {
+    chars: std::iter::Peekable<I>,
+    octave: i32,
+    /// In ticks.
+    default_length: usize,
+}
+
+impl<I: Iterator<Item = Char>> Parser<I> {
+    /// Parses until the end of input, or until the `]` closing the loop opened at `loop_start`.
+    fn parse_sequence(&mut self, loop_start: Option<Char>) -> Result<Vec<Element>, Error> {
+        let mut elements = Vec::new();
+        let mut len_commands: usize = 0;
+
+        while let Some(at) = self.chars.next() {
+            let c = at.c.to_ascii_lowercase();
+            let element = match c {
+                _ if c.is_whitespace() || c == '|' => continue,
+                'c' | 'd' | 'e' | 'f' | 'g' | 'a' | 'b' => {
+                    let semitone = match c {
+                        'c' => 0,
+                        'd' => 2,
+                        'e' => 4,
+                        'f' => 5,
+                        'g' => 7,
+                        'a' => 9,
+                        _ => 11,
+                    };
+                    let accidental = match self.chars.next_if(|c| matches!(c.c, '+' | '#' | '-')) {
+                        Some(Char { c: '-', .. }) => -1,
+                        Some(_) => 1,
+                        None => 0,
+                    };
+                    let pitch = KEY_C0 + self.octave * 12 + semitone + accidental + KEY_TO_PITCH;
+                    if !(MIN_PITCH..=MAX_PITCH).contains(&pitch) {
+                        return Err(error(at, ErrorKind::PitchOutOfRange));
+                    }
+                    Element::Note {
+                        pitch: pitch as u8,
+                        length: self.parse_length(at)?,
+                    }
+                }
+                'r' => Element::Rest {
+                    length: self.parse_length(at)?,
+                },
+                '^' => {
+                    let extra = self.parse_length(at)?;
+                    match elements.last_mut() {
+                        Some(Element::Note { length, .. } | Element::Rest { length }) => *length += extra,
+                        _ => return Err(error(at, ErrorKind::UnattachedTie)),
+                    }
+                    continue;
+                }
+                'l' => {
+                    let number = self.parse_number(at)?;
+                    self.default_length = self.parse_dots(note_length(at, number)?);
+                    continue;
+                }
+                'o' => {
+                    self.octave = self.parse_number_in(at, 0..=8)? as i32;
+                    continue;
+                }
+                '<' => {
+                    self.octave -= 1;
+                    continue;
+                }
+                '>' => {
+                    self.octave += 1;
+                    continue;
+                }
+                't' => Element::Tempo(self.parse_number_in(at, 1..=u16::MAX as usize)? as u16),
+                'v' => Element::Volume(self.parse_number_in(at, 0..=127)? as u8),
+                '@' => Element::Voice(self.parse_number_in(at, 0..=u8::MAX as usize)? as u8),
+                '[' => {
+                    let body = self.parse_sequence(Some(at))?;
+                    let count = match self.chars.peek() {
+                        Some(c) if c.c.is_ascii_digit() => self.parse_number_in(at, 0..=MAX_LOOP_COUNT)?,
+                        _ => 2,
+                    };
+                    Element::Loop { body, count }
+                }
+                ']' => {
+                    return match loop_start {
+                        Some(_) => Ok(elements),
+                        None => Err(error(at, ErrorKind::UnopenedLoop)),
+                    };
+                }
+                _ => return Err(error(at, ErrorKind::UnexpectedChar(at.c))),
+            };
+            len_commands = len_commands.saturating_add(element.len_commands());
+            if len_commands > MAX_TRACK_COMMANDS {
+                return Err(error(at, ErrorKind::TooLong));
+            }
+            elements.push(element);
+        }
+
+        match loop_start {
+            Some(at) => Err(error(at, ErrorKind::UnclosedLoop)),
+            None => Ok(elements),
+        }
+    }
+
+    fn parse_number(&mut self, command: Char) -> Result<usize, Error> {
+        let digits: String = std::iter::from_fn(|| self.chars.next_if(|c| c.c.is_ascii_digit()).map(|c| c.c)).collect();
+        if digits.is_empty() {
+            return Err(error(command, ErrorKind::MissingNumber(command.c)));
+        }
+        digits.parse().map_err(|_| {
+            error(
+                command,
+                ErrorKind::ValueOutOfRange {
+                    command: command.c,
+                    value: usize::MAX,
+                },
+            )
+        })
+    }
+
+    fn parse_number_in(&mut self, command: Char, range: std::ops::RangeInclusive<usize>) -> Result<usize, Error> {
+        let value = self.parse_number(command)?;
+        if range.contains(&value) {
+            Ok(value)
+        } else {
+            Err(error(
+                command,
+                ErrorKind::ValueOutOfRange {
+                    command: command.c,
+                    value,
+                },
+            ))
+        }
+    }
+
+    /// Parses an optional length and dots, returning the length in ticks.
+    fn parse_length(&mut self, command: Char) -> Result<usize, Error> {
+        let length = match self.chars.peek() {
+            Some(c) if c.c.is_ascii_digit() => {
+                let number = self.parse_number(command)?;
+                note_length(command, number)?
+            }
+            _ => self.default_length,
+        };
+        Ok(self.parse_dots(length))
+    }
+
+    /// Each dot lengthens by half as much as the last.
+    fn parse_dots(&mut self, length: usize) -> usize {
+        let mut total = length;
+        let mut extra = length;
+        while self.chars.next_if(|c| c.c == '.').is_some() {
+            extra /= 2;
+            total += extra;
+        }
+        total
+    }
+}
+
+/// Converts a note length (e.g. `4` for a quarter note) to ticks.
+fn note_length(at: Char, number: usize) -> Result<usize, Error> {
+    if number == 0 || !TICKS_PER_WHOLE_NOTE.is_multiple_of(number) {
+        return Err(error(at, ErrorKind::InvalidLength(number)));
+    }
+    Ok(TICKS_PER_WHOLE_NOTE / number)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commands(track_list: &TrackList, track: usize) -> Vec<Command> {
+        track_list.tracks[track]
+            .commands
+            .iter()
+            .map(|event| event.command.clone())
+            .collect()
+    }
+
+    fn note(pitch: u8, length: u16) -> [Command; 2] {
+        [
+            Command::Note {
+                pitch,
+                velocity: DEFAULT_VELOCITY,
+                length,
+            },
+            Command::Delay(length as usize),
+        ]
+    }
+
+    #[test]
+    fn notes() {
+        let track_list = compile("1: c4 d8 e8. f+16 > c-2 r4 <b1^4").unwrap();
+
+        let mut expected = Vec::new();
+        expected.extend(note(0xA4, 48));
+        expected.extend(note(0xA6, 24));
+        expected.extend(note(0xA8, 36));
+        expected.extend(note(0xAA, 12));
+        expected.extend(note(0xAF, 96));
+        expected.push(Command::Delay(48));
+        expected.extend(note(0xAF, 240));
+        expected.push(Command::End);
+        assert_eq!(commands(&track_list, 1), expected);
+        assert_eq!(track_list.len_time(), 48 + 24 + 36 + 12 + 96 + 48 + 240);
+    }
+
+    #[test]
+    fn settings_and_loops() {
+        let track_list = compile(
+            "; a jingle\n\
+             1: t150 l8 @3 v90 [c [d]3 ]2\n\
+             2: o5 c2 t90 c2 ; second track\n\
+             2: c2",
+        )
+        .unwrap();
+
+        let mut expected = vec![Command::SetTrackVoice { index: 3 }, Command::SubTrackVolume(90)];
+        for _ in 0..2 {
+            expected.extend(note(0xA4, 24));
+            for _ in 0..3 {
+                expected.extend(note(0xA6, 24));
+            }
+        }
+        expected.push(Command::End);
+        assert_eq!(commands(&track_list, 1), expected);
+
+        assert_eq!(
+            commands(&track_list, 0),
+            vec![
+                Command::MasterTempo(DEFAULT_BPM),
+                Command::MasterVolume(100),
+                Command::MasterEffect { index: 0, value: 1 },
+                Command::MasterTempo(150),
+                Command::Delay(96),
+                Command::MasterTempo(90),
+                Command::Delay(192),
+                Command::End,
+            ]
+        );
+        assert_eq!(track_list.len_time(), 288);
+        assert!(track_list.tracks[3].is_disabled);
+    }
+
+    #[test]
+    fn errors() {
+        let error = compile("1: c4\n1: c4 x").unwrap_err();
+        assert_eq!(
+            error,
+            Error {
+                line: 2,
+                column: 7,
+                kind: ErrorKind::UnexpectedChar('x'),
+            }
+        );
+
+        assert_eq!(compile("c4").unwrap_err().kind, ErrorKind::MissingTrack);
+        assert_eq!(compile("16: c4").unwrap_err().kind, ErrorKind::InvalidTrack(16));
+        assert_eq!(compile("1: [c4").unwrap_err().kind, ErrorKind::UnclosedLoop);
+        assert_eq!(compile("1: c4]").unwrap_err().kind, ErrorKind::UnopenedLoop);
+        assert_eq!(compile("1: c5").unwrap_err().kind, ErrorKind::InvalidLength(5));
+        assert_eq!(compile("1: o0 c").unwrap_err().kind, ErrorKind::PitchOutOfRange);
+        assert_eq!(
+            compile("1: [c]256").unwrap_err().kind,
+            ErrorKind::ValueOutOfRange {
+                command: '[',
+                value: 256
+            }
+        );
+        assert_eq!(compile("1: [[[c]255]255]255").unwrap_err().kind, ErrorKind::TooLong);
+        assert!(compile("1: [[c]255]100").is_ok());
+    }
+
+    #[test]
+    fn compiled_song_encodes() {
+        let bgm = to_bgm("1: @1 c4 e4 g4\n2: c1").unwrap();
+        assert_eq!(bgm.instruments.len(), 2);
+        assert_eq!(
+            bgm.track_lists.values().next().unwrap().tracks[2]
+                .commands
+                .iter()
+                .next()
+                .unwrap()
+                .command,
+            Command::SetTrackVoice { index: 0 }
+        );
+
+        let data = bgm.as_bytes().unwrap();
+        let decoded = Bgm::from_bytes(&data).unwrap();
+        assert_eq!(decoded.track_lists.values().next().unwrap().len_time(), 192);
+    }
+}
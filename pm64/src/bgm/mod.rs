@@ -4,24 +4,45 @@ pub mod en;
 /// Decoder (.bin -> [Bgm])
 pub mod de;
 
+/// Text assembly format ([Bgm] <-> .s)
+pub mod asm;
+
+/// Annotated listing of binary BGM data
+pub mod disasm;
+
+/// Structural differences between songs
+pub mod diff;
+pub use diff::diff;
+
+/// Three-way merge of songs
+pub mod merge;
+pub use merge::merge;
+
+/// Undoable edits and undo/redo history
+pub mod edit;
+
 /// Mamar-specific editor metadata
 pub mod mamar;
 
 #[cfg(feature = "midly")]
 pub mod midi;
 
+/// Music Macro Language compiler (.mml -> [TrackList])
+pub mod mml;
+
+/// Versioning and migration of the RON format ([Bgm] <-> .ron)
+pub mod ron_format;
+
 use std::collections::BTreeMap;
 use std::ops::Range;
-use std::sync::LazyLock;
 
-use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use typescript_type_def::TypeDef;
 
 mod cmd;
 pub use cmd::*;
 
-use crate::id::{Id, gen_id};
+use crate::id::{Id, IdAllocator, OutOfIds, gen_id};
 
 /// Constant signature string which appears at the start of every binary BGM file.
 pub const MAGIC: &str = "BGM ";
@@ -44,15 +65,22 @@ pub struct Bgm {
 
     pub track_lists: BTreeMap<TrackListId, TrackList>,
 
-    #[serde(skip)]
+    /// Bytes of the file that the decoder didn't read, kept so that encoding writes them back where they were.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub unknowns: Vec<Unknown>,
 }
 
 #[derive(Clone, Default, Copy, PartialEq, Eq, Debug)]
 pub struct NoSpace;
 
-static RON_COMMAND_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r#"name:\s*".*"(?:.|\n)*?commands:\s*(\[(?:.|\n)*?\])"#).unwrap());
+/// Where an [Event] is in a song, from [Bgm::find_event].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, TypeDef)]
+pub struct EventLocation {
+    pub track_list: TrackListId,
+    pub track: usize,
+    /// Time of the event relative to the start of its track.
+    pub time: usize,
+}
 
 impl Bgm {
     pub fn new() -> Bgm {
@@ -62,6 +90,57 @@ impl Bgm {
         }
     }
 
+    /// Every id used by this song's [Event]s and [Segment]s.
+    pub fn ids(&self) -> impl Iterator<Item = Id> + '_ {
+        let segments = self
+            .variations
+            .iter()
+            .flatten()
+            .flat_map(|variation| &variation.segments);
+        let tracks = self.track_lists.values().flat_map(|track_list| &track_list.tracks);
+        segments
+            .filter_map(Segment::id)
+            .chain(tracks.flat_map(|track| track.commands.iter().map(|event| event.id)))
+    }
+
+    /// Runs `f` with new ids allocated after those already used by this song, so that anything `f` adds to it does
+    /// not collide with what is already there. Inside of another [IdAllocator::scope], that scope is used instead.
+    pub fn with_ids<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Result<T, OutOfIds> {
+        if IdAllocator::in_scope() {
+            Ok(f(self))
+        } else {
+            IdAllocator::after(self.ids()).scope(|| f(self))
+        }
+    }
+
+    /// Gives every [Event] and [Segment] a new id from `ids`. Use this to move parts of another song into this one:
+    ///
+    /// ```
+    /// # use pm64::bgm::Bgm;
+    /// # use pm64::id::IdAllocator;
+    /// # let mut song = Bgm::new();
+    /// # let mut other = Bgm::new();
+    /// let mut ids = IdAllocator::after(song.ids());
+    /// other.reassign_ids(&mut ids)?;
+    /// song.track_lists.append(&mut other.track_lists); // No ids in common
+    /// # Ok::<(), pm64::id::OutOfIds>(())
+    /// ```
+    pub fn reassign_ids(&mut self, ids: &mut IdAllocator) -> Result<(), OutOfIds> {
+        for variation in self.variations.iter_mut().flatten() {
+            for segment in &mut variation.segments {
+                *segment.id_mut() = Some(ids.next_id()?);
+            }
+        }
+        for track_list in self.track_lists.values_mut() {
+            for track in &mut track_list.tracks {
+                for id in track.commands.ids_mut() {
+                    *id = ids.next_id()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn can_add_variation(&self) -> bool {
         self.variations.iter().any(|s| s.is_none())
     }
@@ -88,72 +167,81 @@ impl Bgm {
             .map(|(id, _)| *id)
     }
 
-    pub fn add_track_list(&mut self, track_list: TrackList) -> TrackListId {
-        let mut max_id = 0;
-
-        for id in self.track_lists.keys() {
-            if *id > max_id {
-                max_id = *id;
-            }
-        }
+    /// Finds the track holding the event with the given id, searching every track list. This is a scan of every
+    /// event in the song.
+    pub fn find_event(&self, id: Id) -> Option<EventLocation> {
+        #[cfg(test)]
+        self.assert_unique(id);
+        self.track_lists.iter().find_map(|(&track_list, list)| {
+            list.tracks.iter().enumerate().find_map(|(track, t)| {
+                Some(EventLocation {
+                    track_list,
+                    track,
+                    time: t.commands.time_of(id)?,
+                })
+            })
+        })
+    }
 
-        let id = max_id.wrapping_add(1);
+    /// Returns the event with the given id, from whichever track holds it. Like [Bgm::find_event], this is a scan.
+    pub fn event_by_id(&self, id: Id) -> Option<&Event> {
+        #[cfg(test)]
+        self.assert_unique(id);
+        self.track_lists
+            .values()
+            .flat_map(|list| &list.tracks)
+            .find_map(|track| track.commands.get_by_id(id))
+    }
 
-        debug_assert!(!self.track_lists.contains_key(&id));
+    /// Like [CommandSeq::update_by_id], for whichever track holds the event.
+    pub fn update_event_by_id<T>(&mut self, id: Id, update: impl FnOnce(&mut Command) -> T) -> Option<T> {
+        self.commands_with_event_mut(id)?.update_by_id(id, update)
+    }
 
-        self.track_lists.insert(id, track_list);
-        id
+    /// Like [CommandSeq::remove_by_id], for whichever track holds the event.
+    pub fn remove_event_by_id(&mut self, id: Id) -> Option<Event> {
+        let (_, event) = self.commands_with_event_mut(id)?.remove_by_id(id)?;
+        Some(event)
     }
 
-    /// Finds the segment playing at time `time` in variation `variation`, and splits it in two at `time`.
-    /// If a segment already starts/ends at `time`, does nothing.
-    pub fn split_variation_at(&mut self, variation: usize, time: usize) {
-        if variation >= self.variations.len() {
-            return;
-        }
-        let Some(variation_ref) = &self.variations[variation] else {
-            return;
-        };
+    /// The lookups by id return the first match, so they rely on no two events or segments sharing an id. Checking
+    /// this is a scan of every id, so it is only done in tests.
+    #[cfg(test)]
+    fn assert_unique(&self, id: Id) {
+        assert!(
+            self.ids().filter(|&used| used == id).count() <= 1,
+            "more than one event or segment has id {}",
+            id
+        );
+    }
 
-        let mut current_time = 0;
-        let mut new_track_list: Option<(usize, TrackList)> = None;
-        for (i, segment) in variation_ref.segments.iter().enumerate() {
-            let Segment::Subseg { track_list, .. } = segment else {
-                continue;
-            };
+    fn commands_with_event_mut(&mut self, id: Id) -> Option<&mut CommandSeq> {
+        let EventLocation { track_list, track, .. } = self.find_event(id)?;
+        let track = self.track_lists.get_mut(&track_list)?.tracks.get_mut(track)?;
+        Some(&mut track.commands)
+    }
 
-            let duration = self
-                .track_lists
-                .get(track_list)
-                .map(|tl| tl.len_time())
-                .unwrap_or_default();
+    /// The id that [Bgm::add_track_list] would give a new track list.
+    pub fn next_track_list_id(&self) -> TrackListId {
+        self.track_lists.keys().max().copied().unwrap_or(0).wrapping_add(1)
+    }
 
-            let seg_start = current_time;
-            let seg_end = current_time + duration;
+    pub fn add_track_list(&mut self, track_list: TrackList) -> TrackListId {
+        let id = self.next_track_list_id();
 
-            if seg_start < time && time < seg_end {
-                let Some(track_list) = self.track_lists.get_mut(track_list) else {
-                    continue;
-                };
-                new_track_list = Some((i + 1, track_list.split_at(time - seg_start)));
-                break;
-            } else if seg_start == time || seg_end == time {
-                return;
-            }
+        debug_assert!(!self.track_lists.contains_key(&id));
 
-            current_time += duration;
-        }
+        self.track_lists.insert(id, track_list);
+        id
+    }
 
-        if let Some((idx, track_list)) = new_track_list {
-            let track_list = self.add_track_list(track_list);
-            self.variations[variation].as_mut().unwrap().segments.insert(
-                idx,
-                Segment::Subseg {
-                    id: Some(gen_id()),
-                    track_list,
-                },
-            )
-        }
+    /// Finds the segment playing at time `time` in variation `variation`, and splits it in two at `time`.
+    /// If a segment already starts/ends at `time`, does nothing. See [edit::Edit::split_variation_at] to do this as
+    /// an undoable edit.
+    pub fn split_variation_at(&mut self, variation: usize, time: usize) -> Result<(), edit::Error> {
+        let edits = self.with_ids(|bgm| edit::Edit::split_variation_at(bgm, variation, time))?;
+        edit::apply_all(self, edits)?;
+        Ok(())
     }
 
     /// Makes the variation `variation` start `time` ticks in.
@@ -196,89 +284,19 @@ impl Bgm {
         }
     }
 
-    pub fn from_ron_string(input_string: &str) -> Result<Self, ron::Error> {
-        // generate ids for commands
-        let matches: Vec<regex::Captures<'_>> = RON_COMMAND_REGEX.captures_iter(input_string).collect();
-        let mut modified_input_string = input_string.to_string();
-
-        for captures in matches.into_iter().rev() {
-            let commands_group = captures.get(1).unwrap();
-            let (_, [commands_str]) = captures.extract();
-
-            let commands: Vec<Command> = ron::de::from_str(commands_str)?;
-            let events: Vec<Event> = commands
-                .into_iter()
-                .map(|command| Event { id: gen_id(), command })
-                .collect();
-
-            modified_input_string.replace_range(
-                commands_group.start()..commands_group.end(),
-                &ron::ser::to_string(&events)?,
-            );
-        }
-
-        let mut bgm = ron::from_str::<Bgm>(&modified_input_string)?;
-
-        // generate ids for segments
-        for variation in &mut bgm.variations {
-            let Some(variation) = variation else {
-                continue;
-            };
-
-            for segment in &mut variation.segments {
-                segment.add_new_id();
-            }
-        }
-
+    /// Loads a song written by [Bgm::to_ron_string], upgrading it if it was written by an older version.
+    pub fn from_ron_string(input_string: &str) -> Result<Self, ron_format::Error> {
+        let version = ron_format::read_version(input_string)?;
+        let mut bgm = ron_format::parse(input_string, version)?;
+        ron_format::upgrade(&mut bgm, version);
         Ok(bgm)
     }
 
-    pub fn to_ron_string(mut self) -> Result<String, ron::Error> {
-        // strip segments of id
-        for variation in &mut self.variations {
-            let Some(variation) = variation else {
-                continue;
-            };
-
-            for segment in &mut variation.segments {
-                segment.strip_id();
-            }
-        }
-
-        let pretty_config = ron::ser::PrettyConfig::new().indentor("  ").depth_limit(5);
-        let bgm_string = ron::ser::to_string_pretty(&self, pretty_config.clone())?.to_string();
-
-        // strip commands of id field
-        let matches: Vec<regex::Captures<'_>> = RON_COMMAND_REGEX.captures_iter(&bgm_string).collect();
-        let mut modified_bgm_string = bgm_string.clone();
-
-        for captures in matches.into_iter().rev() {
-            let events_group = captures.get(1).unwrap();
-            let (_, [events_str]) = captures.extract();
-
-            let events: Vec<Event> = ron::de::from_str(events_str)?;
-            if events.is_empty() {
-                continue;
-            }
-
-            let commands: Vec<Command> = events.into_iter().map(|event| event.command).collect();
-
-            let mut commands_string = "[\n".to_owned();
-            for line in ron::ser::to_string_pretty(&commands, pretty_config.clone().depth_limit(1))?
-                .lines()
-                .skip(1)
-            {
-                commands_string.push_str("        ");
-                commands_string.push_str(line);
-                commands_string.push('\n');
-            }
-            modified_bgm_string.replace_range(
-                events_group.start()..events_group.end(),
-                &commands_string[..commands_string.len() - 1],
-            );
-        }
-
-        Ok(modified_bgm_string)
+    /// Writes the song as RON. The first field is `version`, the [ron_format::RON_VERSION] of the format, so that
+    /// [Bgm::from_ron_string] can keep loading the file after the format changes. Ids are left out, and new ones are
+    /// generated when the file is loaded.
+    pub fn to_ron_string(&self) -> Result<String, ron::Error> {
+        ron_format::write(self)
     }
 }
 
@@ -290,33 +308,33 @@ pub struct Variation {
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize, TypeDef)]
 pub enum Segment {
     Subseg {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
         track_list: TrackListId,
     },
     StartLoop {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
         label_index: u16,
     },
     Wait {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
     },
     EndLoop {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
         label_index: u8,
         iter_count: u8,
     },
     Unknown6 {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
         label_index: u8,
         iter_count: u8,
     },
     Unknown7 {
-        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default = "ron_format::new_id", skip_serializing_if = "ron_format::skip_id")]
         id: Option<Id>,
         label_index: u8,
         iter_count: u8,
@@ -324,25 +342,25 @@ pub enum Segment {
 }
 
 impl Segment {
-    pub fn add_new_id(&mut self) {
+    pub fn id(&self) -> Option<Id> {
         match self {
-            Segment::Subseg { id, .. } => *id = Some(gen_id()),
-            Segment::StartLoop { id, .. } => *id = Some(gen_id()),
-            Segment::Wait { id } => *id = Some(gen_id()),
-            Segment::EndLoop { id, .. } => *id = Some(gen_id()),
-            Segment::Unknown6 { id, .. } => *id = Some(gen_id()),
-            Segment::Unknown7 { id, .. } => *id = Some(gen_id()),
+            Segment::Subseg { id, .. }
+            | Segment::StartLoop { id, .. }
+            | Segment::Wait { id }
+            | Segment::EndLoop { id, .. }
+            | Segment::Unknown6 { id, .. }
+            | Segment::Unknown7 { id, .. } => *id,
         }
     }
 
-    pub fn strip_id(&mut self) {
+    pub fn id_mut(&mut self) -> &mut Option<Id> {
         match self {
-            Segment::Subseg { id, .. } => *id = None,
-            Segment::StartLoop { id, .. } => *id = None,
-            Segment::Wait { id } => *id = None,
-            Segment::EndLoop { id, .. } => *id = None,
-            Segment::Unknown6 { id, .. } => *id = None,
-            Segment::Unknown7 { id, .. } => *id = None,
+            Segment::Subseg { id, .. }
+            | Segment::StartLoop { id, .. }
+            | Segment::Wait { id }
+            | Segment::EndLoop { id, .. }
+            | Segment::Unknown6 { id, .. }
+            | Segment::Unknown7 { id, .. } => id,
         }
     }
 }
@@ -360,8 +378,9 @@ mod segment_commands {
 #[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize, TypeDef)]
 #[serde(default)]
 pub struct TrackList {
-    /// Encode/decode file position.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Where the track list was in the file it was decoded from. Only a hint to the encoder, to put it back in the same
+    /// place; it is not saved, and it goes stale as the song is edited.
+    #[serde(skip)]
     pub pos: Option<FilePos>,
 
     pub tracks: [Track; 16],
@@ -395,6 +414,10 @@ pub struct Track {
     pub polyphony: Polyphony,
     pub is_drum_track: bool,
     pub commands: CommandSeq,
+    /// Where `commands` was in the file it was decoded from. Like [TrackList::pos], only a hint to the encoder that is
+    /// not saved. Tracks with the same position share their commands when encoded, unless they have been edited apart.
+    #[serde(skip)]
+    pub pos: Option<FilePos>,
 }
 
 impl Default for Track {
@@ -405,6 +428,7 @@ impl Default for Track {
             polyphony: Polyphony::Automatic,
             is_drum_track: false,
             commands: Default::default(),
+            pos: None,
         }
     }
 }
@@ -417,6 +441,7 @@ impl Track {
             polyphony: self.polyphony,
             is_drum_track: self.is_drum_track,
             commands: self.commands.split_at(time),
+            pos: None,
         }
     }
 }
@@ -424,6 +449,9 @@ impl Track {
 /// 255 is never used in vanilla songs so we can repurpose it to mean 'please calculate a good polyphonic_idx for me'
 pub const POLYPHONIC_IDX_AUTO_MAMAR: u8 = 255;
 
+/// The most notes a single track can play at once.
+pub const MAX_TRACK_VOICES: u8 = 4;
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize, TypeDef)]
 pub enum Polyphony {
     Automatic,
@@ -517,12 +545,19 @@ pub struct Instrument {
     pub pad_07: u8,
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize, TypeDef)]
 pub struct Unknown {
-    pub range: Range<u64>,
+    /// Where `data` goes in the file.
+    pub start: FilePos,
     pub data: Vec<u8>,
 }
 
+impl Unknown {
+    pub fn range(&self) -> Range<FilePos> {
+        self.start..self.start + self.data.len() as FilePos
+    }
+}
+
 fn is_default<T: Default + PartialEq>(t: &T) -> bool {
     t == &T::default()
 }
@@ -543,12 +578,13 @@ pub struct PatchAddress {
     pub envelope: u8,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize, TypeDef)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize, TypeDef)]
 pub enum BankSetIndex {
     /// Extra banks loaded at request of BGM file
     Aux,
     Set2,
     /// Used only for au_reset_drum_entry/au_reset_instrument_entry
+    #[default]
     Default,
     /// Where standard music instruments are stored
     Music,
@@ -559,12 +595,6 @@ pub enum BankSetIndex {
     AuxCopy,
 }
 
-impl Default for BankSetIndex {
-    fn default() -> Self {
-        BankSetIndex::Default
-    }
-}
-
 impl TryFrom<u8> for BankSetIndex {
     type Error = ();
 
@@ -583,9 +613,9 @@ impl TryFrom<u8> for BankSetIndex {
     }
 }
 
-impl Into<u8> for BankSetIndex {
-    fn into(self) -> u8 {
-        match self {
+impl From<BankSetIndex> for u8 {
+    fn from(bank_set: BankSetIndex) -> u8 {
+        match bank_set {
             BankSetIndex::Aux => 0,
             BankSetIndex::Set2 => 1,
             BankSetIndex::Default => 2,
@@ -597,3 +627,48 @@ impl Into<u8> for BankSetIndex {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn events_by_id() {
+        let mut bgm = mml::to_bgm("1: l4 c r e\n2: l4 r g").unwrap();
+        let commands = &bgm.track_lists[&1].tracks[2].commands;
+        let (time, note) = commands
+            .iter_time()
+            .find(|(_, event)| matches!(event.command, Command::Note { .. }))
+            .unwrap();
+        let (id, command) = (note.id, note.command.clone());
+
+        assert_eq!(
+            bgm.find_event(id),
+            Some(EventLocation {
+                track_list: 1,
+                track: 2,
+                time
+            })
+        );
+        assert_eq!(bgm.event_by_id(id).map(|event| &event.command), Some(&command));
+
+        let old = bgm.update_event_by_id(id, |command| std::mem::replace(command, Command::TrackTremoloStop));
+        assert_eq!(old, Some(command));
+        assert_eq!(bgm.track_lists[&1].tracks[2].commands.time_of(id), Some(time));
+
+        assert_eq!(bgm.remove_event_by_id(id).unwrap().command, Command::TrackTremoloStop);
+        assert_eq!(bgm.find_event(id), None);
+        assert_eq!(bgm.remove_event_by_id(id), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "more than one event or segment has id")]
+    fn duplicate_ids_are_caught() {
+        let mut bgm = mml::to_bgm("1: l4 c e").unwrap();
+        let mut ids = bgm.track_lists.get_mut(&1).unwrap().tracks[1].commands.ids_mut();
+        let id = *ids.next().unwrap();
+        *ids.next().unwrap() = id;
+        drop(ids);
+        bgm.find_event(id);
+    }
+}
@@ -0,0 +1,287 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize as _, Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+
+use super::*;
+use crate::id::{IdAllocator, OutOfIds, gen_id};
+
+/// Version of the RON format written by [Bgm::to_ron_string].
+///
+/// This must be increased whenever a change to the serialized types (such as renaming a [Command] or [Segment]
+/// variant, or changing what a field means) would make older files load incorrectly or not at all. Each increase
+/// needs a migration in [upgrade], so that files saved by older versions keep working:
+///
+/// - If older files still deserialize, but mean something different, fix them up in [upgrade].
+/// - If older files no longer deserialize, keep a copy of the old types in a `vN` submodule of this one, deserialize
+///   files of that version as those types in [parse], and convert them to the current types.
+pub const RON_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file was written by a newer version of pm64 than this one.
+    UnsupportedVersion(u32),
+    OutOfIds,
+    Ron(ron::Error),
+}
+
+impl From<OutOfIds> for Error {
+    fn from(_: OutOfIds) -> Self {
+        Self::OutOfIds
+    }
+}
+
+impl From<ron::Error> for Error {
+    fn from(ron: ron::Error) -> Self {
+        Self::Ron(ron)
+    }
+}
+
+impl From<ron::error::SpannedError> for Error {
+    fn from(ron: ron::error::SpannedError) -> Self {
+        Self::Ron(ron.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "File is version {} of the format, but only versions up to {} are supported. Try updating",
+                version, RON_VERSION
+            ),
+            Error::OutOfIds => write!(f, "{}", OutOfIds),
+            Error::Ron(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Ron(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the (de)serialization in progress is of a RON file, which leaves out [Event] and [Segment] ids.
+    static WITHOUT_IDS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with ids left out of anything serialized and generated for anything deserialized.
+fn without_ids<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            WITHOUT_IDS.set(self.0);
+        }
+    }
+
+    let _restore = Restore(WITHOUT_IDS.replace(true));
+    f()
+}
+
+/// `skip_serializing_if` for [Segment] ids.
+pub(super) fn skip_id(id: &Option<Id>) -> bool {
+    id.is_none() || WITHOUT_IDS.get()
+}
+
+/// `default` for [Segment] ids, used when the id is missing.
+pub(super) fn new_id() -> Option<Id> {
+    WITHOUT_IDS.get().then(gen_id)
+}
+
+/// (De)serialization of the [Event]s of a [CommandSeq], which are written as just their [Command]s in RON files.
+pub(super) mod events {
+    use super::*;
+
+    pub fn serialize<'a, S: Serializer>(
+        events: impl Iterator<Item = &'a Event>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if WITHOUT_IDS.get() {
+            serializer.collect_seq(events.map(|event| &event.command))
+        } else {
+            serializer.collect_seq(events)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Event>, D::Error> {
+        if WITHOUT_IDS.get() {
+            let commands = Vec::<Command>::deserialize(deserializer)?;
+            Ok(commands.into_iter().map(Event::from).collect())
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
+}
+
+/// What gets written to a RON file: the fields of [Bgm], preceded by the format version.
+#[derive(Serialize)]
+struct VersionedBgm<'a> {
+    version: u32,
+    name: &'a str,
+    variations: &'a [Option<Variation>; 4],
+    drums: &'a [Drum],
+    instruments: &'a [Instrument],
+    track_lists: &'a BTreeMap<TrackListId, TrackList>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    unknowns: &'a [Unknown],
+}
+
+impl<'a> From<&'a Bgm> for VersionedBgm<'a> {
+    fn from(bgm: &'a Bgm) -> Self {
+        Self {
+            version: RON_VERSION,
+            name: &bgm.name,
+            variations: &bgm.variations,
+            drums: &bgm.drums,
+            instruments: &bgm.instruments,
+            track_lists: &bgm.track_lists,
+            unknowns: &bgm.unknowns,
+        }
+    }
+}
+
+pub(super) fn write(bgm: &Bgm) -> Result<String, ron::Error> {
+    let pretty_config = ron::ser::PrettyConfig::new().indentor("  ").depth_limit(5);
+    without_ids(|| ron::ser::to_string_pretty(&VersionedBgm::from(bgm), pretty_config))
+}
+
+/// Reads only the format version of a RON file, ignoring everything else.
+#[derive(Deserialize)]
+struct Header {
+    /// Files written before the format was versioned have no version field.
+    #[serde(default)]
+    version: u32,
+}
+
+pub(super) fn read_version(input: &str) -> Result<u32, Error> {
+    let Header { version } = ron::from_str(input)?;
+    if version > RON_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    Ok(version)
+}
+
+/// Deserializes a file of the given version, which [read_version] has checked is supported.
+pub(super) fn parse(input: &str, version: u32) -> Result<Bgm, Error> {
+    match version {
+        // Unversioned files have the same layout as version 1
+        0 | 1 => Ok(IdAllocator::new().scope(|| without_ids(|| ron::from_str(input)))??),
+        _ => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// Brings a song loaded from a file of the given version up to date.
+pub(super) fn upgrade(_bgm: &mut Bgm, version: u32) {
+    // Nothing has changed meaning since version 0. Migrations go here, oldest first, like so:
+    // if version < 2 { ... }
+    debug_assert!(version <= RON_VERSION);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bgm() -> Bgm {
+        let mut track_list = TrackList::default();
+        track_list.tracks[1].commands = vec![Command::Note {
+            pitch: 140,
+            velocity: 100,
+            length: 48,
+        }]
+        .into();
+
+        let mut bgm = Bgm::new();
+        let track_list = bgm.add_track_list(track_list);
+        bgm.variations[0] = Some(Variation {
+            segments: vec![Segment::Subseg {
+                id: Some(gen_id()),
+                track_list,
+            }],
+        });
+        bgm
+    }
+
+    #[test]
+    fn written_with_version() {
+        let ron = bgm().to_ron_string().unwrap();
+        assert!(
+            ron.starts_with(&format!("(\n  version: {},\n  name: ", RON_VERSION)),
+            "{}",
+            ron
+        );
+        assert_eq!(read_version(&ron).unwrap(), RON_VERSION);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bgm = bgm();
+        let loaded = Bgm::from_ron_string(&bgm.clone().to_ron_string().unwrap()).unwrap();
+        assert_eq!(loaded.as_bytes().unwrap(), bgm.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn ids_are_left_out() {
+        let mut bgm = bgm();
+        bgm.track_lists.get_mut(&1).unwrap().tracks[2].name = r#"commands: [End], name: "\""#.to_owned();
+
+        let ron = bgm.to_ron_string().unwrap();
+        assert!(!ron.contains("id:"), "{}", ron);
+        assert!(ron.contains("Note(pitch: 140, velocity: 100, length: 48),"), "{}", ron);
+
+        let loaded = Bgm::from_ron_string(&ron).unwrap();
+        assert_eq!(
+            loaded.track_lists[&1].tracks[2].name,
+            bgm.track_lists[&1].tracks[2].name
+        );
+        assert!(matches!(
+            loaded.variations[0].as_ref().unwrap().segments[0],
+            Segment::Subseg { id: Some(_), .. }
+        ));
+
+        // Outside of RON files, ids are still (de)serialized
+        let with_ids = ron::to_string(&loaded.track_lists[&1]).unwrap();
+        assert!(with_ids.contains("\"id\":"), "{}", with_ids);
+        assert_eq!(ron::from_str::<TrackList>(&with_ids).unwrap(), loaded.track_lists[&1]);
+    }
+
+    #[test]
+    fn unversioned_files_load() {
+        let ron = r#"(
+            name: "Old ",
+            variations: (Some((segments: [Subseg(track_list: 1)])), None, None, None),
+            track_lists: {
+                1: (tracks: (
+                    (name: "", is_disabled: false, polyphony: Automatic, is_drum_track: false, commands: [
+                        MasterTempo(120),
+                        Delay(48),
+                        End,
+                    ]),
+                    (), (), (), (), (), (), (), (), (), (), (), (), (), (), (),
+                )),
+            },
+        )"#;
+        assert_eq!(read_version(ron).unwrap(), 0);
+
+        let bgm = Bgm::from_ron_string(ron).unwrap();
+        assert_eq!(bgm.name, "Old ");
+        assert_eq!(bgm.track_lists[&1].len_time(), 48);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let ron = format!("(version: {}, name: \"New \")", RON_VERSION + 1);
+        assert!(matches!(
+            Bgm::from_ron_string(&ron),
+            Err(Error::UnsupportedVersion(version)) if version == RON_VERSION + 1
+        ));
+    }
+}
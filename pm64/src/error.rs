@@ -0,0 +1,171 @@
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+use typescript_type_def::TypeDef;
+
+use crate::bgm::{self, TrackListId};
+use crate::{id, rom, sbn};
+
+/// What was being done when an error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub enum ErrorKind {
+    Decode,
+    Encode,
+    Ron,
+    Midi,
+    Sbn,
+    Rom,
+    Edit,
+    /// A value given to the library was not of the expected shape.
+    InvalidInput,
+}
+
+/// Where in a song an error happened. Parts that are not known are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub struct Location {
+    pub variation: Option<usize>,
+    pub segment: Option<usize>,
+    pub track_list: Option<TrackListId>,
+    pub track: Option<usize>,
+}
+
+/// Any error from this library, as plain data that can be sent to other languages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub struct Report {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Byte offset into the file being read.
+    pub offset: Option<u64>,
+    pub location: Option<Location>,
+}
+
+impl Report {
+    pub fn new(kind: ErrorKind, error: impl fmt::Display) -> Self {
+        Self {
+            kind,
+            message: error.to_string(),
+            offset: None,
+            location: None,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Report {}
+
+impl From<bgm::de::Error> for Report {
+    fn from(error: bgm::de::Error) -> Self {
+        let context = &error.context;
+        let location = Location {
+            variation: context.variation,
+            segment: context.segment,
+            // Track lists don't have ids until they are decoded
+            track_list: None,
+            track: context.track,
+        };
+        Self {
+            offset: error.offset,
+            location: Some(location),
+            ..Self::new(ErrorKind::Decode, &error)
+        }
+    }
+}
+
+impl From<bgm::en::Error> for Report {
+    fn from(error: bgm::en::Error) -> Self {
+        Self::new(ErrorKind::Encode, error)
+    }
+}
+
+impl From<bgm::ron_format::Error> for Report {
+    fn from(error: bgm::ron_format::Error) -> Self {
+        Self::new(ErrorKind::Ron, error)
+    }
+}
+
+impl From<ron::Error> for Report {
+    fn from(error: ron::Error) -> Self {
+        Self::new(ErrorKind::Ron, error)
+    }
+}
+
+impl From<sbn::de::Error> for Report {
+    fn from(error: sbn::de::Error) -> Self {
+        Self::new(ErrorKind::Sbn, error)
+    }
+}
+
+impl From<rom::Error> for Report {
+    fn from(error: rom::Error) -> Self {
+        Self::new(ErrorKind::Rom, error)
+    }
+}
+
+impl From<bgm::edit::Error> for Report {
+    fn from(error: bgm::edit::Error) -> Self {
+        use bgm::edit::Error::*;
+
+        let location = match error {
+            NoTrack { track_list, track } => Location {
+                track_list: Some(track_list),
+                track: Some(track),
+                ..Default::default()
+            },
+            NoTrackList { id } | TrackListExists { id } => Location {
+                track_list: Some(id),
+                ..Default::default()
+            },
+            NoVariation { variation } => Location {
+                variation: Some(variation),
+                ..Default::default()
+            },
+            NoSegment { variation, index } => Location {
+                variation: Some(variation),
+                segment: Some(index),
+                ..Default::default()
+            },
+            NoEvent { .. } | NoInstrument { .. } | OutOfIds => Location::default(),
+        };
+        Self {
+            location: Some(location),
+            ..Self::new(ErrorKind::Edit, error)
+        }
+    }
+}
+
+impl From<id::OutOfIds> for Report {
+    fn from(error: id::OutOfIds) -> Self {
+        Self::new(ErrorKind::Edit, error)
+    }
+}
+
+impl From<bgm::NoteEditError> for Report {
+    fn from(error: bgm::NoteEditError) -> Self {
+        Self::new(ErrorKind::Edit, error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn edit_errors_have_locations() {
+        let report = Report::from(bgm::edit::Error::NoSegment { variation: 1, index: 2 });
+        assert_eq!(report.kind, ErrorKind::Edit);
+        assert_eq!(report.message, "variation 1 has no segment 2");
+        assert_eq!(
+            report.location,
+            Some(Location {
+                variation: Some(1),
+                segment: Some(2),
+                ..Default::default()
+            })
+        );
+    }
+}
@@ -1,9 +1,207 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::cell::Cell;
+use std::fmt;
 
 pub type Id = u32;
 
+/// Where [gen_id] starts counting outside of any [IdAllocator::scope]. Documents allocate from zero, so ids from here
+/// up stay clear of theirs.
+const UNSCOPED_START: Id = 1 << 31;
+
+/// Hands out [Id]s in order. Each document gets its own allocator, so the ids given to it only depend on what happens
+/// to that document: decoding the same bytes always yields the same ids.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdAllocator {
+    /// `None` once every id has been handed out.
+    next: Option<Id>,
+}
+
+/// Every [Id] has been handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfIds;
+
+impl fmt::Display for OutOfIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ran out of ids")
+    }
+}
+
+impl std::error::Error for OutOfIds {}
+
+/// The state of the allocator in [IdAllocator::scope].
+#[derive(Clone, Copy)]
+struct Scope {
+    next: Option<Id>,
+    /// Whether [gen_id] was called after `next` ran out.
+    overdrawn: bool,
+}
+
+thread_local! {
+    static SCOPED: Cell<Option<Scope>> = const { Cell::new(None) };
+
+    /// The next id given out by [gen_id] outside of any [IdAllocator::scope].
+    static UNSCOPED: Cell<Id> = const { Cell::new(UNSCOPED_START) };
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self { next: Some(0) }
+    }
+}
+
+impl IdAllocator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an allocator that will only hand out ids greater than every id in `used`.
+    pub fn after<I: IntoIterator<Item = Id>>(used: I) -> Self {
+        Self {
+            next: used.into_iter().max().map_or(Some(0), |max| max.checked_add(1)),
+        }
+    }
+
+    pub fn next_id(&mut self) -> Result<Id, OutOfIds> {
+        let id = self.next.ok_or(OutOfIds)?;
+        self.next = id.checked_add(1);
+        Ok(id)
+    }
+
+    /// Runs `f` with [gen_id] allocating from this allocator, including in any conversions into
+    /// [Event](crate::bgm::Event) that `f` does.
+    ///
+    /// If the allocator runs out, `f` still runs to the end, but [OutOfIds] is returned instead of its result, as the
+    /// ids it was given are not all unique.
+    pub fn scope<T>(&mut self, f: impl FnOnce() -> T) -> Result<T, OutOfIds> {
+        struct Restore<'a> {
+            allocator: &'a mut IdAllocator,
+            previous: Option<Scope>,
+        }
+
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                if let Some(scope) = SCOPED.replace(self.previous) {
+                    self.allocator.next = scope.next;
+                }
+            }
+        }
+
+        let previous = SCOPED.replace(Some(Scope {
+            next: self.next,
+            overdrawn: false,
+        }));
+        let restore = Restore {
+            allocator: self,
+            previous,
+        };
+        let result = f();
+        let overdrawn = SCOPED.get().is_some_and(|scope| scope.overdrawn);
+        drop(restore);
+
+        if overdrawn { Err(OutOfIds) } else { Ok(result) }
+    }
+
+    /// Whether [gen_id] is currently allocating from an allocator in [IdAllocator::scope].
+    pub fn in_scope() -> bool {
+        SCOPED.get().is_some()
+    }
+}
+
+/// Allocates an id from the allocator in [IdAllocator::scope]. If that allocator has run out, this returns [Id::MAX]
+/// and the scope returns [OutOfIds].
+///
+/// Outside of a scope, ids come from a per-thread counter starting at 2^31, above the ids of any document that has not
+/// allocated billions of them. Anything that edits a document should still be done in a scope (see
+/// [Bgm::with_ids](crate::bgm::Bgm::with_ids)), so that its ids stay in order.
 pub fn gen_id() -> Id {
-    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    match SCOPED.get() {
+        Some(Scope {
+            next: Some(next),
+            overdrawn,
+        }) => {
+            SCOPED.set(Some(Scope {
+                next: next.checked_add(1),
+                overdrawn,
+            }));
+            next
+        }
+        Some(scope) => {
+            SCOPED.set(Some(Scope {
+                overdrawn: true,
+                ..scope
+            }));
+            Id::MAX
+        }
+        None => {
+            let next = UNSCOPED.get();
+            UNSCOPED.set(next.checked_add(1).unwrap_or(UNSCOPED_START));
+            next
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scopes_are_independent() {
+        let mut a = IdAllocator::new();
+        let mut b = IdAllocator::after([4, 2]);
+
+        assert_eq!(a.scope(|| [gen_id(), gen_id()]), Ok([0, 1]));
+        assert_eq!(b.scope(gen_id), Ok(5));
+        assert_eq!(
+            a.scope(|| {
+                let inner = b.scope(gen_id).unwrap();
+                (gen_id(), inner)
+            }),
+            Ok((2, 6))
+        );
+        assert!(!IdAllocator::in_scope());
+        assert_eq!(a.next_id(), Ok(3));
+    }
+
+    #[test]
+    fn running_out_is_an_error() {
+        let mut ids = IdAllocator::after([Id::MAX - 1]);
+        assert_eq!(ids.next_id(), Ok(Id::MAX));
+        assert_eq!(ids.next_id(), Err(OutOfIds));
+
+        let mut ids = IdAllocator::after([Id::MAX - 1]);
+        assert_eq!(ids.scope(gen_id), Ok(Id::MAX));
+        assert_eq!(ids.scope(gen_id), Err(OutOfIds));
+        assert_eq!(IdAllocator::after([Id::MAX]).scope(|| ()), Ok(()));
+
+        let mut song = crate::bgm::mml::to_bgm("1: cdef").unwrap();
+        song.track_lists.get_mut(&1).unwrap().tracks[1]
+            .commands
+            .ids_mut()
+            .for_each(|id| *id = Id::MAX);
+        assert_eq!(song.split_variation_at(0, 48), Err(crate::bgm::edit::Error::OutOfIds));
+    }
+
+    #[test]
+    fn unscoped_ids_do_not_collide() {
+        let bgm = crate::bgm::Bgm::from_bytes(include_bytes!("../tests/fixtures/commands.bin")).unwrap();
+        let id = gen_id();
+        assert!(bgm.ids().all(|used| used != id));
+    }
+
+    #[test]
+    fn merged_songs_do_not_collide() {
+        let mut song = crate::bgm::mml::to_bgm("1: cdef").unwrap();
+        let mut other = crate::bgm::mml::to_bgm("1: gab").unwrap();
+        assert!(other.ids().any(|id| song.ids().any(|used| used == id)));
+
+        other.reassign_ids(&mut IdAllocator::after(song.ids())).unwrap();
+        assert!(other.ids().all(|id| song.ids().all(|used| used != id)));
 
-    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        let before = song.ids().count();
+        song.with_ids(|song| song.split_variation_at(0, 48)).unwrap().unwrap();
+        let mut ids: Vec<_> = song.ids().collect();
+        ids.sort();
+        ids.dedup();
+        assert!(ids.len() > before);
+        assert_eq!(ids.len(), song.ids().count());
+    }
 }
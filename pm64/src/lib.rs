@@ -1,4 +1,6 @@
 pub mod bgm;
+pub mod error;
 pub mod id;
+pub mod rom;
 mod rw;
 pub mod sbn;
@@ -1,37 +1,443 @@
-use pm64::bgm::Bgm;
-use std::fs::read;
+use std::error::Error;
+use std::fs::{read, write};
+use std::path::Path;
+use std::process::exit;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        print_help_and_exit();
-    }
-    match args[1].as_ref() {
-        #[cfg(feature = "midly")]
-        "convert" if args.len() == 4 => {
-            let input = read(&args[2])?;
-            let bgm = pm64::bgm::midi::to_bgm(&input)?;
+use pm64::bgm::*;
+use pm64::sbn::Sbn;
 
-            let mut output = std::fs::File::create(&args[3])?;
-            bgm.encode(&mut output)?;
-        }
-        "listinstruments" if args.len() == 3 => {
-            let input = read(&args[2])?;
-            let bgm = Bgm::from_bytes(&input)?;
+type Result<T = ()> = std::result::Result<T, Box<dyn Error>>;
 
-            for instrument in &bgm.instruments {
-                println!("{:?}", instrument);
-            }
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        #[cfg(feature = "midly")]
+        ["convert", input, output] => convert(input, output),
+        ["listinstruments", input] => list_instruments(input),
+        ["decode", input, output] => decode(input, output),
+        ["salvage", input, output] => salvage(input, output),
+        ["encode", input, output] => encode(input, output),
+        ["info", input] => info(input),
+        ["disasm", input] => disasm(input),
+        ["diff", old, new] => diff(old, new),
+        ["merge", base, ours, theirs, output] => merge(base, ours, theirs, output),
+        #[cfg(feature = "midly")]
+        ["midi-export", input, output] => midi_export(input, output, "0"),
+        #[cfg(feature = "midly")]
+        ["midi-export", input, output, variation] => midi_export(input, output, variation),
+        ["validate", inputs @ ..] if !inputs.is_empty() => validate(inputs),
+        ["sbn", "list", input] => sbn_list(input),
+        ["sbn", "extract", input, dir] => sbn_extract(input, dir),
+        ["sbn", "pack", base, dir, output] => sbn_pack(base, dir, output),
+        ["rom", "inject", rom, output, inputs @ ..] if !inputs.is_empty() => rom_inject(rom, output, inputs),
+        _ => {
+            print_help();
+            exit(2);
         }
-        _ => print_help_and_exit(),
+    };
+
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        exit(1);
     }
-    Ok(())
 }
 
-fn print_help_and_exit() {
+fn print_help() {
     eprintln!("Commands:");
     #[cfg(feature = "midly")]
     eprintln!("  convert <input.mid> <output.bgm>");
     eprintln!("  listinstruments <input.bgm>");
-    std::process::exit(1);
+    eprintln!("  decode <input.bgm> <output.ron|output.s>");
+    eprintln!("  salvage <broken.bgm> <output.s>");
+    eprintln!("  encode <input.ron|input.s> <output.bgm>");
+    eprintln!("  info <input.bgm>");
+    eprintln!("  disasm <input.bgm>");
+    eprintln!("  diff <old.bgm> <new.bgm>");
+    eprintln!("  merge <base.bgm> <ours.bgm> <theirs.bgm> <output.bgm|output.ron|output.s>");
+    #[cfg(feature = "midly")]
+    eprintln!("  midi-export <input.bgm> <output.mid> [variation]");
+    eprintln!("  validate <input.bgm>...");
+    eprintln!("  sbn list <input.sbn>");
+    eprintln!("  sbn extract <input.sbn> <output dir>");
+    eprintln!("  sbn pack <base.sbn> <input dir> <output.sbn>");
+    eprintln!("  rom inject <input.z64> <output.z64> <input.bgm>...");
+    eprintln!();
+    eprintln!("Wherever a .bgm is read, a .ron, .s (assembly), .mml or .mid file can be given instead.");
+    eprintln!("Exits with 1 if the command fails, or 2 if the arguments are invalid.");
+}
+
+/// Reads a BGM, RON, assembly, MML or MIDI file.
+fn read_bgm(path: &str) -> Result<Bgm> {
+    let data = read(path)?;
+
+    if data.starts_with(MAGIC.as_bytes()) {
+        return Ok(Bgm::from_bytes(&data)?);
+    }
+
+    #[cfg(feature = "midly")]
+    if data.starts_with(b"MThd") {
+        let (bgm, warnings) = midi::to_bgm(&data)?;
+        for warning in &warnings {
+            eprintln!("warning: {}", warning);
+        }
+        return Ok(bgm);
+    }
+
+    let text = std::str::from_utf8(&data)?;
+    if path.ends_with(".mml") {
+        Ok(mml::to_bgm(text)?)
+    } else if is_asm_path(path) {
+        Ok(Bgm::from_asm_string(text)?)
+    } else {
+        Ok(Bgm::from_ron_string(text)?)
+    }
+}
+
+fn is_asm_path(path: &str) -> bool {
+    matches!(
+        Path::new(path).extension().and_then(|extension| extension.to_str()),
+        Some("s" | "asm")
+    )
+}
+
+#[cfg(feature = "midly")]
+fn convert(input: &str, output: &str) -> Result {
+    let bgm = read_bgm(input)?;
+    write(output, bgm.as_bytes()?)?;
+    Ok(())
+}
+
+fn list_instruments(input: &str) -> Result {
+    let bgm = read_bgm(input)?;
+
+    for instrument in &bgm.instruments {
+        println!("{:?}", instrument);
+    }
+    Ok(())
+}
+
+fn decode(input: &str, output: &str) -> Result {
+    let bgm = read_bgm(input)?;
+    if is_asm_path(output) {
+        write(output, bgm.to_asm_string())?;
+    } else {
+        write(output, bgm.to_ron_string()?)?;
+    }
+    Ok(())
+}
+
+/// Decodes as much of a broken .bgm as possible. Assembly keeps the undecodable bytes, but RON doesn't.
+fn salvage(input: &str, output: &str) -> Result {
+    let data = read(input)?;
+    let (bgm, errors) = Bgm::decode_lenient(&mut std::io::Cursor::new(data))?;
+    for error in &errors {
+        eprintln!("recovered: {}", error);
+    }
+    if is_asm_path(output) {
+        write(output, bgm.to_asm_string())?;
+    } else {
+        write(output, bgm.to_ron_string()?)?;
+    }
+    Ok(())
+}
+
+fn encode(input: &str, output: &str) -> Result {
+    let bgm = read_bgm(input)?;
+    write(output, bgm.as_bytes()?)?;
+    Ok(())
+}
+
+fn info(input: &str) -> Result {
+    let bgm = read_bgm(input)?;
+
+    println!("name: {:?}", bgm.name);
+    match bgm.as_bytes() {
+        Ok(data) => println!("size: {:#X}B", data.len()),
+        Err(error) => println!("size: unknown ({})", error),
+    }
+    println!("drums: {}", bgm.drums.len());
+    println!("instruments: {}", bgm.instruments.len());
+
+    for (i, variation) in bgm.variations.iter().enumerate() {
+        let Some(variation) = variation else {
+            println!("variation {}: none", i);
+            continue;
+        };
+
+        println!("variation {}:", i);
+        for segment in &variation.segments {
+            match segment {
+                Segment::Subseg { track_list, .. } => println!("  track list {}", track_list),
+                Segment::StartLoop { label_index, .. } => println!("  start loop {}", label_index),
+                Segment::Wait { .. } => println!("  wait"),
+                Segment::EndLoop {
+                    label_index,
+                    iter_count,
+                    ..
+                } => println!("  end loop {} (x{})", label_index, iter_count),
+                Segment::Unknown6 {
+                    label_index,
+                    iter_count,
+                    ..
+                } => println!("  unknown6 {} (x{})", label_index, iter_count),
+                Segment::Unknown7 {
+                    label_index,
+                    iter_count,
+                    ..
+                } => println!("  unknown7 {} (x{})", label_index, iter_count),
+            }
+        }
+    }
+
+    for (id, track_list) in &bgm.track_lists {
+        match track_list.pos {
+            Some(pos) => println!("track list {} @ {:#X}: {} ticks", id, pos, track_list.len_time()),
+            None => println!("track list {}: {} ticks", id, track_list.len_time()),
+        }
+
+        for (i, track) in track_list.tracks.iter().enumerate() {
+            if track.commands.is_empty() {
+                continue;
+            }
+
+            println!(
+                "  track {:>2}: {} commands, {} voices, {:?}{}{}{}",
+                i,
+                track.commands.len(),
+                track.commands.max_polyphony(),
+                track.polyphony,
+                if track.is_drum_track { ", drums" } else { "" },
+                if track.is_disabled { ", disabled" } else { "" },
+                if track.name.is_empty() {
+                    String::new()
+                } else {
+                    format!(", {:?}", track.name)
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn disasm(input: &str) -> Result {
+    print!("{}", disasm::disassemble(&read(input)?)?);
+    Ok(())
+}
+
+fn diff(old: &str, new: &str) -> Result {
+    print!("{}", pm64::bgm::diff(&read_bgm(old)?, &read_bgm(new)?));
+    Ok(())
+}
+
+/// Writes the merge even if there are conflicts, in which case our side is kept for them.
+fn merge(base: &str, ours: &str, theirs: &str, output: &str) -> Result {
+    let merge = pm64::bgm::merge(&read_bgm(base)?, &read_bgm(ours)?, &read_bgm(theirs)?)?;
+
+    if is_asm_path(output) {
+        write(output, merge.bgm.to_asm_string())?;
+    } else if output.ends_with(".ron") {
+        write(output, merge.bgm.to_ron_string()?)?;
+    } else {
+        write(output, merge.bgm.as_bytes()?)?;
+    }
+
+    for conflict in &merge.conflicts {
+        eprintln!("conflict: {}", conflict);
+    }
+    if !merge.conflicts.is_empty() {
+        return Err(format!("{} conflicts, kept ours", merge.conflicts.len()).into());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "midly")]
+fn midi_export(input: &str, output: &str, variation: &str) -> Result {
+    let bgm = read_bgm(input)?;
+    write(output, midi::from_bgm(&bgm, variation.parse()?)?)?;
+    Ok(())
+}
+
+fn validate(inputs: &[&str]) -> Result {
+    let mut num_invalid = 0;
+
+    for input in inputs {
+        let problems = match read_bgm(input) {
+            Ok(bgm) => find_problems(&bgm),
+            Err(error) => vec![error.to_string()],
+        };
+
+        if problems.is_empty() {
+            println!("{}: ok", input);
+        } else {
+            num_invalid += 1;
+            for problem in problems {
+                println!("{}: {}", input, problem);
+            }
+        }
+    }
+
+    if num_invalid > 0 {
+        return Err(format!("{} of {} files are invalid", num_invalid, inputs.len()).into());
+    }
+    Ok(())
+}
+
+/// Checks for things the game won't like, or that won't survive being saved.
+fn find_problems(bgm: &Bgm) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (i, variation) in bgm.variations.iter().enumerate() {
+        let Some(variation) = variation else {
+            continue;
+        };
+
+        for segment in &variation.segments {
+            if let Segment::Subseg { track_list, .. } = segment
+                && !bgm.track_lists.contains_key(track_list)
+            {
+                problems.push(format!("variation {} uses missing track list {}", i, track_list));
+            }
+        }
+    }
+
+    for (id, track_list) in &bgm.track_lists {
+        for (i, track) in track_list.tracks.iter().enumerate() {
+            let voices = track.commands.max_polyphony();
+            if track.polyphony == Polyphony::Automatic && voices > MAX_TRACK_VOICES {
+                problems.push(format!(
+                    "track list {} track {} plays {} notes at once, but only {} can play",
+                    id, i, voices, MAX_TRACK_VOICES
+                ));
+            }
+
+            for event in track.commands.iter() {
+                if let Command::SetTrackVoice { index } = event.command
+                    && index as usize >= bgm.instruments.len()
+                {
+                    problems.push(format!(
+                        "track list {} track {} uses missing instrument {}",
+                        id, i, index
+                    ));
+                }
+            }
+        }
+    }
+
+    match bgm.as_bytes() {
+        Ok(data) => match Bgm::from_bytes(&data) {
+            Ok(decoded) => match decoded.as_bytes() {
+                Ok(redecoded) if redecoded == data => {}
+                Ok(_) => problems.push("encoding is not stable across a decode".to_owned()),
+                Err(error) => problems.push(format!("cannot re-encode: {}", error)),
+            },
+            Err(error) => problems.push(format!("cannot decode encoded data: {}", error)),
+        },
+        Err(error) => problems.push(format!("cannot encode: {}", error)),
+    }
+
+    problems
+}
+
+fn read_sbn(path: &str) -> Result<Sbn> {
+    Ok(Sbn::from_bytes(&read(path)?)?)
+}
+
+fn sbn_list(input: &str) -> Result {
+    let sbn = read_sbn(input)?;
+
+    for (i, file) in sbn.files.iter().enumerate() {
+        println!(
+            "file {:>3}: {:?} {} {:#X}B",
+            i,
+            file.name,
+            file.magic().unwrap_or_default().trim_end(),
+            file.data.len()
+        );
+    }
+
+    for (i, song) in sbn.songs.iter().enumerate() {
+        let name = sbn
+            .files
+            .get(song.bgm_file as usize)
+            .map_or("?", |file| file.name.as_str());
+        println!("song {:>3}: file {} {:?}", i, song.bgm_file, name);
+    }
+
+    Ok(())
+}
+
+/// Extracted files are named `<index>_<name>.<type>` so that `sbn pack` knows where to put them back.
+fn sbn_file_name(index: usize, file: &pm64::sbn::File) -> String {
+    let sanitise = |s: &str| -> String { s.trim().chars().filter(|c| c.is_ascii_alphanumeric()).collect() };
+
+    let extension = sanitise(&file.magic().unwrap_or_default()).to_lowercase();
+    format!(
+        "{:03}_{}.{}",
+        index,
+        sanitise(&file.name),
+        if extension.is_empty() { "bin" } else { &extension }
+    )
+}
+
+fn sbn_extract(input: &str, dir: &str) -> Result {
+    let sbn = read_sbn(input)?;
+    let dir = Path::new(dir);
+
+    std::fs::create_dir_all(dir)?;
+    for (i, file) in sbn.files.iter().enumerate() {
+        write(dir.join(sbn_file_name(i, file)), &file.data)?;
+    }
+
+    println!("extracted {} files", sbn.files.len());
+    Ok(())
+}
+
+fn sbn_pack(base: &str, dir: &str, output: &str) -> Result {
+    let mut sbn = read_sbn(base)?;
+
+    let mut num_replaced = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let Some(index) = file_name
+            .split('_')
+            .next()
+            .and_then(|index| index.parse::<usize>().ok())
+        else {
+            return Err(format!("{} does not start with a file index", path.display()).into());
+        };
+        let Some(file) = sbn.files.get_mut(index) else {
+            return Err(format!("{}: there is no file {}", path.display(), index).into());
+        };
+
+        let data = read(&path)?;
+        if data != file.data {
+            file.data = data;
+            num_replaced += 1;
+        }
+    }
+
+    write(output, sbn.as_bytes()?)?;
+    println!("replaced {} files", num_replaced);
+    Ok(())
+}
+
+fn rom_inject(rom: &str, output: &str, inputs: &[&str]) -> Result {
+    let mut rom = read(rom)?;
+    let mut sbn = pm64::rom::read_sbn(&rom)?;
+
+    for input in inputs {
+        let bgm = read_bgm(input)?;
+        let Some(file) = sbn.files.iter_mut().find(|file| file.name == bgm.name) else {
+            return Err(format!("{}: there is no song named {:?} in the ROM", input, bgm.name).into());
+        };
+        file.data = bgm.as_bytes()?;
+    }
+
+    pm64::rom::write_sbn(&mut rom, &sbn)?;
+    write(output, rom)?;
+    Ok(())
 }
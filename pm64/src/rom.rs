@@ -0,0 +1,236 @@
+use std::fmt;
+use std::io::{self, Cursor};
+
+use crate::sbn::{self, SBN_MAX_SIZE, SBN_START, Sbn};
+
+const HEADER_SIZE: usize = 0x40;
+const BOOTCODE_SIZE: usize = 0x1000 - HEADER_SIZE;
+
+const CRC1_OFFSET: usize = 0x10;
+const CRC2_OFFSET: usize = 0x14;
+
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_LENGTH: usize = 0x100000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The ROM is too small to contain an SBN.
+    TooSmall,
+    SbnTooBig {
+        size: u64,
+    },
+    Sbn(sbn::de::Error),
+    Io(io::Error),
+}
+
+impl From<sbn::de::Error> for Error {
+    fn from(sbn: sbn::de::Error) -> Self {
+        Self::Sbn(sbn)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(io: io::Error) -> Self {
+        Self::Io(io)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooSmall => write!(f, "ROM is too small to be Paper Mario"),
+            Error::SbnTooBig { size } => write!(
+                f,
+                "SBN is {:#X}B, but there is only room for {:#X}B in the ROM",
+                size, SBN_MAX_SIZE
+            ),
+            Error::Sbn(source) => write!(f, "{}", source),
+            Error::Io(source) => write!(f, "{}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sbn(source) => Some(source),
+            Error::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn sbn_range(rom: &[u8]) -> Result<std::ops::Range<usize>, Error> {
+    let range = SBN_START as usize..(SBN_START + SBN_MAX_SIZE) as usize;
+    if rom.len() < range.end {
+        return Err(Error::TooSmall);
+    }
+    Ok(range)
+}
+
+pub fn read_sbn(rom: &[u8]) -> Result<Sbn, Error> {
+    let range = sbn_range(rom)?;
+    Ok(Sbn::decode(&mut Cursor::new(&rom[range]))?)
+}
+
+/// Replaces the SBN in the ROM with `sbn`, then fixes the ROM checksum.
+pub fn write_sbn(rom: &mut [u8], sbn: &Sbn) -> Result<(), Error> {
+    let range = sbn_range(rom)?;
+
+    let data = sbn.as_bytes()?;
+    if data.len() as u64 > SBN_MAX_SIZE {
+        return Err(Error::SbnTooBig {
+            size: data.len() as u64,
+        });
+    }
+
+    let region = &mut rom[range];
+    region[..data.len()].copy_from_slice(&data);
+    region[data.len()..].fill(0);
+
+    fix_crc(rom);
+    Ok(())
+}
+
+/// Boot chip of the cartridge, which determines how the checksum is calculated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cic {
+    Nus6101,
+    /// Used by Paper Mario.
+    Nus6102,
+    Nus6103,
+    Nus6105,
+    Nus6106,
+}
+
+impl Cic {
+    /// Identifies the boot chip from the bootcode. Unknown bootcode is assumed to be 6105. Returns `None` if the ROM is
+    /// too small to hold bootcode.
+    pub fn detect(rom: &[u8]) -> Option<Cic> {
+        let bootcode = rom.get(HEADER_SIZE..HEADER_SIZE + BOOTCODE_SIZE)?;
+        Some(match crc32(bootcode) {
+            0x6170A4A1 => Cic::Nus6101,
+            0x90BB6CB5 => Cic::Nus6102,
+            0x0B050EE0 => Cic::Nus6103,
+            0x98BC2C86 => Cic::Nus6105,
+            0xACC8580A => Cic::Nus6106,
+            _ => Cic::Nus6105,
+        })
+    }
+
+    fn seed(self) -> u32 {
+        match self {
+            Cic::Nus6101 | Cic::Nus6102 => 0xF8CA4DDC,
+            Cic::Nus6103 => 0xA3886759,
+            Cic::Nus6105 => 0xDF26F436,
+            Cic::Nus6106 => 0x1FEA617A,
+        }
+    }
+}
+
+/// Calculates the two checksums in the ROM header. Based on uCON64's algorithm, via n64crc.
+pub fn calculate_crc(rom: &[u8]) -> Option<(u32, u32)> {
+    if rom.len() < CHECKSUM_START + CHECKSUM_LENGTH {
+        return None;
+    }
+    Some(calculate_crc_with_cic(rom, Cic::detect(rom)?))
+}
+
+fn calculate_crc_with_cic(rom: &[u8], cic: Cic) -> (u32, u32) {
+    let word = |offset: usize| u32::from_be_bytes(rom[offset..offset + 4].try_into().unwrap());
+
+    let seed = cic.seed();
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+
+    for i in (CHECKSUM_START..CHECKSUM_START + CHECKSUM_LENGTH).step_by(4) {
+        let d = word(i);
+        if t6.wrapping_add(d) < t6 {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = t6.wrapping_add(d);
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1F);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+
+        if cic == Cic::Nus6105 {
+            t1 = t1.wrapping_add(word(HEADER_SIZE + 0x0710 + (i & 0xFF)) ^ d);
+        } else {
+            t1 = t1.wrapping_add(t5 ^ d);
+        }
+    }
+
+    match cic {
+        Cic::Nus6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        Cic::Nus6106 => (
+            t6.wrapping_mul(t4).wrapping_add(t3),
+            t5.wrapping_mul(t2).wrapping_add(t1),
+        ),
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    }
+}
+
+/// Updates the checksums in the ROM header to match its contents. Does nothing if the ROM is too small to have them.
+pub fn fix_crc(rom: &mut [u8]) {
+    if let Some((crc1, crc2)) = calculate_crc(rom) {
+        rom[CRC1_OFFSET..CRC1_OFFSET + 4].copy_from_slice(&crc1.to_be_bytes());
+        rom[CRC2_OFFSET..CRC2_OFFSET + 4].copy_from_slice(&crc2.to_be_bytes());
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        (0..(CHECKSUM_START + CHECKSUM_LENGTH) as u32)
+            .map(|i| (i.wrapping_mul(7) + (i >> 8)) as u8)
+            .collect()
+    }
+
+    /// Expected values are from n64crc.
+    #[test]
+    fn crc() {
+        let rom = rom();
+        assert_eq!(Cic::detect(&rom), Some(Cic::Nus6105));
+        assert_eq!(Cic::detect(&rom[..HEADER_SIZE + BOOTCODE_SIZE - 1]), None);
+        assert_eq!(calculate_crc(&rom), Some((0xDF2AF436, 0xBE3BA2FE)));
+        assert_eq!(calculate_crc_with_cic(&rom, Cic::Nus6102), (0xF8CE4DDC, 0x8D5CCD0E));
+        assert_eq!(calculate_crc_with_cic(&rom, Cic::Nus6103), (0xA3946759, 0xD8588D94));
+        assert_eq!(calculate_crc_with_cic(&rom, Cic::Nus6106), (0x04100F9E, 0x217F1DE2));
+    }
+
+    #[test]
+    fn fix_crc_writes_header() {
+        let mut rom = rom();
+        fix_crc(&mut rom);
+        assert_eq!(
+            rom[CRC1_OFFSET..CRC2_OFFSET + 4],
+            [0xDF, 0x2A, 0xF4, 0x36, 0xBE, 0x3B, 0xA2, 0xFE]
+        );
+    }
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+}
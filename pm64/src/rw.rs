@@ -61,8 +61,8 @@ impl<R: Read> ReadExt for R {
     }
 
     fn read_cstring(&mut self, max_len: u64) -> Result<String> {
-        let buffer: Result<Vec<u8>> = self.take(max_len).bytes().collect();
-        let mut buffer = buffer?;
+        let mut buffer = Vec::new();
+        self.take(max_len).read_to_end(&mut buffer)?;
 
         // Resize `buffer` up to - but not including - its null terminator (if any)
         let mut i = 0;
@@ -169,13 +169,19 @@ impl<W: Write + Seek> WriteExt for W {
     }
 }
 
-/// Aligns a value to the next multiple of n.
+/// Aligns a value to the next multiple of n, or to `u32::MAX` if that is too large.
 pub fn align(value: u32, n: u32) -> u32 {
     if n <= 1 {
         return value;
     }
 
-    if value % n == 0 { n } else { value + (n - value % n) }
+    if value == 0 {
+        n
+    } else if value.is_multiple_of(n) {
+        value
+    } else {
+        value.saturating_add(n - value % n)
+    }
 }
 
 #[cfg(test)]
@@ -187,6 +193,8 @@ mod test {
         assert_eq!(align(0, 5), 5);
         assert_eq!(align(5, 5), 5);
         assert_eq!(align(6, 5), 10);
+        assert_eq!(align(10, 5), 10);
+        assert_eq!(align(u32::MAX - 1, 16), u32::MAX);
 
         // 0 and 1 values for `n` should be a no-op
         assert_eq!(align(36, 0), 36);
@@ -10,6 +10,8 @@ use super::*;
 #[derive(Debug)]
 pub enum Error {
     InvalidMagic,
+    /// The files in the file table add up to more bytes than the whole input, so some of them must overlap.
+    FilesTooLarge,
     Io(io::Error),
 }
 
@@ -25,6 +27,7 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::InvalidMagic => write!(f, "Missing 'SBN' signature at start"),
+            Error::FilesTooLarge => write!(f, "The files in the file table are bigger than the SBN"),
             Error::Io(source) => write!(f, "{}", source),
         }
     }
@@ -71,19 +74,27 @@ impl Sbn {
         let files_start = f.read_u32_be()?;
         let num_files = f.read_u32_be()?;
 
-        f.seek(SeekFrom::Current(12))?; // TODO: what is this data
+        let mut unknowns = Unknowns::default();
+        f.read_exact(&mut unknowns.header_18)?; // TODO: what is this data
 
         let songs_start = f.read_u32_be()?;
 
+        f.read_exact(&mut unknowns.header_28)?;
+
         let mut sbn = Self {
-            files: Vec::with_capacity(num_files as usize),
+            // Not `with_capacity(num_files)`: the count comes from the file, which may be lying
+            files: Vec::new(),
             songs: Vec::new(),
+            unknowns,
         };
 
+        // Each file is read into its own buffer, so entries pointing at the same data would be read many times over
+        let mut files_size = 0u64;
         for i in 0..num_files {
-            f.seek(SeekFrom::Start((files_start + i * 8) as u64))?;
+            f.seek(SeekFrom::Start(files_start as u64 + i as u64 * 8))?;
 
             let file_start = f.read_u32_be()?;
+            let format = f.read_u8()?;
 
             f.seek(SeekFrom::Start(file_start as u64))?;
 
@@ -95,16 +106,26 @@ impl Sbn {
                 data: {
                     f.seek(SeekFrom::Start(file_start as u64))?;
 
-                    let mut bytes = vec![0; file_size as usize];
-                    f.read_exact(&mut bytes)?;
+                    // Read as much as there is, rather than allocating `file_size` bytes up front
+                    let mut bytes = Vec::new();
+                    f.take(file_size as u64).read_to_end(&mut bytes)?;
+                    if bytes.len() != file_size as usize {
+                        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    files_size += file_size as u64;
+                    if files_size > true_size as u64 {
+                        return Err(Error::FilesTooLarge);
+                    }
 
                     bytes
                 },
+                format,
             });
         }
 
-        // Q: why +0x130? what is the data here?
-        f.seek(SeekFrom::Start(songs_start as u64 + 0x130))?;
+        f.seek(SeekFrom::Start(songs_start as u64))?;
+        sbn.unknowns.init_head = vec![0; INIT_SONGS_OFFSET as usize];
+        f.read_exact(&mut sbn.unknowns.init_head)?;
 
         loop {
             sbn.songs.push(Song {
@@ -122,6 +143,57 @@ impl Sbn {
             });
         }
 
+        // The INIT section says how big it is, like any other file
+        let init_end = if sbn.unknowns.init_head.starts_with(INIT_MAGIC.as_bytes()) {
+            songs_start.saturating_add(u32::from_be_bytes(sbn.unknowns.init_head[4..8].try_into().unwrap()))
+        } else {
+            internal_size
+        };
+        let init_tail_len = (init_end as u64).saturating_sub(f.pos()?);
+        f.take(init_tail_len).read_to_end(&mut sbn.unknowns.init_tail)?;
+
         Ok(sbn)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(files_start: u32, num_files: u32) -> Vec<u8> {
+        let mut data = MAGIC.as_bytes().to_vec();
+        data.resize(0x10, 0);
+        data.extend_from_slice(&files_start.to_be_bytes());
+        data.extend_from_slice(&num_files.to_be_bytes());
+        data.resize(0x40, 0);
+        data
+    }
+
+    #[test]
+    fn lying_sizes_are_errors() {
+        // A file table that would wrap around the end of the address space
+        assert!(Sbn::from_bytes(&header(u32::MAX - 4, u32::MAX)).is_err());
+
+        // A file that says it is much bigger than the SBN
+        let mut data = header(0x40, 1);
+        data.extend_from_slice(&[0, 0, 0, 0x48, 0x10, 0, 0, 0]);
+        data.extend_from_slice(b"BGM ");
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        data.extend_from_slice(b"Name");
+        assert!(matches!(Sbn::from_bytes(&data), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn overlapping_files_are_errors() {
+        // Many table entries all pointing at the same file
+        let mut data = header(0x40, 0x100);
+        for _ in 0..0x100 {
+            data.extend_from_slice(&[0, 0, 0x08, 0x40, 0x10, 0, 0, 0]);
+        }
+        data.extend_from_slice(b"BGM ");
+        data.extend_from_slice(&0x400u32.to_be_bytes());
+        data.extend_from_slice(b"Name");
+        data.resize(0x840 + 0x400, 0);
+        assert!(matches!(Sbn::from_bytes(&data), Err(Error::FilesTooLarge)));
+    }
+}
@@ -1,4 +1,5 @@
 use std::io;
+use std::io::SeekFrom;
 use std::io::prelude::*;
 
 use super::*;
@@ -14,7 +15,122 @@ impl Sbn {
         Ok(encoded.into_inner())
     }
 
-    pub fn encode<W: Write + Seek>(&self, _: &mut W) -> Result<()> {
-        todo!("SBN encoding") // TODO
+    /// Writes the header, then the file table, then each file, then the INIT section. Every file and the INIT
+    /// section are aligned to 16 bytes.
+    pub fn encode<W: Write + Seek>(&self, f: &mut W) -> Result<()> {
+        f.seek(SeekFrom::Start(0))?;
+
+        f.write_all(MAGIC.as_bytes())?;
+        let size_offset = SeekFrom::Start(f.pos()?);
+        f.write_u32_be(0)?;
+        f.write_all(&[0; 8])?;
+
+        debug_assert_eq!(f.pos()?, 0x10);
+        f.write_u32_be(HEADER_SIZE)?;
+        f.write_u32_be(self.files.len() as u32)?;
+        f.write_all(&self.unknowns.header_18)?;
+        let init_offset = SeekFrom::Start(f.pos()?);
+        f.write_u32_be(0)?;
+        f.write_all(&self.unknowns.header_28)?;
+
+        debug_assert_eq!(f.pos()? as u32, HEADER_SIZE);
+        let mut file_offsets = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            if file.data.len() > 0xFFFFFF {
+                return Err(Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("file {} is too big", file.name),
+                ));
+            }
+
+            file_offsets.push(SeekFrom::Start(f.pos()?));
+            f.write_u32_be(0)?;
+            f.write_u32_be((file.format as u32) << 24 | file.data.len() as u32)?;
+        }
+
+        for (file, offset) in self.files.iter().zip(file_offsets) {
+            f.align(16)?;
+            let pos = f.pos()?;
+            f.write_u32_be_at(pos as u32, offset)?;
+            f.write_all(&file.data)?;
+        }
+
+        f.align(16)?;
+        let init_start = f.pos()?;
+        f.write_u32_be_at(init_start as u32, init_offset)?;
+
+        let mut init_head = self.unknowns.init_head.clone();
+        init_head.resize(INIT_SONGS_OFFSET as usize, 0);
+        f.write_all(&init_head)?;
+
+        for song in &self.songs {
+            f.write_u16_be(song.bgm_file)?;
+            f.write_u16_be(song.bk_a_file.map_or(0, |file| file.get()))?;
+            f.write_u16_be(song.bk_b_file.map_or(0, |file| file.get()))?;
+            f.write_u16_be(song.unk_file.map_or(0, |file| file.get()))?;
+        }
+        f.write_u16_be(u16::MAX)?; // Terminator
+        f.write_all(&self.unknowns.init_tail)?;
+
+        let init_end = f.pos()?;
+        if init_head.starts_with(INIT_MAGIC.as_bytes()) {
+            f.write_u32_be_at((init_end - init_start) as u32, SeekFrom::Start(init_start + 4))?;
+        }
+
+        f.align(16)?;
+        let size = f.pos()?;
+        f.write_u32_be_at(size as u32, size_offset)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        // File names are read from the files themselves
+        let bgm = crate::bgm::Bgm::new().as_bytes().unwrap();
+
+        let mut init_head = vec![0; INIT_SONGS_OFFSET as usize];
+        init_head[..4].copy_from_slice(INIT_MAGIC.as_bytes());
+        init_head[4..8].copy_from_slice(&(INIT_SONGS_OFFSET + 8 + 2 + 6).to_be_bytes()); // Head, song, terminator, tail
+
+        let sbn = Sbn {
+            files: vec![
+                File {
+                    name: "New ".to_owned(),
+                    data: bgm.clone(),
+                    format: 0x10,
+                },
+                File {
+                    name: "New ".to_owned(),
+                    data: bgm,
+                    format: 0x10,
+                },
+            ],
+            songs: vec![Song {
+                bgm_file: 1,
+                bk_a_file: NonZeroU16::new(2),
+                bk_b_file: None,
+                unk_file: None,
+            }],
+            unknowns: Unknowns {
+                header_18: [1; 12],
+                header_28: [2; 0x18],
+                init_head,
+                init_tail: vec![3; 6],
+            },
+        };
+
+        let encoded = sbn.as_bytes().unwrap();
+        assert_eq!(encoded.len() % 16, 0);
+        assert_eq!(Sbn::from_bytes(&encoded).unwrap(), sbn);
+
+        // Saving and loading keeps what is needed to encode it the same way
+        let saved: Sbn = ron::from_str(&ron::to_string(&sbn).unwrap()).unwrap();
+        assert_eq!(saved.as_bytes().unwrap(), encoded);
     }
 }
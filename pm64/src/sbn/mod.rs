@@ -10,18 +10,52 @@ pub mod de;
 pub mod en;
 
 pub const MAGIC: &str = "SBN ";
+pub const INIT_MAGIC: &str = "INIT";
 pub const SBN_START: u64 = 0xF00000;
 
+/// Space available for the SBN in the ROM, starting at [SBN_START].
+pub const SBN_MAX_SIZE: u64 = 0xA42C40;
+
+/// Size of the SBN header. The file table follows it.
+pub const HEADER_SIZE: u32 = 0x40;
+
+/// Offset of the song table from the start of the INIT section.
+/// Q: what is the data before it?
+pub const INIT_SONGS_OFFSET: u32 = 0x130;
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 pub struct Sbn {
     pub files: Vec<File>,
     pub songs: Vec<Song>,
+
+    /// Data we don't understand yet, kept so that it can be encoded unchanged. It is saved with the rest of the SBN,
+    /// so that one loaded from anywhere but a decoded file encodes the same way.
+    pub unknowns: Unknowns,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
+pub struct Unknowns {
+    /// Header bytes 0x18..0x24.
+    pub header_18: [u8; 12],
+
+    /// Header bytes 0x28..0x40.
+    pub header_28: [u8; 0x18],
+
+    /// The start of the INIT section, up to the song table.
+    pub init_head: Vec<u8>,
+
+    /// The rest of the INIT section, after the song table terminator.
+    pub init_tail: Vec<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TypeDef)]
 pub struct File {
     pub name: String,
     pub data: Vec<u8>,
+
+    /// Upper byte of the file table entry. The game uses this to check the file is of the type it expects.
+    #[serde(default)]
+    pub format: u8,
 }
 
 impl File {
@@ -0,0 +1,305 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+
+use pm64::bgm::*;
+use pm64::sbn::*;
+
+/// Set this environment variable to rewrite the fixtures from their sources, after a change that means the encoded
+/// data should change. Check the new files with the other tests before committing them.
+const BLESS: &str = "PM64_BLESS_FIXTURES";
+
+/// Small songs made for testing, rather than extracted from a ROM. Each `tests/fixtures/*.s` is the assembly source
+/// (see [Bgm::from_asm_string]) of the `.bin` next to it, and `soundbank.sbn` holds all of them.
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+/// The name and source of every fixture, in name order.
+fn sources() -> Vec<(String, String)> {
+    let mut sources: Vec<(String, String)> = fs::read_dir(fixtures_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "s"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read_to_string(path).unwrap())
+        })
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no fixtures found");
+    sources
+}
+
+fn read_fixture(file_name: &str) -> Vec<u8> {
+    fs::read(fixtures_dir().join(file_name))
+        .unwrap_or_else(|e| panic!("cannot read tests/fixtures/{}: {} (run with {}=1)", file_name, e, BLESS))
+}
+
+/// Checks that `data` is what `tests/fixtures/{file_name}` holds, or writes it there when blessing.
+fn check_or_bless(file_name: &str, data: &[u8]) {
+    if std::env::var_os(BLESS).is_some() {
+        fs::write(fixtures_dir().join(file_name), data).unwrap();
+    } else {
+        assert!(
+            read_fixture(file_name) == data,
+            "tests/fixtures/{} is out of date with its source. If that is expected, run with {}=1",
+            file_name,
+            BLESS
+        );
+    }
+}
+
+fn bins() -> Vec<(String, Vec<u8>)> {
+    sources()
+        .into_iter()
+        .map(|(name, _)| {
+            let data = read_fixture(&format!("{}.bin", name));
+            (name, data)
+        })
+        .collect()
+}
+
+#[test]
+fn sources_assemble_to_bins() {
+    for (name, source) in sources() {
+        let bgm = Bgm::from_asm_string(&source).unwrap_or_else(|e| panic!("{}.s: {}", name, e));
+        let data = bgm.as_bytes().unwrap_or_else(|e| panic!("{}.s: {}", name, e));
+        check_or_bless(&format!("{}.bin", name), &data);
+    }
+}
+
+/// The same properties as `tests/matching.rs`:
+///
+///     encode(decode(bin)) == bin
+///     encode(assemble(to_asm(decode(bin)))) == bin
+#[test]
+fn bins_match() {
+    for (name, data) in bins() {
+        let bgm = Bgm::from_bytes(&data).unwrap_or_else(|e| panic!("{}.bin: {}", name, e));
+        assert!(bgm.as_bytes().unwrap() == data, "{}.bin did not match", name);
+
+        let assembled = Bgm::from_asm_string(&bgm.to_asm_string()).unwrap();
+        assert!(
+            assembled.as_bytes().unwrap() == data,
+            "{}.bin did not match after disassembly",
+            name
+        );
+    }
+}
+
+/// Lookups by id such as [Bgm::find_event] assume that no two events or segments share an id.
+#[test]
+fn ids_are_unique() {
+    for (name, source) in sources() {
+        let data = read_fixture(&format!("{}.bin", name));
+        for bgm in [Bgm::from_bytes(&data).unwrap(), Bgm::from_asm_string(&source).unwrap()] {
+            let ids: BTreeSet<_> = bgm.ids().collect();
+            assert_eq!(ids.len(), bgm.ids().count(), "{} has ids in common", name);
+        }
+    }
+}
+
+#[test]
+fn soundbank() {
+    let songs = bins();
+
+    // A song for each BGM
+    let mut init_head = vec![0; INIT_SONGS_OFFSET as usize];
+    init_head[..4].copy_from_slice(INIT_MAGIC.as_bytes());
+    let init_size = INIT_SONGS_OFFSET + songs.len() as u32 * 8 + 2 + 6; // Head, songs, terminator, tail
+    init_head[4..8].copy_from_slice(&init_size.to_be_bytes());
+    let sbn = Sbn {
+        files: songs
+            .iter()
+            .map(|(_, data)| {
+                // Without the Mamar metadata after the end of the BGM, which SBNs leave out
+                let size = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+                File {
+                    name: Bgm::from_bytes(data).unwrap().name,
+                    data: data[..size].to_vec(),
+                    format: 0x10,
+                }
+            })
+            .collect(),
+        songs: (0..songs.len() as u16)
+            .map(|index| Song {
+                bgm_file: index,
+                bk_a_file: NonZeroU16::new(0x30 + index),
+                bk_b_file: None,
+                unk_file: None,
+            })
+            .collect(),
+        unknowns: Unknowns {
+            init_head,
+            init_tail: vec![0; 6],
+            ..Default::default()
+        },
+    };
+    check_or_bless("soundbank.sbn", &sbn.as_bytes().unwrap());
+
+    let data = read_fixture("soundbank.sbn");
+    let decoded = Sbn::from_bytes(&data).unwrap();
+    assert_eq!(decoded, sbn);
+    assert!(decoded.as_bytes().unwrap() == data, "soundbank.sbn did not match");
+    for (file, (name, _)) in decoded.files.iter().zip(&songs) {
+        assert!(
+            file.as_bgm().unwrap().as_bytes().unwrap() == file.data,
+            "{} did not match",
+            name
+        );
+    }
+}
+
+/// The fixtures are meant to test every part of the format between them, so this fails when something isn't covered.
+#[test]
+fn bins_cover_the_format() {
+    let mut seen = BTreeSet::new();
+    for (_, data) in bins() {
+        let bgm = Bgm::from_bytes(&data).unwrap();
+
+        for variation in bgm.variations.iter().flatten() {
+            for segment in &variation.segments {
+                seen.insert(segment_kind(segment));
+            }
+        }
+
+        if !bgm.drums.is_empty() {
+            seen.insert("drums");
+        }
+
+        for track in bgm.track_lists.values().flat_map(|track_list| &track_list.tracks) {
+            if track.is_drum_track {
+                seen.insert("drum track");
+            }
+            if track.is_disabled {
+                seen.insert("disabled track");
+            }
+            if let Polyphony::Link { .. } = track.polyphony {
+                seen.insert("linked polyphony");
+            }
+
+            for event in track.commands.iter() {
+                seen.insert(command_kind(&event.command));
+                match event.command {
+                    // First byte of the delay's encoding
+                    Command::Delay(delay) if delay >= 0x78 => {
+                        seen.insert(EXTENDED_DELAYS[((delay - 0x78) >> 8).min(7)]);
+                    }
+                    Command::Note { length, .. } if length >= 0xC0 => {
+                        seen.insert("long note");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let mut missing: Vec<&str> = SEGMENT_KINDS
+        .iter()
+        .chain(COMMAND_KINDS)
+        .chain(EXTENDED_DELAYS)
+        .chain(&["drums", "drum track", "disabled track", "linked polyphony", "long note"])
+        .filter(|kind| !seen.contains(*kind))
+        .copied()
+        .collect();
+    missing.sort();
+    assert!(missing.is_empty(), "no fixture has {:?}", missing);
+}
+
+const EXTENDED_DELAYS: &[&str] = &[
+    "delay 0x78",
+    "delay 0x79",
+    "delay 0x7A",
+    "delay 0x7B",
+    "delay 0x7C",
+    "delay 0x7D",
+    "delay 0x7E",
+    "delay 0x7F",
+];
+
+const SEGMENT_KINDS: &[&str] = &["Subseg", "StartLoop", "Wait", "EndLoop", "Unknown6", "Unknown7"];
+
+fn segment_kind(segment: &Segment) -> &'static str {
+    // No `_` arm, so that new segments have to be added here (and to a fixture)
+    match segment {
+        Segment::Subseg { .. } => "Subseg",
+        Segment::StartLoop { .. } => "StartLoop",
+        Segment::Wait { .. } => "Wait",
+        Segment::EndLoop { .. } => "EndLoop",
+        Segment::Unknown6 { .. } => "Unknown6",
+        Segment::Unknown7 { .. } => "Unknown7",
+    }
+}
+
+const COMMAND_KINDS: &[&str] = &[
+    "Marker",
+    "End",
+    "Delay",
+    "Note",
+    "MasterTempo",
+    "MasterVolume",
+    "MasterPitchShift",
+    "UnkCmdE3",
+    "MasterTempoFade",
+    "MasterVolumeFade",
+    "MasterEffect",
+    "TrackOverridePatch",
+    "SubTrackVolume",
+    "SubTrackPan",
+    "SubTrackReverb",
+    "SegTrackVolume",
+    "SubTrackCoarseTune",
+    "SubTrackFineTune",
+    "SegTrackTune",
+    "TrackTremolo",
+    "TrackTremoloSpeed",
+    "TrackTremoloTime",
+    "TrackTremoloStop",
+    "UnkCmdF4",
+    "SetTrackVoice",
+    "TrackVolumeFade",
+    "SubTrackReverbType",
+    "Jump",
+    "EventTrigger",
+    "Detour",
+    "UnkCmdFF",
+];
+
+fn command_kind(command: &Command) -> &'static str {
+    // No `_` arm, so that new commands have to be added here (and to a fixture)
+    match command {
+        Command::Marker { .. } => "Marker",
+        Command::End => "End",
+        Command::Delay(..) => "Delay",
+        Command::Note { .. } => "Note",
+        Command::MasterTempo(..) => "MasterTempo",
+        Command::MasterVolume(..) => "MasterVolume",
+        Command::MasterPitchShift { .. } => "MasterPitchShift",
+        Command::UnkCmdE3 { .. } => "UnkCmdE3",
+        Command::MasterTempoFade { .. } => "MasterTempoFade",
+        Command::MasterVolumeFade { .. } => "MasterVolumeFade",
+        Command::MasterEffect { .. } => "MasterEffect",
+        Command::TrackOverridePatch(..) => "TrackOverridePatch",
+        Command::SubTrackVolume(..) => "SubTrackVolume",
+        Command::SubTrackPan(..) => "SubTrackPan",
+        Command::SubTrackReverb(..) => "SubTrackReverb",
+        Command::SegTrackVolume(..) => "SegTrackVolume",
+        Command::SubTrackCoarseTune(..) => "SubTrackCoarseTune",
+        Command::SubTrackFineTune(..) => "SubTrackFineTune",
+        Command::SegTrackTune { .. } => "SegTrackTune",
+        Command::TrackTremolo { .. } => "TrackTremolo",
+        Command::TrackTremoloSpeed(..) => "TrackTremoloSpeed",
+        Command::TrackTremoloTime { .. } => "TrackTremoloTime",
+        Command::TrackTremoloStop => "TrackTremoloStop",
+        Command::UnkCmdF4 { .. } => "UnkCmdF4",
+        Command::SetTrackVoice { .. } => "SetTrackVoice",
+        Command::TrackVolumeFade { .. } => "TrackVolumeFade",
+        Command::SubTrackReverbType { .. } => "SubTrackReverbType",
+        Command::Jump { .. } => "Jump",
+        Command::EventTrigger { .. } => "EventTrigger",
+        Command::Detour { .. } => "Detour",
+        Command::UnkCmdFF { .. } => "UnkCmdFF",
+    }
+}
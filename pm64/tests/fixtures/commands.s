@@ -0,0 +1,42 @@
+; Every sequence command. The master track has the commands that affect the whole song.
+.name "CMD "
+
+.variation 0
+    subseg 1
+
+.instrument bank_set=Music bank=2 instrument=5 envelope=1 volume=90 pan=-10 reverb=20 coarse_tune=1 fine_tune=2 pad_07=3
+
+.track_list 1
+.track 0 polyphony=voices:0
+    master_tempo 140
+    master_volume 100
+    master_pitch_shift 12
+    unk_cmd_e3 2
+    master_tempo_fade 96 120
+    master_volume_fade 48 80
+    master_effect 0 1
+    jump 0x1234 5
+    event_trigger 0xDEADBEEF
+    unk_cmd_ff 1 2 3
+    delay 192
+    end
+.track 1 name="Everything" polyphony=auto
+    set_track_voice 0
+    track_override_patch Aux 3 4 1
+    sub_track_volume 100
+    sub_track_pan -20
+    sub_track_reverb 30
+    seg_track_volume 110
+    sub_track_coarse_tune 2
+    sub_track_fine_tune 3
+    seg_track_tune -100
+    track_tremolo 10 20 30
+    track_tremolo_speed 40
+    track_tremolo_time 50
+    track_tremolo_stop
+    unk_cmd_f4 10 20
+    track_volume_fade 24 90
+    sub_track_reverb_type 1
+    note 140 100 96
+    delay 96
+    end
@@ -0,0 +1,29 @@
+; Detours back over earlier commands, including one inside another's range.
+.name "DTR "
+
+.variation 0
+    subseg 1
+
+.instrument bank_set=Music bank=1 instrument=3 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+
+.track_list 1
+.track 0 polyphony=voices:0
+    master_tempo 120
+    delay 0x200
+    end
+.track 1 polyphony=auto
+    set_track_voice 0
+verse:
+    note 140 100 24
+    delay 24
+chorus:
+    note 144 100 24
+    delay 24
+"chorus end":
+    note 147 100 48
+    delay 48
+"verse end":
+    detour verse "verse end"
+    detour chorus "chorus end"
+    detour chorus "chorus end"
+    end
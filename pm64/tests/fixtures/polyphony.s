@@ -0,0 +1,51 @@
+; Each kind of polyphony, drums, and disabled tracks.
+.name "POLY"
+
+.variation 0
+    subseg 1
+
+.drum bank_set=Aux bank=0 instrument=1 envelope=0 coarse_tune=0 fine_tune=0 volume=100 pan=64 reverb=10 rand_tune=1 rand_volume=2 rand_pan=3 rand_reverb=4
+.drum bank_set=Aux bank=0 instrument=2 envelope=0 coarse_tune=12 fine_tune=0 volume=90 pan=32 reverb=0 rand_tune=0 rand_volume=0 rand_pan=0 rand_reverb=0 pad_0b=1
+.instrument bank_set=Music bank=1 instrument=3 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+.instrument bank_set=Music bank=1 instrument=4 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+
+.track_list 1
+.track 0 polyphony=voices:0
+    master_tempo 120
+    delay 96
+    end
+; Three notes at once, with the voices worked out from them
+.track 1 name="Chords" polyphony=auto
+    set_track_voice 0
+    note 140 100 96
+    note 144 100 96
+    note 147 100 96
+    delay 96
+    end
+; Plays instead of its parent, using the parent's voices
+.track 2 name="Linked" polyphony=link:1
+    set_track_voice 1
+    note 152 100 96
+    delay 96
+    end
+.track 3 name="Manual" polyphony=voices:2
+    set_track_voice 1
+    note 140 100 96
+    delay 96
+    end
+.track 4 name="Priority" polyphony=priority:3
+    set_track_voice 1
+    note 140 100 96
+    delay 96
+    end
+.track 5 name="Drums" drums polyphony=auto
+    note 128 100 24
+    delay 48
+    note 129 100 24
+    delay 48
+    end
+.track 6 name="Muted" disabled polyphony=auto
+    set_track_voice 0
+    note 140 100 96
+    delay 96
+    end
@@ -0,0 +1,44 @@
+; Every segment command, and a track list shared between variations.
+.name "SEG "
+
+.variation 0
+    start_loop 0
+    subseg 1
+    end_loop 0 3
+    wait
+    unknown6 1 2
+    unknown7 2 1
+    subseg 2
+
+.variation 1
+    subseg 1
+
+; Variation 2 is left empty
+.variation 3
+    wait
+    subseg 2
+
+.instrument bank_set=Music bank=1 instrument=3 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+
+.track_list 1
+.track 0 polyphony=voices:0
+    master_tempo 120
+    delay 48
+    end
+.track 1 polyphony=auto
+    set_track_voice 0
+    note 140 100 24
+    delay 24
+    end
+
+.track_list 2
+.track 0 polyphony=voices:0
+    master_tempo 90
+    delay 96
+    end
+.track 2 polyphony=auto
+    set_track_voice 0
+    note 128 80 48
+    note 131 80 48
+    delay 96
+    end
@@ -0,0 +1,43 @@
+; Delays and note lengths at the edges of their one- and two-byte encodings.
+.name "TIME"
+
+.variation 0
+    subseg 1
+
+.instrument bank_set=Music bank=1 instrument=3 envelope=0 volume=100 pan=64 reverb=0 coarse_tune=0 fine_tune=0
+
+.track_list 1
+.track 0 polyphony=voices:0
+    master_tempo 120
+    delay 1
+    delay 0x77
+    ; Extended delays, with each first byte from 0x78 to 0x7F, up to the longest one command can hold
+    delay 0x78
+    delay 0x79
+    delay 0xF0
+    delay 0x178
+    delay 0x278
+    delay 0x378
+    delay 0x478
+    delay 0x578
+    delay 0x678
+    delay 0x778
+    delay 0x7F0
+    delay 0x878
+    ; Too long for one command, so encoded as several
+    delay 0x1000
+    end
+.track 1 polyphony=auto
+    set_track_voice 0
+    note 140 100 1
+    delay 1
+    note 140 100 0xBF
+    delay 0xBF
+    ; Long notes, which take two bytes
+    note 140 100 0xC0
+    delay 0xC0
+    note 140 100 0x100
+    delay 0x100
+    note 140 100 0x40BF
+    delay 0x40BF
+    end
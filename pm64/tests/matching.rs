@@ -14,9 +14,10 @@ fn pad_to_16(cursor: &mut Cursor<Vec<u8>>) {
     cursor.write_all(&vec![0; pad]).unwrap();
 }
 
-/// Each test generated by this macro tests for the following property:
+/// Each test generated by this macro tests for the following properties:
 ///
 ///     encode(decode(bin)) == bin
+///     encode(assemble(to_asm(decode(bin)))) == bin
 ///
 /// That is, decoding and re-encoding a valid song with no changes must equal the original input. We call this
 /// **matching**, and it's required for the [decompilation project](https://github.com/ethteck/papermario).
@@ -96,12 +97,27 @@ macro_rules! test_matching {
                     nonmatching_bin
                 );
             }
+
+            // Assembling the disassembly must match too
+            let assembled = Bgm::from_asm_string(&bgm.to_asm_string()).expect("assemble error");
+            let mut encoded = Cursor::new(Vec::new());
+            assembled.encode(&mut encoded).unwrap();
+            pad_to_16(&mut encoded);
+            assert!(
+                encoded.into_inner() == original,
+                "assembled song did not match original"
+            );
         }
     };
 }
 
-/// Test that passes if the song does *not* match the original.
-/// This is done so we can easily detect breakages in songs that should match already (test_matching).
+/// Each test generated by this macro tests for the following properties, for songs that don't match yet:
+///
+///     encode(decode(bin)) != bin
+///     encode(decode(encode(decode(bin)))) == encode(decode(bin))
+///
+/// So a song that starts to match fails here, and has to be moved to `test_matching!` to keep it matching. Until then,
+/// its encoding must at least be stable, so that a regression in it isn't hidden by it not matching already.
 macro_rules! test_non_matching {
     ($song:ident) => {
         #[allow(non_snake_case)]
@@ -120,9 +136,18 @@ macro_rules! test_non_matching {
             let encoded = encoded.into_inner();
 
             // Check the output DOESN'T match
-            if encoded == original {
-                panic!("Expected song to not match, but it does :D");
-            }
+            assert!(
+                encoded != original,
+                "{} matches now! Move it to test_matching!",
+                stringify!($song)
+            );
+
+            // Re-decoding and re-encoding gives the same output
+            let bgm = Bgm::decode(&mut Cursor::new(&encoded)).expect("re-decode error");
+            let mut reencoded = Cursor::new(Vec::new());
+            bgm.encode(&mut reencoded).unwrap();
+            pad_to_16(&mut reencoded);
+            assert!(reencoded.into_inner() == encoded, "re-encoded song changed");
         }
     };
 }
@@ -299,7 +324,9 @@ test_matching!(The_End_Low_Frequency__2E);
 test_matching!(SMW_Remix_2F);
 test_matching!(New_Partner_82);
 
-// Non-matching songs (tested for NON-matching so regressions or fixes can be detected):
+// Non-matching songs (tested for NON-matching so regressions or fixes can be detected). These have not been checked
+// since command sequences started to be put back where they were decoded from; each one that matches now fails, and
+// should be moved above.
 test_non_matching!(Flower_Fields_30);
 test_non_matching!(Peach_s_Castle_inside_Bubble_5E);
 test_non_matching!(Running_around_the_Heart_Pillar_in_Ch1_84);
@@ -334,8 +361,10 @@ fn shared_subsegment_tracks_ptr() {
     assert_eq!(tracks_0_2, tracks_1_1);
 }
 
+/// Like `test_matching!`, for the whole SBN:
+///
+///     encode(decode(sbn)) == sbn
 #[test]
-#[ignore] // TEMP: bgm::en not yet implemented
 fn sbn() {
     let original = include_bytes!("bin/sbn.bin");
     let sbn = Sbn::from_bytes(original).unwrap();
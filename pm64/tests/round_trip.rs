@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+
+use pm64::bgm::*;
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+proptest! {
+    /// Tests for the following properties, for arbitrary valid songs:
+    ///
+    ///     decode(encode(bgm)) == bgm
+    ///     encode(decode(encode(bgm))) == encode(bgm)
+    ///
+    /// Unlike `tests/matching.rs`, this needs no data extracted from a ROM. Songs are compared without their ids,
+    /// file positions and marker labels, which the decoder makes up.
+    #[test]
+    fn decode_encode(bgm in bgm()) {
+        let data = bgm.as_bytes().unwrap();
+        let decoded = Bgm::from_bytes(&data).unwrap();
+        prop_assert_eq!(decoded.as_bytes().unwrap(), data);
+        prop_assert_eq!(normalized(decoded), normalized(bgm));
+    }
+}
+
+fn normalized(mut bgm: Bgm) -> Bgm {
+    for variation in bgm.variations.iter_mut().flatten() {
+        for segment in &mut variation.segments {
+            *segment.id_mut() = None;
+        }
+    }
+
+    for track_list in bgm.track_lists.values_mut() {
+        track_list.pos = None;
+        for track in &mut track_list.tracks {
+            track.pos = None;
+
+            // Label markers in the order they appear
+            let mut labels = HashMap::new();
+            let mut relabel = |label: MarkerId| {
+                let next = format!("label {}", labels.len());
+                labels.entry(label).or_insert(next).clone()
+            };
+            let commands: Vec<Command> = std::mem::take(&mut track.commands)
+                .to_command_vec()
+                .into_iter()
+                .map(|command| match command {
+                    Command::Marker { label } => Command::Marker { label: relabel(label) },
+                    Command::Detour { start_label, end_label } => Command::Detour {
+                        start_label: relabel(start_label),
+                        end_label: relabel(end_label),
+                    },
+                    command => command,
+                })
+                .collect();
+            track.commands = commands.into();
+            for id in track.commands.ids_mut() {
+                *id = 0;
+            }
+        }
+    }
+
+    bgm
+}
+
+fn bgm() -> impl Strategy<Value = Bgm> {
+    (
+        "[A-Z0-9 ]{0,4}",
+        [variation(), variation(), variation(), variation()],
+        vec(drum(), 0..4),
+        vec(instrument(), 0..8),
+        vec(track_list(), 1..4),
+    )
+        .prop_map(|(name, variations, drums, instruments, track_lists)| {
+            let mut bgm = Bgm {
+                name,
+                drums,
+                instruments,
+                ..Default::default()
+            };
+
+            // Give track lists ids in the order they are first used, like the decoder does, and leave out unused ones
+            let mut ids = HashMap::new();
+            for (index, segments) in variations.into_iter().enumerate() {
+                bgm.variations[index] = segments.map(|mut segments| {
+                    for segment in &mut segments {
+                        if let Segment::Subseg { track_list, .. } = segment {
+                            let index = *track_list as usize % track_lists.len();
+                            *track_list = *ids
+                                .entry(index)
+                                .or_insert_with(|| bgm.add_track_list(track_lists[index].clone()));
+                        }
+                    }
+                    Variation { segments }
+                });
+            }
+
+            bgm
+        })
+}
+
+/// Subseg track lists are indices into the song's track lists, which [bgm] turns into ids.
+fn variation() -> impl Strategy<Value = Option<Vec<Segment>>> {
+    let segment = prop_oneof![
+        3 => any::<u64>().prop_map(|track_list| Segment::Subseg { id: None, track_list }),
+        1 => (0..0x1000u16).prop_map(|label_index| Segment::StartLoop { id: None, label_index }),
+        1 => Just(Segment::Wait { id: None }),
+        1 => (0..0x20u8, 0..0x80u8).prop_map(|(label_index, iter_count)| Segment::EndLoop {
+            id: None,
+            label_index,
+            iter_count,
+        }),
+        1 => (0..0x20u8, 0..0x80u8).prop_map(|(label_index, iter_count)| Segment::Unknown6 {
+            id: None,
+            label_index,
+            iter_count,
+        }),
+        1 => (0..0x20u8, 0..0x80u8).prop_map(|(label_index, iter_count)| Segment::Unknown7 {
+            id: None,
+            label_index,
+            iter_count,
+        }),
+    ];
+    proptest::option::of(vec(segment, 0..6))
+}
+
+fn patch() -> impl Strategy<Value = PatchAddress> {
+    (0..8u8, 0..16u8, 0..16u8, 0..4u8).prop_map(|(bank_set, bank, instrument, envelope)| PatchAddress {
+        bank_set: bank_set.try_into().unwrap(),
+        bank,
+        instrument,
+        envelope,
+    })
+}
+
+fn drum() -> impl Strategy<Value = Drum> {
+    (patch(), any::<[u8; 10]>()).prop_map(|(patch, bytes)| Drum {
+        patch,
+        coarse_tune: bytes[0],
+        fine_tune: bytes[1],
+        volume: bytes[2],
+        pan: bytes[3] as i8,
+        reverb: bytes[4],
+        rand_tune: bytes[5],
+        rand_volume: bytes[6],
+        rand_pan: bytes[7],
+        rand_reverb: bytes[8],
+        pad_0b: bytes[9],
+    })
+}
+
+fn instrument() -> impl Strategy<Value = Instrument> {
+    (patch(), any::<[u8; 6]>()).prop_map(|(patch, bytes)| Instrument {
+        patch,
+        volume: bytes[0],
+        pan: bytes[1] as i8,
+        reverb: bytes[2],
+        coarse_tune: bytes[3],
+        fine_tune: bytes[4],
+        pad_07: bytes[5],
+    })
+}
+
+fn track_list() -> impl Strategy<Value = TrackList> {
+    (track(true), vec(track(false), 15)).prop_map(|(master, tracks)| TrackList {
+        pos: None,
+        tracks: std::iter::once(master)
+            .chain(tracks)
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap(),
+    })
+}
+
+/// Only the names of tracks other than the master track are kept.
+fn track(is_master: bool) -> impl Strategy<Value = Track> {
+    let name = if is_master {
+        Just(String::new()).boxed()
+    } else {
+        "[a-zA-Z ]{0,8}".boxed()
+    };
+    let commands = prop_oneof![1 => Just(Vec::new()), 1 => commands()];
+    (name, any::<bool>(), any::<bool>(), commands, polyphony()).prop_map(
+        |(name, is_disabled, is_drum_track, commands, polyphony)| {
+            let commands: CommandSeq = commands.into();
+            let polyphony = canonical_polyphony(polyphony, commands.max_polyphony());
+            Track {
+                name,
+                is_disabled,
+                polyphony,
+                is_drum_track,
+                commands,
+                pos: None,
+            }
+        },
+    )
+}
+
+fn polyphony() -> impl Strategy<Value = Polyphony> {
+    prop_oneof![
+        Just(Polyphony::Automatic),
+        (0..=4u8).prop_map(|voices| Polyphony::Manual { voices }),
+        (0..15u8).prop_map(|parent| Polyphony::Link { parent }),
+        (2..=4u8).prop_map(|priority| Polyphony::Other { priority }),
+    ]
+}
+
+/// Polyphony is stored as a number of voices, and the decoder gives [Polyphony::Automatic] when that number is what
+/// would be calculated from the commands, so make `polyphony` what the decoder would give for commands that use
+/// `used` voices.
+fn canonical_polyphony(polyphony: Polyphony, used: u8) -> Polyphony {
+    match polyphony {
+        Polyphony::Automatic if used > 4 => Polyphony::Manual { voices: 4 },
+        Polyphony::Manual { voices } if voices == used => Polyphony::Automatic,
+        polyphony => polyphony,
+    }
+}
+
+/// A sequence ending in [Command::End], which may detour back over some of its commands.
+fn commands() -> impl Strategy<Value = Vec<Command>> {
+    (
+        vec(command(), 0..8),
+        vec(command(), 1..8),
+        vec(command(), 0..8),
+        any::<bool>(),
+    )
+        .prop_map(|(before, detoured, after, detour)| {
+            let mut commands = before;
+            if detour {
+                commands.push(Command::Marker { label: "start".into() });
+                commands.extend(detoured);
+                commands.push(Command::Marker { label: "end".into() });
+                commands.push(Command::Detour {
+                    start_label: "start".into(),
+                    end_label: "end".into(),
+                });
+            } else {
+                commands.extend(detoured);
+            }
+            commands.extend(after);
+            commands.push(Command::End);
+            commands
+        })
+}
+
+/// Any command other than the ones that structure a sequence ([End](Command::End), [Marker](Command::Marker) and
+/// [Detour](Command::Detour)), with values that encode to exactly one command.
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        4 => prop_oneof![
+            1..0x78usize,
+            (0..8usize, 0..=0x78usize).prop_map(|(num_256s, extend)| 0x78 + num_256s * 256 + extend),
+        ]
+        .prop_map(Command::Delay),
+        4 => (0x80..=0xD3u8, any::<u8>(), 0..=0x40BFu16).prop_map(|(pitch, velocity, length)| Command::Note {
+            pitch,
+            velocity,
+            length,
+        }),
+        1 => any::<u16>().prop_map(Command::MasterTempo),
+        1 => any::<u8>().prop_map(Command::MasterVolume),
+        1 => any::<u8>().prop_map(|cent| Command::MasterPitchShift { cent }),
+        1 => any::<u8>().prop_map(|effect_type| Command::UnkCmdE3 { effect_type }),
+        1 => any::<(u16, u16)>().prop_map(|(time, value)| Command::MasterTempoFade { time, value }),
+        1 => any::<(u16, u8)>().prop_map(|(time, volume)| Command::MasterVolumeFade { time, volume }),
+        1 => any::<(u8, u8)>().prop_map(|(index, value)| Command::MasterEffect { index, value }),
+        1 => patch().prop_map(Command::TrackOverridePatch),
+        1 => any::<u8>().prop_map(Command::SubTrackVolume),
+        1 => any::<i8>().prop_map(Command::SubTrackPan),
+        1 => any::<u8>().prop_map(Command::SubTrackReverb),
+        1 => any::<u8>().prop_map(Command::SegTrackVolume),
+        1 => any::<u8>().prop_map(Command::SubTrackCoarseTune),
+        1 => any::<u8>().prop_map(Command::SubTrackFineTune),
+        1 => any::<i16>().prop_map(|bend| Command::SegTrackTune { bend }),
+        1 => any::<(u8, u8, u8)>().prop_map(|(amount, speed, time)| Command::TrackTremolo { amount, speed, time }),
+        1 => any::<u8>().prop_map(Command::TrackTremoloSpeed),
+        1 => any::<u8>().prop_map(|time| Command::TrackTremoloTime { time }),
+        1 => Just(Command::TrackTremoloStop),
+        1 => any::<(u8, u8)>().prop_map(|(pan0, pan1)| Command::UnkCmdF4 { pan0, pan1 }),
+        1 => any::<u8>().prop_map(|index| Command::SetTrackVoice { index }),
+        1 => any::<(u16, u8)>().prop_map(|(time, value)| Command::TrackVolumeFade { time, value }),
+        1 => any::<u8>().prop_map(|index| Command::SubTrackReverbType { index }),
+        1 => any::<(u16, u8)>().prop_map(|(unk_00, unk_02)| Command::Jump { unk_00, unk_02 }),
+        1 => any::<u32>().prop_map(|event_info| Command::EventTrigger { event_info }),
+        1 => any::<(u8, u8, u8)>().prop_map(|(unk_00, unk_01, unk_02)| Command::UnkCmdFF { unk_00, unk_01, unk_02 }),
+    ]
+}